@@ -0,0 +1,503 @@
+use std::io::Write;
+use std::time::UNIX_EPOCH;
+
+use flate2::write::DeflateEncoder;
+use thiserror::Error;
+
+use crate::attributes::{AttrValue, Attributes};
+use crate::date;
+use crate::repo::Repo;
+use crate::{decode_object, GitObject, ReadObjectError};
+
+const TREE_MODE: u32 = 0o40000;
+const SYMLINK_MODE: u32 = 0o120000;
+const GITLINK_MODE: u32 = 0o160000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveFormat {
+	Tar,
+	Zip,
+}
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error("{0} is not a tree")]
+	NotATree(String),
+
+	#[error("{0} is not a blob")]
+	NotABlob(String),
+
+	#[error("Path too long for a tar archive: {0}")]
+	PathTooLong(String),
+
+	#[error("File too large for a zip archive: {0}")]
+	TooLarge(String),
+}
+
+/// File, directory or symlink stored in an archive.
+struct Entry {
+	/// Path relative to the archive root, without a trailing `/` for directories.
+	path: String,
+	/// Git mode of the entry (`040000`, `100644`, `100755` or `120000`).
+	mode: u32,
+	/// File content, or target of a symlink. Empty for directories.
+	content: Vec<u8>,
+}
+
+impl Entry {
+	fn is_dir(&self) -> bool {
+		self.mode == TREE_MODE
+	}
+
+	fn is_symlink(&self) -> bool {
+		self.mode == SYMLINK_MODE
+	}
+
+	/// Unix permissions the entry is extracted with, like git's default `tar.umask` of 002.
+	fn permissions(&self) -> u32 {
+		match self.mode {
+			SYMLINK_MODE => 0o777,
+			0o100644 => 0o664,
+			_ => 0o775,
+		}
+	}
+}
+
+/// Writes the contents of tree `tree` to `out` as an archive of given format.
+///
+/// Submodules become empty directories, as their content isn't part of this repository. Paths
+/// with the `export-ignore` attribute in the tree's `.gitattributes` are left out.
+pub fn write_archive(
+	repo: &Repo,
+	tree: &[u8; 20],
+	format: ArchiveFormat,
+	out: impl Write,
+) -> Result<(), ArchiveError> {
+	let attributes = read_attributes(repo, tree)?;
+	let mut entries = Vec::new();
+	collect_entries(repo, "", tree, &attributes, &mut entries)?;
+	let mtime = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+	match format {
+		ArchiveFormat::Tar => write_tar(&entries, mtime, out),
+		ArchiveFormat::Zip => write_zip(&entries, mtime, out),
+	}
+}
+
+/// Parses the `.gitattributes` at the root of tree `hash`, if any.
+fn read_attributes(repo: &Repo, hash: &[u8; 20]) -> Result<Attributes, ArchiveError> {
+	let hash_str = hex::encode(hash);
+	let GitObject::Tree(tree_entries) = decode_object(repo, hash_str.clone())? else {
+		return Err(ArchiveError::NotATree(hash_str));
+	};
+	let Some(entry) = tree_entries.iter().find(|e| e.name == ".gitattributes") else {
+		return Ok(Attributes::default());
+	};
+	let object_hash = hex::encode(*entry.object_hash);
+	let GitObject::Blob(content) = decode_object(repo, object_hash.clone())? else {
+		return Err(ArchiveError::NotABlob(object_hash));
+	};
+	Ok(Attributes::parse(&String::from_utf8_lossy(&content)))
+}
+
+/// Lists the entries of tree `hash` recursively, every directory before its contents.
+fn collect_entries(
+	repo: &Repo,
+	prefix: &str,
+	hash: &[u8; 20],
+	attributes: &Attributes,
+	out: &mut Vec<Entry>,
+) -> Result<(), ArchiveError> {
+	let hash_str = hex::encode(hash);
+	let GitObject::Tree(tree_entries) = decode_object(repo, hash_str.clone())? else {
+		return Err(ArchiveError::NotATree(hash_str));
+	};
+
+	for tree_entry in tree_entries.iter() {
+		let path = format!("{prefix}{}", tree_entry.name);
+		let is_dir = tree_entry.mode == TREE_MODE;
+		if attributes.get(&path, is_dir, "export-ignore") == Some(&AttrValue::Set) {
+			continue;
+		}
+		match tree_entry.mode {
+			TREE_MODE => {
+				out.push(Entry {
+					path: path.clone(),
+					mode: TREE_MODE,
+					content: Vec::new(),
+				});
+				collect_entries(
+					repo,
+					&format!("{path}/"),
+					&tree_entry.object_hash,
+					attributes,
+					out,
+				)?;
+			}
+			GITLINK_MODE => out.push(Entry {
+				path,
+				mode: TREE_MODE,
+				content: Vec::new(),
+			}),
+			mode => {
+				let object_hash = hex::encode(*tree_entry.object_hash);
+				let GitObject::Blob(content) = decode_object(repo, object_hash.clone())? else {
+					return Err(ArchiveError::NotABlob(object_hash));
+				};
+				out.push(Entry {
+					path,
+					mode,
+					content: content.into_owned(),
+				});
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Writes `entries` as a POSIX ustar archive.
+fn write_tar(entries: &[Entry], mtime: u64, mut out: impl Write) -> Result<(), ArchiveError> {
+	for entry in entries {
+		let mut header = [0_u8; 512];
+
+		let path = if entry.is_dir() {
+			format!("{}/", entry.path)
+		} else {
+			entry.path.clone()
+		};
+		let (prefix, name) =
+			split_tar_path(&path).ok_or_else(|| ArchiveError::PathTooLong(entry.path.clone()))?;
+		header[..name.len()].copy_from_slice(name.as_bytes());
+		header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+		let (type_flag, size) = if entry.is_dir() {
+			(b'5', 0)
+		} else if entry.is_symlink() {
+			if entry.content.len() > 100 {
+				return Err(ArchiveError::PathTooLong(
+					String::from_utf8_lossy(&entry.content).into_owned(),
+				));
+			}
+			header[157..157 + entry.content.len()].copy_from_slice(&entry.content);
+			(b'2', 0)
+		} else {
+			(b'0', entry.content.len())
+		};
+
+		write_octal(&mut header[100..108], entry.permissions() as u64);
+		write_octal(&mut header[108..116], 0);
+		write_octal(&mut header[116..124], 0);
+		write_octal(&mut header[124..136], size as u64);
+		write_octal(&mut header[136..148], mtime);
+		header[156] = type_flag;
+		header[257..263].copy_from_slice(b"ustar\0");
+		header[263..265].copy_from_slice(b"00");
+		header[265..269].copy_from_slice(b"root");
+		header[297..301].copy_from_slice(b"root");
+
+		// The checksum is computed with its own field filled with spaces.
+		header[148..156].fill(b' ');
+		let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+		header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+		out.write_all(&header)?;
+		if size > 0 {
+			out.write_all(&entry.content)?;
+			out.write_all(&[0; 512][..(512 - size % 512) % 512])?;
+		}
+	}
+
+	// End of archive marker
+	out.write_all(&[0; 1024])?;
+	out.flush()?;
+	Ok(())
+}
+
+/// Splits `path` into the ustar `prefix` (up to 155 bytes) and `name` (up to 100 bytes) fields.
+fn split_tar_path(path: &str) -> Option<(&str, &str)> {
+	if path.len() <= 100 {
+		return Some(("", path));
+	}
+	// Directories keep their trailing slash in the name field.
+	let search_end = path.len() - 1;
+	path[..search_end]
+		.match_indices('/')
+		.map(|(idx, _)| (&path[..idx], &path[idx + 1..]))
+		.find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+}
+
+/// Writes `value` as a zero padded, null terminated octal number filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+	let digits = format!("{value:0width$o}\0", width = field.len() - 1);
+	field.copy_from_slice(&digits.as_bytes()[digits.len() - field.len()..]);
+}
+
+/// Writes `entries` as a zip archive, deflating file contents.
+fn write_zip(entries: &[Entry], mtime: u64, mut out: impl Write) -> Result<(), ArchiveError> {
+	let (time, date) = dos_date_time(mtime);
+	let mut central_directory = Vec::new();
+	let mut offset = 0_usize;
+
+	for entry in entries {
+		let name = if entry.is_dir() {
+			format!("{}/", entry.path)
+		} else {
+			entry.path.clone()
+		};
+		let too_large = || ArchiveError::TooLarge(entry.path.clone());
+
+		let mut crc = flate2::Crc::new();
+		crc.update(&entry.content);
+		// Directories and symlinks are stored as is.
+		let (method, data) = if entry.is_dir() || entry.is_symlink() {
+			(0_u16, entry.content.clone())
+		} else {
+			let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+			encoder.write_all(&entry.content)?;
+			(8, encoder.finish()?)
+		};
+		let compressed_size = u32::try_from(data.len()).map_err(|_| too_large())?;
+		let size = u32::try_from(entry.content.len()).map_err(|_| too_large())?;
+		let local_header_offset = u32::try_from(offset).map_err(|_| too_large())?;
+
+		// Fields shared by the local header and the central directory record: version needed,
+		// flags (bit 11: UTF-8 names), method, time, date, CRC-32, sizes, name length.
+		let mut common = Vec::with_capacity(26);
+		common.extend(20_u16.to_le_bytes());
+		common.extend(0x0800_u16.to_le_bytes());
+		common.extend(method.to_le_bytes());
+		common.extend(time.to_le_bytes());
+		common.extend(date.to_le_bytes());
+		common.extend(crc.sum().to_le_bytes());
+		common.extend(compressed_size.to_le_bytes());
+		common.extend(size.to_le_bytes());
+		common.extend((name.len() as u16).to_le_bytes());
+
+		let mut local_header = 0x04034b50_u32.to_le_bytes().to_vec();
+		local_header.extend(&common);
+		local_header.extend(0_u16.to_le_bytes()); // extra field length
+		local_header.extend(name.as_bytes());
+		out.write_all(&local_header)?;
+		out.write_all(&data)?;
+		offset += local_header.len() + data.len();
+
+		// Made by unix (3), zip 2.0. The unix mode lives in the upper half of the external
+		// attributes, 0x10 is the MS-DOS directory flag.
+		let unix_mode = (entry.mode & !0o777) | entry.permissions();
+		let dos_attributes = if entry.is_dir() { 0x10 } else { 0 };
+		central_directory.extend(0x02014b50_u32.to_le_bytes());
+		central_directory.extend((3_u16 << 8 | 20).to_le_bytes());
+		central_directory.extend(&common);
+		central_directory.extend(0_u16.to_le_bytes()); // extra field length
+		central_directory.extend(0_u16.to_le_bytes()); // comment length
+		central_directory.extend(0_u16.to_le_bytes()); // disk number
+		central_directory.extend(0_u16.to_le_bytes()); // internal attributes
+		central_directory.extend((unix_mode << 16 | dos_attributes).to_le_bytes());
+		central_directory.extend(local_header_offset.to_le_bytes());
+		central_directory.extend(name.as_bytes());
+	}
+
+	let entry_count = u16::try_from(entries.len())
+		.map_err(|_| ArchiveError::TooLarge("too many entries".to_string()))?;
+	let central_directory_offset =
+		u32::try_from(offset).map_err(|_| ArchiveError::TooLarge("archive".to_string()))?;
+	out.write_all(&central_directory)?;
+
+	let mut end = 0x06054b50_u32.to_le_bytes().to_vec();
+	end.extend(0_u16.to_le_bytes()); // this disk
+	end.extend(0_u16.to_le_bytes()); // disk with the central directory
+	end.extend(entry_count.to_le_bytes());
+	end.extend(entry_count.to_le_bytes());
+	end.extend((central_directory.len() as u32).to_le_bytes());
+	end.extend(central_directory_offset.to_le_bytes());
+	end.extend(0_u16.to_le_bytes()); // comment length
+	out.write_all(&end)?;
+	out.flush()?;
+	Ok(())
+}
+
+/// Converts a unix timestamp to the MS-DOS `(time, date)` pair used by zip, in UTC.
+fn dos_date_time(timestamp: u64) -> (u16, u16) {
+	let secs_of_day = timestamp % 86400;
+	let time =
+		((secs_of_day / 3600) << 11) | ((secs_of_day % 3600 / 60) << 5) | (secs_of_day % 60 / 2);
+
+	let (year, month, day) = date::civil_from_days((timestamp / 86400) as i64);
+	// MS-DOS dates start at 1980.
+	let year = (year.clamp(1980, 2107) - 1980) as u32;
+	let date = (year << 9) | (month << 5) | day;
+	(time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+	use std::io::Read;
+
+	use super::*;
+	use crate::test_utils::init_repo;
+	use crate::{hash_git_object, TreeEntry};
+
+	fn tree_with(repo: &Repo, files: &[(&str, u32, &[u8])]) -> [u8; 20] {
+		let entries = files
+			.iter()
+			.map(|(name, mode, content)| {
+				let blob = GitObject::Blob(Cow::Borrowed(content));
+				TreeEntry {
+					mode: *mode,
+					name: Cow::Owned(name.to_string()),
+					object_hash: Cow::Owned(hash_git_object(repo, blob, true).unwrap().hash),
+				}
+			})
+			.collect::<Vec<_>>();
+		hash_git_object(repo, GitObject::Tree(Cow::Owned(entries)), true)
+			.unwrap()
+			.hash
+	}
+
+	#[test]
+	fn tar_of_two_file_tree() {
+		let (_dir, repo) = init_repo();
+		let tree = tree_with(
+			&repo,
+			&[
+				("hello.txt", 0o100644, b"hello world\n"),
+				("link", SYMLINK_MODE, b"hello.txt"),
+				("run.sh", 0o100755, b"#!/bin/sh\n"),
+			],
+		);
+
+		let mut tar = Vec::new();
+		write_archive(&repo, &tree, ArchiveFormat::Tar, &mut tar).unwrap();
+		assert_eq!(tar.len() % 512, 0);
+
+		let field = |header: &[u8], range: std::ops::Range<usize>| {
+			let field = &header[range];
+			let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+			String::from_utf8(field[..end].to_vec()).unwrap()
+		};
+		let mut entries = Vec::new();
+		let mut rest = &tar[..];
+		while rest[..512].iter().any(|b| *b != 0) {
+			let header = &rest[..512];
+			let size = usize::from_str_radix(&field(header, 124..136), 8).unwrap();
+			let content = &rest[512..512 + size];
+			entries.push((
+				field(header, 0..100),
+				field(header, 100..108),
+				header[156],
+				field(header, 157..257),
+				String::from_utf8(content.to_vec()).unwrap(),
+			));
+			rest = &rest[512 + size.div_ceil(512) * 512..];
+		}
+
+		let expected = [
+			("hello.txt", "0000664", b'0', "", "hello world\n"),
+			("link", "0000777", b'2', "hello.txt", ""),
+			("run.sh", "0000775", b'0', "", "#!/bin/sh\n"),
+		];
+		let entries: Vec<_> = entries
+			.iter()
+			.map(|(name, mode, type_flag, link, content)| {
+				(
+					name.as_str(),
+					mode.as_str(),
+					*type_flag,
+					link.as_str(),
+					content.as_str(),
+				)
+			})
+			.collect();
+		assert_eq!(entries, expected);
+	}
+
+	#[test]
+	fn export_ignore_leaves_out_paths() {
+		let (_dir, repo) = init_repo();
+		let docs = tree_with(&repo, &[("a.md", 0o100644, b"docs\n")]);
+		let blob = |content: &[u8]| {
+			let blob = GitObject::Blob(Cow::Borrowed(content));
+			hash_git_object(&repo, blob, true).unwrap().hash
+		};
+		let entry = |mode, name: &'static str, object_hash| TreeEntry {
+			mode,
+			name: Cow::Borrowed(name),
+			object_hash: Cow::Owned(object_hash),
+		};
+		let entries = vec![
+			entry(
+				0o100644,
+				".gitattributes",
+				blob(b"docs export-ignore\n*.log export-ignore\n"),
+			),
+			entry(0o100644, "debug.log", blob(b"log\n")),
+			entry(TREE_MODE, "docs", docs),
+			entry(0o100644, "main.rs", blob(b"")),
+		];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Owned(entries)), true)
+			.unwrap()
+			.hash;
+
+		let mut tar = Vec::new();
+		write_archive(&repo, &tree, ArchiveFormat::Tar, &mut tar).unwrap();
+		let mut names = Vec::new();
+		let mut rest = &tar[..];
+		while rest[..512].iter().any(|b| *b != 0) {
+			let name_len = rest[..100].iter().position(|b| *b == 0).unwrap();
+			names.push(String::from_utf8(rest[..name_len].to_vec()).unwrap());
+			let size = usize::from_str_radix(
+				std::str::from_utf8(&rest[124..135])
+					.unwrap()
+					.trim_matches('\0'),
+				8,
+			)
+			.unwrap();
+			rest = &rest[512 + size.div_ceil(512) * 512..];
+		}
+		assert_eq!(names, [".gitattributes", "main.rs"]);
+	}
+
+	#[test]
+	fn zip_entries_inflate_to_file_contents() {
+		let (_dir, repo) = init_repo();
+		let tree = tree_with(&repo, &[("a.txt", 0o100644, b"zip me\n")]);
+
+		let mut zip = Vec::new();
+		write_archive(&repo, &tree, ArchiveFormat::Zip, &mut zip).unwrap();
+
+		assert_eq!(zip[..4], 0x04034b50_u32.to_le_bytes());
+		let u16_at = |idx: usize| u16::from_le_bytes([zip[idx], zip[idx + 1]]) as usize;
+		let u32_at = |idx: usize| u32::from_le_bytes(zip[idx..idx + 4].try_into().unwrap());
+		assert_eq!(u16_at(8), 8);
+		let compressed_size = u32_at(18) as usize;
+		let name_len = u16_at(26);
+		assert_eq!(&zip[30..30 + name_len], b"a.txt");
+
+		let data_start = 30 + name_len;
+		let mut content = Vec::new();
+		flate2::read::DeflateDecoder::new(&zip[data_start..data_start + compressed_size])
+			.read_to_end(&mut content)
+			.unwrap();
+		assert_eq!(content, b"zip me\n");
+
+		let end = &zip[zip.len() - 22..];
+		assert_eq!(end[..4], 0x06054b50_u32.to_le_bytes());
+		assert_eq!(u16::from_le_bytes([end[10], end[11]]), 1);
+	}
+
+	#[test]
+	fn dos_date_time_of_known_timestamp() {
+		// 2021-03-04 05:06:08 UTC
+		let (time, date) = dos_date_time(1614834368);
+		assert_eq!(time, 5 << 11 | 6 << 5 | 4);
+		assert_eq!(date, 41 << 9 | 3 << 5 | 4);
+	}
+}
@@ -0,0 +1,162 @@
+use crate::diff;
+use crate::ignore::Pattern;
+
+/// Value of an attribute for a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrValue {
+	/// `attr`
+	Set,
+	/// `-attr`
+	Unset,
+	/// `attr=value`
+	Value(String),
+}
+
+/// Attribute name and its value, `None` if unspecified (`!attr`).
+type Assignment = (String, Option<AttrValue>);
+
+/// Rules of a `.gitattributes` file, see gitattributes(5): a path pattern (with the same syntax
+/// as in `.gitignore`, minus `!`) followed by attributes, each either `attr`, `-attr`,
+/// `attr=value` or `!attr` (unspecified). `binary` stands for `-diff -merge -text`.
+#[derive(Debug, Default, Clone)]
+pub struct Attributes {
+	/// Attributes assigned by each line.
+	rules: Vec<(Pattern, Vec<Assignment>)>,
+}
+
+impl Attributes {
+	pub fn parse(content: &str) -> Self {
+		let rules = content
+			.lines()
+			.filter_map(|line| {
+				let mut fields = line.split_whitespace();
+				let pattern = fields.next().filter(|p| !p.starts_with('#'))?;
+				let mut attributes = Vec::new();
+				for field in fields {
+					if field == "binary" {
+						for name in ["diff", "merge", "text"] {
+							attributes.push((name.to_string(), Some(AttrValue::Unset)));
+						}
+						continue;
+					}
+					let attribute = if let Some(name) = field.strip_prefix('-') {
+						(name, Some(AttrValue::Unset))
+					} else if let Some(name) = field.strip_prefix('!') {
+						(name, None)
+					} else if let Some((name, value)) = field.split_once('=') {
+						(name, Some(AttrValue::Value(value.to_string())))
+					} else {
+						(field, Some(AttrValue::Set))
+					};
+					attributes.push((attribute.0.to_string(), attribute.1));
+				}
+				Some((Pattern::parse(pattern)?, attributes))
+			})
+			.collect();
+		Attributes { rules }
+	}
+
+	/// Value of attribute `name` for `path` (relative to the directory of the `.gitattributes`
+	/// and separated with `/`), `None` if unspecified. The last line setting it wins.
+	pub fn get(&self, path: &str, is_dir: bool, name: &str) -> Option<&AttrValue> {
+		self.rules
+			.iter()
+			.rev()
+			.filter(|(pattern, _)| pattern.matches(path, is_dir))
+			.find_map(|(_, attributes)| {
+				attributes
+					.iter()
+					.rev()
+					.find(|(attribute, _)| attribute == name)
+			})
+			.and_then(|(_, value)| value.as_ref())
+	}
+
+	/// Whether file `path` is text whose line endings are normalized to LF when it's added:
+	/// `text` is set, `eol` is set (implying `text`) or `text=auto` and `content` isn't binary.
+	pub fn is_text(&self, path: &str, content: &[u8]) -> bool {
+		match self.get(path, false, "text") {
+			Some(AttrValue::Set) => true,
+			Some(AttrValue::Unset) => false,
+			Some(AttrValue::Value(value)) if value == "auto" => !diff::is_binary(content),
+			Some(AttrValue::Value(_)) | None => self.get(path, false, "eol").is_some(),
+		}
+	}
+
+	/// Whether the content of file `path` may be converted when it's added, see
+	/// [Attributes::normalize], without looking at it: `text=auto` may apply depending on it.
+	pub fn may_convert(&self, path: &str) -> bool {
+		match self.get(path, false, "text") {
+			Some(AttrValue::Set) => true,
+			Some(AttrValue::Unset) => false,
+			Some(AttrValue::Value(value)) if value == "auto" => true,
+			Some(AttrValue::Value(_)) | None => self.get(path, false, "eol").is_some(),
+		}
+	}
+
+	/// `content` of file `path` as it is stored in the repository: with CRLF line endings
+	/// converted to LF if it is text (see [Attributes::is_text]).
+	pub fn normalize(&self, path: &str, content: Vec<u8>) -> Vec<u8> {
+		if !self.is_text(path, &content) || !content.windows(2).any(|w| w == b"\r\n") {
+			return content;
+		}
+		let mut normalized = Vec::with_capacity(content.len());
+		let mut bytes = content.iter().peekable();
+		while let Some(&byte) = bytes.next() {
+			if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+				continue;
+			}
+			normalized.push(byte);
+		}
+		normalized
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn attribute_lookup() {
+		let attributes = Attributes::parse(
+			"# comment\n*.txt text eol=lf\n*.png binary\ndocs/** export-ignore\nREADME.txt !eol -text\n",
+		);
+		assert_eq!(
+			attributes.get("a/b.txt", false, "eol"),
+			Some(&AttrValue::Value("lf".to_string()))
+		);
+		assert_eq!(
+			attributes.get("a.txt", false, "text"),
+			Some(&AttrValue::Set)
+		);
+		assert_eq!(
+			attributes.get("a.png", false, "text"),
+			Some(&AttrValue::Unset)
+		);
+		assert_eq!(attributes.get("a.png", false, "eol"), None);
+		assert_eq!(
+			attributes.get("docs/x/y.md", false, "export-ignore"),
+			Some(&AttrValue::Set)
+		);
+		assert_eq!(attributes.get("README.txt", false, "eol"), None);
+		assert_eq!(
+			attributes.get("README.txt", false, "text"),
+			Some(&AttrValue::Unset)
+		);
+	}
+
+	#[test]
+	fn line_endings_of_text_files_are_normalized() {
+		let attributes = Attributes::parse("*.txt text eol=lf\n*.auto text=auto\n");
+		let crlf = b"one\r\ntwo\r\n".to_vec();
+		assert_eq!(attributes.normalize("a.txt", crlf.clone()), b"one\ntwo\n");
+		assert_eq!(
+			attributes.normalize("dir/a.txt", crlf.clone()),
+			b"one\ntwo\n"
+		);
+		assert_eq!(attributes.normalize("a.dat", crlf.clone()), crlf);
+		assert_eq!(attributes.normalize("a.auto", crlf.clone()), b"one\ntwo\n");
+		let binary = b"\0\r\n".to_vec();
+		assert_eq!(attributes.normalize("a.auto", binary.clone()), binary);
+	}
+}
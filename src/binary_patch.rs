@@ -0,0 +1,173 @@
+use std::io::{Read, Write};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use thiserror::Error;
+
+const BASE85: &[u8; 85] =
+	b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+/// Bytes encoded per line of binary patch data.
+const LINE_BYTES: usize = 52;
+
+#[derive(Debug, Error)]
+pub enum BinaryPatchError {
+	#[error("Not a binary patch")]
+	MissingHeader,
+
+	#[error("Unsupported binary hunk {0:?}, only literal hunks are supported")]
+	UnsupportedHunk(String),
+
+	#[error("Corrupt binary patch at line {0}")]
+	CorruptLine(usize),
+
+	#[error("Binary patch inflated to {got} bytes, expected {expected}")]
+	SizeMismatch { expected: usize, got: usize },
+
+	#[error("Failed to inflate binary patch: {0}")]
+	Inflate(std::io::Error),
+}
+
+/// Renders a change from `old` to `new` in git's `--binary` patch format: the `GIT binary patch`
+/// line followed by a forward hunk and a reverse hunk, so the patch can be applied both ways.
+///
+/// Only `literal` hunks (the full deflated content) are produced, never deltas.
+pub fn binary_patch(old: &[u8], new: &[u8]) -> String {
+	let mut out = "GIT binary patch\n".to_string();
+	out += &literal_hunk(new);
+	out.push('\n');
+	out += &literal_hunk(old);
+	out.push('\n');
+	out
+}
+
+/// `literal <size>` followed by the deflated content in base85, one line per 52 bytes.
+fn literal_hunk(content: &[u8]) -> String {
+	let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+	encoder
+		.write_all(content)
+		.expect("writing to a Vec doesn't fail");
+	let deflated = encoder.finish().expect("writing to a Vec doesn't fail");
+
+	let mut out = format!("literal {}\n", content.len());
+	for line in deflated.chunks(LINE_BYTES) {
+		// Line length: 'A'..='Z' for 1..=26 bytes, 'a'..='z' for 27..=52.
+		out.push(match line.len() {
+			len @ 1..=26 => (b'A' + len as u8 - 1) as char,
+			len => (b'a' + len as u8 - 27) as char,
+		});
+		out += &encode_base85(line);
+		out.push('\n');
+	}
+	out
+}
+
+/// Applies the forward hunk of a binary patch (as produced by [binary_patch]), returning the new
+/// content. The patch may start with the `diff --git` and `index` header lines.
+pub fn apply_binary_patch(patch: &str) -> Result<Vec<u8>, BinaryPatchError> {
+	let mut lines = patch
+		.lines()
+		.enumerate()
+		.skip_while(|(_, line)| *line != "GIT binary patch");
+	lines.next().ok_or(BinaryPatchError::MissingHeader)?;
+
+	let (_, hunk) = lines.next().ok_or(BinaryPatchError::MissingHeader)?;
+	let size: usize = hunk
+		.strip_prefix("literal ")
+		.and_then(|size| size.parse().ok())
+		.ok_or_else(|| BinaryPatchError::UnsupportedHunk(hunk.to_string()))?;
+
+	let mut deflated = Vec::new();
+	for (idx, line) in lines.take_while(|(_, line)| !line.is_empty()) {
+		let corrupt = || BinaryPatchError::CorruptLine(idx + 1);
+		let (&len, data) = line.as_bytes().split_first().ok_or_else(corrupt)?;
+		let len = match len {
+			b'A'..=b'Z' => len - b'A' + 1,
+			b'a'..=b'z' => len - b'a' + 27,
+			_ => return Err(corrupt()),
+		} as usize;
+		let decoded = decode_base85(data).ok_or_else(corrupt)?;
+		if decoded.len() < len || decoded.len() - len >= 4 {
+			return Err(corrupt());
+		}
+		deflated.extend_from_slice(&decoded[..len]);
+	}
+
+	let mut content = Vec::with_capacity(size);
+	ZlibDecoder::new(&deflated[..])
+		.read_to_end(&mut content)
+		.map_err(BinaryPatchError::Inflate)?;
+	if content.len() != size {
+		return Err(BinaryPatchError::SizeMismatch {
+			expected: size,
+			got: content.len(),
+		});
+	}
+	Ok(content)
+}
+
+/// Encodes `data` 4 bytes at a time (big endian, zero padded) as 5 base85 digits each, most
+/// significant first.
+fn encode_base85(data: &[u8]) -> String {
+	let mut out = String::with_capacity(data.len().div_ceil(4) * 5);
+	for chunk in data.chunks(4) {
+		let mut group = [0_u8; 4];
+		group[..chunk.len()].copy_from_slice(chunk);
+		let mut value = u32::from_be_bytes(group);
+
+		let mut digits = [0_u8; 5];
+		for digit in digits.iter_mut().rev() {
+			*digit = BASE85[(value % 85) as usize];
+			value /= 85;
+		}
+		out.extend(digits.iter().map(|d| *d as char));
+	}
+	out
+}
+
+/// Inverse of [encode_base85]. Returns `None` on invalid digits or a length that isn't a
+/// multiple of 5.
+fn decode_base85(data: &[u8]) -> Option<Vec<u8>> {
+	if !data.len().is_multiple_of(5) {
+		return None;
+	}
+	let mut out = Vec::with_capacity(data.len() / 5 * 4);
+	for group in data.chunks(5) {
+		let mut value = 0_u32;
+		for digit in group {
+			let digit = BASE85.iter().position(|d| d == digit)? as u32;
+			value = value.checked_mul(85)?.checked_add(digit)?;
+		}
+		out.extend(value.to_be_bytes());
+	}
+	Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn binary_patch_round_trip() {
+		let old = b"\x00\x01\x02 old".to_vec();
+		let new: Vec<u8> = (0..=255).chain(0..100).collect();
+
+		let patch = binary_patch(&old, &new);
+		assert!(patch.starts_with(&format!("GIT binary patch\nliteral {}\n", new.len())));
+		assert!(patch.contains(&format!("\n\nliteral {}\n", old.len())));
+		assert_eq!(apply_binary_patch(&patch).unwrap(), new);
+
+		let reverse = binary_patch(&new, &old);
+		assert_eq!(apply_binary_patch(&reverse).unwrap(), old);
+		assert_eq!(apply_binary_patch(&binary_patch(&old, b"")).unwrap(), b"");
+	}
+
+	#[test]
+	fn base85_matches_git() {
+		// Digits of 0x00000001 and 0xffffffff in git's alphabet
+		assert_eq!(encode_base85(&[0, 0, 0, 1]), "00001");
+		assert_eq!(encode_base85(&[0xff; 4]), "|NsC0");
+		assert_eq!(decode_base85(b"|NsC0").unwrap(), [0xff; 4]);
+		assert_eq!(decode_base85(b"|NsC1"), None);
+	}
+}
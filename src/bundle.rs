@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use thiserror::Error;
+
+use crate::pack::{self, PackError};
+use crate::repo::Repo;
+use crate::{
+	decode_payload, read_object_raw, write_encoded_object, GitObject, HashObjectError, ObjectId,
+	ObjectKind, ReadObjectError,
+};
+
+const SIGNATURE: &str = "# v2 git bundle\n";
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	WriteObject(#[from] HashObjectError),
+
+	#[error(transparent)]
+	Pack(#[from] PackError),
+
+	#[error("Not a v2 bundle")]
+	NotABundle,
+
+	#[error("Malformed bundle header line: {0}")]
+	MalformedHeader(String),
+
+	#[error("Bundles with prerequisites are not supported")]
+	Prerequisites,
+}
+
+/// Writes a bundle of `refs`, given by full name and the object they point at, with every
+/// object reachable from them: a header listing the refs, an empty line, then a packfile.
+/// Nothing is left out, so the bundle has no prerequisites.
+pub fn write_bundle(
+	repo: &Repo,
+	refs: &[(String, ObjectId)],
+	mut out: impl Write,
+) -> Result<(), BundleError> {
+	write!(out, "{SIGNATURE}")?;
+	for (name, id) in refs {
+		writeln!(out, "{} {name}", hex::encode(id))?;
+	}
+	writeln!(out)?;
+
+	let tips: Vec<ObjectId> = refs.iter().map(|(_, id)| *id).collect();
+	out.write_all(&pack::encode_pack(&reachable_objects(repo, &tips)?)?)?;
+	Ok(())
+}
+
+/// Kind and payload of every object reachable from `tips`: tagged objects, parents and trees of
+/// commits and entries of trees, except for submodules.
+fn reachable_objects(
+	repo: &Repo,
+	tips: &[ObjectId],
+) -> Result<Vec<(ObjectKind, Vec<u8>)>, BundleError> {
+	let mut objects = Vec::new();
+	let mut visited = HashSet::new();
+	let mut pending = tips.to_vec();
+
+	while let Some(id) = pending.pop() {
+		if !visited.insert(id) {
+			continue;
+		}
+		let hash_str = hex::encode(id);
+		let (kind, payload) = read_object_raw(repo, hash_str.clone())?;
+		if kind != ObjectKind::Blob {
+			match decode_payload(&hash_str, kind, &payload)? {
+				GitObject::Commit(commit) => {
+					pending.push(commit.tree);
+					pending.extend(commit.parents);
+				}
+				GitObject::Tree(entries) => pending.extend(
+					entries
+						.iter()
+						.filter(|entry| entry.mode != 0o160000)
+						.map(|entry| *entry.object_hash),
+				),
+				GitObject::Tag(tag) => pending.push(tag.object),
+				GitObject::Blob(_) => {}
+			}
+		}
+		objects.push((kind, payload));
+	}
+
+	Ok(objects)
+}
+
+/// Stores the objects of `bundle` in the repository, returning the refs it lists. The refs
+/// themselves aren't created.
+pub fn unbundle(repo: &Repo, bundle: &[u8]) -> Result<Vec<(String, ObjectId)>, BundleError> {
+	let mut rest = bundle
+		.strip_prefix(SIGNATURE.as_bytes())
+		.ok_or(BundleError::NotABundle)?;
+
+	let mut refs = Vec::new();
+	loop {
+		let end = rest
+			.iter()
+			.position(|b| *b == b'\n')
+			.ok_or(BundleError::NotABundle)?;
+		let line = String::from_utf8_lossy(&rest[..end]).into_owned();
+		rest = &rest[end + 1..];
+		if line.is_empty() {
+			break;
+		}
+		if line.starts_with('-') {
+			return Err(BundleError::Prerequisites);
+		}
+
+		let mut id = [0_u8; 20];
+		match line.split_once(' ') {
+			Some((hash, name)) if hex::decode_to_slice(hash, &mut id).is_ok() => {
+				refs.push((name.to_string(), id));
+			}
+			_ => return Err(BundleError::MalformedHeader(line)),
+		}
+	}
+
+	for (kind, payload) in pack::parse_pack(rest)? {
+		let mut encoded = format!("{} {}\0", kind.as_str(), payload.len()).into_bytes();
+		encoded.extend(payload);
+		write_encoded_object(repo, &encoded, true)?;
+	}
+	Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+
+	use super::*;
+	use crate::test_utils::init_repo;
+	use crate::{create_commit, decode_object, hash_git_object, TreeEntry};
+
+	#[test]
+	fn bundles_carry_history_to_another_repository() {
+		let (_dir, repo) = init_repo();
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"a\n")), true).unwrap();
+		let entries = vec![TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed("a"),
+			object_hash: Cow::Owned(blob.hash),
+		}];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Owned(entries)), true).unwrap();
+		let first = create_commit(
+			&repo,
+			tree.hash_str.clone(),
+			Vec::new(),
+			"first".to_string(),
+		)
+		.unwrap();
+		let second = create_commit(
+			&repo,
+			tree.hash_str.clone(),
+			vec![first.hash_str.clone()],
+			"second".to_string(),
+		)
+		.unwrap();
+
+		let refs = vec![("refs/heads/master".to_string(), second.hash)];
+		let mut bundle = Vec::new();
+		write_bundle(&repo, &refs, &mut bundle).unwrap();
+		let header = format!("{SIGNATURE}{} refs/heads/master\n\nPACK", second.hash_str);
+		assert!(bundle.starts_with(header.as_bytes()));
+
+		let (_other_dir, other) = init_repo();
+		assert_eq!(unbundle(&other, &bundle).unwrap(), refs);
+		for hash in [&blob, &tree, &first, &second].map(|object| &object.hash_str) {
+			decode_object(&other, hash.clone()).unwrap();
+		}
+
+		let prerequisite = format!("{SIGNATURE}-{}\n\n", first.hash_str);
+		assert!(matches!(
+			unbundle(&other, prerequisite.as_bytes()),
+			Err(BundleError::Prerequisites)
+		));
+		assert!(matches!(
+			unbundle(&other, b"not a bundle"),
+			Err(BundleError::NotABundle)
+		));
+	}
+}
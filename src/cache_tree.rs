@@ -0,0 +1,155 @@
+//! The index's cached-tree (`TREE`) extension: the ids of the trees the staged entries were last
+//! written as, so that write-tree only has to rewrite the directories that changed since.
+
+use crate::ObjectId;
+
+/// A directory of the index and the tree it was last written as. Each node is stored as its
+/// NUL-terminated name (empty for the root), its entry count (`-1` if invalid) and number of
+/// subtrees in ASCII followed by a newline, the tree id if valid, then its subtrees.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheTree {
+	/// Number of index entries below the directory and the id of their tree, unless staged
+	/// changes below it invalidated them.
+	pub tree: Option<(usize, ObjectId)>,
+	/// Subdirectories by name, in the order they were written.
+	pub subtrees: Vec<(String, CacheTree)>,
+}
+
+impl CacheTree {
+	/// Parses the data of a `TREE` extension. Returns `None` if it's malformed, in which case
+	/// it's ignored, like git does.
+	pub fn parse(data: &[u8]) -> Option<Self> {
+		let mut rest = data;
+		let (name, tree) = parse_node(&mut rest, 0)?;
+		(name.is_empty() && rest.is_empty()).then_some(tree)
+	}
+
+	/// Encodes the tree as the data of a `TREE` extension.
+	pub fn encode(&self, out: &mut Vec<u8>) {
+		self.encode_node("", out);
+	}
+
+	fn encode_node(&self, name: &str, out: &mut Vec<u8>) {
+		out.extend(name.as_bytes());
+		out.push(0);
+		let count = match self.tree {
+			Some((count, _)) => count.to_string(),
+			None => "-1".to_string(),
+		};
+		out.extend(format!("{count} {}\n", self.subtrees.len()).as_bytes());
+		if let Some((_, id)) = &self.tree {
+			out.extend(id);
+		}
+		for (name, subtree) in &self.subtrees {
+			subtree.encode_node(name, out);
+		}
+	}
+
+	/// Invalidates the trees containing `path` (relative to this tree), as its entries changed.
+	/// A subtree at `path` itself is dropped, like when a directory is replaced by a file.
+	pub fn invalidate(&mut self, path: &str) {
+		self.tree = None;
+		let Some((dir, rest)) = path.split_once('/') else {
+			self.subtrees.retain(|(name, _)| name != path);
+			return;
+		};
+		if let Some((_, subtree)) = self.subtrees.iter_mut().find(|(name, _)| name == dir) {
+			subtree.invalidate(rest);
+		}
+	}
+
+	/// The subtree named `name`, if any.
+	pub fn subtree(&self, name: &str) -> Option<&CacheTree> {
+		self.subtrees
+			.iter()
+			.find_map(|(n, subtree)| (n == name).then_some(subtree))
+	}
+}
+
+/// Trees nested deeper are rejected rather than recursed into.
+const MAX_DEPTH: usize = 512;
+
+fn parse_node(data: &mut &[u8], depth: usize) -> Option<(String, CacheTree)> {
+	if depth >= MAX_DEPTH {
+		return None;
+	}
+	let name_end = data.iter().position(|&b| b == 0)?;
+	let name = std::str::from_utf8(&data[..name_end]).ok()?.to_string();
+	let line_end = name_end + 1 + data[name_end + 1..].iter().position(|&b| b == b'\n')?;
+	let line = std::str::from_utf8(&data[name_end + 1..line_end]).ok()?;
+	let (count, subtrees) = line.split_once(' ')?;
+	let subtrees: usize = subtrees.parse().ok()?;
+	*data = &data[line_end + 1..];
+
+	let tree = if count == "-1" {
+		None
+	} else {
+		let count = count.parse().ok()?;
+		let id = data.get(..20)?.try_into().unwrap();
+		*data = &data[20..];
+		Some((count, id))
+	};
+	let subtrees = (0..subtrees)
+		.map(|_| parse_node(data, depth + 1))
+		.collect::<Option<_>>()?;
+	Some((name, CacheTree { tree, subtrees }))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_and_encode_round_trip() {
+		// As written by `git write-tree` for `a.txt` and `dir/b.txt`, after `dir/b.txt` was
+		// staged again.
+		let mut data = b"\0-1 1\ndir\x001 0\n".to_vec();
+		data.extend([7; 20]);
+		let tree = CacheTree::parse(&data).unwrap();
+		assert_eq!(
+			tree,
+			CacheTree {
+				tree: None,
+				subtrees: vec![(
+					"dir".to_string(),
+					CacheTree {
+						tree: Some((1, [7; 20])),
+						subtrees: Vec::new(),
+					}
+				)],
+			}
+		);
+		let mut encoded = Vec::new();
+		tree.encode(&mut encoded);
+		assert_eq!(encoded, data);
+
+		assert_eq!(CacheTree::parse(&data[..data.len() - 1]), None);
+		assert_eq!(CacheTree::parse(b"\0-1 0\nextra"), None);
+		assert_eq!(CacheTree::parse(b"root\0-1 0\n"), None);
+	}
+
+	#[test]
+	fn invalidate_marks_the_containing_trees() {
+		let valid = |subtrees| CacheTree {
+			tree: Some((1, [1; 20])),
+			subtrees,
+		};
+		let mut tree = valid(vec![
+			(
+				"a".to_string(),
+				valid(vec![("b".to_string(), valid(vec![]))]),
+			),
+			("c".to_string(), valid(vec![])),
+		]);
+
+		tree.invalidate("a/x.txt");
+		assert_eq!(tree.tree, None);
+		let a = tree.subtree("a").unwrap();
+		assert_eq!(a.tree, None);
+		assert!(a.subtree("b").unwrap().tree.is_some());
+		assert!(tree.subtree("c").unwrap().tree.is_some());
+
+		tree.invalidate("c");
+		assert_eq!(tree.subtree("c"), None);
+	}
+}
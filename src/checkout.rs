@@ -0,0 +1,933 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::attributes::Attributes;
+use crate::repo::Repo;
+use crate::{
+	decode_object, normalize_mode, read_index, read_worktree_attributes, refs, revision,
+	stat_times, worktree_file_matches, write_index, GitObject, Index, IndexEntry, ObjectId,
+	ReadIndexError, ReadObjectError, MAX_TREE_DEPTH,
+};
+
+const TREE_MODE: u32 = 0o40000;
+const SYMLINK_MODE: u32 = 0o120000;
+const GITLINK_MODE: u32 = 0o160000;
+
+#[derive(Debug, Error)]
+pub enum CheckoutError {
+	#[error("Not a valid commit name {0}")]
+	InvalidRevision(String),
+
+	#[error(
+		"A branch is expected, got '{0}'\n\
+		 hint: If you want to detach HEAD at the commit, try again with the --detach option."
+	)]
+	NotABranch(String),
+
+	#[error("'{0}' is not a valid branch name")]
+	InvalidBranchName(String),
+
+	#[error("A branch named '{0}' already exists")]
+	BranchExists(String),
+
+	#[error(
+		"Your local changes to the following files would be overwritten by checkout:\n{}\
+		 Please commit your changes before you switch branches.",
+		list_paths(.0)
+	)]
+	LocalChanges(Vec<String>),
+
+	#[error(
+		"The following untracked working tree files would be overwritten by checkout:\n{}\
+		 Please move or remove them before you switch branches.",
+		list_paths(.0)
+	)]
+	UntrackedFiles(Vec<String>),
+
+	#[error("{}", .0.join("\n"))]
+	Skipped(Vec<String>),
+
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error(transparent)]
+	UpdateRef(#[from] refs::UpdateRefError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error("{0} is not a tree")]
+	NotATree(String),
+
+	#[error("{0} is not a blob")]
+	NotABlob(String),
+
+	#[error("Trees nested deeper than {MAX_TREE_DEPTH} levels at {0}")]
+	TooDeep(String),
+
+	#[error("Invalid path '{0}'")]
+	InvalidPath(String),
+
+	#[error("'{0}' is beyond a symbolic link")]
+	BeyondSymlink(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+fn list_paths(paths: &[String]) -> String {
+	paths.iter().map(|path| format!("\t{path}\n")).collect()
+}
+
+/// Whether `name` can be the name of a file in the working tree: not empty, `.` or `..`, without
+/// slashes, and not `.git` in any case, which would write into the repository itself.
+fn valid_name(name: &str) -> bool {
+	!matches!(name, "" | "." | "..") && !name.contains('/') && !name.eq_ignore_ascii_case(".git")
+}
+
+/// Whether every component of the slash-separated `path` is a [valid_name], so that writing it
+/// stays in the working tree.
+fn valid_path(path: &str) -> bool {
+	path.split('/').all(valid_name)
+}
+
+/// Mode and object of every blob, symlink and submodule in a tree and its subtrees, by path.
+pub type TreeFiles = BTreeMap<String, (u32, ObjectId)>;
+
+/// Lists the files of tree `tree` recursively, see [TreeFiles].
+pub fn tree_files(repo: &Repo, tree: &ObjectId) -> Result<TreeFiles, CheckoutError> {
+	let mut files = BTreeMap::new();
+	collect_tree_files(repo, tree, "", 0, &mut files)?;
+	Ok(files)
+}
+
+fn collect_tree_files(
+	repo: &Repo,
+	tree: &ObjectId,
+	prefix: &str,
+	depth: usize,
+	files: &mut TreeFiles,
+) -> Result<(), CheckoutError> {
+	if depth >= MAX_TREE_DEPTH {
+		return Err(CheckoutError::TooDeep(prefix.to_string()));
+	}
+	let hash_str = hex::encode(tree);
+	let GitObject::Tree(entries) = decode_object(repo, hash_str.clone())? else {
+		return Err(CheckoutError::NotATree(hash_str));
+	};
+	for entry in entries.iter() {
+		let path = format!("{prefix}{}", entry.name);
+		if !valid_name(&entry.name) {
+			return Err(CheckoutError::InvalidPath(path));
+		}
+		if entry.mode == TREE_MODE {
+			collect_tree_files(
+				repo,
+				&entry.object_hash,
+				&format!("{path}/"),
+				depth + 1,
+				files,
+			)?;
+		} else {
+			files.insert(path, (entry.mode, *entry.object_hash));
+		}
+	}
+	Ok(())
+}
+
+/// Switches to branch `name` or, if there's no such branch, detaches `HEAD` at the commit it
+/// names. The working tree and the index are updated to the commit's tree, keeping changes to
+/// files that are the same in both commits. Fails without touching anything if other files
+/// have uncommitted changes or untracked files are in the way.
+pub fn checkout(repo: &Repo, name: &str) -> Result<(), CheckoutError> {
+	let (branch, hash) = resolve_target(repo, name)?;
+	switch_to(repo, name, branch.as_deref(), hash, false)
+}
+
+/// Switches to branch `name` like `git switch`, updating the working tree like [checkout]. With
+/// `create`, the branch is created at `start_point` (`HEAD` by default) once the working tree
+/// is switched. With `detach`, `HEAD` is detached at the commit `name` means; otherwise, it must
+/// be a branch.
+pub fn switch(
+	repo: &Repo,
+	name: &str,
+	create: bool,
+	start_point: Option<&str>,
+	detach: bool,
+) -> Result<(), CheckoutError> {
+	if create {
+		let branch = format!("refs/heads/{name}");
+		if name == "HEAD" || name.starts_with('-') || !refs::check_ref_format(&branch) {
+			return Err(CheckoutError::InvalidBranchName(name.to_string()));
+		}
+		if refs::resolve_ref(repo, &branch)?.is_some() {
+			return Err(CheckoutError::BranchExists(name.to_string()));
+		}
+		let hash = resolve_commit(repo, start_point.unwrap_or("HEAD"))?;
+		return switch_to(repo, name, Some(&branch), hash, true);
+	}
+	match resolve_target(repo, name)? {
+		(Some(branch), hash) if !detach => switch_to(repo, name, Some(&branch), hash, false),
+		(_, hash) if detach => switch_to(repo, name, None, hash, false),
+		_ => Err(CheckoutError::NotABranch(name.to_string())),
+	}
+}
+
+/// Resolves the target of a checkout: branch `name` and its commit or, if there's no such
+/// branch, just the commit `name` names.
+fn resolve_target(repo: &Repo, name: &str) -> Result<(Option<String>, String), CheckoutError> {
+	let branch = format!("refs/heads/{name}");
+	match refs::resolve_ref(repo, &branch)? {
+		Some(hash) if !name.is_empty() => Ok((Some(branch), hash)),
+		_ => Ok((None, resolve_commit(repo, name)?)),
+	}
+}
+
+fn resolve_commit(repo: &Repo, name: &str) -> Result<String, CheckoutError> {
+	match revision::resolve_revision(repo, name)
+		.and_then(|hash| revision::peel(repo, hash, "commit"))
+	{
+		Ok(Some(hash)) => Ok(hash),
+		Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => {
+			Err(CheckoutError::InvalidRevision(name.to_string()))
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// Switches the working tree and the index to commit `hash`, then points `HEAD` at `branch`
+/// (creating it at `hash` if `create`) or, without one, detaches it at the commit.
+fn switch_to(
+	repo: &Repo,
+	name: &str,
+	branch: Option<&str>,
+	hash: String,
+	create: bool,
+) -> Result<(), CheckoutError> {
+	let GitObject::Commit(target) = decode_object(repo, hash.clone())? else {
+		return Err(CheckoutError::InvalidRevision(name.to_string()));
+	};
+
+	switch_tree(repo, &target.tree)?;
+
+	match branch {
+		Some(branch) => {
+			if create {
+				refs::update_ref(repo, branch, &hash, Some(&"0".repeat(40)))?;
+			}
+			refs::write_symbolic_ref(repo, "HEAD", branch)?;
+		}
+		None => refs::write_ref(repo, "HEAD", &hash)?,
+	}
+	match branch {
+		Some(_) if create => eprintln!("Switched to a new branch '{name}'"),
+		Some(_) => eprintln!("Switched to branch '{name}'"),
+		None => {
+			let subject = target.message.lines().next().unwrap_or_default();
+			eprintln!("HEAD is now at {} {subject}", &hash[..7]);
+		}
+	}
+	Ok(())
+}
+
+/// Writes the files of the index entries at `paths` (all of them if `None`) to the working
+/// tree, or to `<prefix><path>` with a `prefix` (e.g. `export/`), like `git checkout-index`.
+/// Without a prefix, the stat information of the entries is updated. Existing files are left
+/// alone, unless `force` or they're up to date; those, paths that aren't in the index and paths
+/// that would be written out of the working tree (see [valid_path]) are reported with
+/// [CheckoutError::Skipped] once the others are written.
+pub fn checkout_index(
+	repo: &Repo,
+	paths: Option<&[String]>,
+	prefix: Option<&str>,
+	force: bool,
+) -> Result<(), CheckoutError> {
+	let (mut index, index_mtime) = read_index_or_empty(repo)?;
+	let attributes = read_worktree_attributes(repo.work_tree())?;
+
+	let merged = |entry: &IndexEntry| (entry.flags >> 12) & 0b11 == 0;
+	// Indices of the entries to write, or why a path can't be, in the order given.
+	let selected: Vec<Result<usize, String>> = match paths {
+		// Conflicted paths have nothing to write.
+		None => (0..index.entries.len())
+			.filter(|idx| merged(&index.entries[*idx]))
+			.map(Ok)
+			.collect(),
+		Some(paths) => paths
+			.iter()
+			.map(
+				|path| match index.entries.iter().position(|entry| entry.path == *path) {
+					Some(idx) if merged(&index.entries[idx]) => Ok(idx),
+					Some(_) => Err(format!("{path} is unmerged")),
+					None => Err(format!("{path} is not in the cache")),
+				},
+			)
+			.collect(),
+	};
+
+	let mut skipped = Vec::new();
+	let mut updated = false;
+	for idx in selected {
+		let idx = match idx {
+			Ok(idx) => idx,
+			Err(reason) => {
+				skipped.push(reason);
+				continue;
+			}
+		};
+		let entry = &mut index.entries[idx];
+		// The index may come from anywhere, unlike checked trees.
+		if !valid_path(&entry.path) {
+			skipped.push(format!("Invalid path '{}'", entry.path));
+			continue;
+		}
+		// Only the directories of the entry's path are checked for symlinks, not the prefix's.
+		let (base, path) = match prefix {
+			Some(prefix) => match prefix.rsplit_once('/') {
+				Some((dir, name)) => (
+					PathBuf::from(format!("{dir}/")),
+					format!("{name}{}", entry.path),
+				),
+				None => (PathBuf::from("."), format!("{prefix}{}", entry.path)),
+			},
+			None => (repo.work_tree().to_owned(), entry.path.clone()),
+		};
+		let file = base.join(&path);
+		if !force && fs::symlink_metadata(&file).is_ok() {
+			if prefix.is_none() && worktree_matches(repo, entry, index_mtime, &attributes)? {
+				continue;
+			}
+			skipped.push(format!("{} already exists, no checkout", entry.path));
+			continue;
+		}
+		write_entry(repo, &base, &path, entry.mode, entry.sha1)?;
+		if prefix.is_none() && entry.mode != GITLINK_MODE {
+			entry.update_stat(&fs::symlink_metadata(&file)?);
+			updated = true;
+		}
+	}
+
+	if updated {
+		write_index(repo, &index)?;
+	}
+	if !skipped.is_empty() {
+		return Err(CheckoutError::Skipped(skipped));
+	}
+	Ok(())
+}
+
+/// Updates the working tree and the index from the tree of `HEAD` to `tree`. Paths with
+/// uncommitted changes (staged or not) are left as they are if they're the same in both trees,
+/// otherwise nothing is changed and [CheckoutError::LocalChanges] lists them.
+fn switch_tree(repo: &Repo, tree: &ObjectId) -> Result<(), CheckoutError> {
+	let (index, index_mtime) = read_index_or_empty(repo)?;
+	let head = match repo.head()? {
+		refs::Head::Symbolic {
+			target: Some(id), ..
+		}
+		| refs::Head::Detached(id) => match decode_object(repo, hex::encode(id))? {
+			GitObject::Commit(commit) => tree_files(repo, &commit.tree)?,
+			_ => TreeFiles::new(),
+		},
+		refs::Head::Symbolic { target: None, .. } => TreeFiles::new(),
+	};
+	let target = tree_files(repo, tree)?;
+	let tracked: BTreeMap<&str, &IndexEntry> = index
+		.entries
+		.iter()
+		.map(|entry| (entry.path.as_str(), entry))
+		.collect();
+
+	// Paths with uncommitted changes, along with their index entries (none if deleted) if
+	// they're kept.
+	let mut kept: BTreeMap<&str, Vec<&IndexEntry>> = BTreeMap::new();
+	let mut conflicts = BTreeSet::new();
+	let attributes = read_worktree_attributes(repo.work_tree())?;
+	let mut dirty = Vec::new();
+	for entry in &index.entries {
+		let staged = (entry.flags >> 12) & 0b11 != 0
+			|| head.get(&entry.path) != Some(&(normalize_mode(entry.mode), entry.sha1));
+		if staged || !worktree_matches(repo, entry, index_mtime, &attributes)? {
+			dirty.push(entry.path.as_str());
+		}
+	}
+	let deleted = head
+		.keys()
+		.map(String::as_str)
+		.filter(|path| !tracked.contains_key(path));
+	for path in dirty.into_iter().chain(deleted) {
+		if head.get(path) != target.get(path) {
+			conflicts.insert(path.to_string());
+			continue;
+		}
+		let entries = index.entries.iter().filter(|entry| entry.path == path);
+		kept.insert(path, entries.collect());
+	}
+	if !conflicts.is_empty() {
+		return Err(CheckoutError::LocalChanges(conflicts.into_iter().collect()));
+	}
+
+	let mut untracked = Vec::new();
+	for (path, (mode, id)) in &target {
+		if tracked.contains_key(path.as_str()) || *mode == GITLINK_MODE {
+			continue;
+		}
+		let file = repo.work_tree().join(path);
+		let Ok(metadata) = fs::symlink_metadata(&file) else {
+			continue;
+		};
+		if metadata.is_dir() {
+			// Unless it only has tracked files, which are removed first.
+			let prefix = format!("{path}/");
+			if !tracked.keys().any(|tracked| tracked.starts_with(&prefix)) {
+				untracked.push(path.clone());
+			}
+			continue;
+		}
+		let entry = new_entry(path, *mode, *id);
+		if !worktree_file_matches(&file, &metadata, &entry, &attributes)? {
+			untracked.push(path.clone());
+		}
+	}
+	if !untracked.is_empty() {
+		return Err(CheckoutError::UntrackedFiles(untracked));
+	}
+
+	for path in tracked.keys() {
+		if !kept.contains_key(path) && !target.contains_key(*path) {
+			remove_worktree_file(repo, path)?;
+		}
+	}
+	let mut entries = Vec::new();
+	for (path, (mode, id)) in &target {
+		if let Some(kept) = kept.remove(path.as_str()) {
+			entries.extend(kept.into_iter().cloned());
+			continue;
+		}
+		match tracked.get(path.as_str()) {
+			// Unchanged, and clean as it wasn't kept.
+			Some(entry) if (normalize_mode(entry.mode), entry.sha1) == (*mode, *id) => {
+				entries.push((*entry).clone());
+			}
+			_ => entries.push(checkout_entry(repo, path, *mode, *id)?),
+		}
+	}
+	// Staged files in neither tree.
+	entries.extend(kept.into_values().flatten().cloned());
+
+	write_index(
+		repo,
+		&Index {
+			sha1: [0; 20],
+			version: 2,
+			entries,
+			cache_tree: None,
+		},
+	)?;
+	Ok(())
+}
+
+/// Reads the index, or an empty one if there's none, along with its modification time.
+fn read_index_or_empty(repo: &Repo) -> Result<(Index, (u32, u32)), CheckoutError> {
+	let index = match read_index(repo) {
+		Ok(index) => index,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+			let empty = Index {
+				sha1: [0; 20],
+				version: 2,
+				entries: Vec::new(),
+				cache_tree: None,
+			};
+			// No entries to check, so the time doesn't matter.
+			return Ok((empty, (0, 0)));
+		}
+		Err(err) => return Err(err.into()),
+	};
+	let (_, _, mtime_s, mtime_n) = stat_times(&fs::metadata(repo.index_path())?);
+	Ok((index, (mtime_s, mtime_n)))
+}
+
+/// Whether the file of index `entry` still has the content recorded in the index. Files whose
+/// stat information matches aren't read, unless they were modified no earlier than
+/// `index_mtime` and could have changed since without it showing ("racily clean").
+fn worktree_matches(
+	repo: &Repo,
+	entry: &IndexEntry,
+	index_mtime: (u32, u32),
+	attributes: &Attributes,
+) -> std::io::Result<bool> {
+	if normalize_mode(entry.mode) == GITLINK_MODE {
+		return Ok(true);
+	}
+	let path = repo.work_tree().join(&entry.path);
+	let metadata = match fs::symlink_metadata(&path) {
+		Ok(metadata) => metadata,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+		Err(err) => return Err(err),
+	};
+	let racy = (entry.mtime_s, entry.mtime_n) >= index_mtime;
+	if entry.stat_matches(&metadata) && !racy {
+		return Ok(true);
+	}
+	worktree_file_matches(&path, &metadata, entry, attributes)
+}
+
+/// Index entry of object `id` at `path`, without stat information.
+pub fn new_entry(path: &str, mode: u32, id: ObjectId) -> IndexEntry {
+	IndexEntry {
+		ctime_s: 0,
+		ctime_n: 0,
+		mtime_s: 0,
+		mtime_n: 0,
+		dev: 0,
+		ino: 0,
+		mode,
+		uid: 0,
+		gid: 0,
+		size: 0,
+		sha1: id,
+		flags: 0,
+		extended_flags: 0,
+		path: path.to_string(),
+	}
+}
+
+/// Writes the file at `path` in the working tree with the content of blob `id`, as a symlink or
+/// an executable file depending on `mode`, and returns its index entry. Submodules only get an
+/// empty directory.
+pub fn checkout_entry(
+	repo: &Repo,
+	path: &str,
+	mode: u32,
+	id: ObjectId,
+) -> Result<IndexEntry, CheckoutError> {
+	let mut entry = new_entry(path, mode, id);
+	let file = write_entry(repo, repo.work_tree(), path, mode, id)?;
+	if mode != GITLINK_MODE {
+		entry.update_stat(&fs::symlink_metadata(&file)?);
+	}
+	Ok(entry)
+}
+
+/// Writes the file at `path` below `base` (anywhere) like [checkout_entry], replacing whatever
+/// is there, and returns where it is.
+fn write_entry(
+	repo: &Repo,
+	base: &Path,
+	path: &str,
+	mode: u32,
+	id: ObjectId,
+) -> Result<PathBuf, CheckoutError> {
+	let file = create_leading_dirs(base, path)?;
+	if mode == GITLINK_MODE {
+		if fs::symlink_metadata(&file).is_err() {
+			fs::create_dir(&file)?;
+		}
+		return Ok(file);
+	}
+
+	let hash_str = hex::encode(id);
+	let GitObject::Blob(content) = decode_object(repo, hash_str.clone())? else {
+		return Err(CheckoutError::NotABlob(hash_str));
+	};
+	let file = file.as_path();
+	match fs::symlink_metadata(file) {
+		// Fails if anything untracked is left in it.
+		Ok(metadata) if metadata.is_dir() => fs::remove_dir(file)?,
+		Ok(_) => fs::remove_file(file)?,
+		Err(_) => {}
+	}
+	if mode == SYMLINK_MODE {
+		write_symlink(&content, file)?;
+	} else {
+		write_file(&content, mode == 0o100755, file)?;
+	}
+	Ok(file.to_owned())
+}
+
+/// Creates `base` and the missing directories of `path` below it, returning the path of the
+/// file. Unlike [fs::create_dir_all], symlinks aren't followed: a tree may have put one where a
+/// directory of `path` is, pointing out of the working tree.
+fn create_leading_dirs(base: &Path, path: &str) -> Result<PathBuf, CheckoutError> {
+	fs::create_dir_all(base)?;
+	let mut dir = base.to_owned();
+	let mut components: Vec<&str> = path.split('/').collect();
+	components.pop();
+	for component in components {
+		dir.push(component);
+		match fs::symlink_metadata(&dir) {
+			Ok(metadata) if metadata.is_symlink() => {
+				return Err(CheckoutError::BeyondSymlink(path.to_string()))
+			}
+			// Anything else than a directory in the way fails to be created over.
+			Ok(metadata) if metadata.is_dir() => {}
+			Ok(_) => fs::create_dir(&dir)?,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+			Err(err) => return Err(err.into()),
+		}
+	}
+	Ok(base.join(path))
+}
+
+#[cfg(unix)]
+fn write_symlink(target: &[u8], path: &Path) -> std::io::Result<()> {
+	use std::os::unix::ffi::OsStrExt;
+
+	std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+}
+
+/// Symlinks usually need extra privileges on Windows, so they're written as plain files holding
+/// the target path like git does with `core.symlinks=false`.
+#[cfg(windows)]
+fn write_symlink(target: &[u8], path: &Path) -> std::io::Result<()> {
+	fs::write(path, target)
+}
+
+#[cfg(unix)]
+fn write_file(content: &[u8], executable: bool, path: &Path) -> std::io::Result<()> {
+	use std::io::Write;
+	use std::os::unix::fs::OpenOptionsExt;
+
+	// Subject to the umask, like git.
+	fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(if executable { 0o777 } else { 0o666 })
+		.open(path)?
+		.write_all(content)
+}
+
+#[cfg(windows)]
+fn write_file(content: &[u8], _executable: bool, path: &Path) -> std::io::Result<()> {
+	fs::write(path, content)
+}
+
+/// Removes the file at `path` in the working tree, if it's still there, and the directories
+/// that were only left empty by it.
+fn remove_worktree_file(repo: &Repo, path: &str) -> std::io::Result<()> {
+	let file = repo.work_tree().join(path);
+	match fs::remove_file(&file) {
+		Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+		_ => {}
+	}
+	let mut dir = file.parent();
+	while let Some(parent) = dir.filter(|dir| *dir != repo.work_tree()) {
+		if fs::remove_dir(parent).is_err() {
+			break;
+		}
+		dir = parent.parent();
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+	use crate::test_utils::{init_repo, write_raw_object, TempDir};
+	use crate::{commit, update_index, write_tree_from_index, ObjectKind};
+
+	/// Stages exactly `files` (replacing the index) and commits them, returning the commit.
+	fn commit_files(repo: &Repo, files: &[(&str, &str)]) -> String {
+		let _ = fs::remove_file(repo.index_path());
+		let mut paths = Vec::new();
+		for (path, content) in files {
+			let file = repo.work_tree().join(path);
+			fs::create_dir_all(file.parent().unwrap()).unwrap();
+			fs::write(file, content).unwrap();
+			paths.push(PathBuf::from(path));
+		}
+		update_index(repo, &paths, true).unwrap();
+		let mut out = Vec::new();
+		commit(repo, "commit".to_string(), true, &mut out).unwrap();
+		String::from_utf8(out).unwrap().trim_end().to_string()
+	}
+
+	fn index_tree(repo: &Repo) -> ObjectId {
+		write_tree_from_index(repo, &read_index(repo).unwrap().entries).unwrap()
+	}
+
+	#[test]
+	fn checkout_switches_trees_and_keeps_local_changes() {
+		let (dir, repo) = init_repo();
+		let work_tree = &dir.0;
+		let one = commit_files(
+			&repo,
+			&[("a", "1"), ("d/f", "1"), ("same", "s"), ("t/z", "1")],
+		);
+		let one_tree = index_tree(&repo);
+		refs::write_ref(&repo, "refs/heads/side", &one).unwrap();
+		fs::remove_dir_all(work_tree.join("t")).unwrap();
+		fs::remove_dir_all(work_tree.join("d")).unwrap();
+		let two = commit_files(
+			&repo,
+			&[("a", "2"), ("n", "new"), ("same", "s"), ("t", "t")],
+		);
+		let two_tree = index_tree(&repo);
+
+		// A file only changed in the working tree that's the same on both branches is kept.
+		fs::write(work_tree.join("same"), "dirty").unwrap();
+		checkout(&repo, "side").unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			"ref: refs/heads/side\n"
+		);
+		assert_eq!(index_tree(&repo), one_tree);
+		assert_eq!(fs::read_to_string(work_tree.join("a")).unwrap(), "1");
+		assert_eq!(fs::read_to_string(work_tree.join("t/z")).unwrap(), "1");
+		assert_eq!(fs::read_to_string(work_tree.join("d/f")).unwrap(), "1");
+		assert_eq!(fs::read_to_string(work_tree.join("same")).unwrap(), "dirty");
+		assert!(!work_tree.join("n").exists());
+
+		fs::write(work_tree.join("a"), "local").unwrap();
+		assert!(matches!(
+			checkout(&repo, "master"),
+			Err(CheckoutError::LocalChanges(paths)) if paths == ["a"]
+		));
+		fs::write(work_tree.join("a"), "1").unwrap();
+		fs::write(work_tree.join("n"), "untracked").unwrap();
+		assert!(matches!(
+			checkout(&repo, "master"),
+			Err(CheckoutError::UntrackedFiles(paths)) if paths == ["n"]
+		));
+		// Nothing was changed.
+		assert_eq!(index_tree(&repo), one_tree);
+		assert!(work_tree.join("t/z").exists());
+
+		fs::remove_file(work_tree.join("n")).unwrap();
+		checkout(&repo, &two[..7]).unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			format!("{two}\n")
+		);
+		assert_eq!(index_tree(&repo), two_tree);
+		assert_eq!(fs::read_to_string(work_tree.join("t")).unwrap(), "t");
+		assert!(!work_tree.join("d").exists());
+
+		assert!(matches!(
+			checkout(&repo, "nope"),
+			Err(CheckoutError::InvalidRevision(_))
+		));
+	}
+
+	#[test]
+	fn switch_changes_branches_and_detaches_only_when_asked() {
+		let (dir, repo) = init_repo();
+		let work_tree = &dir.0;
+		let one = commit_files(&repo, &[("a", "1"), ("only-one", "x")]);
+		switch(&repo, "topic", true, None, false).unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			"ref: refs/heads/topic\n"
+		);
+		let two = commit_files(&repo, &[("a", "2"), ("only-two", "y")]);
+		assert_eq!(
+			refs::resolve_ref(&repo, "refs/heads/topic").unwrap(),
+			Some(two.clone())
+		);
+
+		switch(&repo, "master", false, None, false).unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			"ref: refs/heads/master\n"
+		);
+		assert_eq!(fs::read_to_string(work_tree.join("a")).unwrap(), "1");
+		assert!(work_tree.join("only-one").exists());
+		assert!(!work_tree.join("only-two").exists());
+
+		switch(&repo, "topic", false, None, false).unwrap();
+		assert_eq!(fs::read_to_string(work_tree.join("a")).unwrap(), "2");
+		assert!(!work_tree.join("only-one").exists());
+		assert!(work_tree.join("only-two").exists());
+
+		// Commits and tags aren't switched to without --detach, and nothing changes.
+		for target in [one.as_str(), "topic~1"] {
+			assert!(matches!(
+				switch(&repo, target, false, None, false),
+				Err(CheckoutError::NotABranch(name)) if name == target
+			));
+		}
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			"ref: refs/heads/topic\n"
+		);
+		assert_eq!(fs::read_to_string(work_tree.join("a")).unwrap(), "2");
+
+		switch(&repo, "topic~1", false, None, true).unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			format!("{one}\n")
+		);
+		assert_eq!(fs::read_to_string(work_tree.join("a")).unwrap(), "1");
+		// A branch given with --detach detaches at its commit.
+		switch(&repo, "topic", false, None, true).unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			format!("{two}\n")
+		);
+
+		assert!(matches!(
+			switch(&repo, "topic", true, None, false),
+			Err(CheckoutError::BranchExists(_))
+		));
+		assert!(matches!(
+			switch(&repo, "bad..name", true, None, false),
+			Err(CheckoutError::InvalidBranchName(_))
+		));
+		switch(&repo, "old", true, Some("master"), false).unwrap();
+		assert_eq!(
+			refs::resolve_ref(&repo, "refs/heads/old").unwrap(),
+			Some(one)
+		);
+		assert_eq!(fs::read_to_string(work_tree.join("a")).unwrap(), "1");
+	}
+
+	/// Stores a tree of `entries` (mode, name, object) as they are, unsorted and unchecked.
+	fn raw_tree(repo: &Repo, entries: &[(&str, &str, &str)]) -> String {
+		let mut payload = Vec::new();
+		for (mode, name, hash) in entries {
+			payload.extend(format!("{mode} {name}\0").into_bytes());
+			payload.extend(hex::decode(hash).unwrap());
+		}
+		write_raw_object(repo, ObjectKind::Tree, &payload)
+	}
+
+	fn raw_commit(repo: &Repo, tree: &str) -> String {
+		let commit = format!(
+			"tree {tree}\nauthor A <a@example.com> 1700000000 +0000\n\
+			committer A <a@example.com> 1700000000 +0000\n\ncrafted\n"
+		);
+		write_raw_object(repo, ObjectKind::Commit, commit.as_bytes())
+	}
+
+	#[test]
+	fn checkout_stays_in_the_work_tree() {
+		let (dir, repo) = init_repo();
+		let outside = TempDir::new();
+		let blob = write_raw_object(&repo, ObjectKind::Blob, b"#!/bin/sh\n");
+
+		// Through a symlink checked out first.
+		let target = outside.0.to_str().unwrap().as_bytes();
+		let link = write_raw_object(&repo, ObjectKind::Blob, target);
+		let evil = raw_tree(&repo, &[("100644", "evil", &blob)]);
+		let tree = raw_tree(&repo, &[("120000", "a", &link), ("40000", "a", &evil)]);
+		assert!(matches!(
+			checkout(&repo, &raw_commit(&repo, &tree)),
+			Err(CheckoutError::BeyondSymlink(path)) if path == "a/evil"
+		));
+		assert!(!outside.0.join("evil").exists());
+		fs::remove_file(dir.0.join("a")).unwrap();
+
+		// Into the repository, whatever the case.
+		let hook = raw_tree(&repo, &[("100755", "pre-commit", &blob)]);
+		let hooks = raw_tree(&repo, &[("40000", "hooks", &hook)]);
+		for name in [".git", ".GIT"] {
+			let tree = raw_tree(&repo, &[("40000", name, &hooks)]);
+			assert!(matches!(
+				checkout(&repo, &raw_commit(&repo, &tree)),
+				Err(CheckoutError::InvalidPath(path)) if path == name
+			));
+		}
+		assert!(!repo.git_dir().join("hooks/pre-commit").exists());
+
+		// Up from the work tree, or with names that aren't names.
+		for name in ["..", ".", "", "a/b"] {
+			let tree = raw_tree(&repo, &[("40000", "d", &hooks), ("100644", name, &blob)]);
+			assert!(matches!(
+				checkout(&repo, &raw_commit(&repo, &tree)),
+				Err(CheckoutError::InvalidPath(path)) if path == name
+			));
+		}
+		assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn checkout_index_writes_index_files() {
+		let (dir, repo) = init_repo();
+		commit_files(&repo, &[("a.txt", "a\n"), ("d/b.txt", "b\n")]);
+		let work_tree = repo.work_tree();
+		fs::remove_dir_all(work_tree.join("d")).unwrap();
+		fs::write(work_tree.join("a.txt"), "changed\n").unwrap();
+
+		// d/b.txt is written anyway.
+		let paths = [
+			"a.txt".to_string(),
+			"d/b.txt".to_string(),
+			"nope".to_string(),
+		];
+		assert!(matches!(
+			checkout_index(&repo, Some(&paths), None, false),
+			Err(CheckoutError::Skipped(skipped)) if skipped == [
+				"a.txt already exists, no checkout",
+				"nope is not in the cache",
+			]
+		));
+		assert_eq!(
+			fs::read_to_string(work_tree.join("d/b.txt")).unwrap(),
+			"b\n"
+		);
+		assert_eq!(
+			fs::read_to_string(work_tree.join("a.txt")).unwrap(),
+			"changed\n"
+		);
+		let index = read_index(&repo).unwrap();
+		let entry = index.entries.iter().find(|e| e.path == "d/b.txt").unwrap();
+		assert!(entry.stat_matches(&fs::symlink_metadata(work_tree.join("d/b.txt")).unwrap()));
+
+		checkout_index(&repo, None, None, true).unwrap();
+		assert_eq!(fs::read_to_string(work_tree.join("a.txt")).unwrap(), "a\n");
+		// Up to date files aren't in the way.
+		checkout_index(&repo, None, None, false).unwrap();
+
+		let prefix = format!("{}/", dir.0.join("export").display());
+		checkout_index(&repo, None, Some(&prefix), false).unwrap();
+		assert_eq!(
+			fs::read_to_string(dir.0.join("export/a.txt")).unwrap(),
+			"a\n"
+		);
+		assert_eq!(
+			fs::read_to_string(dir.0.join("export/d/b.txt")).unwrap(),
+			"b\n"
+		);
+		assert!(matches!(
+			checkout_index(&repo, None, Some(&prefix), false),
+			Err(CheckoutError::Skipped(skipped)) if skipped.len() == 2
+		));
+	}
+
+	#[test]
+	fn checkout_index_skips_paths_out_of_the_work_tree() {
+		let (dir, repo) = init_repo();
+		let blob = write_raw_object(&repo, ObjectKind::Blob, b"#!/bin/sh\n");
+		let mut id = [0; 20];
+		hex::decode_to_slice(&blob, &mut id).unwrap();
+		let entries = ["../escaped", ".git/hooks/pre-commit", "ok"]
+			.map(|path| new_entry(path, 0o100755, id))
+			.to_vec();
+		write_index(
+			&repo,
+			&Index {
+				sha1: [0; 20],
+				version: 2,
+				entries,
+				cache_tree: None,
+			},
+		)
+		.unwrap();
+
+		assert!(matches!(
+			checkout_index(&repo, None, None, false),
+			Err(CheckoutError::Skipped(skipped)) if skipped == [
+				"Invalid path '../escaped'",
+				"Invalid path '.git/hooks/pre-commit'",
+			]
+		));
+		assert!(dir.0.join("ok").exists());
+		assert!(!dir.0.parent().unwrap().join("escaped").exists());
+		assert!(!repo.git_dir().join("hooks/pre-commit").exists());
+	}
+}
@@ -0,0 +1,238 @@
+const MONTHS: [&str; 12] = [
+	"Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Parses a date given in one of the formats git accepts in `GIT_AUTHOR_DATE` and
+/// `GIT_COMMITTER_DATE`. Returns the unix timestamp and the timezone offset in minutes east of
+/// UTC.
+///
+/// Supported formats:
+/// - git's internal format: `<unix timestamp> <+hhmm>`, optionally prefixed with `@`, in which
+///   case the offset may be omitted
+/// - RFC 2822: `Thu, 07 Apr 2005 22:13:13 +0200`, the day of week being optional
+/// - ISO 8601: `2005-04-07T22:13:13+02:00`, with `T` or a space between date and time
+///
+/// Dates without an offset are taken to be UTC.
+pub fn parse_date(date: &str) -> Option<(i64, i32)> {
+	let date = date.trim();
+	if let Some(raw) = date.strip_prefix('@') {
+		return parse_raw(raw, true);
+	}
+	parse_raw(date, false)
+		.or_else(|| parse_rfc2822(date))
+		.or_else(|| parse_iso8601(date))
+}
+
+/// `<timestamp> <offset>`, the offset only being optional if `offset_optional`.
+fn parse_raw(date: &str, offset_optional: bool) -> Option<(i64, i32)> {
+	let (timestamp, offset) = match date.split_once(' ') {
+		Some((timestamp, offset)) => (timestamp, Some(offset.trim())),
+		None => (date, None),
+	};
+	if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+	let offset = match offset {
+		Some(offset) => parse_offset(offset)?,
+		None if offset_optional => 0,
+		None => return None,
+	};
+	Some((timestamp.parse().ok()?, offset))
+}
+
+/// `[Thu, ]07 Apr 2005 22:13:13 +0200`
+fn parse_rfc2822(date: &str) -> Option<(i64, i32)> {
+	let date = match date.split_once(", ") {
+		Some((_day_of_week, date)) => date,
+		None => date,
+	};
+	let mut parts = date.split_whitespace();
+	let day = parts.next()?.parse().ok()?;
+	let month = parts.next()?;
+	let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+	let year = parts.next()?.parse().ok()?;
+	let seconds = parse_time(parts.next()?)?;
+	let offset = match parts.next() {
+		Some(offset) => parse_offset(offset)?,
+		None => 0,
+	};
+	if parts.next().is_some() {
+		return None;
+	}
+	to_timestamp(year, month, day, seconds, offset)
+}
+
+/// `2005-04-07T22:13:13+02:00`, `2005-04-07 22:13:13 +0200`, `2005-04-07T22:13:13Z`
+fn parse_iso8601(date: &str) -> Option<(i64, i32)> {
+	let (day, time) = date.split_once(['T', ' '])?;
+	let mut day = day.splitn(3, '-');
+	let year = day.next()?.parse().ok()?;
+	let month = day.next()?.parse().ok()?;
+	let day = day.next()?.parse().ok()?;
+
+	let time = time.trim_start();
+	let (time, offset) = match time.find(['+', '-', 'Z', ' ']) {
+		Some(idx) => (&time[..idx], time[idx..].trim_start()),
+		None => (time, ""),
+	};
+	let offset = match offset {
+		"" | "Z" => 0,
+		offset => parse_offset(&offset.replace(':', ""))?,
+	};
+	to_timestamp(year, month, day, parse_time(time)?, offset)
+}
+
+/// `HH:MM[:SS]`, returns seconds since midnight.
+fn parse_time(time: &str) -> Option<i64> {
+	let mut parts = time.split(':');
+	let hours: i64 = parts.next()?.parse().ok()?;
+	let minutes: i64 = parts.next()?.parse().ok()?;
+	let seconds: i64 = match parts.next() {
+		Some(seconds) => seconds.parse().ok()?,
+		None => 0,
+	};
+	if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+		return None;
+	}
+	Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// `+hhmm` or `-hhmm`, returns minutes east of UTC.
+fn parse_offset(offset: &str) -> Option<i32> {
+	let (sign, digits) = match offset.as_bytes().first()? {
+		b'+' => (1, &offset[1..]),
+		b'-' => (-1, &offset[1..]),
+		_ => return None,
+	};
+	if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+	let hours: i32 = digits[..2].parse().ok()?;
+	let minutes: i32 = digits[2..].parse().ok()?;
+	if minutes > 59 {
+		return None;
+	}
+	Some(sign * (hours * 60 + minutes))
+}
+
+/// Converts a local date and time in timezone `offset` to a unix timestamp.
+fn to_timestamp(year: i64, month: u32, day: u32, seconds: i64, offset: i32) -> Option<(i64, i32)> {
+	if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+		return None;
+	}
+	let local = days_from_civil(year, month, day) * 86400 + seconds;
+	Some((local - offset as i64 * 60, offset))
+}
+
+/// Formats a timezone offset in minutes east of UTC the way git stores it, e.g. `+0100`.
+pub fn format_offset(offset: i32) -> String {
+	let sign = if offset < 0 { '-' } else { '+' };
+	let offset = offset.unsigned_abs();
+	format!("{sign}{:02}{:02}", offset / 60, offset % 60)
+}
+
+/// Formats a date the way `git log` shows it by default, in its own timezone, e.g.
+/// `Thu Apr 7 22:13:13 2005 +0200`.
+pub fn format_date(timestamp: i64, offset: i32) -> String {
+	let local = timestamp + offset as i64 * 60;
+	let days = local.div_euclid(86400);
+	let seconds = local.rem_euclid(86400);
+	let (year, month, day) = civil_from_days(days);
+	// 1970-01-01 was a Thursday
+	let weekday = WEEKDAYS[(days + 4).rem_euclid(7) as usize];
+	format!(
+		"{weekday} {} {day} {:02}:{:02}:{:02} {year} {}",
+		MONTHS[month as usize - 1],
+		seconds / 3600,
+		seconds / 60 % 60,
+		seconds % 60,
+		format_offset(offset)
+	)
+}
+
+// Source: Howard Hinnant, "chrono-Compatible Low-Level Date Algorithms"
+
+/// Number of days since 1970-01-01 of given date in the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let month = month as i64;
+	let day_of_year =
+		(153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+/// `(year, month, day)` of the date `days` days after 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let days = days + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days - era * 146097;
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+	let year = year_of_era + era * 400 + i64::from(month <= 2);
+	(year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_supported_formats() {
+		assert_eq!(parse_date("@1700000000 +0000"), Some((1700000000, 0)));
+		assert_eq!(parse_date("@1700000000"), Some((1700000000, 0)));
+		assert_eq!(parse_date("1700000000 -0130"), Some((1700000000, -90)));
+		assert_eq!(
+			parse_date("Thu, 07 Apr 2005 22:13:13 +0200"),
+			Some((1112904793, 120))
+		);
+		assert_eq!(
+			parse_date("7 Apr 2005 22:13:13 +0200"),
+			Some((1112904793, 120))
+		);
+		assert_eq!(
+			parse_date("2005-04-07T22:13:13+02:00"),
+			Some((1112904793, 120))
+		);
+		assert_eq!(
+			parse_date("2005-04-07 20:13:13 +0000"),
+			Some((1112904793, 0))
+		);
+		assert_eq!(parse_date("2005-04-07T20:13:13Z"), Some((1112904793, 0)));
+	}
+
+	#[test]
+	fn reject_invalid_dates() {
+		assert_eq!(parse_date(""), None);
+		assert_eq!(parse_date("1700000000"), None);
+		assert_eq!(parse_date("@1700000000 0200"), None);
+		assert_eq!(parse_date("Thu, 07 Foo 2005 22:13:13 +0200"), None);
+		assert_eq!(parse_date("2005-13-07T22:13:13Z"), None);
+		assert_eq!(parse_date("yesterday"), None);
+	}
+
+	#[test]
+	fn civil_days_round_trip() {
+		for days in [-719468, -1, 0, 11016, 19675, 2932896] {
+			let (year, month, day) = civil_from_days(days);
+			assert_eq!(days_from_civil(year, month, day), days);
+		}
+		assert_eq!(civil_from_days(0), (1970, 1, 1));
+		assert_eq!(days_from_civil(2000, 3, 1), 11017);
+		assert_eq!(format_offset(-90), "-0130");
+		assert_eq!(format_offset(60), "+0100");
+		assert_eq!(
+			format_date(1112904793, 120),
+			"Thu Apr 7 22:13:13 2005 +0200"
+		);
+		assert_eq!(format_date(0, -90), "Wed Dec 31 22:30:00 1969 -0130");
+	}
+}
@@ -0,0 +1,425 @@
+use std::ops::Range;
+
+/// Single step of an edit script turning `a` into `b`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+	/// `a[a]` and `b[b]` are the same.
+	Equal { a: usize, b: usize },
+	/// `a[a]` is removed.
+	Delete { a: usize },
+	/// `b[b]` is inserted.
+	Insert { b: usize },
+}
+
+/// Range of `a` replaced by a range of `b`. Either range may be empty (pure insertion or
+/// deletion).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+	pub a: Range<usize>,
+	pub b: Range<usize>,
+}
+
+/// Computes the shortest edit script between `a` and `b`.
+///
+/// Source: Eugene W. Myers, "An O(ND) Difference Algorithm and Its Variations"
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Op> {
+	let n = a.len() as isize;
+	let m = b.len() as isize;
+	let max = (n + m) as usize;
+	let offset = max as isize + 1;
+
+	// v[k + offset] holds the furthest x reached on diagonal k.
+	let mut v = vec![0_isize; 2 * max + 3];
+	let mut trace = Vec::new();
+
+	'search: for d in 0..=(max as isize) {
+		trace.push(v.clone());
+		for k in (-d..=d).step_by(2) {
+			let idx = (k + offset) as usize;
+			let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+				v[idx + 1]
+			} else {
+				v[idx - 1] + 1
+			};
+			let mut y = x - k;
+			while x < n && y < m && a[x as usize] == b[y as usize] {
+				x += 1;
+				y += 1;
+			}
+			v[idx] = x;
+			if x >= n && y >= m {
+				break 'search;
+			}
+		}
+	}
+
+	let mut ops = Vec::with_capacity(max);
+	let (mut x, mut y) = (n, m);
+	for (d, v) in trace.iter().enumerate().rev() {
+		let d = d as isize;
+		let k = x - y;
+		let idx = (k + offset) as usize;
+		let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+			k + 1
+		} else {
+			k - 1
+		};
+		let prev_x = v[(prev_k + offset) as usize];
+		let prev_y = prev_x - prev_k;
+
+		while x > prev_x && y > prev_y {
+			x -= 1;
+			y -= 1;
+			ops.push(Op::Equal {
+				a: x as usize,
+				b: y as usize,
+			});
+		}
+		if d > 0 {
+			if x == prev_x {
+				y -= 1;
+				ops.push(Op::Insert { b: y as usize });
+			} else {
+				x -= 1;
+				ops.push(Op::Delete { a: x as usize });
+			}
+		}
+		x = prev_x;
+		y = prev_y;
+	}
+
+	ops.reverse();
+	ops
+}
+
+/// Groups consecutive non-equal [Op]s into [Hunk]s.
+pub fn hunks(ops: &[Op]) -> Vec<Hunk> {
+	let mut hunks = Vec::new();
+	let mut current: Option<Hunk> = None;
+	// Position in a and b right after the last processed op.
+	let (mut a_pos, mut b_pos) = (0, 0);
+
+	for op in ops {
+		match *op {
+			Op::Equal { a, b } => {
+				hunks.extend(current.take());
+				a_pos = a + 1;
+				b_pos = b + 1;
+			}
+			Op::Delete { a } => {
+				let hunk = current.get_or_insert(Hunk {
+					a: a..a,
+					b: b_pos..b_pos,
+				});
+				hunk.a.end = a + 1;
+				a_pos = a + 1;
+			}
+			Op::Insert { b } => {
+				let hunk = current.get_or_insert(Hunk {
+					a: a_pos..a_pos,
+					b: b..b,
+				});
+				hunk.b.end = b + 1;
+				b_pos = b + 1;
+			}
+		}
+	}
+	hunks.extend(current);
+
+	hunks
+}
+
+/// Renders the hunks of a unified diff between `old` and `new`: `@@` headers followed by the
+/// unchanged (` `), removed (`-`) and added (`+`) lines, with `context` unchanged lines around
+/// each change. Changes closer than twice the context share a hunk.
+pub fn unified(old: &[u8], new: &[u8], context: usize) -> Vec<u8> {
+	render_hunks(
+		old,
+		new,
+		context,
+		|out, lines| {
+			for line in lines {
+				write_line(out, b' ', line);
+			}
+		},
+		|out, removed, added| {
+			for line in removed {
+				write_line(out, b'-', line);
+			}
+			for line in added {
+				write_line(out, b'+', line);
+			}
+		},
+	)
+}
+
+/// Renders the hunks of a diff between `old` and `new` like [unified], but with the changed
+/// lines as a [word_diff] and no markers in front of lines, like `git diff --word-diff`.
+pub fn unified_words(old: &[u8], new: &[u8], context: usize) -> Vec<u8> {
+	let write_text = |out: &mut Vec<u8>, text: &[u8]| {
+		out.extend_from_slice(text);
+		if !text.ends_with(b"\n") {
+			out.push(b'\n');
+		}
+	};
+	render_hunks(
+		old,
+		new,
+		context,
+		|out, lines| {
+			for line in lines {
+				write_text(out, line);
+			}
+		},
+		|out, removed, added| {
+			let removed = String::from_utf8_lossy(&removed.concat()).into_owned();
+			let added = String::from_utf8_lossy(&added.concat()).into_owned();
+			write_text(out, word_diff(&removed, &added).as_bytes());
+		},
+	)
+}
+
+/// Lays out the hunks of a diff between the lines of `old` and `new` with `context` unchanged
+/// lines around each change, see [unified]. Unchanged lines are written by `unchanged`, each
+/// run of removed lines and the added lines replacing it by `changed`.
+fn render_hunks(
+	old: &[u8],
+	new: &[u8],
+	context: usize,
+	unchanged: impl Fn(&mut Vec<u8>, &[&[u8]]),
+	changed: impl Fn(&mut Vec<u8>, &[&[u8]], &[&[u8]]),
+) -> Vec<u8> {
+	let old_lines = lines(old);
+	let new_lines = lines(new);
+	let changes = hunks(&diff(&old_lines, &new_lines));
+
+	let mut out = Vec::new();
+	let mut first = 0;
+	while first < changes.len() {
+		let mut last = first;
+		while last + 1 < changes.len()
+			&& changes[last + 1].a.start - changes[last].a.end <= 2 * context
+		{
+			last += 1;
+		}
+
+		// Lines around changes are unchanged, so there are as many of them in `old` as in `new`.
+		let a_start = changes[first].a.start.saturating_sub(context);
+		let b_start = changes[first].b.start - (changes[first].a.start - a_start);
+		let a_end = (changes[last].a.end + context).min(old_lines.len());
+		let b_end = changes[last].b.end + (a_end - changes[last].a.end);
+		out.extend(
+			format!(
+				"@@ -{} +{} @@\n",
+				hunk_range(a_start, a_end),
+				hunk_range(b_start, b_end)
+			)
+			.into_bytes(),
+		);
+
+		let mut a = a_start;
+		for change in &changes[first..=last] {
+			unchanged(&mut out, &old_lines[a..change.a.start]);
+			changed(
+				&mut out,
+				&old_lines[change.a.clone()],
+				&new_lines[change.b.clone()],
+			);
+			a = change.a.end;
+		}
+		unchanged(&mut out, &old_lines[a..a_end]);
+
+		first = last + 1;
+	}
+	out
+}
+
+/// `start,len` of a hunk header, 1-based. An empty range starts at the line before it and a
+/// length of 1 is omitted.
+fn hunk_range(start: usize, end: usize) -> String {
+	match end - start {
+		0 => format!("{start},0"),
+		1 => format!("{}", start + 1),
+		len => format!("{},{len}", start + 1),
+	}
+}
+
+fn write_line(out: &mut Vec<u8>, marker: u8, line: &[u8]) {
+	out.push(marker);
+	out.extend_from_slice(line);
+	if !line.ends_with(b"\n") {
+		out.extend_from_slice(b"\n\\ No newline at end of file\n");
+	}
+}
+
+/// Whether `content` looks binary like git decides it: a NUL byte in its first 8000 bytes.
+pub fn is_binary(content: &[u8]) -> bool {
+	content[..content.len().min(8000)].contains(&0)
+}
+
+/// Splits `content` into lines, keeping the trailing `\n` of each line.
+pub fn lines(content: &[u8]) -> Vec<&[u8]> {
+	content.split_inclusive(|b| *b == b'\n').collect()
+}
+
+/// Renders the changes between `old` and `new` word by word, in git's default `--word-diff`
+/// style: removed words as `[-...-]` and added words as `{+...+}`, unchanged text as is. Words
+/// are runs of non-whitespace; whitespace around them is taken from `new` and a marked run
+/// spanning several lines is marked on each line.
+pub fn word_diff(old: &str, new: &str) -> String {
+	let old_words = words(old);
+	let new_words = words(new);
+	let old_texts: Vec<_> = old_words.iter().map(|word| &old[word.clone()]).collect();
+	let new_texts: Vec<_> = new_words.iter().map(|word| &new[word.clone()]).collect();
+	let ops = diff(&old_texts, &new_texts);
+
+	let mut out = String::new();
+	// End of the text of `new` written so far.
+	let mut pos = 0;
+	for hunk in hunks(&ops) {
+		let start = if !hunk.b.is_empty() {
+			new_words[hunk.b.start].start
+		} else if hunk.b.start > 0 {
+			// Removed words are shown right after the word before them.
+			new_words[hunk.b.start - 1].end
+		} else {
+			0
+		};
+		out += &new[pos..start];
+		pos = start;
+		if !hunk.a.is_empty() {
+			let text = &old[old_words[hunk.a.start].start..old_words[hunk.a.end - 1].end];
+			mark(&mut out, text, "[-", "-]");
+		}
+		if !hunk.b.is_empty() {
+			pos = new_words[hunk.b.end - 1].end;
+			mark(&mut out, &new[start..pos], "{+", "+}");
+		}
+	}
+	out += &new[pos..];
+	out
+}
+
+/// Writes `text` between `open` and `close`, line by line.
+fn mark(out: &mut String, text: &str, open: &str, close: &str) {
+	for (idx, line) in text.split('\n').enumerate() {
+		if idx > 0 {
+			out.push('\n');
+		}
+		if !line.is_empty() {
+			*out += &format!("{open}{line}{close}");
+		}
+	}
+}
+
+/// Ranges of the runs of non-whitespace in `text`.
+fn words(text: &str) -> Vec<Range<usize>> {
+	let mut words = Vec::new();
+	let mut start = None;
+	for (idx, c) in text.char_indices() {
+		match (c.is_whitespace(), start) {
+			(true, Some(word_start)) => {
+				words.push(word_start..idx);
+				start = None;
+			}
+			(false, None) => start = Some(idx),
+			_ => {}
+		}
+	}
+	words.extend(start.map(|start| start..text.len()));
+	words
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn apply(a: &[char], b: &[char], ops: &[Op]) -> Vec<char> {
+		ops.iter()
+			.filter_map(|op| match *op {
+				Op::Equal { a: idx, .. } => Some(a[idx]),
+				Op::Insert { b: idx } => Some(b[idx]),
+				Op::Delete { .. } => None,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn shortest_edit_script() {
+		let a: Vec<char> = "ABCABBA".chars().collect();
+		let b: Vec<char> = "CBABAC".chars().collect();
+		let ops = diff(&a, &b);
+
+		let edits = ops
+			.iter()
+			.filter(|op| !matches!(op, Op::Equal { .. }))
+			.count();
+		assert_eq!(edits, 5);
+		assert_eq!(apply(&a, &b, &ops), b);
+	}
+
+	#[test]
+	fn empty_inputs() {
+		assert!(diff::<u8>(&[], &[]).is_empty());
+		assert_eq!(diff(&[], &[1]), vec![Op::Insert { b: 0 }]);
+		assert_eq!(diff(&[1], &[]), vec![Op::Delete { a: 0 }]);
+	}
+
+	#[test]
+	fn hunks_of_changed_lines() {
+		let a = lines(b"one\ntwo\nthree\nfour\n");
+		let b = lines(b"one\n2\nthree\nfour\nfive\n");
+		let hunks = hunks(&diff(&a, &b));
+		assert_eq!(
+			hunks,
+			vec![Hunk { a: 1..2, b: 1..2 }, Hunk { a: 4..4, b: 4..5 }]
+		);
+	}
+
+	#[test]
+	fn unified_diff_with_context() {
+		let old: String = (1..=14).map(|n| format!("{n}\n")).collect();
+		let new = old.replacen("2\n", "two\n", 1).replacen("5\n", "", 1) + "15";
+		assert_eq!(
+			String::from_utf8(unified(old.as_bytes(), new.as_bytes(), 3)).unwrap(),
+			"\
+@@ -1,8 +1,7 @@
+ 1
+-2
++two
+ 3
+ 4
+-5
+ 6
+ 7
+ 8
+@@ -12,3 +11,4 @@
+ 12
+ 13
+ 14
++15
+\\ No newline at end of file
+"
+		);
+		assert_eq!(
+			String::from_utf8(unified(b"", b"new\n", 3)).unwrap(),
+			"@@ -0,0 +1 @@\n+new\n"
+		);
+		assert!(unified(b"same\n", b"same\n", 3).is_empty());
+	}
+
+	#[test]
+	fn word_diff_marks_changed_words() {
+		assert_eq!(
+			word_diff("the quick brown fox", "the slow brown fox"),
+			"the [-quick-]{+slow+} brown fox"
+		);
+		assert_eq!(word_diff("a b", "a b c"), "a b {+c+}");
+		assert_eq!(word_diff("a b\n", "a\n"), "a[-b-]\n");
+		assert_eq!(
+			word_diff("ten\n", "TEN\nnew line\n"),
+			"[-ten-]{+TEN+}\n{+new line+}\n"
+		);
+		assert_eq!(word_diff("same", "same"), "same");
+	}
+}
@@ -0,0 +1,548 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+use thiserror::Error;
+
+use crate::repo::Repo;
+use crate::{
+	decode_object, hash_git_object, refs, sort_tree_entries, Commit, GitObject, HashObjectError,
+	ObjectId, ObjectKind, ReadObjectError, Signature, Tag, TreeEntry,
+};
+
+const TREE_MODE: u32 = 0o40000;
+const GITLINK_MODE: u32 = 0o160000;
+
+#[derive(Debug, Error)]
+pub enum FastImportError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	WriteObject(#[from] HashObjectError),
+
+	#[error("{0} is not a commit")]
+	NotACommit(String),
+
+	#[error("Merge commit {0} can't be exported yet")]
+	MergeCommit(String),
+
+	#[error("Unsupported command: {0}")]
+	UnsupportedCommand(String),
+
+	#[error("Malformed line: {0}")]
+	Malformed(String),
+
+	#[error("Unknown mark {0}")]
+	UnknownMark(String),
+
+	#[error("Stream ended in the middle of a command")]
+	Truncated,
+}
+
+/// Files of a commit by path, with their mode and blob (or commit, for submodules).
+type Files = BTreeMap<String, (u32, ObjectId)>;
+
+/// Writes the history of `refs`, given by full name and the commit or annotated tag they point
+/// at, as a fast-import stream: every blob once, then every commit with the files it changed
+/// compared to its parent, oldest first, then a `tag` command for a tag. Objects are referred
+/// to by marks, numbered in order from 1.
+///
+/// Commits reachable from `excluded` are left out, like with `excluded..ref`. A commit whose
+/// parent is left out is written as a root commit, with all of its files.
+///
+/// Only linear history is supported, a merge fails with [FastImportError::MergeCommit].
+pub fn export(
+	repo: &Repo,
+	refs: &[(String, ObjectId)],
+	excluded: &[ObjectId],
+	out: &mut impl Write,
+) -> Result<(), FastImportError> {
+	let excluded = ancestors(repo, excluded)?;
+	let mut marks: HashMap<ObjectId, usize> = HashMap::new();
+	for (name, tip) in refs {
+		let (tip, tag) = peel(repo, *tip)?;
+		// Commits not exported yet, newest first.
+		let mut commits = Vec::new();
+		let mut next = Some(tip);
+		while let Some(id) = next.filter(|id| !marks.contains_key(id) && !excluded.contains(id)) {
+			let commit = read_commit(repo, &id)?;
+			if commit.parents.len() > 1 {
+				return Err(FastImportError::MergeCommit(hex::encode(id)));
+			}
+			next = commit.parents.first().copied();
+			commits.push((id, commit));
+		}
+		if commits.is_empty() && tag.is_none() {
+			if let Some(mark) = marks.get(&tip) {
+				writeln!(out, "reset {name}\nfrom :{mark}\n")?;
+			}
+			continue;
+		}
+
+		for (id, mut commit) in commits.into_iter().rev() {
+			commit.parents.retain(|parent| !excluded.contains(parent));
+			let old_files = match commit.parents.first() {
+				Some(parent) => read_files(repo, &read_commit(repo, parent)?.tree)?,
+				None => Files::new(),
+			};
+			let files = read_files(repo, &commit.tree)?;
+			let changed: Vec<_> = files
+				.iter()
+				.filter(|(path, file)| old_files.get(*path) != Some(file))
+				.collect();
+
+			for (_, (mode, blob)) in &changed {
+				if *mode == GITLINK_MODE || marks.contains_key(blob) {
+					continue;
+				}
+				let GitObject::Blob(content) = decode_object(repo, hex::encode(blob))? else {
+					return Err(ReadObjectError::CorruptedObject {
+						hash: hex::encode(blob),
+						context: "tree entry isn't a blob",
+					}
+					.into());
+				};
+				let mark = marks.len() + 1;
+				marks.insert(*blob, mark);
+				write!(out, "blob\nmark :{mark}\ndata {}\n", content.len())?;
+				out.write_all(&content)?;
+				writeln!(out)?;
+			}
+
+			let mark = marks.len() + 1;
+			marks.insert(id, mark);
+			writeln!(out, "commit {name}\nmark :{mark}")?;
+			writeln!(
+				out,
+				"author {}\ncommitter {}",
+				commit.author, commit.committer
+			)?;
+			write!(out, "data {}\n{}", commit.message.len(), commit.message)?;
+			if let Some(parent) = commit.parents.first() {
+				writeln!(out, "from :{}", marks[parent])?;
+			}
+			for path in old_files.keys().filter(|path| !files.contains_key(*path)) {
+				writeln!(out, "D {path}")?;
+			}
+			for (path, (mode, object)) in changed {
+				match marks.get(object) {
+					Some(mark) => writeln!(out, "M {mode:06o} :{mark} {path}")?,
+					None => writeln!(out, "M {mode:06o} {} {path}", hex::encode(object))?,
+				}
+			}
+			writeln!(out)?;
+		}
+
+		if let Some(tag) = tag {
+			let tag_name = name.strip_prefix("refs/tags/").unwrap_or(&tag.name);
+			match marks.get(&tip) {
+				Some(mark) => writeln!(out, "tag {tag_name}\nfrom :{mark}")?,
+				None => writeln!(out, "tag {tag_name}\nfrom {}", hex::encode(tip))?,
+			}
+			writeln!(out, "tagger {}", tag.tagger)?;
+			writeln!(out, "data {}\n{}", tag.message.len(), tag.message)?;
+		}
+	}
+	Ok(())
+}
+
+/// Follows annotated tags from `id` to a commit. Returns the commit and the outermost tag, if
+/// `id` is one.
+fn peel(repo: &Repo, id: ObjectId) -> Result<(ObjectId, Option<Tag>), FastImportError> {
+	let mut outermost = None;
+	let mut id = id;
+	loop {
+		match decode_object(repo, hex::encode(id))? {
+			GitObject::Commit(_) => return Ok((id, outermost)),
+			GitObject::Tag(tag) => {
+				id = tag.object;
+				outermost.get_or_insert(tag);
+			}
+			_ => return Err(FastImportError::NotACommit(hex::encode(id))),
+		}
+	}
+}
+
+/// Commits reachable from `tips`, themselves included.
+fn ancestors(repo: &Repo, tips: &[ObjectId]) -> Result<HashSet<ObjectId>, FastImportError> {
+	let mut seen = HashSet::new();
+	let mut pending = tips.to_vec();
+	while let Some(id) = pending.pop() {
+		if seen.insert(id) {
+			pending.extend(read_commit(repo, &id)?.parents);
+		}
+	}
+	Ok(seen)
+}
+
+/// Creates the objects described by fast-import stream `input` and points the refs it names at
+/// their commits. Returns the refs, by name.
+///
+/// Supports the `blob`, `commit` (without `merge`), `tag` (of commits), `reset` and `done`
+/// commands, and `data` with an exact byte count.
+pub fn import(repo: &Repo, input: &[u8]) -> Result<Vec<(String, ObjectId)>, FastImportError> {
+	let mut stream = Stream { data: input };
+	let mut marks: HashMap<String, ObjectId> = HashMap::new();
+	let mut branches: BTreeMap<String, ObjectId> = BTreeMap::new();
+
+	while let Some(line) = stream.next_line() {
+		if line.is_empty() {
+			continue;
+		}
+		let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+		match command {
+			"blob" => {
+				let mark = stream.optional_field("mark");
+				let content = stream.data()?;
+				let blob = hash_git_object(repo, GitObject::Blob(Cow::Owned(content)), true)?;
+				if let Some(mark) = mark {
+					marks.insert(mark, blob.hash);
+				}
+			}
+			"commit" => {
+				let mark = stream.optional_field("mark");
+				let author = stream.optional_field("author");
+				let committer = stream
+					.optional_field("committer")
+					.ok_or(FastImportError::Truncated)?;
+				let message = String::from_utf8_lossy(&stream.data()?).into_owned();
+				let parent = match stream.optional_field("from") {
+					Some(from) => Some(resolve_commitish(&marks, &from)?),
+					None => branches.get(arg).copied(),
+				};
+				if let Some(merge) = stream.optional_field("merge") {
+					return Err(FastImportError::UnsupportedCommand(format!(
+						"merge {merge}"
+					)));
+				}
+
+				let mut files = match parent {
+					Some(parent) => read_files(repo, &read_commit(repo, &parent)?.tree)?,
+					None => Files::new(),
+				};
+				while let Some(change) = stream.file_change() {
+					apply_change(&mut files, &marks, &change)?;
+				}
+
+				let committer = signature(&committer)?;
+				let commit = Commit {
+					tree: write_tree(repo, &files)?,
+					parents: parent.into_iter().collect(),
+					author: author.as_deref().map_or(Ok(committer.clone()), signature)?,
+					committer,
+					extra_headers: Vec::new(),
+					message,
+				};
+				let commit = hash_git_object(repo, GitObject::Commit(commit), true)?;
+				if let Some(mark) = mark {
+					marks.insert(mark, commit.hash);
+				}
+				branches.insert(arg.to_string(), commit.hash);
+			}
+			"tag" => {
+				let from = stream
+					.optional_field("from")
+					.ok_or(FastImportError::Truncated)?;
+				let object = resolve_commitish(&marks, &from)?;
+				stream.optional_field("original-oid");
+				let tagger = stream
+					.optional_field("tagger")
+					.ok_or(FastImportError::Truncated)?;
+				let message = String::from_utf8_lossy(&stream.data()?).into_owned();
+				let tag = Tag {
+					object,
+					kind: ObjectKind::Commit,
+					name: arg.to_string(),
+					tagger: signature(&tagger)?,
+					extra_headers: Vec::new(),
+					message,
+				};
+				let tag = hash_git_object(repo, GitObject::Tag(tag), true)?;
+				branches.insert(format!("refs/tags/{arg}"), tag.hash);
+			}
+			"reset" => {
+				if let Some(from) = stream.optional_field("from") {
+					branches.insert(arg.to_string(), resolve_commitish(&marks, &from)?);
+				}
+			}
+			"done" => break,
+			_ => return Err(FastImportError::UnsupportedCommand(line)),
+		}
+	}
+
+	for (name, id) in &branches {
+		refs::write_ref(repo, name, &hex::encode(id))?;
+	}
+	Ok(branches.into_iter().collect())
+}
+
+/// Parses the identity and date of an `author`, `committer` or `tagger` line.
+fn signature(line: &str) -> Result<Signature, FastImportError> {
+	Signature::parse(line).ok_or_else(|| FastImportError::Malformed(line.to_string()))
+}
+
+/// Reader of the lines and data blocks of a fast-import stream.
+struct Stream<'a> {
+	data: &'a [u8],
+}
+
+impl Stream<'_> {
+	fn peek_line(&self) -> Option<(String, usize)> {
+		if self.data.is_empty() {
+			return None;
+		}
+		let end = self
+			.data
+			.iter()
+			.position(|b| *b == b'\n')
+			.unwrap_or(self.data.len());
+		let next = (end + 1).min(self.data.len());
+		Some((
+			String::from_utf8_lossy(&self.data[..end]).into_owned(),
+			next,
+		))
+	}
+
+	fn next_line(&mut self) -> Option<String> {
+		self.peek_line().map(|(line, next)| {
+			self.data = &self.data[next..];
+			line
+		})
+	}
+
+	/// Value of the next line if it's `<name> <value>`.
+	fn optional_field(&mut self, name: &str) -> Option<String> {
+		let (line, next) = self.peek_line()?;
+		let value = line.strip_prefix(name)?.strip_prefix(' ')?.to_string();
+		self.data = &self.data[next..];
+		Some(value)
+	}
+
+	/// Reads a `data <count>` line and the bytes following it, skipping the optional newline
+	/// after them.
+	fn data(&mut self) -> Result<Vec<u8>, FastImportError> {
+		let line = self.next_line().ok_or(FastImportError::Truncated)?;
+		let count = line
+			.strip_prefix("data ")
+			.ok_or_else(|| FastImportError::Malformed(line.clone()))?;
+		if count.starts_with("<<") {
+			return Err(FastImportError::UnsupportedCommand(line));
+		}
+		let count: usize = count
+			.parse()
+			.map_err(|_| FastImportError::Malformed(line.clone()))?;
+		let content = self.data.get(..count).ok_or(FastImportError::Truncated)?;
+		self.data = &self.data[count..];
+		if let Some(rest) = self.data.strip_prefix(b"\n") {
+			self.data = rest;
+		}
+		Ok(content.to_vec())
+	}
+
+	/// Next `M`, `D` or `deleteall` line of a commit, if any.
+	fn file_change(&mut self) -> Option<String> {
+		match self.peek_line() {
+			Some((line, _))
+				if line.starts_with("M ") || line.starts_with("D ") || line == "deleteall" =>
+			{
+				self.next_line()
+			}
+			_ => None,
+		}
+	}
+}
+
+/// Applies a `M <mode> <object> <path>`, `D <path>` or `deleteall` line to `files`.
+fn apply_change(
+	files: &mut Files,
+	marks: &HashMap<String, ObjectId>,
+	change: &str,
+) -> Result<(), FastImportError> {
+	let malformed = || FastImportError::Malformed(change.to_string());
+	if change == "deleteall" {
+		files.clear();
+	} else if let Some(path) = change.strip_prefix("D ") {
+		// Deleting a directory deletes everything in it.
+		files.retain(|file, _| file != path && !file.starts_with(&format!("{path}/")));
+	} else {
+		let mut fields = change.splitn(4, ' ').skip(1);
+		let (Some(mode), Some(object), Some(path)) = (fields.next(), fields.next(), fields.next())
+		else {
+			return Err(malformed());
+		};
+		let mode = match u32::from_str_radix(mode, 8).map_err(|_| malformed())? {
+			0o644 => 0o100644,
+			0o755 => 0o100755,
+			mode => mode,
+		};
+		let object = resolve_commitish(marks, object)?;
+		files.insert(path.to_string(), (mode, object));
+	}
+	Ok(())
+}
+
+/// Resolves a `:<mark>` or a full object name.
+fn resolve_commitish(
+	marks: &HashMap<String, ObjectId>,
+	name: &str,
+) -> Result<ObjectId, FastImportError> {
+	if name.starts_with(':') {
+		return marks
+			.get(name)
+			.copied()
+			.ok_or_else(|| FastImportError::UnknownMark(name.to_string()));
+	}
+	let mut id = [0_u8; 20];
+	hex::decode_to_slice(name, &mut id)
+		.map_err(|_| FastImportError::Malformed(name.to_string()))?;
+	Ok(id)
+}
+
+fn read_commit(repo: &Repo, id: &ObjectId) -> Result<Commit, FastImportError> {
+	match decode_object(repo, hex::encode(id))? {
+		GitObject::Commit(commit) => Ok(commit),
+		_ => Err(FastImportError::NotACommit(hex::encode(id))),
+	}
+}
+
+/// Lists every file of `tree`, recursively.
+fn read_files(repo: &Repo, tree: &ObjectId) -> Result<Files, FastImportError> {
+	let mut files = Files::new();
+	collect_files(repo, "", tree, &mut files)?;
+	Ok(files)
+}
+
+fn collect_files(
+	repo: &Repo,
+	prefix: &str,
+	tree: &ObjectId,
+	files: &mut Files,
+) -> Result<(), FastImportError> {
+	let GitObject::Tree(entries) = decode_object(repo, hex::encode(tree))? else {
+		return Err(ReadObjectError::CorruptedObject {
+			hash: hex::encode(tree),
+			context: "not a tree",
+		}
+		.into());
+	};
+	for entry in entries.iter() {
+		let path = format!("{prefix}{}", entry.name);
+		if entry.mode == TREE_MODE {
+			collect_files(repo, &format!("{path}/"), &entry.object_hash, files)?;
+		} else {
+			files.insert(path, (entry.mode, *entry.object_hash));
+		}
+	}
+	Ok(())
+}
+
+/// Writes the trees holding `files`, returning the id of the root one.
+fn write_tree(repo: &Repo, files: &Files) -> Result<ObjectId, FastImportError> {
+	let mut entries = Vec::new();
+	let mut dirs: BTreeMap<&str, Files> = BTreeMap::new();
+	for (path, (mode, object)) in files {
+		match path.split_once('/') {
+			Some((dir, rest)) => {
+				dirs.entry(dir)
+					.or_default()
+					.insert(rest.to_string(), (*mode, *object));
+			}
+			None => entries.push(TreeEntry {
+				mode: *mode,
+				name: Cow::Borrowed(path.as_str()),
+				object_hash: Cow::Borrowed(object),
+			}),
+		}
+	}
+	for (dir, files) in dirs {
+		entries.push(TreeEntry {
+			mode: TREE_MODE,
+			name: Cow::Borrowed(dir),
+			object_hash: Cow::Owned(write_tree(repo, &files)?),
+		});
+	}
+
+	sort_tree_entries(&mut entries);
+	Ok(hash_git_object(repo, GitObject::Tree(Cow::Owned(entries)), true)?.hash)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::init_repo;
+
+	#[test]
+	fn history_round_trips_through_a_stream() {
+		let (_dir, repo) = init_repo();
+		let blob = |content: &[u8]| {
+			hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(content)), true)
+				.unwrap()
+				.hash
+		};
+		let signature = Signature::parse("A U Thor <a@example.com> 1700000000 +0100").unwrap();
+		let mut parent: Option<ObjectId> = None;
+		let mut commit = |files: &[(&str, u32, ObjectId)], message: &str| {
+			let files = files
+				.iter()
+				.map(|(path, mode, id)| (path.to_string(), (*mode, *id)))
+				.collect();
+			let commit = Commit {
+				tree: write_tree(&repo, &files).unwrap(),
+				parents: parent.into_iter().collect(),
+				author: signature.clone(),
+				committer: signature.clone(),
+				extra_headers: Vec::new(),
+				message: message.to_string(),
+			};
+			let id = hash_git_object(&repo, GitObject::Commit(commit), true)
+				.unwrap()
+				.hash;
+			parent = Some(id);
+			id
+		};
+		let (a, b, run) = (blob(b"a\n"), blob(b"b\n"), blob(b"#!/bin/sh\n"));
+		commit(&[("a.txt", 0o100644, a)], "first\n");
+		commit(
+			&[("a.txt", 0o100644, b), ("dir/run.sh", 0o100755, run)],
+			"second\n",
+		);
+		let tip = commit(&[("dir/run.sh", 0o100755, run)], "third\n");
+
+		let refs = [
+			("refs/heads/master".to_string(), tip),
+			("refs/tags/v1".to_string(), tip),
+		];
+		let mut stream = Vec::new();
+		export(&repo, &refs, &[], &mut stream).unwrap();
+		let stream_text = String::from_utf8(stream.clone()).unwrap();
+		assert!(stream_text.starts_with("blob\nmark :1\ndata 2\na\n\n"));
+		assert!(stream_text.contains("from :2\nM 100644 :3 a.txt\nM 100755 :4 dir/run.sh\n"));
+		assert!(stream_text.contains("from :5\nD a.txt\n\n"));
+		assert!(stream_text.ends_with("reset refs/tags/v1\nfrom :6\n\n"));
+
+		let (_other_dir, other) = init_repo();
+		assert_eq!(import(&other, &stream).unwrap(), refs);
+		assert_eq!(
+			refs::resolve_ref(&other, "refs/heads/master").unwrap(),
+			Some(hex::encode(tip))
+		);
+	}
+
+	#[test]
+	fn import_rejects_unknown_marks_and_commands() {
+		let (_dir, repo) = init_repo();
+		let commit = b"commit refs/heads/master\ncommitter A <a@b> 0 +0000\ndata 0\nM 644 :9 f\n";
+		assert!(matches!(
+			import(&repo, commit),
+			Err(FastImportError::UnknownMark(mark)) if mark == ":9"
+		));
+		assert!(matches!(
+			import(&repo, b"checkpoint\n"),
+			Err(FastImportError::UnsupportedCommand(_))
+		));
+	}
+}
@@ -0,0 +1,397 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use thiserror::Error;
+
+use crate::repo::Repo;
+use crate::{decode_object, Commit, GitObject, ObjectId, ReadObjectError};
+
+#[derive(Debug, Error)]
+pub enum GraphError {
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error("{0} is not a commit")]
+	NotACommit(String),
+}
+
+/// Reads and parses the commit `id`. The parents of a shallow commit ([Repo::is_shallow])
+/// aren't in the repository, so it's returned without any like a root commit.
+pub fn read_commit(repo: &Repo, id: &ObjectId) -> Result<Commit, GraphError> {
+	let hash_str = hex::encode(id);
+	match decode_object(repo, hash_str.clone())? {
+		GitObject::Commit(mut commit) => {
+			if repo.is_shallow(id) {
+				commit.parents.clear();
+			}
+			Ok(commit)
+		}
+		_ => Err(GraphError::NotACommit(hash_str)),
+	}
+}
+
+/// Walks the history of commit `tip` following first parents only, like `git log
+/// --first-parent`: yields `tip`, its first parent, that one's first parent and so on until a
+/// root commit. Commits are read as the walk goes, so it can be stopped early.
+pub fn first_parents(
+	repo: &Repo,
+	tip: ObjectId,
+) -> impl Iterator<Item = Result<(ObjectId, Commit), GraphError>> + '_ {
+	let mut next = Some(tip);
+	std::iter::from_fn(move || {
+		let id = next.take()?;
+		Some(read_commit(repo, &id).map(|commit| {
+			next = commit.parents.first().copied();
+			(id, commit)
+		}))
+	})
+}
+
+/// Maps every commit reachable from `tips` to its children, the reverse of parent links, in
+/// the order they were found. Commits without children (like the tips) map to an empty list.
+pub fn children(
+	repo: &Repo,
+	tips: &[ObjectId],
+) -> Result<HashMap<ObjectId, Vec<ObjectId>>, GraphError> {
+	let mut children: HashMap<ObjectId, Vec<ObjectId>> = HashMap::new();
+	let mut visited = HashSet::new();
+	let mut pending = tips.to_vec();
+
+	while let Some(id) = pending.pop() {
+		if !visited.insert(id) {
+			continue;
+		}
+		children.entry(id).or_default();
+
+		for parent in read_commit(repo, &id)?.parents {
+			let parent_children = children.entry(parent).or_default();
+			// The same parent may be listed twice.
+			if !parent_children.contains(&id) {
+				parent_children.push(id);
+			}
+			pending.push(parent);
+		}
+	}
+
+	Ok(children)
+}
+
+/// Lists the commits reachable from `tips` with every commit coming before its parents, like
+/// `git log --topo-order`. Among commits whose children were all listed, the one seen last goes
+/// first, so the history of a merged branch is listed before its first parent's.
+pub fn topo_order(repo: &Repo, tips: &[ObjectId]) -> Result<Vec<(ObjectId, Commit)>, GraphError> {
+	let mut pending_children: HashMap<ObjectId, usize> = children(repo, tips)?
+		.into_iter()
+		.map(|(id, children)| (id, children.len()))
+		.collect();
+	let mut ready: Vec<ObjectId> = Vec::new();
+	for tip in tips.iter().rev() {
+		if pending_children[tip] == 0 && !ready.contains(tip) {
+			ready.push(*tip);
+		}
+	}
+
+	let mut commits = Vec::with_capacity(pending_children.len());
+	while let Some(id) = ready.pop() {
+		let commit = read_commit(repo, &id)?;
+		for parent in unique(&commit.parents) {
+			let pending = pending_children
+				.get_mut(&parent)
+				.expect("parents were visited");
+			*pending -= 1;
+			if *pending == 0 {
+				ready.push(parent);
+			}
+		}
+		commits.push((id, commit));
+	}
+	Ok(commits)
+}
+
+/// Lists the commits reachable from `tips`, most recently committed first, like `git rev-list`.
+/// Commits dated the same are listed in the order they were found. A parent can be listed
+/// before a child with a skewed (older) commit date.
+pub fn date_order(repo: &Repo, tips: &[ObjectId]) -> Result<Vec<(ObjectId, Commit)>, GraphError> {
+	let mut seen = HashSet::new();
+	// Commits in the order they were found, taken out as they're listed.
+	let mut found: Vec<Option<(ObjectId, Commit)>> = Vec::new();
+	// By commit date, then by when they were found.
+	let mut queue = BinaryHeap::new();
+	let mut push = |found: &mut Vec<_>, queue: &mut BinaryHeap<_>, id: ObjectId| {
+		if seen.insert(id) {
+			let commit = read_commit(repo, &id)?;
+			queue.push((commit.committer.timestamp, Reverse(found.len())));
+			found.push(Some((id, commit)));
+		}
+		Ok::<_, GraphError>(())
+	};
+	for tip in tips {
+		push(&mut found, &mut queue, *tip)?;
+	}
+
+	let mut commits = Vec::with_capacity(found.len());
+	while let Some((_, Reverse(idx))) = queue.pop() {
+		let (id, commit) = found[idx].take().expect("listed once");
+		for parent in &commit.parents {
+			push(&mut found, &mut queue, *parent)?;
+		}
+		commits.push((id, commit));
+	}
+	Ok(commits)
+}
+
+/// `ids` without repetitions, keeping the first occurrence.
+fn unique(ids: &[ObjectId]) -> Vec<ObjectId> {
+	let mut unique = Vec::with_capacity(ids.len());
+	for id in ids {
+		if !unique.contains(id) {
+			unique.push(*id);
+		}
+	}
+	unique
+}
+
+/// Draws the history as ASCII lanes next to each commit, like `git log --graph`. Commits must be
+/// fed in an order where children come before their parents, e.g. [topo_order].
+#[derive(Debug, Default)]
+pub struct GraphRenderer {
+	/// Commit expected next in each lane.
+	columns: Vec<ObjectId>,
+}
+
+impl GraphRenderer {
+	/// Places commit `id` in the graph. Returns the prefix of the commit's line (including the
+	/// separating space) and the connector lines to be printed after it.
+	pub fn next(&mut self, id: &ObjectId, parents: &[ObjectId]) -> (String, Vec<String>) {
+		let parents = unique(parents);
+		let column = match self.columns.iter().position(|c| c == id) {
+			Some(column) => column,
+			None => {
+				self.columns.push(*id);
+				self.columns.len() - 1
+			}
+		};
+		let before = self.columns.len();
+		// The first parent takes over the commit's lane, the others get new lanes next to it.
+		self.columns
+			.splice(column..=column, parents.iter().copied());
+		let after = self.columns.len();
+
+		let mut commit_line = vec![b' '; 2 * before.max(after)];
+		for k in 0..before {
+			commit_line[2 * k] = if k == column { b'*' } else { b'|' };
+		}
+
+		let mut lines = Vec::new();
+		if parents.len() > 1 {
+			let extra = parents.len() - 1;
+			let mut line = vec![b' '; 2 * after];
+			for k in 0..=column {
+				line[2 * k] = b'|';
+			}
+			for k in column + 1..after {
+				line[2 * k - 1] = b'\\';
+			}
+			debug_assert_eq!(after, before + extra);
+			lines.push(line);
+		} else if parents.is_empty() && column < after {
+			// Lanes to the right of a root commit move into its place.
+			let mut line = vec![b' '; 2 * before];
+			for k in 0..column {
+				line[2 * k] = b'|';
+			}
+			for k in column..after {
+				line[2 * k + 1] = b'/';
+			}
+			lines.push(line);
+		}
+
+		// Lanes waiting for the same commit merge into the leftmost one.
+		while let Some(j) =
+			(1..self.columns.len()).find(|&j| self.columns[..j].contains(&self.columns[j]))
+		{
+			let mut line = vec![b' '; 2 * self.columns.len()];
+			for k in 0..j {
+				line[2 * k] = b'|';
+			}
+			for k in j..self.columns.len() {
+				line[2 * k - 1] = b'/';
+			}
+			self.columns.remove(j);
+			lines.push(line);
+		}
+
+		let to_string = |line: Vec<u8>| String::from_utf8(line).expect("graph is ASCII");
+		let lines = lines
+			.into_iter()
+			.map(|line| to_string(line).trim_end().to_string())
+			.collect();
+		(to_string(commit_line), lines)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+
+	use super::*;
+	use crate::test_utils::init_repo;
+	use crate::{hash_git_object, Signature};
+
+	/// Writes a commit of the empty tree with given parents and message.
+	fn commit(repo: &Repo, parents: &[ObjectId], message: &str) -> ObjectId {
+		commit_at(repo, parents, message, 1700000000)
+	}
+
+	/// Like [commit], dated `timestamp`.
+	fn commit_at(repo: &Repo, parents: &[ObjectId], message: &str, timestamp: i64) -> ObjectId {
+		let tree = hash_git_object(repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let signature = Signature {
+			name: "A U Thor".to_string(),
+			email: "author@example.com".to_string(),
+			timestamp,
+			tz_offset: 0,
+		};
+		let commit = Commit {
+			tree: tree.hash,
+			parents: parents.to_vec(),
+			author: signature.clone(),
+			committer: signature,
+			extra_headers: Vec::new(),
+			message: format!("{message}\n"),
+		};
+		hash_git_object(repo, GitObject::Commit(commit), true)
+			.unwrap()
+			.hash
+	}
+
+	#[test]
+	fn children_of_diamond() {
+		let (_dir, repo) = init_repo();
+		let base = commit(&repo, &[], "base");
+		let left = commit(&repo, &[base], "left");
+		let right = commit(&repo, &[base], "right");
+		let merge = commit(&repo, &[left, right], "merge");
+
+		let children = children(&repo, &[merge]).unwrap();
+		assert_eq!(children.len(), 4);
+
+		let mut base_children = children[&base].clone();
+		base_children.sort();
+		let mut expected = vec![left, right];
+		expected.sort();
+		assert_eq!(base_children, expected);
+		assert_eq!(children[&left], [merge]);
+		assert_eq!(children[&right], [merge]);
+		assert!(children[&merge].is_empty());
+	}
+
+	#[test]
+	fn date_order_lists_newest_first() {
+		let (_dir, repo) = init_repo();
+		let base = commit_at(&repo, &[], "base", 100);
+		let left = commit_at(&repo, &[base], "left", 300);
+		let right = commit_at(&repo, &[base], "right", 200);
+		let right2 = commit_at(&repo, &[right], "right2", 400);
+		let merge = commit_at(&repo, &[left, right2], "merge", 500);
+		let other = commit_at(&repo, &[], "other", 500);
+
+		let listed: Vec<ObjectId> = date_order(&repo, &[merge, other])
+			.unwrap()
+			.into_iter()
+			.map(|(id, _)| id)
+			.collect();
+		// Same date: the tip given first goes first.
+		assert_eq!(listed, [merge, other, right2, left, right, base]);
+	}
+
+	#[test]
+	fn first_parents_skip_merged_branches() {
+		let (_dir, repo) = init_repo();
+		let base = commit(&repo, &[], "base");
+		let left = commit(&repo, &[base], "left");
+		let right = commit(&repo, &[base], "right");
+		let merge = commit(&repo, &[left, right], "merge");
+
+		let walked: Vec<ObjectId> = first_parents(&repo, merge)
+			.map(|entry| entry.unwrap().0)
+			.collect();
+		assert_eq!(walked, [merge, left, base]);
+
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"x")), true).unwrap();
+		let mut walk = first_parents(&repo, blob.hash);
+		assert!(matches!(walk.next(), Some(Err(GraphError::NotACommit(_)))));
+		assert!(walk.next().is_none());
+	}
+
+	/// Renders the history from `tips` with each commit shown by its name in `names`.
+	fn render(repo: &Repo, tips: &[ObjectId], names: &HashMap<ObjectId, &str>) -> String {
+		let mut renderer = GraphRenderer::default();
+		let mut out = String::new();
+		for (id, commit) in topo_order(repo, tips).unwrap() {
+			let (prefix, lines) = renderer.next(&id, &commit.parents);
+			out += &format!("{prefix}{}\n", names[&id]);
+			for line in lines {
+				out += &format!("{line}\n");
+			}
+		}
+		out
+	}
+
+	#[test]
+	fn graph_of_linear_history() {
+		let (_dir, repo) = init_repo();
+		let first = commit(&repo, &[], "first");
+		let second = commit(&repo, &[first], "second");
+		let names = HashMap::from([(first, "first"), (second, "second")]);
+
+		assert_eq!(render(&repo, &[second], &names), "* second\n* first\n");
+	}
+
+	#[test]
+	fn graph_of_merge() {
+		let (_dir, repo) = init_repo();
+		let base = commit(&repo, &[], "base");
+		let left = commit(&repo, &[base], "left");
+		let right = commit(&repo, &[base], "right");
+		let right_2 = commit(&repo, &[right], "right 2");
+		let merge = commit(&repo, &[left, right_2], "merge");
+		let top = commit(&repo, &[merge], "top");
+		let names = HashMap::from([
+			(base, "base"),
+			(left, "left"),
+			(right, "right"),
+			(right_2, "right 2"),
+			(merge, "merge"),
+			(top, "top"),
+		]);
+
+		assert_eq!(
+			render(&repo, &[top], &names),
+			"\
+* top
+*   merge
+|\\
+| * right 2
+| * right
+* | left
+|/
+* base
+"
+		);
+	}
+
+	#[test]
+	fn graph_of_unrelated_roots() {
+		let (_dir, repo) = init_repo();
+		let a = commit(&repo, &[], "a");
+		let b = commit(&repo, &[], "b");
+		let merge = commit(&repo, &[a, b], "merge");
+		let names = HashMap::from([(a, "a"), (b, "b"), (merge, "merge")]);
+
+		assert_eq!(
+			render(&repo, &[merge], &names),
+			"*   merge\n|\\\n| * b\n* a\n"
+		);
+	}
+}
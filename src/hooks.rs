@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+use thiserror::Error;
+
+use crate::repo::Repo;
+
+#[derive(Debug, Error)]
+pub enum HookError {
+	#[error("Could not run the {name} hook: {err}")]
+	Io {
+		name: String,
+		#[source]
+		err: std::io::Error,
+	},
+
+	#[error("The {name} hook failed ({status})")]
+	Failed { name: String, status: ExitStatus },
+}
+
+/// Directory hooks are looked for in: `core.hooksPath` (relative to the working tree) or
+/// `hooks/` in the git directory.
+pub fn hooks_dir(repo: &Repo) -> PathBuf {
+	match repo.config("core.hooksPath") {
+		Some(path) => repo.work_tree().join(path),
+		None => repo.git_dir().join("hooks"),
+	}
+}
+
+/// Runs hook `name` from the top of the working tree with the same stdin, stdout and stderr,
+/// if there's an executable file for it. Fails with [HookError::Failed] if it exits with a
+/// non-zero status, which should abort the operation it's a hook of.
+pub fn run_hook(repo: &Repo, name: &str) -> Result<(), HookError> {
+	let path = hooks_dir(repo).join(name);
+	if !is_executable(&path) {
+		return Ok(());
+	}
+
+	let status = std::process::Command::new(&path)
+		.current_dir(repo.work_tree())
+		.status()
+		.map_err(|err| HookError::Io {
+			name: name.to_string(),
+			err,
+		})?;
+	if !status.success() {
+		return Err(HookError::Failed {
+			name: name.to_string(),
+			status,
+		});
+	}
+	Ok(())
+}
+
+/// Whether `path` is a file its owner may execute. Like git, a hook that isn't executable is
+/// ignored.
+#[cfg(unix)]
+fn is_executable(path: &std::path::Path) -> bool {
+	use std::os::unix::fs::PermissionsExt;
+
+	fs::metadata(path)
+		.is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o100 != 0)
+}
+
+/// Whether `path` is a file. Windows has no executable bit, so any hook file is run.
+#[cfg(windows)]
+fn is_executable(path: &std::path::Path) -> bool {
+	fs::metadata(path).is_ok_and(|metadata| metadata.is_file())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use std::os::unix::fs::PermissionsExt;
+
+	use super::*;
+	use crate::test_utils::init_repo;
+
+	fn write_hook(dir: &std::path::Path, name: &str, script: &str, mode: u32) {
+		fs::create_dir_all(dir).unwrap();
+		let path = dir.join(name);
+		fs::write(&path, script).unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+	}
+
+	#[test]
+	fn hooks_run_from_hooks_dir_or_hooks_path() {
+		let (dir, repo) = init_repo();
+		// Missing hooks are fine.
+		run_hook(&repo, "pre-commit").unwrap();
+
+		let hooks = repo.git_dir().join("hooks");
+		write_hook(&hooks, "pre-commit", "#!/bin/sh\nexit 3\n", 0o755);
+		assert!(matches!(
+			run_hook(&repo, "pre-commit"),
+			Err(HookError::Failed { status, .. }) if status.code() == Some(3)
+		));
+		// Not executable, so not run.
+		write_hook(&hooks, "pre-commit", "#!/bin/sh\nexit 3\n", 0o644);
+		run_hook(&repo, "pre-commit").unwrap();
+
+		let repo = Repo::new(repo.git_dir()).with_env(|name| match name {
+			"GIT_CONFIG_COUNT" => Some("1".into()),
+			"GIT_CONFIG_KEY_0" => Some("core.hooksPath".into()),
+			"GIT_CONFIG_VALUE_0" => Some("my-hooks".into()),
+			_ => None,
+		});
+		assert_eq!(hooks_dir(&repo), dir.0.join("my-hooks"));
+		// Runs from the top of the working tree.
+		write_hook(
+			&dir.0.join("my-hooks"),
+			"pre-commit",
+			"#!/bin/sh\ntouch ran\n",
+			0o755,
+		);
+		run_hook(&repo, "pre-commit").unwrap();
+		assert!(dir.0.join("ran").exists());
+	}
+}
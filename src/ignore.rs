@@ -0,0 +1,211 @@
+use std::fs;
+use std::path::Path;
+
+/// Patterns of a `.gitignore` file deciding which paths are left out of trees.
+///
+/// Supported syntax (see gitignore(5)):
+/// - blank lines and lines starting with `#` are skipped, `\#` and `\!` escape those characters
+/// - `!` negates a pattern, re-including paths excluded by an earlier one
+/// - a trailing `/` only matches directories
+/// - a pattern without any other `/` matches the name at any level below the `.gitignore`,
+///   otherwise it matches the whole path relative to it
+/// - `*`, `?` and `[...]` match within a path component, `**` across components
+#[derive(Debug, Default, Clone)]
+pub struct Matcher {
+	/// Patterns and whether they are negated.
+	patterns: Vec<(Pattern, bool)>,
+}
+
+/// Path pattern of a `.gitignore` (without the `!` prefix) or `.gitattributes` line.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+	glob: String,
+	dir_only: bool,
+	/// Matched against the whole path instead of just the name.
+	anchored: bool,
+}
+
+impl Pattern {
+	/// Returns `None` for an empty pattern.
+	pub fn parse(pattern: &str) -> Option<Self> {
+		let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+		let (dir_only, pattern) = match pattern.strip_suffix('/') {
+			Some(pattern) => (true, pattern),
+			None => (false, pattern),
+		};
+		let anchored = pattern.contains('/');
+		let glob = pattern.strip_prefix('/').unwrap_or(pattern);
+		(!glob.is_empty()).then(|| Pattern {
+			glob: glob.to_string(),
+			dir_only,
+			anchored,
+		})
+	}
+
+	/// Whether `path`, relative to the directory of the file the pattern is from and separated
+	/// with `/`, matches.
+	pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+		let text = if self.anchored {
+			path
+		} else {
+			path.rsplit('/').next().unwrap_or(path)
+		};
+		(is_dir || !self.dir_only) && glob_match(self.glob.as_bytes(), text.as_bytes())
+	}
+}
+
+impl Matcher {
+	pub fn parse(content: &str) -> Self {
+		let patterns = content
+			.lines()
+			.filter_map(|line| {
+				let line = line.trim_end();
+				if line.is_empty() || line.starts_with('#') {
+					return None;
+				}
+				match line.strip_prefix('!') {
+					Some(line) => Some((Pattern::parse(line)?, true)),
+					None => Some((Pattern::parse(line)?, false)),
+				}
+			})
+			.collect();
+		Matcher { patterns }
+	}
+
+	/// Decides about `path`, relative to the directory of the `.gitignore` and separated with
+	/// `/`: `Some(true)` if it is ignored, `Some(false)` if it is explicitly re-included and
+	/// `None` if no pattern matches. The last matching pattern wins.
+	pub fn matched(&self, path: &str, is_dir: bool) -> Option<bool> {
+		self.patterns
+			.iter()
+			.rev()
+			.find(|(pattern, _)| pattern.matches(path, is_dir))
+			.map(|(_, negated)| !negated)
+	}
+}
+
+/// The `.gitignore` files of the directories a walk of the working tree is in.
+#[derive(Debug, Default)]
+pub struct Ignores {
+	/// Matchers with the path (relative to the working tree) of the directory they apply to,
+	/// innermost last.
+	matchers: Vec<(String, Matcher)>,
+}
+
+impl Ignores {
+	/// Reads the `.gitignore` of directory `dir`, which is at `rel_path` (empty or ending with a
+	/// `/`) in the working tree. Returns whether there was one, in which case [Ignores::leave]
+	/// must be called when done with the directory.
+	pub fn enter(&mut self, dir: &Path, rel_path: &str) -> std::io::Result<bool> {
+		let gitignore = dir.join(".gitignore");
+		if !gitignore.is_file() {
+			return Ok(false);
+		}
+		let matcher = Matcher::parse(&fs::read_to_string(gitignore)?);
+		self.matchers.push((rel_path.to_string(), matcher));
+		Ok(true)
+	}
+
+	pub fn leave(&mut self) {
+		self.matchers.pop();
+	}
+
+	/// Whether `rel_path` is ignored. The innermost `.gitignore` with a matching pattern decides.
+	pub fn is_ignored(&self, rel_path: &str, is_dir: bool) -> bool {
+		let ignored = self.matchers.iter().rev().find_map(|(base, matcher)| {
+			matcher.matched(rel_path.strip_prefix(base.as_str())?, is_dir)
+		});
+		ignored == Some(true)
+	}
+}
+
+/// Whether `text` matches glob `pattern`. `*` and `?` don't match `/`, `**` does.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+	match pattern {
+		[] => text.is_empty(),
+		[b'*', b'*', b'/', rest @ ..] => {
+			// Zero or more leading directories.
+			glob_match(rest, text)
+				|| text
+					.iter()
+					.enumerate()
+					.any(|(idx, b)| *b == b'/' && glob_match(rest, &text[idx + 1..]))
+		}
+		[b'*', b'*', rest @ ..] => (0..=text.len()).any(|idx| glob_match(rest, &text[idx..])),
+		[b'*', rest @ ..] => {
+			let component_len = text.iter().position(|b| *b == b'/').unwrap_or(text.len());
+			(0..=component_len).any(|idx| glob_match(rest, &text[idx..]))
+		}
+		[b'?', rest @ ..] => {
+			matches!(text.first(), Some(b) if *b != b'/') && glob_match(rest, &text[1..])
+		}
+		[b'[', class @ ..] => {
+			let Some(end) = class.iter().skip(1).position(|b| *b == b']').map(|e| e + 1) else {
+				return text.first() == Some(&b'[') && glob_match(class, &text[1..]);
+			};
+			let Some((&c, text_rest)) = text.split_first() else {
+				return false;
+			};
+			c != b'/' && class_matches(&class[..end], c) && glob_match(&class[end + 1..], text_rest)
+		}
+		[b'\\', c, rest @ ..] | [c, rest @ ..] => {
+			text.first() == Some(c) && glob_match(rest, &text[1..])
+		}
+	}
+}
+
+/// Whether `c` is in the contents of a `[...]` class, e.g. `a-z0-9`, negated with `!` or `^`.
+fn class_matches(class: &[u8], c: u8) -> bool {
+	let (negated, mut class) = match class {
+		[b'!' | b'^', rest @ ..] => (true, rest),
+		_ => (false, class),
+	};
+	let mut found = false;
+	while let Some((&first, rest)) = class.split_first() {
+		match rest {
+			[b'-', last, rest @ ..] => {
+				found |= (first..=*last).contains(&c);
+				class = rest;
+			}
+			_ => {
+				found |= first == c;
+				class = rest;
+			}
+		}
+	}
+	found != negated
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn globs() {
+		assert!(glob_match(b"*.log", b"debug.log"));
+		assert!(!glob_match(b"*.log", b"logs/debug.log"));
+		assert!(glob_match(b"debug?.log", b"debug1.log"));
+		assert!(glob_match(b"file[0-9].txt", b"file7.txt"));
+		assert!(!glob_match(b"file[!0-9].txt", b"file7.txt"));
+		assert!(glob_match(b"**/build", b"build"));
+		assert!(glob_match(b"**/build", b"a/b/build"));
+		assert!(glob_match(b"docs/**/*.md", b"docs/a.md"));
+		assert!(glob_match(b"docs/**/*.md", b"docs/x/y/a.md"));
+		assert!(glob_match(b"out/**", b"out/a/b"));
+		assert!(glob_match(b"\\*", b"*"));
+		assert!(!glob_match(b"\\*", b"a"));
+	}
+
+	#[test]
+	fn last_matching_pattern_wins() {
+		let matcher = Matcher::parse("# build output\ntarget/\n*.log\n!keep.log\n/root.txt\n");
+		assert_eq!(matcher.matched("target", true), Some(true));
+		assert_eq!(matcher.matched("src/target", true), Some(true));
+		assert_eq!(matcher.matched("target", false), None);
+		assert_eq!(matcher.matched("a/debug.log", false), Some(true));
+		assert_eq!(matcher.matched("a/keep.log", false), Some(false));
+		assert_eq!(matcher.matched("root.txt", false), Some(true));
+		assert_eq!(matcher.matched("src/root.txt", false), None);
+		assert_eq!(matcher.matched("main.rs", false), None);
+	}
+}
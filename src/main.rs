@@ -1,21 +1,59 @@
 #![allow(dead_code)]
 
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::{BufReader, Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStringExt;
+#[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::UNIX_EPOCH;
 
 use clap::{Parser, Subcommand};
 use flate2::write::ZlibEncoder;
 use thiserror::Error;
 
+mod archive;
+mod attributes;
+mod binary_patch;
+mod bundle;
+mod cache_tree;
+mod checkout;
+mod date;
+mod diff;
+mod fast_import;
+mod graph;
+mod hooks;
+mod ignore;
+mod merge;
+mod notes;
+mod pack;
+mod refs;
+mod repo;
+mod revision;
 mod sha1;
+#[cfg(test)]
+mod test_utils;
+mod transport;
+
+use attributes::Attributes;
+use cache_tree::CacheTree;
+use repo::Repo;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+	/// Don't substitute objects according to refs/replace/
+	#[arg(long)]
+	no_replace_objects: bool,
+
+	/// Don't check that objects hash to their names when reading them
+	#[arg(long)]
+	no_verify_objects: bool,
+
 	#[command(subcommand)]
 	command: Command,
 }
@@ -25,761 +63,8510 @@ enum Command {
 	Init,
 
 	CatFile {
-		#[arg(short, long)]
+		#[arg(short, long, group = "mode", conflicts_with = "batch_mode")]
 		pretty_print: bool,
 
-		#[arg(required = true)]
-		object: String,
+		/// Print the type of the object
+		#[arg(short = 't', group = "mode", conflicts_with = "batch_mode")]
+		show_type: bool,
+
+		/// Print the size of the object's content
+		#[arg(short = 's', group = "mode", conflicts_with = "batch_mode")]
+		show_size: bool,
+
+		/// Report the type and size of objects of unknown types instead of failing
+		#[arg(long, requires = "mode", conflicts_with = "pretty_print")]
+		allow_unknown_type: bool,
+
+		/// Read object names from stdin, one per line
+		#[arg(long)]
+		stdin: bool,
+
+		/// Print `<object> <type> <size>` (or FORMAT) and the content of every object named on
+		/// stdin
+		#[arg(long, group = "batch_mode", value_name = "FORMAT", num_args = 0..=1, require_equals = true)]
+		batch: Option<Option<String>>,
+
+		/// Print `<object> <type> <size>` (or FORMAT) of every object named on stdin
+		#[arg(long, group = "batch_mode", value_name = "FORMAT", num_args = 0..=1, require_equals = true)]
+		batch_check: Option<Option<String>>,
+
+		/// Batch input and output records are separated by NUL instead of newline
+		#[arg(short = 'z', requires = "batch_mode")]
+		nul_terminated: bool,
+
+		#[arg(
+			required_unless_present_any = ["stdin", "batch_mode"],
+			conflicts_with_all = ["stdin", "batch_mode"],
+		)]
+		object: Option<String>,
 	},
 
 	HashObject {
-		#[arg(short)]
+		#[arg(short, group = "store")]
 		write: bool,
 
-		#[arg(required = true)]
-		file: PathBuf,
+		/// Don't write the object, only show what -w would write; use with --print-path
+		#[arg(long, group = "store", conflicts_with = "write")]
+		dry_run: bool,
+
+		/// Also print the path of the written loose object, or the one it would be written to
+		/// with --dry-run
+		#[arg(long, requires = "store")]
+		print_path: bool,
+
+		/// Read the content from stdin instead of a file
+		#[arg(long)]
+		stdin: bool,
+
+		/// Report how much of the file was hashed on stderr
+		#[arg(long, conflicts_with = "stdin")]
+		progress: bool,
+
+		#[arg(required_unless_present = "stdin", conflicts_with = "stdin")]
+		file: Option<PathBuf>,
 	},
 
 	LsTree {
 		#[arg(short, long)]
 		name_only: bool,
 
-		#[arg(required = true)]
-		object: String,
+		/// Recurse into subtrees, listing only their files with full paths
+		#[arg(short)]
+		recursive: bool,
+
+		/// Read tree names from stdin, one per line
+		#[arg(long)]
+		stdin: bool,
+
+		#[arg(required_unless_present = "stdin", conflicts_with = "stdin")]
+		object: Option<String>,
+	},
+
+	WriteTree {
+		/// Hash the files of the working directory instead of the index
+		#[arg(long, hide = true)]
+		from_worktree: bool,
 	},
 
-	WriteTree,
+	/// List references and the objects they point at
+	ShowRef {
+		/// Also show HEAD
+		#[arg(long)]
+		head: bool,
+	},
 
-	CommitTree {
+	/// Three-way merge of trees, without touching the index or the working tree
+	MergeTree {
 		#[arg(required = true)]
-		tree: String,
+		base: String,
 
-		#[arg(short, long)]
-		parent: Option<String>,
+		#[arg(required = true)]
+		ours: String,
 
-		#[arg(short, long, required = true)]
-		message: String,
+		#[arg(required = true)]
+		theirs: String,
 	},
-}
-
-fn main() {
-	let args = Args::parse();
 
-	let result: Result<(), Box<dyn std::error::Error>> = match args.command {
-		Command::Init => init().map_err(Into::into),
-		Command::CatFile {
-			pretty_print,
-			object,
-		} => cat_file(object, pretty_print).map_err(Into::into),
-		Command::HashObject { write, file } => hash_object_cmd(file, write).map_err(Into::into),
-		Command::LsTree { name_only, object } => ls_tree(object, name_only).map_err(Into::into),
-		Command::WriteTree => write_tree().map_err(Into::into),
-		Command::CommitTree {
-			tree,
-			parent,
-			message,
-		} => commit_tree(tree, parent, message).map_err(Into::into),
-	};
+	/// Show the paths added (A), deleted (D) or modified (M) between two trees
+	DiffTree {
+		/// Recurse into subtrees instead of showing them as modified
+		#[arg(short)]
+		recursive: bool,
 
-	if let Err(err) = result {
-		println!("{err}");
-		std::process::exit(1);
-	}
-}
+		#[arg(required = true)]
+		old: String,
 
-#[derive(Debug, Error)]
-enum InitError {
-	#[error(transparent)]
-	Io(#[from] std::io::Error),
-}
+		#[arg(required = true)]
+		new: String,
+	},
 
-fn init() -> Result<(), InitError> {
-	fs::create_dir(".git")?;
-	fs::create_dir(".git/objects")?;
-	fs::create_dir_all(".git/refs/heads")?;
-	fs::write(".git/HEAD", "ref: refs/heads/master\n")?;
-	eprintln!("Initialized git directory");
+	/// List, create or delete branches
+	Branch {
+		/// Delete the branch, which must be merged into HEAD
+		#[arg(short, long, requires = "name", conflicts_with = "start_point")]
+		delete: bool,
 
-	Ok(())
-}
+		/// Delete the branch even if it isn't merged
+		#[arg(short = 'D', requires = "name", conflicts_with_all = ["start_point", "delete"])]
+		force_delete: bool,
 
-#[derive(Debug, Error)]
-enum CatFileError {
-	#[error("Not a valid object name {0}")]
-	InvalidObjectName(String),
+		/// Branch to create or delete; branches are listed without it
+		name: Option<String>,
 
-	#[error(transparent)]
-	Io(#[from] std::io::Error),
+		/// Commit the new branch points at instead of HEAD
+		start_point: Option<String>,
+	},
 
-	#[error("You must use -p option right now :/")]
-	MustUsePrettyPrint,
+	/// Point a ref at an object, optionally checking its current value first
+	UpdateRef {
+		#[arg(required = true)]
+		ref_name: String,
 
-	#[error(transparent)]
-	ReadObject(#[from] ReadObjectError),
-}
+		#[arg(required = true)]
+		new_value: String,
 
-fn cat_file(object: String, pretty_print: bool) -> Result<(), CatFileError> {
-	if object.len() != 40 {
-		return Err(CatFileError::InvalidObjectName(object));
-	}
+		/// Value the ref must have; the null hash means it must not exist
+		old_value: Option<String>,
+	},
 
-	if !pretty_print {
-		return Err(CatFileError::MustUsePrettyPrint);
-	}
+	/// List the commits reachable from revisions, most recent first
+	RevList {
+		/// Also list the trees and blobs of the commits, with their paths
+		#[arg(long)]
+		objects: bool,
 
-	let file = decode_object(object)?;
+		#[arg(required = true)]
+		revisions: Vec<String>,
+	},
 
-	let file_content_bytes: &[u8] = match file {
-		GitObject::Blob(ref file_content) => file_content,
-		_ => unimplemented!(),
-	};
+	/// Name a commit after the nearest tag it descends from
+	Describe {
+		/// Also use lightweight tags, not only annotated ones
+		#[arg(long)]
+		tags: bool,
 
-	let file_content = String::from_utf8_lossy(file_content_bytes);
-	print!("{file_content}");
+		/// Commit to describe instead of HEAD
+		commit: Option<String>,
+	},
 
-	Ok(())
-}
+	/// List refs with the objects they point at
+	ForEachRef {
+		/// How to show each ref, with placeholders like %(refname), %(objectname) and
+		/// %(objecttype)
+		#[arg(long)]
+		format: Option<String>,
 
-#[derive(Debug, Error)]
-enum HashObjectError {
-	#[error("Could not open {path} for reading: {err}")]
-	InputIo {
-		#[source]
-		err: std::io::Error,
+		/// Field to sort by, prefixed with - for descending order
+		#[arg(long)]
+		sort: Option<String>,
 
-		path: PathBuf,
+		/// Only show refs under these prefixes or matching these globs
+		patterns: Vec<String>,
 	},
 
-	#[error("Could not open {path}: {err}")]
-	OutputIo {
-		#[source]
-		err: std::io::Error,
+	/// Show the ref a symbolic ref like HEAD points at, or point it at another one
+	SymbolicRef {
+		#[arg(required = true)]
+		name: String,
 
-		path: PathBuf,
+		/// Ref to point at, e.g. refs/heads/master
+		target: Option<String>,
 	},
 
-	#[error("Failed to encode: {0}")]
-	EncodeObject(std::io::Error),
-}
+	/// List or create tags
+	Tag {
+		/// Make an annotated tag object
+		#[arg(short, requires = "message")]
+		annotate: bool,
 
-fn hash_object_cmd(path: PathBuf, write: bool) -> Result<(), HashObjectError> {
-	let sha1_str = hash_object(&path, write)?.hash_str;
-	println!("{sha1_str}");
-	Ok(())
-}
+		/// Message of the annotated tag, implies -a
+		#[arg(short, long, requires = "name")]
+		message: Option<String>,
 
-fn hash_object(path: &Path, write: bool) -> Result<HashedObject, HashObjectError> {
-	let file_contents = fs::read(path).map_err(|err| HashObjectError::InputIo {
-		path: path.to_owned(),
-		err,
-	})?;
+		/// Tag to create; tags are listed without it
+		name: Option<String>,
 
-	let hashed_object = hash_git_object(GitObject::Blob(Cow::Borrowed(&file_contents)), write)?;
-	Ok(hashed_object)
-}
+		/// Object the tag points at instead of HEAD
+		object: Option<String>,
+	},
 
-/// Encodes and hashes given [GitObject]. Returns the SHA1 hash of that object.
-fn hash_git_object(object: GitObject, write: bool) -> Result<HashedObject, HashObjectError> {
-	let is_commit = matches!(object, GitObject::Commit(_));
+	/// Switch to a branch, or detach HEAD at a commit, updating the working tree and the index
+	Checkout {
+		#[arg(required = true)]
+		target: String,
+	},
 
-	let mut encoded_file_content = Vec::new();
-	encode_object(object, &mut encoded_file_content).map_err(HashObjectError::EncodeObject)?;
+	/// Switch to a branch, creating it with -c, or detach HEAD at a commit with --detach
+	Switch {
+		/// Create the branch at <start_point> (HEAD by default) before switching to it
+		#[arg(short = 'c', long)]
+		create: bool,
 
-	let sha1_hash = sha1::sha1(&encoded_file_content);
-	let sha1_str = hex::encode(sha1_hash);
+		/// Detach HEAD at the commit instead of switching to a branch
+		#[arg(long, conflicts_with = "create")]
+		detach: bool,
 
-	if write {
-		let dirname = &sha1_str[0..2];
-		let dirpath = PathBuf::from(format!(".git/objects/{dirname}"));
-		if !dirpath
-			.try_exists()
-			.map_err(|err| HashObjectError::OutputIo {
-				err,
-				path: dirpath.clone(),
-			})? {
-			fs::create_dir(&dirpath).map_err(|err| HashObjectError::OutputIo {
-				err,
-				path: dirpath.clone(),
-			})?;
-		}
+		#[arg(required = true)]
+		branch: String,
 
-		let filename = dirpath.join(&sha1_str[2..]);
-		if filename.exists() {
-			return Ok(HashedObject {
-				hash: sha1_hash,
-				hash_str: sha1_str,
-			});
-		}
-		let mut file = fs::File::create(&filename).map_err(|err| HashObjectError::OutputIo {
-			err,
-			path: filename.clone(),
-		})?;
+		#[arg(requires = "create")]
+		start_point: Option<String>,
+	},
 
-		let mut zlibencoder = ZlibEncoder::new(&mut file, flate2::Compression::default());
-		zlibencoder
-			.write_all(&encoded_file_content)
-			.map_err(|err| HashObjectError::OutputIo {
-				err,
-				path: filename,
-			})?;
+	/// Move or rename a tracked file or directory
+	Mv {
+		#[arg(required = true)]
+		from: PathBuf,
 
-		if is_commit {
-			let path = Path::new(".git/refs/heads/master");
-			fs::write(path, format!("{}\n", sha1_str)).map_err(|err| {
-				HashObjectError::OutputIo {
-					err,
-					path: path.to_owned(),
-				}
-			})?;
-		}
-	}
+		#[arg(required = true)]
+		to: PathBuf,
+	},
 
-	Ok(HashedObject {
-		hash: sha1_hash,
-		hash_str: sha1_str,
-	})
-}
+	/// Check that objects are intact and hash to their names
+	Verify {
+		/// Read object names from stdin, one per line
+		#[arg(long, required = true)]
+		stdin: bool,
+	},
 
-struct HashedObject {
-	hash: [u8; 20],
-	hash_str: String,
-}
+	/// Delete the loose objects that aren't reachable from HEAD, any ref or the index
+	Prune {
+		/// Only list the objects that would be deleted
+		#[arg(short = 'n', long)]
+		dry_run: bool,
 
-enum GitObject<'a> {
-	Blob(Cow<'a, [u8]>),
-	Commit(Commit),
-	Tag,
-	Tree(Cow<'a, [TreeEntry<'a>]>),
-}
+		/// List the deleted objects
+		#[arg(short, long)]
+		verbose: bool,
+	},
 
-#[derive(Clone)]
-struct TreeEntry<'a> {
-	mode: u32,
-	name: Cow<'a, str>,
-	object_hash: Cow<'a, [u8; 20]>,
-}
+	/// Create an archive of the files of a tree
+	Archive {
+		#[arg(long, value_enum, default_value_t = archive::ArchiveFormat::Tar)]
+		format: archive::ArchiveFormat,
 
-impl From<IndexEntry> for TreeEntry<'static> {
-	fn from(entry: IndexEntry) -> Self {
-		TreeEntry {
-			mode: entry.mode,
-			name: entry.path.into(),
-			object_hash: Cow::Owned(entry.sha1),
-		}
-	}
-}
+		/// Write the archive to this file
+		#[arg(short, long, required = true)]
+		output: PathBuf,
 
-struct Commit {
-	tree: [u8; 20],
-	parent: Option<[u8; 20]>,
-	message: String,
-	timestamp: u64,
-	author: String,
-}
+		#[arg(required = true)]
+		tree: String,
+	},
 
-fn encode_object<W: Write>(kind: GitObject, w: &mut W) -> Result<(), std::io::Error> {
-	match kind {
-		GitObject::Blob(blob) => encode_blob(blob, w),
-		GitObject::Tree(entries) => encode_tree(&entries, w),
-		GitObject::Commit(commit) => encode_commit(commit, w),
-		_ => unimplemented!(),
-	}
-}
+	CommitTree {
+		#[arg(required = true)]
+		tree: String,
 
-fn encode_blob<W: Write>(blob: Cow<[u8]>, w: &mut W) -> Result<(), std::io::Error> {
-	let header = format!("blob {}", blob.len());
-	w.write_all(header.as_bytes())?;
-	w.write_all(&[0_u8])?;
-	w.write_all(&blob)?;
-	Ok(())
-}
+		#[arg(short, long)]
+		parent: Vec<String>,
 
-fn encode_tree<W: Write>(entries: &[TreeEntry], w: &mut W) -> Result<(), std::io::Error> {
-	w.write_all(b"tree ")?;
+		#[arg(short, long, required = true)]
+		message: String,
+	},
 
-	let mut size = 21 * entries.len();
-	for entry in entries {
-		size += format!("{:o} {}", entry.mode, entry.name).len();
-	}
+	/// Record the staged changes as a new commit on the current branch
+	Commit {
+		#[arg(short, long, required = true)]
+		message: String,
 
-	w.write_all(size.to_string().as_bytes())?;
-	w.write_all(&[0_u8])?;
+		/// Don't run the pre-commit hook
+		#[arg(short = 'n', long)]
+		no_verify: bool,
+	},
 
-	for entry in entries {
-		w.write_all(format!("{:o} {}", entry.mode, entry.name).as_bytes())?;
-		w.write_all(&[0])?;
-		w.write_all(entry.object_hash.as_slice())?;
-	}
+	/// Show changes of the working tree not yet staged, or between two files
+	Diff {
+		/// Compare two paths on the filesystem instead of the working tree and the index
+		#[arg(long, requires_all = ["old", "new"])]
+		no_index: bool,
 
-	Ok(())
-}
+		/// Exit with 1 if there are differences
+		#[arg(long)]
+		exit_code: bool,
 
-fn encode_commit<W: Write>(commit: Commit, w: &mut W) -> Result<(), std::io::Error> {
-	w.write_all(b"commit ")?;
+		/// Show changed words instead of changed lines, as `[-removed-]{+added+}`
+		#[arg(long)]
+		word_diff: bool,
 
-	let mut temp_buf = Vec::new();
-	temp_buf.write_all(format!("tree {}\n", hex::encode(commit.tree)).as_bytes())?;
+		/// Output a binary diff that can be applied, instead of only noting that binary files
+		/// differ
+		#[arg(long)]
+		binary: bool,
 
-	if let Some(parent) = commit.parent {
-		temp_buf.write_all(format!("parent {}\n", hex::encode(parent)).as_bytes())?;
-	}
+		#[arg(requires = "no_index")]
+		old: Option<PathBuf>,
 
-	let author_time = format!("{} +0100", commit.timestamp);
-	temp_buf.write_all(format!("author {} {}\n", commit.author, author_time).as_bytes())?;
-	temp_buf.write_all(format!("committer {} {}\n\n", commit.author, author_time).as_bytes())?;
-	temp_buf.write_all(commit.message.as_bytes())?;
-	temp_buf.write_all(b"\n")?;
+		#[arg(requires = "no_index")]
+		new: Option<PathBuf>,
+	},
 
-	w.write_all(temp_buf.len().to_string().as_bytes())?;
-	w.write_all(&[0_u8])?;
-	w.write_all(&temp_buf)?;
+	/// Remove untracked files from the working tree
+	Clean {
+		/// Actually remove the files
+		#[arg(short, long)]
+		force: bool,
 
-	Ok(())
-}
+		/// Also remove untracked directories
+		#[arg(short)]
+		directories: bool,
 
-#[derive(Debug, Error)]
-enum ReadObjectError {
-	#[error(transparent)]
-	Io(#[from] std::io::Error),
+		/// Only show what would be removed
+		#[arg(short = 'n', long)]
+		dry_run: bool,
 
-	#[error("Invalid object: {0}")]
-	InvalidHash(#[from] hex::FromHexError),
+		/// Also remove ignored files
+		#[arg(short = 'x')]
+		ignored: bool,
+	},
 
-	#[error("Corrupted object {context}")]
-	CorruptedObject { context: &'static str },
+	/// Show the history of HEAD
+	Log {
+		/// Draw the history graph next to the commits, one per line, following every parent
+		#[arg(long)]
+		graph: bool,
 
-	#[error("Unknown object kind")]
-	UnknownObjectKind,
+		/// Show each commit on a single line
+		#[arg(long)]
+		oneline: bool,
 
-	#[error("Invalid object size")]
-	InvalidObjectSize,
+		/// Show at most this many commits
+		#[arg(short = 'n', long)]
+		max_count: Option<usize>,
 
-	#[error("Corrupted tree entry")]
-	CorruptedTreeEntry,
+		/// Commit to start from instead of HEAD
+		revision: Option<String>,
+	},
 
-	#[error("Corrupted tree entry mode")]
-	TreeEntryMode,
+	/// Clone a repository over the smart HTTP protocol
+	Clone {
+		/// Fetch only the last <DEPTH> commits of each branch and tag
+		#[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+		depth: Option<u32>,
 
-	#[error("Invalid tree entry name: {0}")]
-	TreeEntryName(std::str::Utf8Error),
+		#[arg(required = true)]
+		url: String,
 
-	#[error("Corrupted tree entry SHA1")]
-	CorruptedTreeEntrySha1,
-}
+		#[arg(required = true)]
+		dir: PathBuf,
+	},
 
-fn decode_object(mut sha1: String) -> Result<GitObject<'static>, ReadObjectError> {
-	sha1.make_ascii_lowercase();
-	// Just a check that a given sha1 is correct
-	let _ = hex::decode(&sha1)?;
+	/// Show the paths staged in the index
+	LsFiles {
+		/// Also show the mode, object name and stage of the entries
+		#[arg(short, long)]
+		stage: bool,
 
-	let dirname = &sha1[0..2];
-	let filename = &sha1[2..];
+		/// Terminate entries with NUL instead of newline
+		#[arg(short = 'z')]
+		nul_terminated: bool,
+	},
 
-	let file = fs::File::open(format!(".git/objects/{dirname}/{filename}"))?;
-	let file_buffered = BufReader::new(file);
-	let mut decoder = flate2::bufread::ZlibDecoder::new(file_buffered);
+	/// Copy files from the index to the working tree
+	CheckoutIndex {
+		/// Check out every file in the index
+		#[arg(short, long)]
+		all: bool,
 
-	let mut file_content_bytes = Vec::new();
-	decoder.read_to_end(&mut file_content_bytes)?;
+		/// Overwrite existing files
+		#[arg(short, long)]
+		force: bool,
 
-	if file_content_bytes.len() <= 1 {
-		return Err(ReadObjectError::CorruptedObject {
-			context: "too short",
-		});
-	}
-	let space_idx = file_content_bytes.iter().position(|x| *x == b' ').ok_or(
-		ReadObjectError::CorruptedObject {
-			context: "no space",
-		},
-	)?;
+		/// Write the files to <PREFIX><path> instead, e.g. --prefix=export/
+		#[arg(long)]
+		prefix: Option<String>,
 
-	let (object_type, mut rest) = file_content_bytes.split_at(space_idx);
-	// Skip space
-	rest = &rest[1..];
+		/// Files to check out, relative to the current directory
+		#[arg(required_unless_present = "all", conflicts_with = "all")]
+		paths: Vec<String>,
+	},
 
-	let null_byte_idx =
-		rest.iter()
-			.position(|x| !x.is_ascii_digit())
-			.ok_or(ReadObjectError::CorruptedObject {
-				context: "only ascii digits???",
-			})?;
-	if rest[null_byte_idx] != 0 {
-		return Err(ReadObjectError::CorruptedObject {
-			context: "byte after digits isn't null",
-		});
-	}
+	/// Replace the index with the files of a tree
+	ReadTree {
+		#[arg(required = true)]
+		tree: String,
+	},
 
-	// Safety iterator to find the null byte checked that all bytes are ascii digits
-	let size: u64 = unsafe {
-		std::str::from_utf8_unchecked(&rest[..null_byte_idx])
-			.parse()
-			.expect("all bytes were checked to be ascii digits")
-	};
+	/// Update the index
+	UpdateIndex {
+		/// Refresh the stat information of entries whose content didn't change
+		#[arg(long)]
+		refresh: bool,
 
-	rest = &rest[(null_byte_idx + 1)..];
-	rest = rest
-		.get(..(size as usize))
-		.ok_or(ReadObjectError::InvalidObjectSize)?;
+		/// Also stage files that aren't in the index yet
+		#[arg(long, requires = "paths")]
+		add: bool,
 
-	match object_type {
-		b"blob" => Ok(GitObject::Blob(Cow::Owned(rest.to_vec()))),
-		b"commit" => {
-			unimplemented!()
-		}
-		b"tag" => {
-			unimplemented!()
-		}
-		b"tree" => {
-			let mut tree_entries = Vec::new();
-			loop {
-				let space_idx = rest
-					.iter()
-					.position(|x| *x == b' ')
-					.ok_or(ReadObjectError::CorruptedTreeEntry)?;
+		/// Mark the entries of the paths as left out of a sparse checkout instead of staging
+		/// them
+		#[arg(long, conflicts_with = "add")]
+		skip_worktree: bool,
 
-				let mode: u32 = std::str::from_utf8(&rest[..space_idx])
-					.map_err(|_| ReadObjectError::TreeEntryMode)
-					.and_then(|mode_str| {
-						u32::from_str_radix(mode_str, 8).map_err(|_| ReadObjectError::TreeEntryMode)
-					})?;
+		/// Unmark the entries of the paths instead of staging them, see --skip-worktree
+		#[arg(long, conflicts_with_all = ["add", "skip_worktree"])]
+		no_skip_worktree: bool,
 
-				rest = rest
-					.get((space_idx + 1)..)
-					.ok_or(ReadObjectError::CorruptedTreeEntry)?;
+		/// Files to stage, relative to the current directory
+		#[arg(required_unless_present = "refresh", conflicts_with = "refresh")]
+		paths: Vec<PathBuf>,
+	},
 
-				let null_byte_idx = rest
-					.iter()
-					.position(|x| *x == 0)
-					.ok_or(ReadObjectError::CorruptedTreeEntry)?;
+	/// Show where the repository is, or the objects revisions name
+	RevParse {
+		/// Print the path of the git directory
+		#[arg(long)]
+		git_dir: bool,
 
-				let name = std::str::from_utf8(&rest[..null_byte_idx])
-					.map_err(ReadObjectError::TreeEntryName)?;
-				let name = Cow::Owned(name.to_string());
+		/// Print the absolute path of the top of the working tree
+		#[arg(long)]
+		show_toplevel: bool,
 
-				rest = rest
-					.get((null_byte_idx + 1)..)
-					.ok_or(ReadObjectError::CorruptedTreeEntry)?;
+		/// Print whether the current directory is inside the working tree
+		#[arg(long)]
+		is_inside_work_tree: bool,
 
-				if rest.len() < 20 {
-					return Err(ReadObjectError::CorruptedTreeEntrySha1);
-				}
+		/// Revisions to print the full object names of, e.g. HEAD~2, v1.0^{tree} or master:src
+		revisions: Vec<String>,
+	},
 
-				let object_hash = unsafe { &*(rest[..20].as_ptr() as *const [u8; 20]) };
+	/// Move refs and their objects between repositories through a file
+	Bundle {
+		#[command(subcommand)]
+		command: BundleCommand,
+	},
 
-				tree_entries.push(TreeEntry {
-					mode,
-					name,
-					object_hash: Cow::Borrowed(object_hash),
-				});
+	/// Write the history of given refs as a fast-import stream
+	FastExport {
+		#[arg(required = true)]
+		refs: Vec<String>,
+	},
 
-				if rest.len() > 20 {
-					rest = &rest[20..];
-				} else {
-					break;
-				}
-			}
+	/// Create the objects and refs described by a fast-import stream read from stdin
+	FastImport,
 
-			Ok(GitObject::Tree(Cow::Owned(tree_entries)))
-		}
-		_ => Err(ReadObjectError::UnknownObjectKind),
-	}
+	/// Attach notes to objects, or show them
+	Notes {
+		#[command(subcommand)]
+		command: NotesCommand,
+	},
 }
 
-#[derive(Debug, Error)]
-enum LsTreeError {
-	#[error("You must use --name-only option right now :/")]
-	MustUseNameOnly,
+#[derive(Debug, Subcommand)]
+enum NotesCommand {
+	/// Attach a note to an object (HEAD by default)
+	Add {
+		/// Text of the note
+		#[arg(short, long, required = true)]
+		message: String,
 
-	#[error(transparent)]
-	ReadObject(#[from] ReadObjectError),
+		/// Replace the note the object already has
+		#[arg(short, long)]
+		force: bool,
 
-	#[error("Not a tree object")]
-	NotATree,
+		object: Option<String>,
+	},
+
+	/// Print the note attached to an object (HEAD by default)
+	Show { object: Option<String> },
+}
+
+#[derive(Debug, Subcommand)]
+enum BundleCommand {
+	/// Write given refs and every object reachable from them to a bundle file
+	Create {
+		#[arg(required = true)]
+		file: PathBuf,
+
+		#[arg(required = true)]
+		refs: Vec<String>,
+	},
+
+	/// Store the objects of a bundle file and print the refs it contains
+	Unbundle {
+		#[arg(required = true)]
+		file: PathBuf,
+	},
 }
 
-fn ls_tree(object: String, name_only: bool) -> Result<(), LsTreeError> {
-	if !name_only {
-		return Err(LsTreeError::MustUseNameOnly);
+/// Finds the git directory `command`, run in `dir`, works in, see [repo::find_git_dir].
+fn find_command_git_dir(
+	command: &Command,
+	dir: &Path,
+	git_dir_env: Option<std::ffi::OsString>,
+) -> std::io::Result<PathBuf> {
+	match command {
+		Command::Init => Ok(git_dir_env.map_or_else(|| PathBuf::from(".git"), PathBuf::from)),
+		// Creates its own repository in the destination.
+		Command::Clone { dir, .. } => Ok(dir.join(".git")),
+		// Compares files on the filesystem, which works outside of a repository too.
+		Command::Diff { no_index: true, .. } => {
+			Ok(repo::find_git_dir(dir, git_dir_env).unwrap_or_else(|_| PathBuf::from(".git")))
+		}
+		_ => repo::find_git_dir(dir, git_dir_env),
 	}
+}
 
-	let object = decode_object(object)?;
+/// Path of directory `cwd` relative to the top of the working tree of `repo`, empty if it's the
+/// top or outside of it.
+fn worktree_prefix(repo: &Repo, cwd: &Path) -> PathBuf {
+	let (Ok(top), Ok(cwd)) = (repo.work_tree().canonicalize(), cwd.canonicalize()) else {
+		return PathBuf::new();
+	};
+	cwd.strip_prefix(top)
+		.map(Path::to_path_buf)
+		.unwrap_or_default()
+}
 
-	let GitObject::Tree(tree_entries) = object else {
-		return Err(LsTreeError::NotATree);
+/// Path arguments are relative to the current directory, while commands take paths relative to
+/// the top of the working tree: resolves `path` from `prefix`, the current directory relative
+/// to the top (see [worktree_prefix]). `..` is resolved without looking at the filesystem, those
+/// going above the top are kept, for the path to be rejected as out of the working tree.
+fn from_prefix(prefix: &Path, path: &Path) -> PathBuf {
+	let mut resolved = PathBuf::new();
+	let mut above_top = false;
+	for component in prefix.join(path).components() {
+		match component {
+			std::path::Component::ParentDir if !above_top && resolved.pop() => {}
+			std::path::Component::ParentDir => {
+				above_top = true;
+				resolved.push(component);
+			}
+			std::path::Component::CurDir => {}
+			component => resolved.push(component),
+		}
+	}
+	resolved
+}
+
+fn main() {
+	let args = Args::parse();
+	let git_dir_env = std::env::var_os("GIT_DIR");
+	let cwd = match std::env::current_dir() {
+		Ok(cwd) => cwd,
+		Err(err) => {
+			println!("{err}");
+			std::process::exit(1);
+		}
+	};
+	let git_dir = match find_command_git_dir(&args.command, &cwd, git_dir_env) {
+		Ok(git_dir) => git_dir,
+		Err(err) => {
+			println!("{err}");
+			std::process::exit(1);
+		}
+	};
+	let mut repo = Repo::new(git_dir).with_env(|name| std::env::var_os(name));
+	let prefix = worktree_prefix(&repo, &cwd);
+	let from_cwd = |path: PathBuf| from_prefix(&prefix, &path);
+	if args.no_replace_objects {
+		repo = repo.with_replace_objects(false);
+	}
+	if args.no_verify_objects {
+		repo = repo.with_verify_objects(false);
+	}
+
+	let result: Result<(), Box<dyn std::error::Error>> = match args.command {
+		Command::Init => init(&repo).map_err(Into::into),
+		Command::CatFile {
+			batch,
+			batch_check,
+			nul_terminated,
+			..
+		} if batch.is_some() || batch_check.is_some() => cat_file_batch(
+			&repo,
+			std::io::stdin().lock(),
+			&mut std::io::stdout().lock(),
+			batch.as_ref().or(batch_check.as_ref()).unwrap().as_deref(),
+			batch.is_some(),
+			nul_terminated,
+		)
+		.map_err(Into::into),
+		Command::CatFile {
+			pretty_print,
+			show_type,
+			show_size,
+			allow_unknown_type,
+			stdin,
+			object,
+			..
+		} => {
+			let mode = if pretty_print {
+				Some(CatFileMode::PrettyPrint)
+			} else if show_type {
+				Some(CatFileMode::Type)
+			} else if show_size {
+				Some(CatFileMode::Size)
+			} else {
+				None
+			};
+			with_object_names(object, stdin, |object, out| {
+				let mode = mode.ok_or(CatFileError::MissingMode)?;
+				cat_file(&repo, object, mode, allow_unknown_type, out)
+			})
+			.map_err(Into::into)
+		}
+		Command::HashObject {
+			write,
+			print_path,
+			progress,
+			file,
+			..
+		} => hash_object_cmd(
+			&repo,
+			file,
+			write,
+			print_path,
+			progress,
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::LsTree {
+			name_only,
+			recursive,
+			stdin,
+			object,
+		} => with_object_names(object, stdin, |object, out| {
+			ls_tree(&repo, object, name_only, recursive, out)
+		})
+		.map_err(Into::into),
+		Command::WriteTree { from_worktree } => {
+			write_tree(&repo, from_worktree).map_err(Into::into)
+		}
+		Command::ShowRef { head } => show_ref(&repo, head).map_err(Into::into),
+		Command::MergeTree { base, ours, theirs } => {
+			merge_tree(&repo, base, ours, theirs).map_err(Into::into)
+		}
+		Command::DiffTree {
+			recursive,
+			old,
+			new,
+		} => diff_tree(&repo, &old, &new, recursive, &mut std::io::stdout().lock())
+			.map_err(Into::into),
+		Command::CommitTree {
+			tree,
+			parent,
+			message,
+		} => commit_tree(&repo, tree, parent, message).map_err(Into::into),
+		Command::Commit { message, no_verify } => {
+			commit(&repo, message, no_verify, &mut std::io::stdout().lock()).map_err(Into::into)
+		}
+		Command::Branch {
+			delete,
+			force_delete,
+			name,
+			start_point,
+		} => {
+			let out = &mut std::io::stdout().lock();
+			match name {
+				Some(name) if delete || force_delete => {
+					delete_branch(&repo, &name, force_delete, out)
+				}
+				Some(name) => create_branch(&repo, &name, start_point.as_deref()),
+				None => list_branches(&repo, out),
+			}
+			.map_err(Into::into)
+		}
+		Command::UpdateRef {
+			ref_name,
+			new_value,
+			old_value,
+		} => update_ref(&repo, &ref_name, &new_value, old_value.as_deref()).map_err(Into::into),
+		Command::RevList { objects, revisions } => {
+			rev_list(&repo, &revisions, objects, &mut std::io::stdout().lock()).map_err(Into::into)
+		}
+		Command::Describe { tags, commit } => describe(
+			&repo,
+			commit.as_deref().unwrap_or("HEAD"),
+			tags,
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::ForEachRef {
+			format,
+			sort,
+			patterns,
+		} => for_each_ref(
+			&repo,
+			&patterns,
+			format.as_deref(),
+			sort.as_deref(),
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::SymbolicRef { name, target } => symbolic_ref(
+			&repo,
+			&name,
+			target.as_deref(),
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::Tag {
+			message,
+			name,
+			object,
+			..
+		} => match name {
+			Some(name) => create_tag(&repo, &name, object.as_deref(), message),
+			None => list_tags(&repo, &mut std::io::stdout().lock()),
+		}
+		.map_err(Into::into),
+		Command::Checkout { target } => checkout::checkout(&repo, &target).map_err(Into::into),
+		Command::Switch {
+			create,
+			detach,
+			branch,
+			start_point,
+		} => checkout::switch(&repo, &branch, create, start_point.as_deref(), detach)
+			.map_err(Into::into),
+		Command::Mv { from, to } => mv(&repo, &from_cwd(from), &from_cwd(to)).map_err(Into::into),
+		Command::Verify { .. } => verify(
+			&repo,
+			std::io::stdin().lock(),
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::Prune { dry_run, verbose } => {
+			prune(&repo, dry_run, verbose, &mut std::io::stdout().lock()).map_err(Into::into)
+		}
+		Command::Archive {
+			format,
+			output,
+			tree,
+		} => archive(&repo, tree, &output, format).map_err(Into::into),
+		Command::Diff {
+			no_index,
+			exit_code,
+			word_diff,
+			binary,
+			old,
+			new,
+		} => {
+			let out = &mut std::io::stdout().lock();
+			let options = DiffOptions { word_diff, binary };
+			let result = match (old, new) {
+				(Some(old), Some(new)) if no_index => diff_no_index(&old, &new, options, out),
+				_ => diff_worktree(&repo, options, out),
+			};
+			check_differences(result, exit_code)
+		}
+		Command::Clean {
+			force,
+			directories,
+			dry_run,
+			ignored,
+		} => clean(
+			&repo,
+			CleanOptions {
+				force,
+				directories,
+				dry_run,
+				ignored,
+			},
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::Log {
+			graph,
+			oneline,
+			max_count,
+			revision,
+		} => log(
+			&repo,
+			LogOptions {
+				graph,
+				oneline,
+				max_count,
+				revision,
+			},
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
+		Command::LsFiles {
+			stage,
+			nul_terminated,
+		} => ls_files(&repo, stage, nul_terminated, &mut std::io::stdout().lock())
+			.map_err(Into::into),
+		Command::UpdateIndex { refresh: true, .. } => {
+			check_differences(refresh_index(&repo, &mut std::io::stdout().lock()), true)
+		}
+		Command::CheckoutIndex {
+			all,
+			force,
+			prefix,
+			paths,
+		} => {
+			let paths: Vec<String> = paths
+				.into_iter()
+				.map(|path| from_cwd(path.into()).to_string_lossy().into_owned())
+				.collect();
+			checkout::checkout_index(
+				&repo,
+				(!all).then_some(paths.as_slice()),
+				prefix.as_deref(),
+				force,
+			)
+			.map_err(Into::into)
+		}
+		Command::ReadTree { tree } => read_tree(&repo, &tree).map_err(Into::into),
+		Command::UpdateIndex {
+			skip_worktree: true,
+			paths,
+			..
+		} => mark_skip_worktree(
+			&repo,
+			&paths.into_iter().map(from_cwd).collect::<Vec<_>>(),
+			true,
+		)
+		.map_err(Into::into),
+		Command::UpdateIndex {
+			no_skip_worktree: true,
+			paths,
+			..
+		} => mark_skip_worktree(
+			&repo,
+			&paths.into_iter().map(from_cwd).collect::<Vec<_>>(),
+			false,
+		)
+		.map_err(Into::into),
+		Command::UpdateIndex { add, paths, .. } => {
+			let paths: Vec<PathBuf> = paths.into_iter().map(from_cwd).collect();
+			update_index(&repo, &paths, add).map_err(Into::into)
+		}
+		Command::Clone { url, dir, depth } => clone(&url, &dir, depth).map_err(Into::into),
+		Command::RevParse {
+			git_dir,
+			show_toplevel,
+			is_inside_work_tree,
+			revisions,
+		} => std::env::current_dir()
+			.map_err(RevParseError::from)
+			.and_then(|cwd| {
+				rev_parse(
+					&repo,
+					&cwd,
+					RevParseOptions {
+						git_dir,
+						show_toplevel,
+						is_inside_work_tree,
+					},
+					&revisions,
+					&mut std::io::stdout().lock(),
+				)
+			})
+			.map_err(Into::into),
+		Command::Bundle {
+			command: BundleCommand::Create { file, refs },
+		} => bundle_create(&repo, &file, &refs).map_err(Into::into),
+		Command::Bundle {
+			command: BundleCommand::Unbundle { file },
+		} => unbundle(&repo, &file, &mut std::io::stdout().lock()).map_err(Into::into),
+		Command::FastExport { refs } => {
+			fast_export(&repo, &refs, &mut std::io::stdout().lock()).map_err(Into::into)
+		}
+		Command::FastImport => fast_import(&repo, std::io::stdin().lock()).map_err(Into::into),
+		Command::Notes {
+			command: NotesCommand::Add {
+				message,
+				force,
+				object,
+			},
+		} => notes::add_note(&repo, object.as_deref().unwrap_or("HEAD"), &message, force)
+			.map_err(Into::into),
+		Command::Notes {
+			command: NotesCommand::Show { object },
+		} => notes::show_note(
+			&repo,
+			object.as_deref().unwrap_or("HEAD"),
+			&mut std::io::stdout().lock(),
+		)
+		.map_err(Into::into),
 	};
-	for entry in tree_entries.iter() {
-		println!("{}", entry.name);
+
+	if let Err(err) = result {
+		if !err.is::<DifferencesFound>() {
+			println!("{err}");
+		}
+		std::process::exit(1);
+	}
+}
+
+/// Runs `f` with `object`, or with every object name read from stdin if `stdin` is set.
+fn with_object_names<E: From<std::io::Error>>(
+	object: Option<String>,
+	stdin: bool,
+	mut f: impl FnMut(String, &mut std::io::StdoutLock<'static>) -> Result<(), E>,
+) -> Result<(), E> {
+	let mut out = std::io::stdout().lock();
+	match object {
+		Some(object) if !stdin => f(object, &mut out),
+		_ => for_each_object_name(std::io::stdin().lock(), &mut out, f),
 	}
+}
 
+/// Runs `f` for every non-empty line of `input`, separating the outputs with an empty line.
+fn for_each_object_name<W: Write, E: From<std::io::Error>>(
+	input: impl BufRead,
+	out: &mut W,
+	mut f: impl FnMut(String, &mut W) -> Result<(), E>,
+) -> Result<(), E> {
+	let mut first = true;
+	for line in input.lines() {
+		let line = line?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		if !first {
+			writeln!(out)?;
+		}
+		first = false;
+		f(line, out)?;
+	}
 	Ok(())
 }
 
 #[derive(Debug, Error)]
-enum WriteTreeError {
-	#[error("index: {0}")]
-	ReadIndex(#[from] ReadIndexError),
+enum InitError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+fn init(repo: &Repo) -> Result<(), InitError> {
+	let git_dir = repo.git_dir();
+	fs::create_dir(git_dir)?;
+	fs::create_dir(git_dir.join("objects"))?;
+	fs::create_dir_all(git_dir.join("refs/heads"))?;
+	fs::write(git_dir.join("HEAD"), "ref: refs/heads/master\n")?;
+	eprintln!("Initialized git directory");
+
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum CatFileError {
+	#[error("Not a valid object name {0}")]
+	InvalidObjectName(String),
 
 	#[error(transparent)]
 	Io(#[from] std::io::Error),
 
+	#[error("You must use one of -p, -t or -s")]
+	MissingMode,
+
+	#[error("Unknown format element: {0}")]
+	UnknownFormatAtom(String),
+
 	#[error(transparent)]
-	HashObject(#[from] HashObjectError),
+	ReadObject(#[from] ReadObjectError),
+}
+
+/// What `cat-file` prints about an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CatFileMode {
+	/// The object's content (`-p`).
+	PrettyPrint,
+	/// The object's type (`-t`).
+	Type,
+	/// The size of the object's content (`-s`).
+	Size,
 }
 
-fn write_tree() -> Result<(), WriteTreeError> {
-	// Commented out, because test harness on CodeCrafters doesn't add files to index when doing
-	// `git add` (they are using a go implementation of git, not actual git).
-	// let index = read_index()?;
-	//
-	// let tree_entries = index
-	// 	.entries
-	// 	.into_iter()
-	// 	.map(TreeEntry::from)
-	// 	.collect::<Vec<_>>();
-	// let sha1_str = hash_object(GitObject::Tree(tree_entries), true)?;
-	// println!("{sha1_str}");
+fn cat_file(
+	repo: &Repo,
+	object: String,
+	mode: CatFileMode,
+	allow_unknown_type: bool,
+	out: &mut impl Write,
+) -> Result<(), CatFileError> {
+	let object = match revision::resolve_revision(repo, object.trim()) {
+		Ok(v) => v,
+		Err(ReadObjectError::ObjectNotFound(_)) => {
+			return Err(CatFileError::InvalidObjectName(object))
+		}
+		Err(err) => return Err(err.into()),
+	};
+
+	if mode != CatFileMode::PrettyPrint {
+		// Only the header is needed, so objects with a payload that doesn't parse can still be
+		// inspected.
+		let (object, bytes) = inflate_object(repo, object)?;
+		let (kind, size, _) = parse_object_header(&object, &bytes)?;
+		if !allow_unknown_type && ObjectKind::from_bytes(kind).is_none() {
+			return Err(ReadObjectError::UnknownObjectKind { hash: object }.into());
+		}
+		match mode {
+			CatFileMode::Type => out.write_all(kind)?,
+			_ => write!(out, "{size}")?,
+		}
+		writeln!(out)?;
+		return Ok(());
+	}
 
-	let tree = write_tree_at_dir(".".as_ref())?;
-	let hash_str = hex::encode(tree.hash.as_slice());
-	println!("{hash_str}",);
+	let (kind, payload) = read_object_raw(repo, object.clone())?;
+	match decode_payload(&object, kind, &payload)? {
+		GitObject::Blob(ref file_content) => out.write_all(file_content)?,
+		GitObject::Tree(ref entries) => write_tree_entries(entries, false, out)?,
+		// Printed as stored, headers the parser doesn't know about included.
+		GitObject::Commit(_) | GitObject::Tag(_) => out.write_all(&payload)?,
+	}
 
 	Ok(())
 }
 
-struct Tree<'a> {
-	hash: Cow<'a, [u8; 20]>,
-	mode: u32,
-	name: Cow<'a, str>,
-	entries: Vec<TreeEntry<'a>>,
-}
+/// Prints `<object> <type> <size>` for every object named in `input`, followed by the object's
+/// raw content if `contents` is set, like `git cat-file --batch`/`--batch-check`. Objects that
+/// can't be found are reported as `<name> missing`.
+///
+/// Names and output records are separated by newlines, or by NUL bytes if `nul_terminated`.
+fn cat_file_batch(
+	repo: &Repo,
+	input: impl BufRead,
+	out: &mut impl Write,
+	format: Option<&str>,
+	contents: bool,
+	nul_terminated: bool,
+) -> Result<(), CatFileError> {
+	let delimiter = if nul_terminated { b'\0' } else { b'\n' };
+	let format = format.unwrap_or("%(objectname) %(objecttype) %(objectsize)");
+	expand_batch_format(format, |atom| {
+		if !BATCH_ATOMS.contains(&atom) {
+			return Err(CatFileError::UnknownFormatAtom(atom.to_string()));
+		}
+		Ok(String::new())
+	})?;
 
-fn write_tree_at_dir(path: &Path) -> Result<Tree<'static>, WriteTreeError> {
-	let mut entries = Vec::new();
+	for name in input.split(delimiter) {
+		let name = String::from_utf8_lossy(&name?).into_owned();
+		if name.trim().is_empty() {
+			continue;
+		}
 
-	let read_dir = fs::read_dir(path)?;
-	for entry in read_dir {
-		let entry = match entry {
-			Ok(v) => v,
-			Err(err) => {
-				eprintln!("WARN cannot read: {err}");
+		let object = revision::resolve_revision(repo, name.trim()).and_then(|object| {
+			inflate_object(repo, object.clone()).map(|(_, bytes)| (object, bytes))
+		});
+		let found = match object {
+			Ok(v) => Some(v),
+			Err(ReadObjectError::ObjectNotFound(_) | ReadObjectError::InvalidHash(_)) => None,
+			Err(ReadObjectError::AmbiguousObjectName { .. }) => {
+				write!(out, "{name} ambiguous")?;
+				out.write_all(&[delimiter])?;
 				continue;
 			}
+			Err(err) => return Err(err.into()),
 		};
-
-		let path = entry.path();
-		let path = path.strip_prefix(".").unwrap_or(&path);
-		let Some(file_name) = path.file_name() else {
+		let Some((object, bytes)) = found else {
+			write!(out, "{name} missing")?;
+			out.write_all(&[delimiter])?;
 			continue;
 		};
 
-		let file_name = file_name.to_str().unwrap().to_string();
-		if file_name.starts_with('.') {
-			continue;
-		}
-
-		if path.is_file() {
-			let hashed_object = hash_object(path, true)?;
-			entries.push(TreeEntry {
-				mode: path.metadata().unwrap().mode(),
-				name: Cow::Owned(file_name),
-				object_hash: Cow::Owned(hashed_object.hash),
-			});
-		} else {
-			let tree = write_tree_at_dir(path)?;
-			entries.push(TreeEntry {
-				mode: tree.mode,
-				name: Cow::Owned(file_name),
-				object_hash: tree.hash,
-			});
+		let (kind, size, header_len) = parse_object_header(&object, &bytes)?;
+		let kind = String::from_utf8_lossy(kind);
+		let mut peeled = None;
+		let line = expand_batch_format(format, |atom| {
+			Ok(match atom {
+				"objectname" => object.clone(),
+				"objecttype" => kind.to_string(),
+				"objectsize" => size.to_string(),
+				// Only tags are peeled, other objects leave these empty.
+				_ if kind != "tag" => String::new(),
+				_ => {
+					if peeled.is_none() {
+						peeled = Some(peel_tag(repo, object.clone(), bytes.clone())?);
+					}
+					let (name, kind) = peeled.as_ref().unwrap();
+					match atom {
+						"*objectname" => name.clone(),
+						_ => kind.clone(),
+					}
+				}
+			})
+		})?;
+		out.write_all(line.as_bytes())?;
+		out.write_all(&[delimiter])?;
+		if contents {
+			out.write_all(&bytes[header_len..header_len + size])?;
+			out.write_all(&[delimiter])?;
 		}
 	}
 
-	entries.sort_by_key(|e| e.name.clone());
-	let hashed_object = hash_git_object(GitObject::Tree(Cow::Borrowed(&entries)), true)?;
+	out.flush()?;
+	Ok(())
+}
 
-	Ok(Tree {
-		hash: Cow::Owned(hashed_object.hash),
-		mode: 0o40000,
-		name: Cow::Owned(path.display().to_string()),
-		entries,
-	})
+/// Atoms supported in `--batch`/`--batch-check` formats. The `*` ones describe the object a tag
+/// ultimately points at.
+const BATCH_ATOMS: [&str; 5] = [
+	"objectname",
+	"objecttype",
+	"objectsize",
+	"*objectname",
+	"*objecttype",
+];
+
+/// Replaces every `%(atom)` in `format` with `value(atom)`.
+fn expand_batch_format(
+	format: &str,
+	mut value: impl FnMut(&str) -> Result<String, CatFileError>,
+) -> Result<String, CatFileError> {
+	let mut out = String::new();
+	let mut rest = format;
+	while let Some(start) = rest.find("%(") {
+		let Some(len) = rest[start..].find(')') else {
+			break;
+		};
+		out += &rest[..start];
+		out += &value(&rest[start + 2..start + len])?;
+		rest = &rest[start + len + 1..];
+	}
+	out += rest;
+	Ok(out)
+}
+
+/// Follows tag `object`, whose loose object is `bytes`, through any further tags to the object
+/// it ultimately points at. Returns that object's name and type.
+fn peel_tag(
+	repo: &Repo,
+	mut object: String,
+	mut bytes: Vec<u8>,
+) -> Result<(String, String), ReadObjectError> {
+	loop {
+		let (kind, size, header_len) = parse_object_header(&object, &bytes)?;
+		if kind != b"tag" {
+			return Ok((object, String::from_utf8_lossy(kind).into_owned()));
+		}
+		let target = bytes[header_len..header_len + size]
+			.strip_prefix(b"object ")
+			.and_then(|rest| rest.get(..40))
+			.filter(|target| target.iter().all(u8::is_ascii_hexdigit))
+			.ok_or_else(|| ReadObjectError::CorruptedObject {
+				hash: object.clone(),
+				context: "malformed object line",
+			})?;
+		let target = String::from_utf8_lossy(target).to_ascii_lowercase();
+		(object, bytes) = inflate_object(repo, target.clone()).map(|(_, bytes)| (target, bytes))?;
+	}
 }
 
 #[derive(Debug, Error)]
-enum ReadIndexError {
-	#[error(transparent)]
-	Io(#[from] std::io::Error),
+enum HashObjectError {
+	#[error("Could not open {path} for reading: {err}")]
+	InputIo {
+		#[source]
+		err: std::io::Error,
 
-	#[error("Failed to read index SHA1 hash")]
-	NoIndexHash,
+		path: PathBuf,
+	},
 
-	#[error("Failed to read index header")]
-	NoIndexHeader,
+	#[error("Could not read stdin: {0}")]
+	InputStdin(std::io::Error),
 
-	#[error("Invalid index signature {0}")]
-	InvalidSignature(String),
+	#[error("Could not open {path}: {err}")]
+	OutputIo {
+		#[source]
+		err: std::io::Error,
 
-	#[error("Failed to read index entries")]
-	NoIndexEntries,
+		path: PathBuf,
+	},
 
-	#[error("Missing index entries. Expected {expected}, got {got}.")]
-	MissingEntries { expected: usize, got: usize },
+	#[error("Failed to encode: {0}")]
+	EncodeObject(std::io::Error),
 
-	#[error("Path missing from an index entry")]
-	NoIndexEntryPath,
+	#[error("{0} changed while it was being hashed")]
+	FileChanged(PathBuf),
 
-	#[error("Path is not a valid string: {0}")]
-	CorruptedPath(std::str::Utf8Error),
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct Index {
-	sha1: [u8; 20],
-	version: u32,
-	entries: Vec<IndexEntry>,
+/// Hashes file `path`, or stdin if `None`, printing the hash and, with `print_path`, the path of
+/// its loose object to `out`. The object is only stored if `write` is set.
+fn hash_object_cmd(
+	repo: &Repo,
+	path: Option<PathBuf>,
+	write: bool,
+	print_path: bool,
+	progress: bool,
+	out: &mut impl Write,
+) -> Result<(), HashObjectError> {
+	let sha1_str = match path {
+		Some(path) => {
+			let mut stderr = std::io::stderr().lock();
+			let progress = progress.then_some(&mut stderr as &mut dyn Write);
+			hash_object(repo, &path, write, progress)?
+		}
+		None => hash_reader(repo, std::io::stdin().lock(), write)?,
+	}
+	.hash_str;
+	writeln!(out, "{sha1_str}")?;
+	if print_path {
+		writeln!(out, "{}", repo.object_path(&sha1_str).display())?;
+	}
+	Ok(())
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct IndexEntry {
-	ctime_s: u32,
-	ctime_n: u32,
-	mtime_s: u32,
-	mtime_n: u32,
-	dev: u32,
-	ino: u32,
-	mode: u32,
-	uid: u32,
-	gid: u32,
-	size: u32,
-	sha1: [u8; 20],
-	flags: u16,
-	path: String,
+/// Size of the chunks files are hashed in.
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hashes file `path` as a blob, storing it if `write` is set, see [hash_blob]. Progress is
+/// reported to `progress` if given.
+fn hash_object(
+	repo: &Repo,
+	path: &Path,
+	write: bool,
+	progress: Option<&mut dyn Write>,
+) -> Result<HashedObject, HashObjectError> {
+	let input_err = |err| HashObjectError::InputIo {
+		path: path.to_owned(),
+		err,
+	};
+	let file = fs::File::open(path).map_err(input_err)?;
+	let len = file.metadata().map_err(input_err)?.len();
+	hash_blob(repo, path, file, len, write, progress)
 }
 
-fn read_index() -> Result<Index, ReadIndexError> {
-	let index = fs::read(".git/index")?;
+/// Hashes the `len` bytes read from `reader`, the content of file `path`, as a blob. Blobs
+/// larger than [Repo::big_file_threshold] are streamed (see [stream_blob]), smaller ones are
+/// read whole, which is faster.
+fn hash_blob(
+	repo: &Repo,
+	path: &Path,
+	mut reader: impl Read,
+	len: u64,
+	write: bool,
+	progress: Option<&mut dyn Write>,
+) -> Result<HashedObject, HashObjectError> {
+	if len > repo.big_file_threshold() {
+		return stream_blob(repo, path, reader, len, write, progress);
+	}
 
-	let sha1 = index
-		.get((index.len() - 20)..)
-		.ok_or(ReadIndexError::NoIndexHash)?;
-	let sha1 = unsafe { *(sha1.as_ptr() as *const [u8; 20]) };
+	let input_err = |err| HashObjectError::InputIo {
+		path: path.to_owned(),
+		err,
+	};
+	let mut content = vec![0; len as usize];
+	match reader.read_exact(&mut content) {
+		Ok(()) => {}
+		Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+			return Err(HashObjectError::FileChanged(path.to_owned()))
+		}
+		Err(err) => return Err(input_err(err)),
+	}
+	if reader.read(&mut [0]).map_err(input_err)? != 0 {
+		return Err(HashObjectError::FileChanged(path.to_owned()));
+	}
+	if let Some(progress) = progress {
+		let _ = writeln!(
+			progress,
+			"\rHashing object: 100% ({len}/{len} bytes), done."
+		);
+	}
+	hash_git_object(repo, GitObject::Blob(Cow::Owned(content)), write)
+}
 
-	let header = index.get(..12).ok_or(ReadIndexError::NoIndexHeader)?;
-	let signature = &header[0..4];
-	let version = unsafe { (*header.as_ptr().add(4).cast::<u32>()).to_be() };
-	let num_entries = unsafe { (*header.as_ptr().add(8).cast::<u32>()).to_be() };
+/// Hashes the `len` bytes read from `reader`, the content of file `path`, as a blob, storing it
+/// if `write` is set. The content is streamed through the hasher and the compressor in chunks
+/// (into a temporary file, renamed once the id is known), so memory use doesn't depend on its
+/// size.
+fn stream_blob(
+	repo: &Repo,
+	path: &Path,
+	mut reader: impl Read,
+	len: u64,
+	write: bool,
+	mut progress: Option<&mut dyn Write>,
+) -> Result<HashedObject, HashObjectError> {
+	let input_err = |err| HashObjectError::InputIo {
+		path: path.to_owned(),
+		err,
+	};
+	let header = format!("blob {len}\0");
+
+	let mut hasher = sha1::Sha1::new();
+	hasher.update(header.as_bytes());
+	let mut temp = if write {
+		let temp = TempObject::create(repo)?;
+		let mut encoder = ZlibEncoder::new(temp, flate2::Compression::default());
+		encoder
+			.write_all(header.as_bytes())
+			.map_err(|err| encoder.get_ref().err(err))?;
+		Some(encoder)
+	} else {
+		None
+	};
 
-	if signature != b"DIRC" {
-		return Err(ReadIndexError::InvalidSignature(
-			String::from_utf8_lossy(signature).to_string(),
-		));
+	let mut buffer = vec![0; HASH_CHUNK_SIZE];
+	let mut hashed = 0_u64;
+	let mut reported = None;
+	loop {
+		let n = match reader.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(n) => n,
+			Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(input_err(err)),
+		};
+		hasher.update(&buffer[..n]);
+		if let Some(encoder) = &mut temp {
+			encoder
+				.write_all(&buffer[..n])
+				.map_err(|err| encoder.get_ref().err(err))?;
+		}
+		hashed += n as u64;
+
+		if let Some(progress) = &mut progress {
+			let percent = (hashed * 100).checked_div(len).unwrap_or(100);
+			if reported != Some(percent) {
+				reported = Some(percent);
+				// Progress isn't worth failing over.
+				let _ = write!(
+					progress,
+					"\rHashing object: {percent:3}% ({hashed}/{len} bytes)"
+				);
+			}
+		}
+	}
+	if let Some(progress) = &mut progress {
+		let _ = writeln!(progress, ", done.");
+	}
+	if hashed != len {
+		return Err(HashObjectError::FileChanged(path.to_owned()));
 	}
 
-	let mut entries_bytes = index
-		.get(12..(index.len() - 20))
-		.ok_or(ReadIndexError::NoIndexEntries)?;
+	let hash = hasher.finalize();
+	let hash_str = hex::encode(hash);
+	if let Some(encoder) = temp {
+		let temp = encoder.finish().map_err(|err| HashObjectError::OutputIo {
+			err,
+			path: repo.objects_dir(),
+		})?;
+		temp.persist(repo, &hash_str)?;
+	}
+	Ok(HashedObject { hash, hash_str })
+}
 
-	let mut entries = Vec::new();
+/// Object being written to a temporary file in `objects/`, removed when dropped unless it was
+/// persisted. The name (`tmp_obj_...`) isn't the one of an object, so the file is never read
+/// as one.
+struct TempObject {
+	file: fs::File,
+	path: PathBuf,
+	persisted: bool,
+}
 
-	let idx = 0;
-	while idx + 62 < entries_bytes.len() {
-		let fields = &entries_bytes[..62];
+impl TempObject {
+	fn create(repo: &Repo) -> Result<Self, HashObjectError> {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
 
-		let null_byte_idx = entries_bytes
-			.iter()
-			.skip(idx + 62)
-			.position(|x| *x == 0)
-			.ok_or(ReadIndexError::NoIndexEntryPath)?;
+		let path = repo.objects_dir().join(format!(
+			"tmp_obj_{}_{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		let file = fs::File::create(&path).map_err(|err| HashObjectError::OutputIo {
+			err,
+			path: path.clone(),
+		})?;
+		Ok(TempObject {
+			file,
+			path,
+			persisted: false,
+		})
+	}
 
-		let path = &entries_bytes[(idx + 62)..(idx + 62 + null_byte_idx)];
-		let path = std::str::from_utf8(path)
-			.map_err(ReadIndexError::CorruptedPath)?
-			.to_string();
-		let path_len = path.len();
+	fn err(&self, err: std::io::Error) -> HashObjectError {
+		HashObjectError::OutputIo {
+			err,
+			path: self.path.clone(),
+		}
+	}
 
-		let fields_u32_ptr = fields.as_ptr().cast::<u32>();
-
-		entries.push(unsafe {
-			IndexEntry {
-				ctime_s: (*fields_u32_ptr).to_be(),
-				ctime_n: (*fields_u32_ptr.add(1)).to_be(),
-				mtime_s: (*fields_u32_ptr.add(2)).to_be(),
-				mtime_n: (*fields_u32_ptr.add(3)).to_be(),
-				dev: (*fields_u32_ptr.add(4)).to_be(),
-				ino: (*fields_u32_ptr.add(5)).to_be(),
-				mode: (*fields_u32_ptr.add(6)).to_be(),
-				uid: (*fields_u32_ptr.add(7)).to_be(),
-				gid: (*fields_u32_ptr.add(8)).to_be(),
-				size: (*fields_u32_ptr.add(9)).to_be(),
-				sha1: *(fields[40..60].as_ptr() as *const [u8; 20]),
-				flags: (*fields.as_ptr().add(60).cast::<u16>()).to_be(),
-				path,
-			}
-		});
+	/// Moves the file to where object `hash_str` is stored, unless it's already there.
+	fn persist(mut self, repo: &Repo, hash_str: &str) -> Result<(), HashObjectError> {
+		let object_path = repo.object_path(hash_str);
+		if object_path.exists() {
+			return Ok(());
+		}
+		let dir = object_path.parent().unwrap();
+		fs::create_dir_all(dir).map_err(|err| HashObjectError::OutputIo {
+			err,
+			path: dir.to_owned(),
+		})?;
+		match fs::rename(&self.path, &object_path) {
+			Ok(()) => self.persisted = true,
+			// Another writer stored the same object in the meantime (renaming over an existing
+			// file fails on Windows), this copy is removed when dropped.
+			Err(_) if object_path.exists() => {}
+			Err(err) => return Err(self.err(err)),
+		}
+		Ok(())
+	}
+}
 
-		entries_bytes = &entries_bytes[(((62 + path_len + 8) / 8) * 8)..];
+impl Write for TempObject {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.file.write(buf)
 	}
 
-	if entries.len() != num_entries as usize {
-		return Err(ReadIndexError::MissingEntries {
-			expected: num_entries as usize,
-			got: entries.len(),
-		});
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.file.flush()
 	}
+}
 
-	Ok(Index {
-		sha1,
-		version,
-		entries,
+impl Drop for TempObject {
+	fn drop(&mut self) {
+		if !self.persisted {
+			let _ = fs::remove_file(&self.path);
+		}
+	}
+}
+
+/// Like [hash_object], but hashes everything read from `input`.
+fn hash_reader(
+	repo: &Repo,
+	mut input: impl Read,
+	write: bool,
+) -> Result<HashedObject, HashObjectError> {
+	let mut contents = Vec::new();
+	input
+		.read_to_end(&mut contents)
+		.map_err(HashObjectError::InputStdin)?;
+	hash_git_object(repo, GitObject::Blob(Cow::Borrowed(&contents)), write)
+}
+
+/// Encodes and hashes given [GitObject]. Returns the SHA1 hash of that object.
+fn hash_git_object(
+	repo: &Repo,
+	object: GitObject,
+	write: bool,
+) -> Result<HashedObject, HashObjectError> {
+	let mut encoded_file_content = Vec::new();
+	encode_object(object, &mut encoded_file_content).map_err(HashObjectError::EncodeObject)?;
+	write_encoded_object(repo, &encoded_file_content, write)
+}
+
+/// Hashes an already encoded object (`<kind> <size>\0<payload>`), storing it if `write` is set.
+fn write_encoded_object(
+	repo: &Repo,
+	encoded_file_content: &[u8],
+	write: bool,
+) -> Result<HashedObject, HashObjectError> {
+	let sha1_hash = sha1::sha1(encoded_file_content);
+	if write {
+		store_encoded_object(repo, encoded_file_content, &sha1_hash)?;
+	}
+
+	Ok(HashedObject {
+		hash: sha1_hash,
+		hash_str: hex::encode(sha1_hash),
 	})
 }
 
-#[derive(Debug, Error)]
-enum CommitTreeError {
-	#[error("Hash object: {0}")]
-	HashObject(#[from] HashObjectError),
+/// Compresses and stores an encoded object with id `id`, unless it's already stored.
+fn store_encoded_object(
+	repo: &Repo,
+	encoded_file_content: &[u8],
+	id: &ObjectId,
+) -> Result<(), HashObjectError> {
+	let sha1_str = hex::encode(id);
+	if repo.object_path(&sha1_str).exists() {
+		return Ok(());
+	}
+	// The object is written to a temporary file first, so that it's never seen half-written.
+	let mut encoder = ZlibEncoder::new(TempObject::create(repo)?, flate2::Compression::default());
+	encoder
+		.write_all(encoded_file_content)
+		.map_err(|err| encoder.get_ref().err(err))?;
+	let temp = encoder.finish().map_err(|err| HashObjectError::OutputIo {
+		err,
+		path: repo.objects_dir(),
+	})?;
+	#[cfg(test)]
+	test_utils::COMPRESSED_OBJECTS
+		.lock()
+		.unwrap()
+		.push(repo.object_path(&sha1_str));
+	temp.persist(repo, &sha1_str)
+}
 
-	#[error("Invalid tree object: {0}")]
-	InvalidTreeSha1(hex::FromHexError),
+/// Id of an object of given kind and payload, the SHA1 of `<kind> <size>\0<payload>`. Nothing
+/// is written, so this can predict the id of an object before deciding to store it.
+fn compute_id(kind: ObjectKind, payload: &[u8]) -> ObjectId {
+	let mut framed = format!("{} {}\0", kind.as_str(), payload.len()).into_bytes();
+	framed.extend_from_slice(payload);
+	sha1::sha1(&framed)
+}
 
-	#[error("Invalid parent object: {0}")]
-	InvalidParentSha1(hex::FromHexError),
+struct HashedObject {
+	hash: [u8; 20],
+	hash_str: String,
 }
 
-fn commit_tree(
-	tree_hash_str: String,
-	parent_hash_str: Option<String>,
-	message: String,
-) -> Result<(), CommitTreeError> {
-	let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs();
+enum GitObject<'a> {
+	Blob(Cow<'a, [u8]>),
+	Commit(Commit),
+	Tag(Tag),
+	Tree(Cow<'a, [TreeEntry<'a>]>),
+}
 
-	let mut tree = [0_u8; 20];
-	hex::decode_to_slice(tree_hash_str, &mut tree).map_err(CommitTreeError::InvalidTreeSha1)?;
+/// SHA1 of an encoded object, identifying it in the object store.
+type ObjectId = [u8; 20];
+
+/// Maps a Unix file mode to one of the modes git stores in trees: `040000` for directories,
+/// `120000` for symlinks, `160000` for submodules and `100755` or `100644` for files, depending
+/// on whether the owner may execute them.
+fn normalize_mode(mode: u32) -> u32 {
+	match mode & 0o170000 {
+		0o040000 => 0o40000,
+		0o120000 => 0o120000,
+		0o160000 => 0o160000,
+		_ if mode & 0o100 != 0 => 0o100755,
+		_ => 0o100644,
+	}
+}
 
-	let parent = match parent_hash_str {
-		Some(parent_hash_str) => {
-			let mut parent = [0_u8; 20];
-			hex::decode_to_slice(parent_hash_str.as_bytes(), &mut parent)
-				.map_err(CommitTreeError::InvalidTreeSha1)?;
-			Some(parent)
+/// Git mode of the file at `path` described by `metadata` (not following symlinks), see
+/// [normalize_mode].
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata, _path: &Path) -> u32 {
+	normalize_mode(metadata.mode())
+}
+
+/// Git mode of the file at `path` described by `metadata` (not following symlinks). Windows has
+/// no executable bit, so files are executable if Windows would run them, going by their
+/// extension. Being read-only doesn't matter, git has no mode for it.
+#[cfg(windows)]
+fn file_mode(metadata: &fs::Metadata, path: &Path) -> u32 {
+	const EXECUTABLE_EXTENSIONS: [&str; 4] = ["exe", "bat", "cmd", "com"];
+
+	if metadata.is_symlink() {
+		0o120000
+	} else if metadata.is_dir() {
+		0o40000
+	} else if path.extension().is_some_and(|ext| {
+		EXECUTABLE_EXTENSIONS
+			.iter()
+			.any(|executable| ext.eq_ignore_ascii_case(executable))
+	}) {
+		0o100755
+	} else {
+		0o100644
+	}
+}
+
+/// Target of the symlink at `path`, as stored in the blob of a link.
+#[cfg(unix)]
+fn read_link_target(path: &Path) -> std::io::Result<Vec<u8>> {
+	Ok(fs::read_link(path)?.into_os_string().into_vec())
+}
+
+/// Target of the symlink at `path`, as stored in the blob of a link: with `/` separators, like
+/// on Unix.
+#[cfg(windows)]
+fn read_link_target(path: &Path) -> std::io::Result<Vec<u8>> {
+	Ok(fs::read_link(path)?
+		.to_string_lossy()
+		.replace('\\', "/")
+		.into_bytes())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ObjectKind {
+	Blob,
+	Tree,
+	Commit,
+	Tag,
+}
+
+impl ObjectKind {
+	fn from_bytes(kind: &[u8]) -> Option<Self> {
+		match kind {
+			b"blob" => Some(ObjectKind::Blob),
+			b"tree" => Some(ObjectKind::Tree),
+			b"commit" => Some(ObjectKind::Commit),
+			b"tag" => Some(ObjectKind::Tag),
+			_ => None,
 		}
-		None => None,
-	};
+	}
 
-	let sha1 = hash_git_object(
-		GitObject::Commit(Commit {
-			tree,
-			parent,
-			message,
+	/// Kind of the object a tree entry with given mode points at.
+	fn from_mode(mode: u32) -> Self {
+		match mode {
+			0o40000 => ObjectKind::Tree,
+			0o160000 => ObjectKind::Commit,
+			_ => ObjectKind::Blob,
+		}
+	}
+
+	fn as_str(&self) -> &'static str {
+		match self {
+			ObjectKind::Blob => "blob",
+			ObjectKind::Tree => "tree",
+			ObjectKind::Commit => "commit",
+			ObjectKind::Tag => "tag",
+		}
+	}
+}
+
+#[derive(Clone)]
+struct TreeEntry<'a> {
+	mode: u32,
+	name: Cow<'a, str>,
+	object_hash: Cow<'a, [u8; 20]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Commit {
+	tree: [u8; 20],
+	/// No parents for a root commit, several for a merge.
+	parents: Vec<[u8; 20]>,
+	author: Signature,
+	committer: Signature,
+	/// Headers after `committer` (e.g. `encoding`, `gpgsig`, `mergetag`) as name and value,
+	/// multi-line values joined with `\n`. Kept so the commit encodes back to the same bytes.
+	extra_headers: Vec<(String, String)>,
+	/// Message as stored, usually ending with a newline.
+	message: String,
+}
+
+/// Annotated tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tag {
+	/// Object the tag points at.
+	object: [u8; 20],
+	/// Kind of that object, as the tag claims.
+	kind: ObjectKind,
+	name: String,
+	tagger: Signature,
+	/// Headers after `tagger` as name and value, like for [Commit::extra_headers].
+	extra_headers: Vec<(String, String)>,
+	/// Message as stored, usually ending with a newline. A signature, if any, is part of it.
+	message: String,
+}
+
+/// Identity and time of an author or committer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Signature {
+	name: String,
+	email: String,
+	timestamp: i64,
+	/// Timezone offset in minutes east of UTC.
+	tz_offset: i32,
+}
+
+impl Signature {
+	/// Parses the `Name <email> 1700000000 +0100` format of commits.
+	fn parse(signature: &str) -> Option<Self> {
+		let (rest, tz_offset) = signature.rsplit_once(' ')?;
+		let (identity, timestamp) = rest.rsplit_once(' ')?;
+		let (name, email) = identity.strip_suffix('>')?.split_once('<')?;
+		// Same check the date parser does on raw dates
+		let (timestamp, tz_offset) = date::parse_date(&format!("{timestamp} {tz_offset}"))?;
+		Some(Signature {
+			name: name.trim_end().to_string(),
+			email: email.to_string(),
 			timestamp,
-			author: "Foo Bar <foo@bar.com>".to_string(),
-		}),
-		true,
-	)?;
+			tz_offset,
+		})
+	}
+}
 
-	println!("{}", sha1.hash_str);
+impl std::fmt::Display for Signature {
+	/// Formats the signature the way it's stored in commits: `Name <email> 1700000000 +0100`.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{} <{}> {} {}",
+			self.name,
+			self.email,
+			self.timestamp,
+			date::format_offset(self.tz_offset)
+		)
+	}
+}
+
+fn encode_object<W: Write>(kind: GitObject, w: &mut W) -> Result<(), std::io::Error> {
+	match kind {
+		GitObject::Blob(blob) => encode_blob(blob, w),
+		GitObject::Tree(entries) => encode_tree(&entries, w),
+		GitObject::Commit(commit) => encode_commit(commit, w),
+		GitObject::Tag(tag) => encode_tag(tag, w),
+	}
+}
+
+fn encode_blob<W: Write>(blob: Cow<[u8]>, w: &mut W) -> Result<(), std::io::Error> {
+	let header = format!("blob {}", blob.len());
+	w.write_all(header.as_bytes())?;
+	w.write_all(&[0_u8])?;
+	w.write_all(&blob)?;
+	Ok(())
+}
+
+fn encode_tree<W: Write>(entries: &[TreeEntry], w: &mut W) -> Result<(), std::io::Error> {
+	w.write_all(b"tree ")?;
+
+	let mut size = 21 * entries.len();
+	for entry in entries {
+		size += format!("{:o} {}", entry.mode, entry.name).len();
+	}
+
+	w.write_all(size.to_string().as_bytes())?;
+	w.write_all(&[0_u8])?;
+
+	for entry in entries {
+		w.write_all(format!("{:o} {}", entry.mode, entry.name).as_bytes())?;
+		w.write_all(&[0])?;
+		w.write_all(entry.object_hash.as_slice())?;
+	}
+
+	Ok(())
+}
+
+fn encode_commit<W: Write>(commit: Commit, w: &mut W) -> Result<(), std::io::Error> {
+	w.write_all(b"commit ")?;
+
+	let mut temp_buf = Vec::new();
+	temp_buf.write_all(format!("tree {}\n", hex::encode(commit.tree)).as_bytes())?;
+
+	for parent in commit.parents {
+		temp_buf.write_all(format!("parent {}\n", hex::encode(parent)).as_bytes())?;
+	}
+
+	temp_buf.write_all(format!("author {}\n", commit.author).as_bytes())?;
+	temp_buf.write_all(format!("committer {}\n", commit.committer).as_bytes())?;
+	for (name, value) in &commit.extra_headers {
+		// Continuation lines start with a space.
+		let value = value.replace('\n', "\n ");
+		temp_buf.write_all(format!("{name} {value}\n").as_bytes())?;
+	}
+	temp_buf.write_all(b"\n")?;
+	temp_buf.write_all(commit.message.as_bytes())?;
+
+	w.write_all(temp_buf.len().to_string().as_bytes())?;
+	w.write_all(&[0_u8])?;
+	w.write_all(&temp_buf)?;
 
 	Ok(())
 }
+
+fn encode_tag<W: Write>(tag: Tag, w: &mut W) -> Result<(), std::io::Error> {
+	let mut payload = format!(
+		"object {}\ntype {}\ntag {}\ntagger {}\n",
+		hex::encode(tag.object),
+		tag.kind.as_str(),
+		tag.name,
+		tag.tagger
+	);
+	for (name, value) in &tag.extra_headers {
+		payload += &format!("{name} {}\n", value.replace('\n', "\n "));
+	}
+	payload += "\n";
+	payload += &tag.message;
+
+	write!(w, "tag {}\0", payload.len())?;
+	w.write_all(payload.as_bytes())
+}
+
+#[derive(Debug, Error)]
+enum ReadObjectError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error("Invalid object: {0}")]
+	InvalidHash(#[from] hex::FromHexError),
+
+	#[error("Corrupted object {hash}: {context}")]
+	CorruptedObject { hash: String, context: &'static str },
+
+	#[error("Unknown object kind of {hash}")]
+	UnknownObjectKind { hash: String },
+
+	#[error("Corrupted tree entry in {hash}")]
+	CorruptedTreeEntry { hash: String },
+
+	#[error("Corrupted tree entry mode in {hash}")]
+	TreeEntryMode { hash: String },
+
+	#[error("Invalid tree entry name in {hash}: {err}")]
+	TreeEntryName {
+		hash: String,
+		#[source]
+		err: std::str::Utf8Error,
+	},
+
+	#[error("Corrupted SHA1 of tree entry {name} in {hash}")]
+	CorruptedTreeEntrySha1 { hash: String, name: String },
+
+	#[error("Not a valid object name {0}")]
+	ObjectNotFound(String),
+
+	#[error(transparent)]
+	Pack(#[from] pack::PackError),
+
+	#[error("Object {expected} hashes to {actual}")]
+	HashMismatch { expected: String, actual: String },
+
+	#[error("Short object name {prefix} is ambiguous, candidates: {}", candidates.join(", "))]
+	AmbiguousObjectName {
+		prefix: String,
+		candidates: Vec<String>,
+	},
+}
+
+/// Shortest abbreviated object name that is looked up.
+const MIN_ABBREV_LEN: usize = 4;
+
+/// Length git abbreviates object names to by default, `core.abbrev`'s default.
+const DEFAULT_ABBREV_LEN: usize = 7;
+
+/// Normalizes a user supplied object name: surrounding whitespace is trimmed and hex digits are
+/// lowercased. Returns `None` if it isn't a full 40 character hex hash or an abbreviation of
+/// at least [MIN_ABBREV_LEN] hex digits.
+fn normalize_object_name(object: &str) -> Option<String> {
+	let object = object.trim();
+	((MIN_ABBREV_LEN..=40).contains(&object.len()) && object.bytes().all(|b| b.is_ascii_hexdigit()))
+		.then(|| object.to_ascii_lowercase())
+}
+
+/// Expands the lowercase object name `sha1` to a full hash. Full hashes are returned as is,
+/// abbreviated ones must be the prefix of exactly one loose object.
+fn expand_object_name(repo: &Repo, sha1: &str) -> Result<String, ReadObjectError> {
+	if sha1.len() == 40 {
+		// Just a check that a given sha1 is correct
+		let _ = hex::decode(sha1)?;
+		return Ok(sha1.to_string());
+	}
+	if let Some((index, c)) = sha1.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+		return Err(hex::FromHexError::InvalidHexCharacter { c, index }.into());
+	}
+	if sha1.len() < MIN_ABBREV_LEN || sha1.len() > 40 {
+		return Err(ReadObjectError::ObjectNotFound(sha1.to_string()));
+	}
+
+	let (dir, rest) = sha1.split_at(2);
+	let read_dir = match fs::read_dir(repo.objects_dir().join(dir)) {
+		Ok(v) => Some(v),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+		Err(err) => return Err(err.into()),
+	};
+	let mut candidates = Vec::new();
+	for entry in read_dir.into_iter().flatten() {
+		let name = entry?.file_name();
+		let Some(name) = name.to_str() else {
+			continue;
+		};
+		// Skips temporary files of objects being written.
+		if name.len() == 38 && name.starts_with(rest) && hex::decode(name).is_ok() {
+			candidates.push(format!("{dir}{name}"));
+		}
+	}
+	for id in pack::packed_ids(repo)? {
+		let hash = hex::encode(id);
+		if hash.starts_with(sha1) && !candidates.contains(&hash) {
+			candidates.push(hash);
+		}
+	}
+
+	match candidates.len() {
+		0 => Err(ReadObjectError::ObjectNotFound(sha1.to_string())),
+		1 => Ok(candidates.remove(0)),
+		_ => {
+			candidates.sort();
+			Err(ReadObjectError::AmbiguousObjectName {
+				prefix: sha1.to_string(),
+				candidates,
+			})
+		}
+	}
+}
+
+/// Length of the shortest prefix of `id` (at least `min_len`, and no less than
+/// [MIN_ABBREV_LEN]) that no other loose or packed object starts with, so that the abbreviation
+/// stays unambiguous. `id` itself doesn't have to be stored.
+fn min_unique_abbrev_len(
+	repo: &Repo,
+	id: &ObjectId,
+	min_len: usize,
+) -> Result<usize, ReadObjectError> {
+	let hash = hex::encode(id);
+	let common_prefix_len = |other: &str| {
+		hash.bytes()
+			.zip(other.bytes())
+			.take_while(|(a, b)| a == b)
+			.count()
+	};
+
+	let mut len = min_len.clamp(MIN_ABBREV_LEN, 40);
+	// Only objects in the same fanout directory share more than the first 2 digits.
+	let (dir, _) = hash.split_at(2);
+	let read_dir = match fs::read_dir(repo.objects_dir().join(dir)) {
+		Ok(v) => Some(v),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+		Err(err) => return Err(err.into()),
+	};
+	for entry in read_dir.into_iter().flatten() {
+		let name = entry?.file_name();
+		let Some(name) = name.to_str().filter(|name| name.len() == 38) else {
+			continue;
+		};
+		let other = format!("{dir}{name}");
+		if other != hash {
+			len = len.max(common_prefix_len(&other) + 1);
+		}
+	}
+	for other in pack::packed_ids(repo)? {
+		if other != *id {
+			len = len.max(common_prefix_len(&hex::encode(other)) + 1);
+		}
+	}
+	Ok(len.min(40))
+}
+
+/// Parses a user supplied, possibly abbreviated, object name into an id.
+fn parse_object_name(repo: &Repo, object: &str) -> Result<ObjectId, ReadObjectError> {
+	let normalized = normalize_object_name(object)
+		.ok_or_else(|| ReadObjectError::ObjectNotFound(object.to_string()))?;
+	let mut id = [0_u8; 20];
+	hex::decode_to_slice(expand_object_name(repo, &normalized)?, &mut id)?;
+	Ok(id)
+}
+
+/// Reads and parses object `sha1`. Unless disabled with [Repo::with_verify_objects], the object
+/// is checked to hash to its id first.
+fn decode_object(repo: &Repo, sha1: String) -> Result<GitObject<'static>, ReadObjectError> {
+	// Expanded first so that errors name the full object.
+	let sha1 = expand_object_name(repo, &sha1.to_ascii_lowercase())?;
+	let (kind, payload) = read_object_raw(repo, sha1.clone())?;
+	decode_payload(&sha1, kind, &payload)
+}
+
+/// Reads object `sha1` without parsing its payload, returning its kind and the payload, which
+/// is checked to have the size given in the header. Unless disabled with
+/// [Repo::with_verify_objects], the object is checked to hash to its id first.
+fn read_object_raw(repo: &Repo, sha1: String) -> Result<(ObjectKind, Vec<u8>), ReadObjectError> {
+	let (sha1, mut bytes) = inflate_object(repo, sha1)?;
+	if repo.verify_objects() {
+		let actual = hex::encode(sha1::sha1(&bytes));
+		if actual != sha1 {
+			return Err(ReadObjectError::HashMismatch {
+				expected: sha1,
+				actual,
+			});
+		}
+	}
+
+	let (kind, size, header_len) = parse_object_header(&sha1, &bytes)?;
+	let kind = ObjectKind::from_bytes(kind)
+		.ok_or_else(|| ReadObjectError::UnknownObjectKind { hash: sha1.clone() })?;
+	if bytes.len() - header_len < size {
+		return Err(ReadObjectError::CorruptedObject {
+			hash: sha1,
+			context: "truncated payload",
+		});
+	}
+	bytes.truncate(header_len + size);
+	bytes.drain(..header_len);
+	Ok((kind, bytes))
+}
+
+/// Reads and inflates the object `sha1` (after replacement, which is returned along with it),
+/// without parsing its payload. Loose objects are looked up first, then packs.
+fn inflate_object(repo: &Repo, mut sha1: String) -> Result<(String, Vec<u8>), ReadObjectError> {
+	sha1.make_ascii_lowercase();
+	let sha1 = expand_object_name(repo, &sha1)?;
+	let sha1 = refs::replacement(repo, &sha1)?;
+
+	let bytes = match inflate_loose_object(repo, &sha1) {
+		Err(ReadObjectError::ObjectNotFound(_)) => {
+			let mut id = [0_u8; 20];
+			hex::decode_to_slice(&sha1, &mut id)?;
+			let Some((kind, payload)) = pack::read_packed_object(repo, &id)? else {
+				return Err(ReadObjectError::ObjectNotFound(sha1));
+			};
+			let mut bytes = format!("{} {}\0", kind.as_str(), payload.len()).into_bytes();
+			bytes.extend(payload);
+			bytes
+		}
+		bytes => bytes?,
+	};
+	Ok((sha1, bytes))
+}
+
+/// Inflates the loose object stored under `sha1`, as is, ignoring replacements.
+fn inflate_loose_object(repo: &Repo, sha1: &str) -> Result<Vec<u8>, ReadObjectError> {
+	let file = match fs::File::open(repo.object_path(sha1)) {
+		Ok(file) => file,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+			return Err(ReadObjectError::ObjectNotFound(sha1.to_string()))
+		}
+		Err(err) => return Err(err.into()),
+	};
+	let file_buffered = BufReader::new(file);
+	let mut decoder = flate2::bufread::ZlibDecoder::new(file_buffered);
+
+	let mut file_content_bytes = Vec::new();
+	match decoder.read_to_end(&mut file_content_bytes) {
+		Ok(_) => Ok(file_content_bytes),
+		// A damaged stream still yields everything that could be inflated before the damage, so
+		// check that first to tell a truncated payload apart from e.g. a missing checksum. Older
+		// flate2 backends report a stream ending early as invalid input, newer ones as an EOF.
+		Err(err)
+			if matches!(
+				err.kind(),
+				std::io::ErrorKind::InvalidInput | std::io::ErrorKind::UnexpectedEof
+			) =>
+		{
+			let (_, size, header_len) = parse_object_header(sha1, &file_content_bytes)?;
+			let context = if file_content_bytes.len() - header_len < size {
+				"truncated payload"
+			} else {
+				"corrupt zlib stream"
+			};
+			Err(ReadObjectError::CorruptedObject {
+				hash: sha1.to_string(),
+				context,
+			})
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// Checks that the stored object `sha1` is intact: it inflates, its header is well-formed, its
+/// kind is known, the payload has exactly the size from the header and the whole object hashes
+/// to `sha1`.
+fn verify_object(repo: &Repo, sha1: &str) -> Result<(), ReadObjectError> {
+	let corrupted = |context| ReadObjectError::CorruptedObject {
+		hash: sha1.to_string(),
+		context,
+	};
+
+	let bytes = inflate_loose_object(repo, sha1)?;
+	let (kind, size, header_len) = parse_object_header(sha1, &bytes)?;
+	if ObjectKind::from_bytes(kind).is_none() {
+		return Err(ReadObjectError::UnknownObjectKind {
+			hash: sha1.to_string(),
+		});
+	}
+	if bytes.len() - header_len < size {
+		return Err(corrupted("truncated payload"));
+	}
+	if bytes.len() - header_len > size {
+		return Err(corrupted("trailing data after payload"));
+	}
+	if hex::encode(sha1::sha1(&bytes)) != sha1 {
+		return Err(corrupted("hash mismatch"));
+	}
+	Ok(())
+}
+
+/// Parses the `<type> <size>\0` header of an inflated object. Returns the type, the payload size
+/// and the length of the header including the null byte. `hash` is only used for error reporting.
+fn parse_object_header<'a>(
+	hash: &str,
+	file_content_bytes: &'a [u8],
+) -> Result<(&'a [u8], usize, usize), ReadObjectError> {
+	let corrupted = |context| ReadObjectError::CorruptedObject {
+		hash: hash.to_string(),
+		context,
+	};
+
+	if file_content_bytes.len() <= 1 {
+		return Err(corrupted("too short"));
+	}
+	let space_idx = file_content_bytes
+		.iter()
+		.position(|x| *x == b' ')
+		.ok_or_else(|| corrupted("no space"))?;
+
+	let (object_type, rest) = file_content_bytes.split_at(space_idx);
+	// Skip space
+	let rest = &rest[1..];
+
+	let null_byte_idx = rest
+		.iter()
+		.position(|x| !x.is_ascii_digit())
+		.ok_or_else(|| corrupted("only ascii digits???"))?;
+	if rest[null_byte_idx] != 0 {
+		return Err(corrupted("byte after digits isn't null"));
+	}
+	if null_byte_idx == 0 {
+		return Err(corrupted("missing object size"));
+	}
+
+	// Safety iterator to find the null byte checked that all bytes are ascii digits
+	let size: usize = unsafe { std::str::from_utf8_unchecked(&rest[..null_byte_idx]) }
+		.parse()
+		.map_err(|_| corrupted("object size out of range"))?;
+
+	Ok((object_type, size, space_idx + 1 + null_byte_idx + 1))
+}
+
+/// Reads only the header of the loose object at `path`, without inflating its payload.
+fn read_object_header(path: &Path, hash: &str) -> Result<(ObjectKind, usize), ReadObjectError> {
+	let file = fs::File::open(path)?;
+	let decoder = flate2::bufread::ZlibDecoder::new(BufReader::new(file));
+	// The longest valid header, "commit <u64::MAX>\0", fits easily.
+	let mut header = Vec::new();
+	BufReader::new(decoder.take(64)).read_until(0, &mut header)?;
+
+	let (kind, size, _) = parse_object_header(hash, &header)?;
+	let kind = ObjectKind::from_bytes(kind).ok_or_else(|| ReadObjectError::UnknownObjectKind {
+		hash: hash.to_string(),
+	})?;
+	Ok((kind, size))
+}
+
+/// Parses an inflated object (`<type> <size>\0<payload>`) into a [GitObject]. `hash` is only
+/// used for error reporting.
+fn decode_object_bytes(
+	hash: &str,
+	file_content_bytes: &[u8],
+) -> Result<GitObject<'static>, ReadObjectError> {
+	let corrupted = |context| ReadObjectError::CorruptedObject {
+		hash: hash.to_string(),
+		context,
+	};
+
+	let (object_type, size, header_len) = parse_object_header(hash, file_content_bytes)?;
+	let kind =
+		ObjectKind::from_bytes(object_type).ok_or_else(|| ReadObjectError::UnknownObjectKind {
+			hash: hash.to_string(),
+		})?;
+	let payload = file_content_bytes[header_len..]
+		.get(..size)
+		.ok_or_else(|| corrupted("truncated payload"))?;
+	decode_payload(hash, kind, payload)
+}
+
+/// Parses the `payload` of an object of given kind. `hash` is only used for error reporting.
+fn decode_payload(
+	hash: &str,
+	kind: ObjectKind,
+	mut rest: &[u8],
+) -> Result<GitObject<'static>, ReadObjectError> {
+	let corrupted_tree_entry = || ReadObjectError::CorruptedTreeEntry {
+		hash: hash.to_string(),
+	};
+	let tree_entry_mode = || ReadObjectError::TreeEntryMode {
+		hash: hash.to_string(),
+	};
+
+	match kind {
+		ObjectKind::Blob => Ok(GitObject::Blob(Cow::Owned(rest.to_vec()))),
+		ObjectKind::Commit => Ok(GitObject::Commit(parse_commit(hash, rest)?)),
+		ObjectKind::Tag => Ok(GitObject::Tag(parse_tag(hash, rest)?)),
+		ObjectKind::Tree => {
+			let mut tree_entries = Vec::new();
+			while !rest.is_empty() {
+				let space_idx = rest
+					.iter()
+					.position(|x| *x == b' ')
+					.ok_or_else(corrupted_tree_entry)?;
+
+				let mode: u32 = std::str::from_utf8(&rest[..space_idx])
+					.map_err(|_| tree_entry_mode())
+					.and_then(|mode_str| {
+						u32::from_str_radix(mode_str, 8).map_err(|_| tree_entry_mode())
+					})?;
+
+				rest = rest
+					.get((space_idx + 1)..)
+					.ok_or_else(corrupted_tree_entry)?;
+
+				let null_byte_idx = rest
+					.iter()
+					.position(|x| *x == 0)
+					.ok_or_else(corrupted_tree_entry)?;
+
+				let name = std::str::from_utf8(&rest[..null_byte_idx]).map_err(|err| {
+					ReadObjectError::TreeEntryName {
+						hash: hash.to_string(),
+						err,
+					}
+				})?;
+				let name: Cow<str> = Cow::Owned(name.to_string());
+
+				rest = rest
+					.get((null_byte_idx + 1)..)
+					.ok_or_else(corrupted_tree_entry)?;
+
+				if rest.len() < 20 {
+					return Err(ReadObjectError::CorruptedTreeEntrySha1 {
+						hash: hash.to_string(),
+						name: name.into_owned(),
+					});
+				}
+
+				// Copied out, the entry must not borrow from the inflated buffer that's about to
+				// be dropped.
+				let object_hash: [u8; 20] = rest[..20].try_into().unwrap();
+
+				tree_entries.push(TreeEntry {
+					mode,
+					name,
+					object_hash: Cow::Owned(object_hash),
+				});
+
+				rest = &rest[20..];
+			}
+
+			Ok(GitObject::Tree(Cow::Owned(tree_entries)))
+		}
+	}
+}
+
+/// Parses the payload of a commit object. `hash` is only used for error reporting.
+fn parse_commit(hash: &str, payload: &[u8]) -> Result<Commit, ReadObjectError> {
+	let corrupted = |context| ReadObjectError::CorruptedObject {
+		hash: hash.to_string(),
+		context,
+	};
+	let parse_hash = |value: &str, context| {
+		let mut id = [0_u8; 20];
+		hex::decode_to_slice(value, &mut id).map_err(|_| corrupted(context))?;
+		Ok::<_, ReadObjectError>(id)
+	};
+
+	let payload = String::from_utf8_lossy(payload);
+	// Headers end at the first empty line, a commit without a message may not have one.
+	let (headers, message) = match payload.split_once("\n\n") {
+		Some((headers, message)) => (headers, message),
+		None => (payload.strip_suffix('\n').unwrap_or(&payload), ""),
+	};
+
+	let mut lines = headers.split('\n').peekable();
+	let mut header = |name: &str| {
+		lines
+			.next_if(|line| {
+				line.starts_with(name) && line.as_bytes().get(name.len()) == Some(&b' ')
+			})
+			.map(|line| &line[name.len() + 1..])
+	};
+
+	let tree = header("tree").ok_or_else(|| corrupted("missing tree"))?;
+	let tree = parse_hash(tree, "malformed tree line")?;
+	let mut parents = Vec::new();
+	while let Some(parent) = header("parent") {
+		parents.push(parse_hash(parent, "malformed parent line")?);
+	}
+	let author = header("author").ok_or_else(|| corrupted("missing author"))?;
+	let author = Signature::parse(author).ok_or_else(|| corrupted("malformed author line"))?;
+	let committer = header("committer").ok_or_else(|| corrupted("missing committer"))?;
+	let committer =
+		Signature::parse(committer).ok_or_else(|| corrupted("malformed committer line"))?;
+
+	let mut extra_headers: Vec<(String, String)> = Vec::new();
+	for line in lines {
+		match (line.strip_prefix(' '), extra_headers.last_mut()) {
+			(Some(continuation), Some((_, value))) => {
+				value.push('\n');
+				value.push_str(continuation);
+			}
+			(Some(_), None) => return Err(corrupted("continuation line without a header")),
+			(None, _) => {
+				let (name, value) = line
+					.split_once(' ')
+					.ok_or_else(|| corrupted("malformed header line"))?;
+				extra_headers.push((name.to_string(), value.to_string()));
+			}
+		}
+	}
+
+	Ok(Commit {
+		tree,
+		parents,
+		author,
+		committer,
+		extra_headers,
+		message: message.to_string(),
+	})
+}
+
+/// Parses the payload of a tag object: `object`, `type`, `tag` and `tagger` headers in this
+/// order, maybe followed by others, then the message. `hash` is only used for error reporting.
+fn parse_tag(hash: &str, payload: &[u8]) -> Result<Tag, ReadObjectError> {
+	let corrupted = |context| ReadObjectError::CorruptedObject {
+		hash: hash.to_string(),
+		context,
+	};
+
+	let payload = String::from_utf8_lossy(payload);
+	let (headers, message) = match payload.split_once("\n\n") {
+		Some((headers, message)) => (headers, message),
+		None => (payload.strip_suffix('\n').unwrap_or(&payload), ""),
+	};
+
+	let mut lines = headers.split('\n');
+	let mut header = |name: &str, context| {
+		lines
+			.next()
+			.and_then(|line| line.strip_prefix(name)?.strip_prefix(' '))
+			.ok_or_else(|| corrupted(context))
+	};
+
+	let mut object = [0_u8; 20];
+	hex::decode_to_slice(header("object", "missing object line")?, &mut object)
+		.map_err(|_| corrupted("malformed object line"))?;
+	let kind = ObjectKind::from_bytes(header("type", "missing type line")?.as_bytes())
+		.ok_or_else(|| corrupted("malformed type line"))?;
+	let name = header("tag", "missing tag line")?.to_string();
+	let tagger = Signature::parse(header("tagger", "missing tagger line")?)
+		.ok_or_else(|| corrupted("malformed tagger line"))?;
+
+	let mut extra_headers: Vec<(String, String)> = Vec::new();
+	for line in lines {
+		match (line.strip_prefix(' '), extra_headers.last_mut()) {
+			(Some(continuation), Some((_, value))) => {
+				value.push('\n');
+				value.push_str(continuation);
+			}
+			(Some(_), None) => return Err(corrupted("continuation line without a header")),
+			(None, _) => {
+				let (name, value) = line
+					.split_once(' ')
+					.ok_or_else(|| corrupted("malformed header line"))?;
+				extra_headers.push((name.to_string(), value.to_string()));
+			}
+		}
+	}
+
+	Ok(Tag {
+		object,
+		kind,
+		name,
+		tagger,
+		extra_headers,
+		message: message.to_string(),
+	})
+}
+
+#[derive(Debug, Error)]
+enum LsTreeError {
+	#[error("Not a valid object name {0}")]
+	InvalidObjectName(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error("Not a tree object")]
+	NotATree,
+
+	#[error("Trees nested deeper than {MAX_TREE_DEPTH} levels at {0}")]
+	TooDeep(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// How deep `ls-tree -r` descends before giving up on a (likely corrupted) tree.
+const MAX_TREE_DEPTH: usize = 512;
+
+fn ls_tree(
+	repo: &Repo,
+	object: String,
+	name_only: bool,
+	recursive: bool,
+	out: &mut impl Write,
+) -> Result<(), LsTreeError> {
+	let object = normalize_object_name(&object).ok_or(LsTreeError::InvalidObjectName(object))?;
+	let object = decode_object(repo, object)?;
+
+	let GitObject::Tree(tree_entries) = object else {
+		return Err(LsTreeError::NotATree);
+	};
+	if recursive {
+		write_tree_entries_recursive(repo, &tree_entries, "", 0, name_only, out)?;
+	} else {
+		write_tree_entries(&tree_entries, name_only, out)?;
+	}
+
+	Ok(())
+}
+
+/// Like [write_tree_entries], but descends into subtrees instead of listing them, naming
+/// entries by their path joined with `prefix`.
+fn write_tree_entries_recursive(
+	repo: &Repo,
+	entries: &[TreeEntry],
+	prefix: &str,
+	depth: usize,
+	name_only: bool,
+	out: &mut impl Write,
+) -> Result<(), LsTreeError> {
+	if depth >= MAX_TREE_DEPTH {
+		return Err(LsTreeError::TooDeep(prefix.to_string()));
+	}
+	for entry in entries {
+		let path = format!("{prefix}{}", entry.name);
+		if ObjectKind::from_mode(entry.mode) == ObjectKind::Tree {
+			let GitObject::Tree(subtree) = decode_object(repo, hex::encode(*entry.object_hash))?
+			else {
+				return Err(LsTreeError::NotATree);
+			};
+			write_tree_entries_recursive(
+				repo,
+				&subtree,
+				&format!("{path}/"),
+				depth + 1,
+				name_only,
+				out,
+			)?;
+			continue;
+		}
+		let entry = TreeEntry {
+			mode: entry.mode,
+			name: Cow::Owned(path),
+			object_hash: Cow::Borrowed(&entry.object_hash),
+		};
+		write_tree_entries(&[entry], name_only, out)?;
+	}
+	Ok(())
+}
+
+/// Writes `entries` one per line, in `ls-tree`'s default format (see [format_tree_entry]) or
+/// just their names. Shared by `ls-tree` and `cat-file -p` so both print trees the same way.
+fn write_tree_entries(
+	entries: &[TreeEntry],
+	name_only: bool,
+	out: &mut impl Write,
+) -> std::io::Result<()> {
+	for entry in entries {
+		if name_only {
+			writeln!(out, "{}", entry.name)?;
+		} else {
+			writeln!(out, "{}", format_tree_entry(entry))?;
+		}
+	}
+	Ok(())
+}
+
+/// Formats a tree entry like git: `<mode> <type> <hash>\t<name>`, the mode being six octal
+/// digits.
+fn format_tree_entry(entry: &TreeEntry) -> String {
+	format!(
+		"{:06o} {} {}\t{}",
+		entry.mode,
+		ObjectKind::from_mode(entry.mode).as_str(),
+		hex::encode(*entry.object_hash),
+		entry.name
+	)
+}
+
+#[derive(Debug, Error)]
+enum WriteTreeError {
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error(transparent)]
+	HashObject(#[from] HashObjectError),
+
+	#[error("{0}: unmerged (cannot write tree)")]
+	Unmerged(String),
+}
+
+fn write_tree(repo: &Repo, from_worktree: bool) -> Result<(), WriteTreeError> {
+	let hash = if from_worktree {
+		// The CodeCrafters test harness doesn't add files to the index when doing `git add`
+		// (they are using a go implementation of git, not actual git).
+		*write_tree_at_dir(repo, repo.work_tree())?.hash
+	} else if repo.index_path().exists() {
+		// Like git, the trees written are cached in the index for the next time.
+		let mut index = read_index(repo)?;
+		let (hash, cache_tree) =
+			write_cached_tree_from_index(repo, &index.entries, index.cache_tree.as_ref())?;
+		if index.cache_tree.as_ref() != Some(&cache_tree) {
+			index.cache_tree = Some(cache_tree);
+			write_index(repo, &index)?;
+		}
+		hash
+	} else {
+		write_tree_from_index(repo, &[])?
+	};
+	println!("{}", hex::encode(hash));
+
+	Ok(())
+}
+
+/// Sorts entries the way git orders them in trees: by name, subtrees as if their names ended
+/// with a slash.
+fn sort_tree_entries(entries: &mut [TreeEntry]) {
+	entries.sort_by_cached_key(|e| {
+		let mut key = e.name.as_bytes().to_vec();
+		if e.mode == 0o40000 {
+			key.push(b'/');
+		}
+		key
+	});
+}
+
+/// Writes the trees of the staged `entries` bottom-up, returning the id of the root tree.
+fn write_tree_from_index(repo: &Repo, entries: &[IndexEntry]) -> Result<ObjectId, WriteTreeError> {
+	Ok(write_cached_tree_from_index(repo, entries, None)?.0)
+}
+
+/// Like [write_tree_from_index], but reuses the trees of `cache` that are still valid instead of
+/// writing them again. Returns the cached trees of the result along with the root tree's id.
+fn write_cached_tree_from_index(
+	repo: &Repo,
+	entries: &[IndexEntry],
+	cache: Option<&CacheTree>,
+) -> Result<(ObjectId, CacheTree), WriteTreeError> {
+	if let Some(entry) = entries.iter().find(|e| (e.flags >> 12) & 0b11 != 0) {
+		return Err(WriteTreeError::Unmerged(entry.path.clone()));
+	}
+	let entries = entries
+		.iter()
+		.map(|e| (e.path.as_str(), e))
+		.collect::<Vec<_>>();
+	write_index_subtree(repo, &entries, cache)
+}
+
+/// Writes the tree of index `entries`, given in index order along with their paths relative to
+/// that tree, unless `cache` has it for as many entries and it's stored. Returns its id and its
+/// cached trees.
+fn write_index_subtree(
+	repo: &Repo,
+	entries: &[(&str, &IndexEntry)],
+	cache: Option<&CacheTree>,
+) -> Result<(ObjectId, CacheTree), WriteTreeError> {
+	if let Some(cache) = cache {
+		if let Some((count, id)) = cache.tree {
+			if count == entries.len() && has_object(repo, &id) {
+				return Ok((id, cache.clone()));
+			}
+		}
+	}
+
+	let mut subtrees = Vec::new();
+	let mut tree_entries = Vec::new();
+	let mut idx = 0;
+	while idx < entries.len() {
+		let (path, entry) = entries[idx];
+		let Some((dir, _)) = path.split_once('/') else {
+			tree_entries.push(TreeEntry {
+				mode: normalize_mode(entry.mode),
+				name: Cow::Borrowed(path),
+				object_hash: Cow::Borrowed(&entry.sha1),
+			});
+			idx += 1;
+			continue;
+		};
+
+		// The index is sorted by path, so everything under `dir` comes in one run.
+		let children = entries[idx..]
+			.iter()
+			.map_while(|(path, entry)| {
+				let rest = path.strip_prefix(dir)?.strip_prefix('/')?;
+				Some((rest, *entry))
+			})
+			.collect::<Vec<_>>();
+		idx += children.len();
+		let cached = cache.and_then(|cache| cache.subtree(dir));
+		let (id, subtree) = write_index_subtree(repo, &children, cached)?;
+		subtrees.push((dir.to_string(), subtree));
+		tree_entries.push(TreeEntry {
+			mode: 0o40000,
+			name: Cow::Borrowed(dir),
+			object_hash: Cow::Owned(id),
+		});
+	}
+
+	sort_tree_entries(&mut tree_entries);
+	let tree = hash_git_object(repo, GitObject::Tree(Cow::Owned(tree_entries)), true)?;
+	let cache = CacheTree {
+		tree: Some((entries.len(), tree.hash)),
+		subtrees,
+	};
+	Ok((tree.hash, cache))
+}
+
+/// Whether object `id` is stored, loose or packed.
+fn has_object(repo: &Repo, id: &ObjectId) -> bool {
+	let hash = hex::encode(id);
+	repo.object_path(&hash).exists() || inflate_object(repo, hash).is_ok()
+}
+
+struct Tree<'a> {
+	hash: Cow<'a, [u8; 20]>,
+	mode: u32,
+	name: Cow<'a, str>,
+	entries: Vec<TreeEntry<'a>>,
+}
+
+fn write_tree_at_dir(repo: &Repo, path: &Path) -> Result<Tree<'static>, WriteTreeError> {
+	let attributes = read_worktree_attributes(path)?;
+	write_worktree_dir(repo, path, "", &mut ignore::Ignores::default(), &attributes)
+}
+
+/// Whether file `path` is streamed when it's added rather than read whole: it's bigger than
+/// [Repo::big_file_threshold] and stored as is, which doesn't need its content up front.
+fn is_big_file(repo: &Repo, path: &str, metadata: &fs::Metadata, attributes: &Attributes) -> bool {
+	metadata.is_file()
+		&& metadata.len() > repo.big_file_threshold()
+		&& !attributes.may_convert(path)
+}
+
+/// Reads the `.gitattributes` at the root of the working tree `work_tree`, if any.
+fn read_worktree_attributes(work_tree: &Path) -> std::io::Result<Attributes> {
+	match fs::read_to_string(work_tree.join(".gitattributes")) {
+		Ok(content) => Ok(Attributes::parse(&content)),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Attributes::default()),
+		Err(err) => Err(err),
+	}
+}
+
+/// Writes the tree of directory `path`, which is at `rel_path` in the working tree, leaving
+/// out `.git` and whatever the `.gitignore` files in it and the `ignores` of its ancestors
+/// exclude.
+fn write_worktree_dir(
+	repo: &Repo,
+	path: &Path,
+	rel_path: &str,
+	ignores: &mut ignore::Ignores,
+	attributes: &Attributes,
+) -> Result<Tree<'static>, WriteTreeError> {
+	let has_gitignore = ignores.enter(path, rel_path)?;
+
+	let mut entries = Vec::new();
+	// Hashed once the directory was read, in parallel.
+	let mut files = Vec::new();
+
+	let read_dir = fs::read_dir(path)?;
+	for entry in read_dir {
+		let entry = match entry {
+			Ok(v) => v,
+			Err(err) => {
+				eprintln!("WARN cannot read: {err}");
+				continue;
+			}
+		};
+
+		let path = entry.path();
+		let path = path.strip_prefix(".").unwrap_or(&path);
+		let Some(file_name) = path.file_name() else {
+			continue;
+		};
+
+		let file_name = file_name.to_str().unwrap().to_string();
+		if file_name == ".git" {
+			continue;
+		}
+		let entry_rel_path = format!("{rel_path}{file_name}");
+		// Not following symlinks, a symlink to a directory isn't a directory.
+		let metadata = fs::symlink_metadata(path)?;
+		if ignores.is_ignored(&entry_rel_path, metadata.is_dir()) {
+			continue;
+		}
+
+		if metadata.is_symlink() || metadata.is_file() {
+			files.push(WorktreeFile {
+				path: path.to_owned(),
+				name: file_name,
+				rel_path: entry_rel_path,
+				metadata,
+			});
+		} else {
+			let tree = write_worktree_dir(
+				repo,
+				path,
+				&format!("{entry_rel_path}/"),
+				ignores,
+				attributes,
+			)?;
+			// Git doesn't track empty directories.
+			if tree.entries.is_empty() {
+				continue;
+			}
+			entries.push(TreeEntry {
+				mode: tree.mode,
+				name: Cow::Owned(file_name),
+				object_hash: tree.hash,
+			});
+		}
+	}
+	if has_gitignore {
+		ignores.leave();
+	}
+
+	let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+	entries.extend(hash_worktree_files(repo, &files, attributes, threads)?);
+	sort_tree_entries(&mut entries);
+	let hashed_object = hash_git_object(repo, GitObject::Tree(Cow::Borrowed(&entries)), true)?;
+
+	Ok(Tree {
+		hash: Cow::Owned(hashed_object.hash),
+		mode: 0o40000,
+		name: Cow::Owned(path.display().to_string()),
+		entries,
+	})
+}
+
+/// File or symlink of the working tree to be stored as a blob.
+struct WorktreeFile {
+	path: PathBuf,
+	name: String,
+	/// Path relative to the working tree.
+	rel_path: String,
+	metadata: fs::Metadata,
+}
+
+/// Stores the blobs of `files`, returning their tree entries in no particular order. Reading,
+/// hashing and compressing them is split across up to `threads` threads. Files with the same
+/// content are compressed once, even when threads get to them at the same time.
+fn hash_worktree_files(
+	repo: &Repo,
+	files: &[WorktreeFile],
+	attributes: &Attributes,
+	threads: usize,
+) -> Result<Vec<TreeEntry<'static>>, WriteTreeError> {
+	let claimed = &Mutex::new(HashSet::new());
+	let threads = threads.min(files.len());
+	if threads <= 1 {
+		return files
+			.iter()
+			.map(|file| hash_worktree_file(repo, file, attributes, claimed))
+			.collect();
+	}
+
+	let chunks = std::thread::scope(|scope| {
+		let workers: Vec<_> = files
+			.chunks(files.len().div_ceil(threads))
+			.map(|chunk| {
+				scope.spawn(move || {
+					chunk
+						.iter()
+						.map(|file| hash_worktree_file(repo, file, attributes, claimed))
+						.collect::<Result<Vec<_>, _>>()
+				})
+			})
+			.collect();
+		workers
+			.into_iter()
+			.map(|worker| worker.join().expect("hashing files doesn't panic"))
+			.collect::<Result<Vec<_>, _>>()
+	})?;
+	Ok(chunks.into_iter().flatten().collect())
+}
+
+/// Stores the blob of `file`: the target path of a symlink or the normalized content of a file.
+/// The blob is only written by the caller that first adds its id to `claimed`.
+fn hash_worktree_file(
+	repo: &Repo,
+	file: &WorktreeFile,
+	attributes: &Attributes,
+	claimed: &Mutex<HashSet<ObjectId>>,
+) -> Result<TreeEntry<'static>, WriteTreeError> {
+	if is_big_file(repo, &file.rel_path, &file.metadata, attributes) {
+		let hashed = hash_object(repo, &file.path, true, None)?;
+		return Ok(TreeEntry {
+			mode: file_mode(&file.metadata, &file.path),
+			name: Cow::Owned(file.name.clone()),
+			object_hash: Cow::Owned(hashed.hash),
+		});
+	}
+	let (mode, content) = if file.metadata.is_symlink() {
+		(0o120000, read_link_target(&file.path)?)
+	} else {
+		let content = attributes.normalize(&file.rel_path, fs::read(&file.path)?);
+		(file_mode(&file.metadata, &file.path), content)
+	};
+	let mut encoded = Vec::new();
+	encode_object(GitObject::Blob(Cow::Owned(content)), &mut encoded)
+		.map_err(HashObjectError::EncodeObject)?;
+	let id = sha1::sha1(&encoded);
+	if claimed.lock().unwrap().insert(id) {
+		store_encoded_object(repo, &encoded, &id)?;
+	}
+	Ok(TreeEntry {
+		mode,
+		name: Cow::Owned(file.name.clone()),
+		object_hash: Cow::Owned(id),
+	})
+}
+
+fn show_ref(repo: &Repo, head: bool) -> Result<(), std::io::Error> {
+	if head {
+		if let Some(hash) = refs::resolve_ref(repo, "HEAD")? {
+			println!("{hash} HEAD");
+		}
+	}
+	for (name, hash) in refs::all_refs(repo)? {
+		println!("{hash} {name}");
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum DiffTreeError {
+	#[error("Not a valid tree name {0}")]
+	InvalidTreeName(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error("{0} is not a tree")]
+	NotATree(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Prints `<status>\t<path>` for every path that differs between trees `old` and `new` (or
+/// the trees of commits), like `git diff-tree --name-status`, sorted by path. Status is `A`
+/// for added, `D` for deleted and `M` for modified paths, a path that changed between a tree
+/// and something else being deleted and added. Subtrees that differ are shown as modified
+/// unless `recursive`, in which case the paths in them are compared.
+fn diff_tree(
+	repo: &Repo,
+	old: &str,
+	new: &str,
+	recursive: bool,
+	out: &mut impl Write,
+) -> Result<(), DiffTreeError> {
+	let parse = |name: &str| {
+		let tree = revision::resolve_revision(repo, name)
+			.and_then(|hash| revision::peel(repo, hash, "tree"));
+		match tree {
+			Ok(Some(hash)) => {
+				let mut id = [0_u8; 20];
+				hex::decode_to_slice(hash, &mut id).map_err(ReadObjectError::from)?;
+				Ok(id)
+			}
+			Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => {
+				Err(DiffTreeError::InvalidTreeName(name.to_string()))
+			}
+			Err(err) => Err(err.into()),
+		}
+	};
+	let (old, new) = (parse(old)?, parse(new)?);
+
+	let mut changes = Vec::new();
+	diff_subtrees(repo, "", Some(old), Some(new), recursive, &mut changes)?;
+	// Sorted like tree entries, so a file comes before the directory replacing it.
+	changes.sort_by_cached_key(|(_, path, is_tree)| {
+		let mut key = path.as_bytes().to_vec();
+		if *is_tree {
+			key.push(b'/');
+		}
+		key
+	});
+	for (status, path, _) in changes {
+		writeln!(out, "{status}\t{path}")?;
+	}
+	Ok(())
+}
+
+/// Collects the status and path of every entry that differs between trees `old` and `new`
+/// (either missing for a tree that doesn't exist on that side) at `prefix`, and whether the
+/// entry is a tree.
+fn diff_subtrees(
+	repo: &Repo,
+	prefix: &str,
+	old: Option<ObjectId>,
+	new: Option<ObjectId>,
+	recursive: bool,
+	changes: &mut Vec<(char, String, bool)>,
+) -> Result<(), DiffTreeError> {
+	let mut paths: BTreeMap<String, [Option<(u32, ObjectId)>; 2]> = BTreeMap::new();
+	for (side, tree) in [old, new].into_iter().enumerate() {
+		let Some(tree) = tree else {
+			continue;
+		};
+		let hash_str = hex::encode(tree);
+		let GitObject::Tree(entries) = decode_object(repo, hash_str.clone())? else {
+			return Err(DiffTreeError::NotATree(hash_str));
+		};
+		for entry in entries.iter() {
+			paths.entry(entry.name.to_string()).or_default()[side] =
+				Some((entry.mode, *entry.object_hash));
+		}
+	}
+
+	for (name, [old, new]) in paths {
+		let path = if prefix.is_empty() {
+			name
+		} else {
+			format!("{prefix}/{name}")
+		};
+		if old == new {
+			continue;
+		}
+		let tree = |entry: Option<(u32, ObjectId)>| {
+			entry.filter(|(mode, _)| *mode == 0o40000).map(|(_, id)| id)
+		};
+		let leaf = |entry: Option<(u32, ObjectId)>| entry.filter(|(mode, _)| *mode != 0o40000);
+
+		match (tree(old), tree(new)) {
+			(None, None) => {}
+			(old, new) if recursive => diff_subtrees(repo, &path, old, new, recursive, changes)?,
+			(Some(_), Some(_)) => changes.push(('M', path.clone(), true)),
+			(Some(_), None) => changes.push(('D', path.clone(), true)),
+			(None, Some(_)) => changes.push(('A', path.clone(), true)),
+		}
+		match (leaf(old), leaf(new)) {
+			(Some(_), Some(_)) => changes.push(('M', path, false)),
+			(Some(_), None) => changes.push(('D', path, false)),
+			(None, Some(_)) => changes.push(('A', path, false)),
+			(None, None) => {}
+		}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum MergeTreeError {
+	#[error("Not a valid object name {0}")]
+	InvalidObjectName(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Merge(#[from] merge::MergeError),
+}
+
+fn merge_tree(
+	repo: &Repo,
+	base: String,
+	ours: String,
+	theirs: String,
+) -> Result<(), MergeTreeError> {
+	let parse = |object: String| match parse_object_name(repo, &object) {
+		Ok(hash) => Ok(hash),
+		Err(ReadObjectError::ObjectNotFound(_)) => Err(MergeTreeError::InvalidObjectName(object)),
+		Err(err) => Err(err.into()),
+	};
+	let (base, ours, theirs) = (parse(base)?, parse(ours)?, parse(theirs)?);
+
+	let entries = merge::merge_trees(repo, Some(&base), &ours, &theirs)?;
+	for entry in entries {
+		print!("{}", format_merge_entry(&entry));
+	}
+
+	Ok(())
+}
+
+fn format_merge_entry(entry: &merge::MergeEntry) -> String {
+	let format_stage = |stage: &merge::Stage| {
+		let kind = if stage.mode == 0o160000 {
+			"commit"
+		} else {
+			"blob"
+		};
+		format!("{:06o} {kind} {}", stage.mode, hex::encode(stage.hash))
+	};
+
+	match entry {
+		merge::MergeEntry::Clean { path, stage } => format!("{}\t{path}\n", format_stage(stage)),
+		merge::MergeEntry::Conflict {
+			path,
+			kind,
+			base,
+			ours,
+			theirs,
+			content,
+		} => {
+			let mut out = format!("CONFLICT ({kind}): {path}\n");
+			for (label, stage) in [("base ", base), ("our  ", ours), ("their", theirs)] {
+				if let Some(stage) = stage {
+					out += &format!("  {label} {}\t{path}\n", format_stage(stage));
+				}
+			}
+			if let Some(content) = content {
+				out += &String::from_utf8_lossy(content);
+			}
+			out
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+enum VerifyError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error("{0} object(s) failed verification")]
+	Failed(usize),
+}
+
+/// Verifies every object named in `input`, one per line, printing `<name> ok` or
+/// `<name> fail: <reason>` for each. Fails if any of them didn't verify.
+fn verify(repo: &Repo, input: impl BufRead, out: &mut impl Write) -> Result<(), VerifyError> {
+	let mut failed = 0;
+	for line in input.lines() {
+		let line = line?;
+		let name = line.trim();
+		if name.is_empty() {
+			continue;
+		}
+
+		let result = match normalize_object_name(name) {
+			Some(object) => expand_object_name(repo, &object)
+				.and_then(|object| verify_object(repo, &object))
+				.map_err(|err| err.to_string()),
+			None => Err("not a valid object name".to_string()),
+		};
+		match result {
+			Ok(()) => writeln!(out, "{name} ok")?,
+			Err(err) => {
+				failed += 1;
+				writeln!(out, "{name} fail: {err}")?;
+			}
+		}
+	}
+
+	if failed > 0 {
+		return Err(VerifyError::Failed(failed));
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum PruneError {
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Deletes the loose objects that aren't reachable (see [reachable_objects]), printing
+/// `<hash> <type>` for each with `verbose` or `dry_run`, the latter deleting nothing.
+fn prune(
+	repo: &Repo,
+	dry_run: bool,
+	verbose: bool,
+	out: &mut impl Write,
+) -> Result<(), PruneError> {
+	let reachable = reachable_objects(repo)?;
+	let mut unreachable = Vec::new();
+	for object in repo.objects()? {
+		let (id, kind) = object?;
+		if !reachable.contains(&id) {
+			unreachable.push((hex::encode(id), kind));
+		}
+	}
+	unreachable.sort();
+
+	for (hash, kind) in unreachable {
+		if dry_run || verbose {
+			writeln!(out, "{hash} {}", kind.as_str())?;
+		}
+		if dry_run {
+			continue;
+		}
+		let path = repo.object_path(&hash);
+		fs::remove_file(&path)?;
+		// Like git, fanout directories left empty are removed too.
+		let _ = fs::remove_dir(path.parent().unwrap());
+	}
+	Ok(())
+}
+
+/// Every object reachable from `HEAD`, the refs of all namespaces (heads, tags, remotes, notes,
+/// stash, replace, ...) and the entries of the index. Submodule commits and the parents of
+/// shallow commits aren't followed, they aren't in this repository.
+fn reachable_objects(repo: &Repo) -> Result<HashSet<ObjectId>, PruneError> {
+	let mut reachable = HashSet::new();
+	let mut pending = Vec::new();
+	let head = refs::resolve_ref(repo, "HEAD")?;
+	for hash in head
+		.into_iter()
+		.chain(refs::all_refs(repo)?.into_iter().map(|(_, hash)| hash))
+	{
+		pending.push(parse_object_name(repo, &hash)?);
+	}
+	if repo.index_path().exists() {
+		let entries = read_index(repo)?.entries;
+		reachable.extend(
+			entries
+				.iter()
+				.filter(|e| e.mode != 0o160000)
+				.map(|e| e.sha1),
+		);
+	}
+
+	while let Some(id) = pending.pop() {
+		if !reachable.insert(id) {
+			continue;
+		}
+		let hash = hex::encode(id);
+		match decode_object(repo, hash)? {
+			GitObject::Commit(commit) => {
+				pending.push(commit.tree);
+				if !repo.is_shallow(&id) {
+					pending.extend(commit.parents);
+				}
+			}
+			GitObject::Tree(entries) => {
+				for entry in entries.iter() {
+					match entry.mode {
+						0o40000 => pending.push(*entry.object_hash),
+						0o160000 => {}
+						// Blobs have nothing to follow, so aren't read.
+						_ => {
+							reachable.insert(*entry.object_hash);
+						}
+					}
+				}
+			}
+			GitObject::Tag(tag) => pending.push(tag.object),
+			GitObject::Blob(_) => {}
+		}
+	}
+	Ok(reachable)
+}
+
+#[derive(Debug, Error)]
+enum ArchiveCmdError {
+	#[error("Not a valid object name {0}")]
+	InvalidObjectName(String),
+
+	#[error("Could not create {path}: {err}")]
+	OutputIo {
+		#[source]
+		err: std::io::Error,
+
+		path: PathBuf,
+	},
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Archive(#[from] archive::ArchiveError),
+}
+
+fn archive(
+	repo: &Repo,
+	tree: String,
+	output: &Path,
+	format: archive::ArchiveFormat,
+) -> Result<(), ArchiveCmdError> {
+	let hash = match parse_object_name(repo, &tree) {
+		Ok(hash) => hash,
+		Err(ReadObjectError::ObjectNotFound(_)) => {
+			return Err(ArchiveCmdError::InvalidObjectName(tree))
+		}
+		Err(err) => return Err(err.into()),
+	};
+
+	let file = fs::File::create(output).map_err(|err| ArchiveCmdError::OutputIo {
+		err,
+		path: output.to_owned(),
+	})?;
+	archive::write_archive(repo, &hash, format, std::io::BufWriter::new(file))?;
+
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum ReadIndexError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error("Failed to read index SHA1 hash")]
+	NoIndexHash,
+
+	#[error("Failed to read index header")]
+	NoIndexHeader,
+
+	#[error("Invalid index signature {0}")]
+	InvalidSignature(String),
+
+	#[error("Missing index entries. Expected {expected}, got {got}.")]
+	MissingEntries { expected: usize, got: usize },
+
+	#[error("Path missing from an index entry")]
+	NoIndexEntryPath,
+
+	#[error("Path is not a valid string: {0}")]
+	CorruptedPath(std::str::Utf8Error),
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+struct Index {
+	sha1: [u8; 20],
+	version: u32,
+	entries: Vec<IndexEntry>,
+	/// The `TREE` extension, if the index has a valid one.
+	cache_tree: Option<CacheTree>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+struct IndexEntry {
+	ctime_s: u32,
+	ctime_n: u32,
+	mtime_s: u32,
+	mtime_n: u32,
+	dev: u32,
+	ino: u32,
+	mode: u32,
+	uid: u32,
+	gid: u32,
+	size: u32,
+	sha1: [u8; 20],
+	flags: u16,
+	/// Version 3 flags like [SKIP_WORKTREE], following `flags` when they have [INDEX_EXTENDED].
+	extended_flags: u16,
+	path: String,
+}
+
+/// Flag of index entries with extended flags, only valid in version 3 and later.
+const INDEX_EXTENDED: u16 = 0x4000;
+
+/// Extended flag of index entries whose file is left out of a sparse checkout: it's absent from
+/// the working tree on purpose and the entry is kept as is.
+const SKIP_WORKTREE: u16 = 0x4000;
+
+impl IndexEntry {
+	fn skip_worktree(&self) -> bool {
+		self.extended_flags & SKIP_WORKTREE != 0
+	}
+
+	/// Whether the file's stat information is the one recorded when the entry was last updated.
+	fn stat_matches(&self, metadata: &fs::Metadata) -> bool {
+		(self.ctime_s, self.ctime_n, self.mtime_s, self.mtime_n) == stat_times(metadata)
+			&& (self.dev, self.ino) == stat_ids(metadata)
+			&& normalize_mode(self.mode) == file_mode(metadata, Path::new(&self.path))
+			&& self.size == metadata.len() as u32
+	}
+
+	/// Records the file's stat information, see [IndexEntry::stat_matches].
+	fn update_stat(&mut self, metadata: &fs::Metadata) {
+		(self.ctime_s, self.ctime_n, self.mtime_s, self.mtime_n) = stat_times(metadata);
+		(self.dev, self.ino) = stat_ids(metadata);
+		(self.uid, self.gid) = stat_owner(metadata);
+		self.size = metadata.len() as u32;
+	}
+}
+
+/// ctime and mtime of a file, as seconds and nanoseconds, truncated to 32 bits like in the index.
+#[cfg(unix)]
+fn stat_times(metadata: &fs::Metadata) -> (u32, u32, u32, u32) {
+	(
+		metadata.ctime() as u32,
+		metadata.ctime_nsec() as u32,
+		metadata.mtime() as u32,
+		metadata.mtime_nsec() as u32,
+	)
+}
+
+/// Creation and modification times of a file, as seconds and nanoseconds, truncated to 32 bits
+/// like in the index. Windows has no ctime, git for Windows uses the creation time instead.
+#[cfg(windows)]
+fn stat_times(metadata: &fs::Metadata) -> (u32, u32, u32, u32) {
+	let split = |time: std::io::Result<std::time::SystemTime>| {
+		let since_epoch = time
+			.ok()
+			.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+			.unwrap_or_default();
+		(since_epoch.as_secs() as u32, since_epoch.subsec_nanos())
+	};
+	let (ctime_s, ctime_n) = split(metadata.created());
+	let (mtime_s, mtime_n) = split(metadata.modified());
+	(ctime_s, ctime_n, mtime_s, mtime_n)
+}
+
+/// Device and inode numbers of a file, truncated to 32 bits like in the index.
+#[cfg(unix)]
+fn stat_ids(metadata: &fs::Metadata) -> (u32, u32) {
+	(metadata.dev() as u32, metadata.ino() as u32)
+}
+
+/// Windows has no device and inode numbers, like git for Windows they are recorded as 0.
+#[cfg(windows)]
+fn stat_ids(_metadata: &fs::Metadata) -> (u32, u32) {
+	(0, 0)
+}
+
+/// Owner user and group ids of a file.
+#[cfg(unix)]
+fn stat_owner(metadata: &fs::Metadata) -> (u32, u32) {
+	(metadata.uid(), metadata.gid())
+}
+
+/// Windows has no user and group ids, like git for Windows they are recorded as 0.
+#[cfg(windows)]
+fn stat_owner(_metadata: &fs::Metadata) -> (u32, u32) {
+	(0, 0)
+}
+
+fn read_index(repo: &Repo) -> Result<Index, ReadIndexError> {
+	parse_index(&fs::read(repo.index_path())?)
+}
+
+/// Big endian `u32` at `offset` of `bytes`, which must be long enough.
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+	u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn parse_index(index: &[u8]) -> Result<Index, ReadIndexError> {
+	// An index without entries is just the header followed by the checksum.
+	let checksum_start = index
+		.len()
+		.checked_sub(20)
+		.ok_or(ReadIndexError::NoIndexHash)?;
+	let sha1: [u8; 20] = index[checksum_start..].try_into().unwrap();
+
+	let header = index
+		.get(..12)
+		.filter(|_| checksum_start >= 12)
+		.ok_or(ReadIndexError::NoIndexHeader)?;
+	let signature = &header[0..4];
+	let version = be_u32(header, 4);
+	let num_entries = be_u32(header, 8);
+
+	if signature != b"DIRC" {
+		return Err(ReadIndexError::InvalidSignature(
+			String::from_utf8_lossy(signature).to_string(),
+		));
+	}
+
+	// Entries are followed by optional extensions, so only `num_entries` of them are read.
+	let mut entries_bytes = &index[12..checksum_start];
+
+	let mut entries = Vec::new();
+
+	while entries.len() < num_entries as usize && entries_bytes.len() > 62 {
+		let fields = &entries_bytes[..62];
+		let flags = u16::from_be_bytes([fields[60], fields[61]]);
+		// Extended flags come before the path.
+		let (extended_flags, path_start) = match entries_bytes.get(62..64) {
+			Some(extended) if version >= 3 && flags & INDEX_EXTENDED != 0 => {
+				(u16::from_be_bytes([extended[0], extended[1]]), 64)
+			}
+			_ => (0, 62),
+		};
+
+		let null_byte_idx = entries_bytes[path_start..]
+			.iter()
+			.position(|x| *x == 0)
+			.ok_or(ReadIndexError::NoIndexEntryPath)?;
+
+		let path = &entries_bytes[path_start..(path_start + null_byte_idx)];
+		let path = std::str::from_utf8(path)
+			.map_err(ReadIndexError::CorruptedPath)?
+			.to_string();
+		let path_len = path.len();
+
+		entries.push(IndexEntry {
+			ctime_s: be_u32(fields, 0),
+			ctime_n: be_u32(fields, 4),
+			mtime_s: be_u32(fields, 8),
+			mtime_n: be_u32(fields, 12),
+			dev: be_u32(fields, 16),
+			ino: be_u32(fields, 20),
+			mode: be_u32(fields, 24),
+			uid: be_u32(fields, 28),
+			gid: be_u32(fields, 32),
+			size: be_u32(fields, 36),
+			sha1: fields[40..60].try_into().unwrap(),
+			flags,
+			extended_flags,
+			path,
+		});
+
+		// Entries are padded with 1-8 null bytes to a multiple of 8 bytes.
+		entries_bytes = entries_bytes
+			.get((((path_start + path_len + 8) / 8) * 8)..)
+			.unwrap_or_default();
+	}
+
+	if entries.len() != num_entries as usize {
+		return Err(ReadIndexError::MissingEntries {
+			expected: num_entries as usize,
+			got: entries.len(),
+		});
+	}
+
+	// Extensions are a signature, a big endian size and that much data. Only `TREE` is used,
+	// the others are dropped when the index is written again.
+	let mut cache_tree = None;
+	while entries_bytes.len() >= 8 {
+		let size = be_u32(entries_bytes, 4) as usize;
+		let Some(data) = entries_bytes.get(8..8 + size) else {
+			break;
+		};
+		if &entries_bytes[..4] == b"TREE" {
+			cache_tree = CacheTree::parse(data);
+		}
+		entries_bytes = &entries_bytes[8 + size..];
+	}
+
+	Ok(Index {
+		sha1,
+		version,
+		entries,
+		cache_tree,
+	})
+}
+
+/// Writes `index` to the index file, replacing it atomically through `<index>.lock`. Entries are
+/// written sorted by path, as version 2 or, if some have extended flags (like [SKIP_WORKTREE]),
+/// version 3, followed by the `TREE` extension if there's a cached tree.
+fn write_index(repo: &Repo, index: &Index) -> std::io::Result<()> {
+	let mut entries: Vec<&IndexEntry> = index.entries.iter().collect();
+	entries.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+	let extended = entries.iter().any(|entry| entry.extended_flags != 0);
+
+	let mut bytes = b"DIRC".to_vec();
+	bytes.extend(if extended { 3_u32 } else { 2 }.to_be_bytes());
+	bytes.extend((entries.len() as u32).to_be_bytes());
+	for entry in entries {
+		let start = bytes.len();
+		for field in [
+			entry.ctime_s,
+			entry.ctime_n,
+			entry.mtime_s,
+			entry.mtime_n,
+			entry.dev,
+			entry.ino,
+			entry.mode,
+			entry.uid,
+			entry.gid,
+			entry.size,
+		] {
+			bytes.extend(field.to_be_bytes());
+		}
+		bytes.extend(entry.sha1);
+		// Keeps the assume-valid flag and the stage, the extended flag is set from the extended
+		// flags. Longer names store 0xfff as their length.
+		let mut flags = (entry.flags & 0xb000) | (entry.path.len().min(0xfff) as u16);
+		if entry.extended_flags != 0 {
+			flags |= INDEX_EXTENDED;
+		}
+		bytes.extend(flags.to_be_bytes());
+		if entry.extended_flags != 0 {
+			bytes.extend(entry.extended_flags.to_be_bytes());
+		}
+		bytes.extend(entry.path.as_bytes());
+		// Entries are padded with 1-8 null bytes to a multiple of 8 bytes.
+		let len = bytes.len() - start;
+		bytes.resize(start + (len + 8) / 8 * 8, 0);
+	}
+	if let Some(cache_tree) = &index.cache_tree {
+		let mut data = Vec::new();
+		cache_tree.encode(&mut data);
+		bytes.extend(b"TREE");
+		bytes.extend((data.len() as u32).to_be_bytes());
+		bytes.extend(data);
+	}
+	bytes.extend(sha1::sha1(&bytes));
+
+	let mut lock_path = repo.index_path().as_os_str().to_owned();
+	lock_path.push(".lock");
+	fs::write(&lock_path, &bytes)?;
+	fs::rename(&lock_path, repo.index_path())
+}
+
+#[derive(Debug, Error)]
+enum ReadTreeError {
+	#[error("Failed to unpack tree object {0}")]
+	InvalidTree(String),
+
+	#[error(transparent)]
+	Checkout(#[from] checkout::CheckoutError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Replaces the index with the files of tree `name` (or of the tree of a commit), without
+/// stat information and leaving the working tree alone.
+fn read_tree(repo: &Repo, name: &str) -> Result<(), ReadTreeError> {
+	let invalid = || ReadTreeError::InvalidTree(name.to_string());
+	let tree =
+		revision::resolve_revision(repo, name).and_then(|hash| revision::peel(repo, hash, "tree"));
+	let tree = match tree {
+		Ok(Some(tree)) => tree,
+		Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => return Err(invalid()),
+		Err(err) => return Err(err.into()),
+	};
+	let mut id = [0_u8; 20];
+	hex::decode_to_slice(tree, &mut id).map_err(|_| invalid())?;
+
+	let entries = checkout::tree_files(repo, &id)?
+		.into_iter()
+		.map(|(path, (mode, id))| checkout::new_entry(&path, mode, id))
+		.collect();
+	write_index(
+		repo,
+		&Index {
+			sha1: [0; 20],
+			version: 2,
+			entries,
+			cache_tree: None,
+		},
+	)?;
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum UpdateIndexError {
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error("Invalid path '{0}'")]
+	InvalidPath(PathBuf),
+
+	#[error("{0}: does not exist")]
+	Missing(String),
+
+	#[error("{0}: cannot add to the index - missing --add option?")]
+	NotInIndex(String),
+
+	#[error("Unable to mark file {0}")]
+	CannotMark(String),
+
+	#[error("'{0}' appears as both a file and as a directory")]
+	FileDirectoryConflict(String),
+
+	#[error(transparent)]
+	HashObject(#[from] HashObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Stages the files at `paths` (relative to the top of the working tree) as they are now: hashes
+/// and stores their content and records their stat information, replacing their entries (all
+/// stages of them). Content is normalized by the `.gitattributes` of the working tree, like in
+/// write-tree. Files not in the index yet are only added if `add` is set, and not if the index
+/// already has a file at one of their leading directories or entries below them. Entries marked
+/// [SKIP_WORKTREE] are kept as they are, whether their file is there or not.
+fn update_index(repo: &Repo, paths: &[PathBuf], add: bool) -> Result<(), UpdateIndexError> {
+	let mut index = match read_index(repo) {
+		Ok(v) => v,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Index {
+			sha1: [0; 20],
+			version: 2,
+			entries: Vec::new(),
+			cache_tree: None,
+		},
+		Err(err) => return Err(err.into()),
+	};
+	let attributes = read_worktree_attributes(repo.work_tree())?;
+
+	for path in paths {
+		let index_path =
+			index_path_of(path).ok_or_else(|| UpdateIndexError::InvalidPath(path.clone()))?;
+		if index
+			.entries
+			.iter()
+			.any(|entry| entry.path == index_path && entry.skip_worktree())
+		{
+			continue;
+		}
+		let file = repo.work_tree().join(path);
+		let metadata = match fs::symlink_metadata(&file) {
+			Ok(v) if !v.is_dir() => v,
+			Ok(_) => return Err(UpdateIndexError::InvalidPath(path.clone())),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+				return Err(UpdateIndexError::Missing(index_path))
+			}
+			Err(err) => return Err(err.into()),
+		};
+		if !add && !index.entries.iter().any(|entry| entry.path == index_path) {
+			return Err(UpdateIndexError::NotInIndex(index_path));
+		}
+		if index.entries.iter().any(|entry| {
+			let (outer, inner) = if entry.path.len() < index_path.len() {
+				(&entry.path, &index_path)
+			} else {
+				(&index_path, &entry.path)
+			};
+			inner
+				.strip_prefix(outer.as_str())
+				.is_some_and(|rest| rest.starts_with('/'))
+		}) {
+			return Err(UpdateIndexError::FileDirectoryConflict(index_path));
+		}
+
+		let hashed = if is_big_file(repo, &index_path, &metadata, &attributes) {
+			hash_object(repo, &file, true, None)?
+		} else {
+			let content = if metadata.is_symlink() {
+				read_link_target(&file)?
+			} else {
+				attributes.normalize(&index_path, fs::read(&file)?)
+			};
+			hash_git_object(repo, GitObject::Blob(Cow::Owned(content)), true)?
+		};
+		let mut entry = IndexEntry {
+			ctime_s: 0,
+			ctime_n: 0,
+			mtime_s: 0,
+			mtime_n: 0,
+			dev: 0,
+			ino: 0,
+			mode: file_mode(&metadata, &file),
+			uid: 0,
+			gid: 0,
+			size: 0,
+			sha1: hashed.hash,
+			flags: 0,
+			extended_flags: 0,
+			path: index_path,
+		};
+		entry.update_stat(&metadata);
+
+		if let Some(cache_tree) = &mut index.cache_tree {
+			cache_tree.invalidate(&entry.path);
+		}
+		// Kept sorted by path, then stage, a staged file replaces any conflict.
+		index.entries.retain(|e| e.path != entry.path);
+		let at = index
+			.entries
+			.partition_point(|e| e.path.as_bytes() < entry.path.as_bytes());
+		index.entries.insert(at, entry);
+	}
+
+	write_index(repo, &index)?;
+	Ok(())
+}
+
+/// Sets the [SKIP_WORKTREE] flag of the index entries at `paths` or, unless `skip`, clears it,
+/// like `git update-index --[no-]skip-worktree`. Every path must be in the index.
+fn mark_skip_worktree(repo: &Repo, paths: &[PathBuf], skip: bool) -> Result<(), UpdateIndexError> {
+	let mut entries = match read_index(repo) {
+		Ok(index) => index.entries,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+		Err(err) => return Err(err.into()),
+	};
+	for path in paths {
+		let index_path =
+			index_path_of(path).ok_or_else(|| UpdateIndexError::InvalidPath(path.clone()))?;
+		let Some(entry) = entries.iter_mut().find(|entry| entry.path == index_path) else {
+			return Err(UpdateIndexError::CannotMark(index_path));
+		};
+		if skip {
+			entry.extended_flags |= SKIP_WORKTREE;
+		} else {
+			entry.extended_flags &= !SKIP_WORKTREE;
+		}
+	}
+	write_index(
+		repo,
+		&Index {
+			sha1: [0; 20],
+			version: 2,
+			entries,
+			cache_tree: None,
+		},
+	)?;
+	Ok(())
+}
+
+/// Updates the stat information of index entries whose file still has the content and mode
+/// recorded in the index, like `git update-index --refresh`. Entries modified no earlier than
+/// the index was written are rehashed even if their stat information matches, as they could
+/// have changed since without it showing ("racily clean"). Prints `<path>: needs update` for
+/// every file that differs and returns whether there were any. [SKIP_WORKTREE] entries are left
+/// alone.
+fn refresh_index(repo: &Repo, out: &mut impl Write) -> Result<bool, UpdateIndexError> {
+	let mut index = match read_index(repo) {
+		Ok(v) => v,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+			return Ok(false)
+		}
+		Err(err) => return Err(err.into()),
+	};
+	let (_, _, mtime_s, mtime_n) = stat_times(&fs::metadata(repo.index_path())?);
+	let index_mtime = (mtime_s, mtime_n);
+	let attributes = read_worktree_attributes(repo.work_tree())?;
+
+	let mut needs_update = false;
+	let mut changed = false;
+	let mut unmerged: Option<String> = None;
+	for entry in &mut index.entries {
+		if (entry.flags >> 12) & 0b11 != 0 {
+			// Reported once for all of the path's stages.
+			if unmerged.as_ref() != Some(&entry.path) {
+				writeln!(out, "{}: needs merge", entry.path)?;
+				unmerged = Some(entry.path.clone());
+			}
+			needs_update = true;
+			continue;
+		}
+		if entry.skip_worktree() {
+			continue;
+		}
+		let path = repo.work_tree().join(&entry.path);
+		let metadata = match fs::symlink_metadata(&path) {
+			Ok(v) => v,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+				writeln!(out, "{}: needs update", entry.path)?;
+				needs_update = true;
+				continue;
+			}
+			Err(err) => return Err(err.into()),
+		};
+		let racy = (entry.mtime_s, entry.mtime_n) >= index_mtime;
+		if entry.stat_matches(&metadata) && !racy {
+			continue;
+		}
+		if !worktree_file_matches(&path, &metadata, entry, &attributes)? {
+			writeln!(out, "{}: needs update", entry.path)?;
+			needs_update = true;
+			continue;
+		}
+		entry.update_stat(&metadata);
+		changed = true;
+	}
+
+	if changed {
+		write_index(repo, &index)?;
+	}
+	Ok(needs_update)
+}
+
+/// Whether the file at `path`, with given `metadata`, has the mode and content recorded in index
+/// `entry`. Its content is read and hashed, stat information isn't trusted.
+fn worktree_file_matches(
+	path: &Path,
+	metadata: &fs::Metadata,
+	entry: &IndexEntry,
+	attributes: &Attributes,
+) -> std::io::Result<bool> {
+	if normalize_mode(entry.mode) != file_mode(metadata, path) {
+		return Ok(false);
+	}
+	let content = if metadata.is_symlink() {
+		read_link_target(path)?
+	} else {
+		attributes.normalize(&entry.path, fs::read(path)?)
+	};
+	Ok(compute_id(ObjectKind::Blob, &content) == entry.sha1)
+}
+
+/// Prints the path of every index entry, sorted, or `<mode> <object> <stage>\t<path>` with
+/// `stage`, each followed by a newline or, if `nul_terminated`, NUL. Without `stage`, the path
+/// of a conflict is printed once for all of its stages. A missing index has no entries.
+fn ls_files(
+	repo: &Repo,
+	stage: bool,
+	nul_terminated: bool,
+	out: &mut impl Write,
+) -> Result<(), ReadIndexError> {
+	let terminator = if nul_terminated { '\0' } else { '\n' };
+	let mut index = match read_index(repo) {
+		Ok(v) => v,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err),
+	};
+	// Already sorted in a valid index, stages of a path included.
+	index
+		.entries
+		.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+
+	let mut last_path = None;
+	for entry in &index.entries {
+		if stage {
+			write!(
+				out,
+				"{:06o} {} {}\t{}{terminator}",
+				entry.mode,
+				hex::encode(entry.sha1),
+				(entry.flags >> 12) & 0b11,
+				entry.path
+			)?;
+		} else if last_path != Some(&entry.path) {
+			write!(out, "{}{terminator}", entry.path)?;
+		}
+		last_path = Some(&entry.path);
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum MvError {
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error("Bad path {0}")]
+	InvalidPath(PathBuf),
+
+	#[error("Not under version control: {0}")]
+	NotTracked(PathBuf),
+
+	#[error("Destination exists: {0}")]
+	DestinationExists(PathBuf),
+}
+
+/// Renames the tracked file or directory `from` to `to`, both relative to the work tree, in the
+/// work tree and in the index. Index entries keep their blob hash and mode. If `to` is an
+/// existing directory, `from` is moved into it.
+fn mv(repo: &Repo, from: &Path, to: &Path) -> Result<(), MvError> {
+	let work_tree = repo.work_tree();
+	let to = if work_tree.join(to).is_dir() {
+		let file_name = from
+			.file_name()
+			.ok_or_else(|| MvError::InvalidPath(from.to_owned()))?;
+		to.join(file_name)
+	} else {
+		to.to_owned()
+	};
+	let from_path = index_path_of(from).ok_or_else(|| MvError::InvalidPath(from.to_owned()))?;
+	let to_path = index_path_of(&to).ok_or_else(|| MvError::InvalidPath(to.clone()))?;
+
+	if work_tree.join(&to).symlink_metadata().is_ok() {
+		return Err(MvError::DestinationExists(to));
+	}
+
+	let mut index = match read_index(repo) {
+		Ok(v) => v,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+			return Err(MvError::NotTracked(from.to_owned()))
+		}
+		Err(err) => return Err(err.into()),
+	};
+
+	// A directory moves all the entries below it.
+	let mut moved = 0;
+	for entry in &mut index.entries {
+		let rest = match entry.path.strip_prefix(&from_path) {
+			Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+			_ => continue,
+		};
+		entry.path = format!("{to_path}{rest}");
+		moved += 1;
+	}
+	if moved == 0 {
+		return Err(MvError::NotTracked(from.to_owned()));
+	}
+	if let Some(cache_tree) = &mut index.cache_tree {
+		cache_tree.invalidate(&from_path);
+		cache_tree.invalidate(&to_path);
+	}
+
+	fs::rename(work_tree.join(from), work_tree.join(&to))?;
+	write_index(repo, &index)?;
+
+	Ok(())
+}
+
+/// Converts a path relative to the work tree to the `/` separated form used in the index.
+/// Returns `None` for paths that escape the work tree or are empty.
+fn index_path_of(path: &Path) -> Option<String> {
+	let mut components = Vec::new();
+	for component in path.components() {
+		match component {
+			std::path::Component::Normal(name) => components.push(name.to_str()?),
+			std::path::Component::CurDir => {}
+			_ => return None,
+		}
+	}
+	(!components.is_empty()).then(|| components.join("/"))
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid date format: {0}")]
+struct InvalidDate(String);
+
+/// Identity of `role` (`author` or `committer`) at time `now`, unless the environment says
+/// otherwise. The environment comes first, then `<role>.name` and `user.name` (same for
+/// emails).
+fn signature(
+	repo: &Repo,
+	identity: &repo::IdentityOverrides,
+	role: &str,
+	now: i64,
+) -> Result<Signature, InvalidDate> {
+	let (timestamp, tz_offset) = match &identity.date {
+		Some(date) => date::parse_date(date).ok_or_else(|| InvalidDate(date.to_string()))?,
+		None => (now, 60),
+	};
+	let config = |field: &str| {
+		repo.config(&format!("{role}.{field}"))
+			.or_else(|| repo.config(&format!("user.{field}")))
+	};
+	Ok(Signature {
+		name: identity
+			.name
+			.as_deref()
+			.or_else(|| config("name"))
+			.unwrap_or("Foo Bar")
+			.to_string(),
+		email: identity
+			.email
+			.as_deref()
+			.or_else(|| config("email"))
+			.unwrap_or("foo@bar.com")
+			.to_string(),
+		timestamp,
+		tz_offset,
+	})
+}
+
+#[derive(Debug, Error)]
+enum CommitTreeError {
+	#[error("Hash object: {0}")]
+	HashObject(#[from] HashObjectError),
+
+	#[error("Invalid tree object: {0}")]
+	InvalidTreeSha1(hex::FromHexError),
+
+	#[error("Invalid parent object: {0}")]
+	InvalidParentSha1(hex::FromHexError),
+
+	#[error(transparent)]
+	InvalidDate(#[from] InvalidDate),
+}
+
+fn commit_tree(
+	repo: &Repo,
+	tree_hash_str: String,
+	parent_hash_strs: Vec<String>,
+	message: String,
+) -> Result<(), CommitTreeError> {
+	let sha1 = create_commit(repo, tree_hash_str, parent_hash_strs, message)?;
+	println!("{}", sha1.hash_str);
+
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum CommitError {
+	#[error(transparent)]
+	Hook(#[from] hooks::HookError),
+
+	#[error(transparent)]
+	WriteTree(#[from] WriteTreeError),
+
+	#[error(transparent)]
+	CommitTree(#[from] CommitTreeError),
+
+	#[error("Failed to update HEAD: {0}")]
+	UpdateHead(#[from] refs::UpdateRefError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Commits the tree of the index on top of `HEAD` (as a root commit if the branch is unborn),
+/// moving the branch to the new commit and printing its hash. The pre-commit hook runs first,
+/// unless `no_verify`, and aborts the commit if it fails. The branch is only moved if it's
+/// still at the parent, so that a concurrent commit isn't lost.
+fn commit(
+	repo: &Repo,
+	message: String,
+	no_verify: bool,
+	out: &mut impl Write,
+) -> Result<(), CommitError> {
+	if !no_verify {
+		hooks::run_hook(repo, "pre-commit")?;
+	}
+
+	// Read after the hook, which may have staged changes.
+	let entries = match read_index(repo) {
+		Ok(index) => index.entries,
+		Err(ReadIndexError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+		Err(err) => return Err(WriteTreeError::from(err).into()),
+	};
+	let tree = write_tree_from_index(repo, &entries)?;
+	let parents = match repo.head()? {
+		refs::Head::Symbolic {
+			target: Some(id), ..
+		}
+		| refs::Head::Detached(id) => vec![hex::encode(id)],
+		refs::Head::Symbolic { target: None, .. } => Vec::new(),
+	};
+	// The null hash for an unborn branch, which must still not exist.
+	let old = parents.first().cloned().unwrap_or_else(|| "0".repeat(40));
+
+	let commit = create_commit(repo, hex::encode(tree), parents, message)?;
+	refs::update_ref(repo, "HEAD", &commit.hash_str, Some(&old))?;
+	writeln!(out, "{}", commit.hash_str)?;
+	Ok(())
+}
+
+/// Writes a commit object of `tree_hash_str` on top of `parent_hash_strs`, signed with the
+/// configured identities and the current time. No ref is moved.
+fn create_commit(
+	repo: &Repo,
+	tree_hash_str: String,
+	parent_hash_strs: Vec<String>,
+	message: String,
+) -> Result<HashedObject, CommitTreeError> {
+	let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+	let author = signature(repo, repo.author(), "author", now)?;
+	let committer = signature(repo, repo.committer(), "committer", now)?;
+
+	let mut tree = [0_u8; 20];
+	hex::decode_to_slice(tree_hash_str, &mut tree).map_err(CommitTreeError::InvalidTreeSha1)?;
+
+	let parents = parent_hash_strs
+		.iter()
+		.map(|parent_hash_str| {
+			let mut parent = [0_u8; 20];
+			hex::decode_to_slice(parent_hash_str, &mut parent)
+				.map_err(CommitTreeError::InvalidParentSha1)?;
+			Ok(parent)
+		})
+		.collect::<Result<Vec<_>, CommitTreeError>>()?;
+
+	let sha1 = hash_git_object(
+		repo,
+		GitObject::Commit(Commit {
+			tree,
+			parents,
+			author,
+			committer,
+			extra_headers: Vec::new(),
+			message: format!("{message}\n"),
+		}),
+		true,
+	)?;
+
+	Ok(sha1)
+}
+
+#[derive(Debug, Error)]
+enum DiffError {
+	#[error("Could not access '{path}': {err}")]
+	ReadFile { err: std::io::Error, path: PathBuf },
+
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error("{0} is not a blob")]
+	NotABlob(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Error of a command run with `--exit-code` that found differences. The process exits with 1
+/// without printing it.
+#[derive(Debug, Error)]
+#[error("Found differences")]
+struct DifferencesFound;
+
+/// Turns the outcome of a comparison (whether there are differences) into a command result,
+/// failing with [DifferencesFound] if there are any and `exit_code` is set.
+fn check_differences(
+	result: Result<bool, impl Into<Box<dyn std::error::Error>>>,
+	exit_code: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+	match result {
+		Ok(true) if exit_code => Err(DifferencesFound.into()),
+		Ok(_) => Ok(()),
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// Reads the content, or the target if it is a symlink, and normalized mode of file `path`.
+fn read_diff_file(path: &Path) -> Result<(Vec<u8>, u32), DiffError> {
+	let read_err = |err| DiffError::ReadFile {
+		err,
+		path: path.to_owned(),
+	};
+	let metadata = fs::symlink_metadata(path).map_err(read_err)?;
+	let content = if metadata.is_symlink() {
+		read_link_target(path)
+	} else {
+		fs::read(path)
+	};
+	Ok((content.map_err(read_err)?, file_mode(&metadata, path)))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct DiffOptions {
+	/// Show changed words instead of changed lines, see [diff::unified_words].
+	word_diff: bool,
+	/// Write binary changes as a [binary_patch::binary_patch], with full object names.
+	binary: bool,
+}
+
+/// Writes a unified diff of files `old` and `new` in git's format. Returns whether they differ.
+fn diff_no_index(
+	old: &Path,
+	new: &Path,
+	options: DiffOptions,
+	out: &mut impl Write,
+) -> Result<bool, DiffError> {
+	let (old_content, old_mode) = read_diff_file(old)?;
+	let (new_content, new_mode) = read_diff_file(new)?;
+	let (old, new) = (old.display().to_string(), new.display().to_string());
+	Ok(write_file_diff(
+		out,
+		(&old, Some((&old_content, old_mode))),
+		(&new, Some((&new_content, new_mode))),
+		options,
+	)?)
+}
+
+/// Writes a unified diff of the files in the working tree that differ from the index, leaving out
+/// [SKIP_WORKTREE] entries. Returns whether there were any.
+fn diff_worktree(
+	repo: &Repo,
+	options: DiffOptions,
+	out: &mut impl Write,
+) -> Result<bool, DiffError> {
+	let entries = if repo.index_path().exists() {
+		read_index(repo)?.entries
+	} else {
+		Vec::new()
+	};
+	let attributes = read_worktree_attributes(repo.work_tree())?;
+
+	let mut differs = false;
+	for entry in entries.into_iter().filter(|entry| !entry.skip_worktree()) {
+		let path = repo.work_tree().join(&entry.path);
+		let new = match read_diff_file(&path) {
+			Ok((content, 0o120000)) => Some((content, 0o120000)),
+			Ok((content, mode)) => Some((attributes.normalize(&entry.path, content), mode)),
+			Err(DiffError::ReadFile { err, .. }) if err.kind() == std::io::ErrorKind::NotFound => {
+				None
+			}
+			Err(err) => return Err(err),
+		};
+		let mode = normalize_mode(entry.mode);
+		if let Some((content, new_mode)) = &new {
+			if *new_mode == mode && compute_id(ObjectKind::Blob, content) == entry.sha1 {
+				continue;
+			}
+		}
+
+		let hash = hex::encode(entry.sha1);
+		let GitObject::Blob(old) = decode_object(repo, hash.clone())? else {
+			return Err(DiffError::NotABlob(hash));
+		};
+		let new = new.as_ref().map(|(content, mode)| (&content[..], *mode));
+		differs |= write_file_diff(
+			out,
+			(&entry.path, Some((&old, mode))),
+			(&entry.path, new),
+			options,
+		)?;
+	}
+	Ok(differs)
+}
+
+/// One side of a file diff: the file's name and its content and mode, if it exists on that side.
+type DiffSide<'a> = (&'a str, Option<(&'a [u8], u32)>);
+
+/// Writes the diff of a file in git's format, from the `diff --git` line down to the hunks.
+/// Returns whether there were any differences.
+fn write_file_diff(
+	out: &mut impl Write,
+	(old_name, old): DiffSide,
+	(new_name, new): DiffSide,
+	options: DiffOptions,
+) -> std::io::Result<bool> {
+	if old == new {
+		return Ok(false);
+	}
+	let id_len = if options.binary { 40 } else { 7 };
+	let short_id = |side: Option<(&[u8], u32)>| match side {
+		Some((content, _)) => {
+			hex::encode(compute_id(ObjectKind::Blob, content))[..id_len].to_string()
+		}
+		None => "0".repeat(id_len),
+	};
+	let ids = format!("{}..{}", short_id(old), short_id(new));
+
+	writeln!(out, "diff --git a/{old_name} b/{new_name}")?;
+	match (old, new) {
+		(None, Some((_, mode))) => writeln!(out, "new file mode {mode:06o}\nindex {ids}")?,
+		(Some((_, mode)), None) => writeln!(out, "deleted file mode {mode:06o}\nindex {ids}")?,
+		(Some((_, old_mode)), Some((_, new_mode))) if old_mode == new_mode => {
+			writeln!(out, "index {ids} {old_mode:06o}")?
+		}
+		(Some((old_content, old_mode)), Some((new_content, new_mode))) => {
+			writeln!(out, "old mode {old_mode:06o}\nnew mode {new_mode:06o}")?;
+			if old_content == new_content {
+				return Ok(true);
+			}
+			writeln!(out, "index {ids}")?;
+		}
+		(None, None) => unreachable!("both sides are equal"),
+	}
+
+	let old_content = old.map_or(&[][..], |(content, _)| content);
+	let new_content = new.map_or(&[][..], |(content, _)| content);
+	let old_name = match old {
+		Some(_) => format!("a/{old_name}"),
+		None => "/dev/null".to_string(),
+	};
+	let new_name = match new {
+		Some(_) => format!("b/{new_name}"),
+		None => "/dev/null".to_string(),
+	};
+	if diff::is_binary(old_content) || diff::is_binary(new_content) {
+		if options.binary {
+			write!(
+				out,
+				"{}",
+				binary_patch::binary_patch(old_content, new_content)
+			)?;
+		} else {
+			writeln!(out, "Binary files {old_name} and {new_name} differ")?;
+		}
+	} else if !old_content.is_empty() || !new_content.is_empty() {
+		writeln!(out, "--- {old_name}")?;
+		writeln!(out, "+++ {new_name}")?;
+		if options.word_diff {
+			out.write_all(&diff::unified_words(old_content, new_content, 3))?;
+		} else {
+			out.write_all(&diff::unified(old_content, new_content, 3))?;
+		}
+	}
+	Ok(true)
+}
+
+#[derive(Debug, Error)]
+enum CleanError {
+	#[error("Refusing to clean without -f or -n")]
+	NotForced,
+
+	#[error("index: {0}")]
+	ReadIndex(#[from] ReadIndexError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CleanOptions {
+	force: bool,
+	directories: bool,
+	dry_run: bool,
+	ignored: bool,
+}
+
+/// Removes (or with `dry_run` lists) the files of the working tree that aren't in the index,
+/// untracked directories as a whole if `directories` is set. Ignored files are kept unless
+/// `ignored` is set.
+fn clean(repo: &Repo, options: CleanOptions, out: &mut impl Write) -> Result<(), CleanError> {
+	if !options.force && !options.dry_run {
+		return Err(CleanError::NotForced);
+	}
+	let tracked = if repo.index_path().exists() {
+		read_index(repo)?
+			.entries
+			.into_iter()
+			.map(|e| e.path)
+			.collect()
+	} else {
+		std::collections::HashSet::new()
+	};
+	// Directories with tracked files, with a trailing slash.
+	let tracked_dirs = tracked
+		.iter()
+		.flat_map(|path: &String| path.match_indices('/').map(|(idx, _)| &path[..=idx]))
+		.collect::<std::collections::HashSet<_>>();
+
+	let mut removals = Vec::new();
+	let walk = UntrackedWalk {
+		tracked: &tracked,
+		tracked_dirs: &tracked_dirs,
+		options,
+	};
+	walk.collect(
+		repo.work_tree(),
+		"",
+		&mut ignore::Ignores::default(),
+		&mut removals,
+	)?;
+
+	for path in removals {
+		if options.dry_run {
+			writeln!(out, "Would remove {path}")?;
+			continue;
+		}
+		let full_path = repo.work_tree().join(&path);
+		if path.ends_with('/') {
+			fs::remove_dir_all(full_path)?;
+		} else {
+			fs::remove_file(full_path)?;
+		}
+		writeln!(out, "Removing {path}")?;
+	}
+	Ok(())
+}
+
+/// Finds what `clean` removes.
+struct UntrackedWalk<'a> {
+	tracked: &'a std::collections::HashSet<String>,
+	tracked_dirs: &'a std::collections::HashSet<&'a str>,
+	options: CleanOptions,
+}
+
+impl UntrackedWalk<'_> {
+	/// Adds the paths to remove in directory `dir`, at `rel_path` in the working tree, to
+	/// `removals`. Directories are added with a trailing slash. Returns whether everything in
+	/// `dir` is to be removed.
+	fn collect(
+		&self,
+		dir: &Path,
+		rel_path: &str,
+		ignores: &mut ignore::Ignores,
+		removals: &mut Vec<String>,
+	) -> std::io::Result<bool> {
+		let has_gitignore = ignores.enter(dir, rel_path)?;
+		let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+		entries.sort_by_key(|e| e.file_name());
+
+		let mut remove_all = true;
+		for entry in entries {
+			let file_name = entry.file_name().to_string_lossy().into_owned();
+			let entry_rel_path = format!("{rel_path}{file_name}");
+			let is_dir = entry.file_type()?.is_dir();
+			if file_name == ".git" || self.tracked.contains(&entry_rel_path) {
+				remove_all = false;
+				continue;
+			}
+			if !self.options.ignored && ignores.is_ignored(&entry_rel_path, is_dir) {
+				remove_all = false;
+				continue;
+			}
+			if !is_dir {
+				removals.push(entry_rel_path);
+				continue;
+			}
+
+			let dir_rel_path = format!("{entry_rel_path}/");
+			let untracked = !self.tracked_dirs.contains(dir_rel_path.as_str());
+			// Nested repositories are left alone.
+			if untracked && (!self.options.directories || entry.path().join(".git").exists()) {
+				remove_all = false;
+				continue;
+			}
+			let start = removals.len();
+			if self.collect(&entry.path(), &dir_rel_path, ignores, removals)? && untracked {
+				removals.truncate(start);
+				removals.push(dir_rel_path);
+			} else {
+				remove_all = false;
+			}
+		}
+
+		if has_gitignore {
+			ignores.leave();
+		}
+		Ok(remove_all)
+	}
+}
+
+#[derive(Debug, Error)]
+enum LogError {
+	#[error("Your current branch '{0}' does not have any commits yet")]
+	UnbornBranch(String),
+
+	#[error("Not a valid commit name {0}")]
+	InvalidRevision(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error(transparent)]
+	Graph(#[from] graph::GraphError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+}
+
+struct LogOptions {
+	graph: bool,
+	oneline: bool,
+	max_count: Option<usize>,
+	/// Where to start instead of `HEAD`.
+	revision: Option<String>,
+}
+
+/// Prints the history of `HEAD` (or the given revision), children before parents: hash,
+/// author, date and message of each commit, or only hash and subject with `oneline`. Only first
+/// parents are followed, so a merged branch isn't shown, unless the history is drawn as a
+/// `graph` (one line per commit).
+fn log(repo: &Repo, options: LogOptions, out: &mut impl Write) -> Result<(), LogError> {
+	let tip = match &options.revision {
+		Some(revision) => {
+			let invalid = || LogError::InvalidRevision(revision.clone());
+			let hash = revision::resolve_revision(repo, revision)
+				.and_then(|hash| revision::peel(repo, hash, "commit"));
+			let hash = match hash {
+				Ok(Some(hash)) => hash,
+				Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => return Err(invalid()),
+				Err(err) => return Err(err.into()),
+			};
+			let mut id = [0_u8; 20];
+			hex::decode_to_slice(hash, &mut id).map_err(|_| invalid())?;
+			id
+		}
+		None => match repo.head()? {
+			refs::Head::Symbolic {
+				target: Some(id), ..
+			}
+			| refs::Head::Detached(id) => id,
+			refs::Head::Symbolic {
+				ref_name,
+				target: None,
+			} => {
+				let branch = ref_name.strip_prefix("refs/heads/").unwrap_or(&ref_name);
+				return Err(LogError::UnbornBranch(branch.to_string()));
+			}
+		},
+	};
+	let max_count = options.max_count.unwrap_or(usize::MAX);
+
+	if options.graph {
+		let mut renderer = graph::GraphRenderer::default();
+		for (id, commit) in graph::topo_order(repo, &[tip])?.into_iter().take(max_count) {
+			let subject = commit.message.lines().next().unwrap_or_default();
+			let (prefix, lines) = renderer.next(&id, &commit.parents);
+			writeln!(out, "{prefix}{} {subject}", hex::encode(id))?;
+			for line in lines {
+				writeln!(out, "{line}")?;
+			}
+		}
+		return Ok(());
+	}
+
+	for (shown, entry) in graph::first_parents(repo, tip).take(max_count).enumerate() {
+		let (id, commit) = entry?;
+		if options.oneline {
+			let subject = commit.message.lines().next().unwrap_or_default();
+			writeln!(out, "{} {subject}", hex::encode(id))?;
+			continue;
+		}
+		if shown > 0 {
+			writeln!(out)?;
+		}
+		writeln!(out, "commit {}", hex::encode(id))?;
+		if commit.parents.len() > 1 {
+			let mut parents = Vec::with_capacity(commit.parents.len());
+			for parent in &commit.parents {
+				let len = min_unique_abbrev_len(repo, parent, DEFAULT_ABBREV_LEN)?;
+				parents.push(hex::encode(parent)[..len].to_string());
+			}
+			writeln!(out, "Merge: {}", parents.join(" "))?;
+		}
+		let author = &commit.author;
+		writeln!(out, "Author: {} <{}>", author.name, author.email)?;
+		writeln!(
+			out,
+			"Date:   {}",
+			date::format_date(author.timestamp, author.tz_offset)
+		)?;
+		writeln!(out)?;
+		for line in commit.message.trim_end_matches('\n').lines() {
+			writeln!(out, "    {line}")?;
+		}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum BranchError {
+	#[error("'{0}' is not a valid branch name")]
+	InvalidName(String),
+
+	#[error("A branch named '{0}' already exists")]
+	AlreadyExists(String),
+
+	#[error("Branch '{0}' not found")]
+	NotFound(String),
+
+	#[error("Cannot delete branch '{0}' checked out")]
+	CheckedOut(String),
+
+	#[error("The branch '{0}' is not fully merged")]
+	NotMerged(String),
+
+	#[error("Not a valid object name: '{0}'")]
+	InvalidStartPoint(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error(transparent)]
+	Graph(#[from] graph::GraphError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+}
+
+/// Lists the branches, marking the one `HEAD` points at with `*`. A detached `HEAD` is listed
+/// first, as `(HEAD detached at <hash>)`.
+fn list_branches(repo: &Repo, out: &mut impl Write) -> Result<(), BranchError> {
+	let current = match repo.head()? {
+		refs::Head::Symbolic { ref_name, .. } => Some(ref_name),
+		refs::Head::Detached(id) => {
+			let len = min_unique_abbrev_len(repo, &id, DEFAULT_ABBREV_LEN)?;
+			writeln!(out, "* (HEAD detached at {})", &hex::encode(id)[..len])?;
+			None
+		}
+	};
+	for (ref_name, _) in refs::all_refs(repo)? {
+		if let Some(branch) = ref_name.strip_prefix("refs/heads/") {
+			let marker = if current.as_ref() == Some(&ref_name) {
+				'*'
+			} else {
+				' '
+			};
+			writeln!(out, "{marker} {branch}")?;
+		}
+	}
+	Ok(())
+}
+
+/// Creates branch `name` pointing at the commit `start_point` (`HEAD` by default), without
+/// switching to it.
+fn create_branch(repo: &Repo, name: &str, start_point: Option<&str>) -> Result<(), BranchError> {
+	let ref_name = format!("refs/heads/{name}");
+	if name == "HEAD" || name.starts_with('-') || !refs::check_ref_format(&ref_name) {
+		return Err(BranchError::InvalidName(name.to_string()));
+	}
+	if refs::resolve_ref(repo, &ref_name)?.is_some() {
+		return Err(BranchError::AlreadyExists(name.to_string()));
+	}
+
+	let start_point = start_point.unwrap_or("HEAD");
+	let hash = revision::resolve_revision(repo, start_point)
+		.and_then(|hash| revision::peel(repo, hash, "commit"));
+	let hash = match hash {
+		Ok(Some(hash)) => hash,
+		Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => {
+			return Err(BranchError::InvalidStartPoint(start_point.to_string()))
+		}
+		Err(err) => return Err(err.into()),
+	};
+	refs::write_ref(repo, &ref_name, &hash)?;
+	Ok(())
+}
+
+/// Deletes branch `name`, which can't be the current one. Unless `force`, the branch must be
+/// merged into `HEAD` so that no commit is lost.
+fn delete_branch(
+	repo: &Repo,
+	name: &str,
+	force: bool,
+	out: &mut impl Write,
+) -> Result<(), BranchError> {
+	let ref_name = format!("refs/heads/{name}");
+	let Some(hash) = refs::resolve_ref(repo, &ref_name)? else {
+		return Err(BranchError::NotFound(name.to_string()));
+	};
+	let mut id = [0_u8; 20];
+	hex::decode_to_slice(&hash, &mut id).map_err(|_| BranchError::NotFound(name.to_string()))?;
+
+	let head = match repo.head()? {
+		refs::Head::Symbolic { ref_name: head, .. } if head == ref_name => {
+			return Err(BranchError::CheckedOut(name.to_string()));
+		}
+		refs::Head::Symbolic { target, .. } => target,
+		refs::Head::Detached(id) => Some(id),
+	};
+	if !force {
+		let merged = match head {
+			Some(head) => graph::children(repo, &[head])?.contains_key(&id),
+			None => false,
+		};
+		if !merged {
+			return Err(BranchError::NotMerged(name.to_string()));
+		}
+	}
+
+	let len = min_unique_abbrev_len(repo, &id, DEFAULT_ABBREV_LEN)?;
+	refs::delete_ref(repo, &ref_name)?;
+	writeln!(out, "Deleted branch {name} (was {}).", &hash[..len])?;
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum UpdateRefCmdError {
+	#[error("{0}: not a valid SHA1")]
+	InvalidValue(String),
+
+	#[error(transparent)]
+	UpdateRef(#[from] refs::UpdateRefError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+}
+
+/// Points ref `name` at the object `new_value` names, through symbolic refs. With `old_value`,
+/// the ref must point at the object it names first, or not exist if it's the null hash (or
+/// empty).
+fn update_ref(
+	repo: &Repo,
+	name: &str,
+	new_value: &str,
+	old_value: Option<&str>,
+) -> Result<(), UpdateRefCmdError> {
+	let resolve = |value: &str| match revision::resolve_revision(repo, value) {
+		Ok(hash) => Ok(hash),
+		Err(ReadObjectError::ObjectNotFound(_)) => {
+			Err(UpdateRefCmdError::InvalidValue(value.to_string()))
+		}
+		Err(err) => Err(err.into()),
+	};
+	let new = resolve(new_value)?;
+	let old = match old_value {
+		Some(value) if value.is_empty() || value.bytes().all(|b| b == b'0') => Some("0".repeat(40)),
+		Some(value) => Some(resolve(value)?),
+		None => None,
+	};
+	refs::update_ref(repo, name, &new, old.as_deref())?;
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum RevListError {
+	#[error("Not a valid commit name {0}")]
+	InvalidRevision(String),
+
+	#[error("{0} is not a tree")]
+	NotATree(String),
+
+	#[error(transparent)]
+	Graph(#[from] graph::GraphError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Prints the commits reachable from `revisions`, see [graph::date_order]. With `objects`, the
+/// tags among `revisions`, then the trees and blobs of those commits follow, each once, as
+/// `<hash> <name>`: the revision a tag was given as, or the path of a tree or blob (empty for
+/// root trees). Trees come before their entries.
+fn rev_list(
+	repo: &Repo,
+	revisions: &[String],
+	objects: bool,
+	out: &mut impl Write,
+) -> Result<(), RevListError> {
+	let mut tips = Vec::with_capacity(revisions.len());
+	let mut tags = Vec::new();
+	for revision in revisions {
+		let invalid = || RevListError::InvalidRevision(revision.clone());
+		let unpeeled = match revision::resolve_revision(repo, revision) {
+			Ok(hash) => hash,
+			Err(ReadObjectError::ObjectNotFound(_)) => return Err(invalid()),
+			Err(err) => return Err(err.into()),
+		};
+		let hash = revision::peel(repo, unpeeled.clone(), "commit")?.ok_or_else(invalid)?;
+		// Only tags are peeled to get to the commit.
+		if unpeeled != hash && !tags.iter().any(|(tag, _)| *tag == unpeeled) {
+			tags.push((unpeeled, revision));
+		}
+		let mut id = [0_u8; 20];
+		hex::decode_to_slice(hash, &mut id).map_err(|_| invalid())?;
+		tips.push(id);
+	}
+
+	let commits = graph::date_order(repo, &tips)?;
+	for (id, _) in &commits {
+		writeln!(out, "{}", hex::encode(id))?;
+	}
+	if objects {
+		// Tags given as revisions are listed by the name they were given as.
+		for (tag, name) in tags {
+			writeln!(out, "{tag} {name}")?;
+		}
+		let mut seen = HashSet::new();
+		for (_, commit) in &commits {
+			list_tree_objects(repo, commit.tree, "", &mut seen, out)?;
+		}
+	}
+	Ok(())
+}
+
+/// Prints `tree` and, recursively, its entries not in `seen` yet for [rev_list]. Submodules are
+/// skipped, their commits aren't in this repository.
+fn list_tree_objects(
+	repo: &Repo,
+	tree: ObjectId,
+	path: &str,
+	seen: &mut HashSet<ObjectId>,
+	out: &mut impl Write,
+) -> Result<(), RevListError> {
+	if !seen.insert(tree) {
+		return Ok(());
+	}
+	let hash = hex::encode(tree);
+	writeln!(out, "{hash} {path}")?;
+	let GitObject::Tree(entries) = decode_object(repo, hash.clone())? else {
+		return Err(RevListError::NotATree(hash));
+	};
+	for entry in entries.iter() {
+		let entry_path = match path {
+			"" => entry.name.to_string(),
+			path => format!("{path}/{}", entry.name),
+		};
+		match entry.mode {
+			0o40000 => list_tree_objects(repo, *entry.object_hash, &entry_path, seen, out)?,
+			0o160000 => {}
+			_ => {
+				if seen.insert(*entry.object_hash) {
+					writeln!(out, "{} {entry_path}", hex::encode(*entry.object_hash))?;
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Number of tagged commits `describe` considers, git's default `--candidates`.
+const MAX_DESCRIBE_CANDIDATES: usize = 10;
+
+#[derive(Debug, Error)]
+enum DescribeError {
+	#[error("Not a valid commit name {0}")]
+	InvalidRevision(String),
+
+	#[error("No tags can describe '{0}'")]
+	NoTags(String),
+
+	#[error(
+		"No annotated tags can describe '{0}'.\nHowever, there were unannotated tags: try --tags."
+	)]
+	NoAnnotatedTags(String),
+
+	#[error(transparent)]
+	Graph(#[from] graph::GraphError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Prints the name of the tag closest to commit `name`: just the tag if it points at the
+/// commit, otherwise `<tag>-<n>-g<abbreviated hash>`, `n` being the number of commits reachable
+/// from `name` but not from the tag. Only annotated tags are used unless `lightweight`.
+///
+/// Like git, only the [MAX_DESCRIBE_CANDIDATES] most recently committed tagged commits are
+/// candidates. The candidate with the fewest commits to add wins, the most recently committed
+/// one if several are as close. Between tags of the same commit, annotated tags are preferred,
+/// then the most recent.
+fn describe(
+	repo: &Repo,
+	name: &str,
+	lightweight: bool,
+	out: &mut impl Write,
+) -> Result<(), DescribeError> {
+	let invalid = || DescribeError::InvalidRevision(name.to_string());
+	let hash = revision::resolve_revision(repo, name)
+		.and_then(|hash| revision::peel(repo, hash, "commit"));
+	let hash = match hash {
+		Ok(Some(hash)) => hash,
+		Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => return Err(invalid()),
+		Err(err) => return Err(err.into()),
+	};
+	let mut id = [0_u8; 20];
+	hex::decode_to_slice(&hash, &mut id).map_err(|_| invalid())?;
+
+	// The best tag of each tagged commit, ranked by (annotated, tagger date).
+	let mut tagged: HashMap<ObjectId, (String, (bool, i64))> = HashMap::new();
+	let mut lightweight_tagged = HashSet::new();
+	for (ref_name, tag_hash) in refs::all_refs(repo)? {
+		let Some(tag_name) = ref_name.strip_prefix("refs/tags/") else {
+			continue;
+		};
+		let commit = match revision::peel(repo, tag_hash.clone(), "commit")? {
+			Some(commit) => parse_object_name(repo, &commit)?,
+			// Tags of trees or blobs.
+			None => continue,
+		};
+		let rank = match decode_object(repo, tag_hash)? {
+			GitObject::Tag(tag) => (true, tag.tagger.timestamp),
+			_ if lightweight => (false, 0),
+			_ => {
+				lightweight_tagged.insert(commit);
+				continue;
+			}
+		};
+		if tagged.get(&commit).is_none_or(|(_, best)| rank > *best) {
+			tagged.insert(commit, (tag_name.to_string(), rank));
+		}
+	}
+
+	// The candidates are the tagged commits found first by date, like git's walk finds them.
+	let history = graph::topo_order(repo, &[id])?;
+	let mut by_date: Vec<_> = history
+		.iter()
+		.filter(|(commit, _)| tagged.contains_key(commit))
+		.collect();
+	by_date.sort_by_key(|(_, parsed)| std::cmp::Reverse(parsed.committer.timestamp));
+	let mut candidates: Vec<(ObjectId, &str, usize)> = by_date
+		.into_iter()
+		.take(MAX_DESCRIBE_CANDIDATES)
+		.map(|(commit, _)| (*commit, tagged[commit].0.as_str(), 0))
+		.collect();
+
+	// One more pass, children before parents, with a bit for each candidate marking the commits
+	// it reaches: a candidate's distance is the number of commits without its bit.
+	let mut reached: HashMap<ObjectId, u32> = HashMap::new();
+	for (commit, parsed) in &history {
+		let mut bits = reached.remove(commit).unwrap_or(0);
+		for (bit, (candidate, _, distance)) in candidates.iter_mut().enumerate() {
+			if candidate == commit {
+				bits |= 1 << bit;
+			} else if bits & 1 << bit == 0 {
+				*distance += 1;
+			}
+		}
+		for parent in &parsed.parents {
+			*reached.entry(*parent).or_default() |= bits;
+		}
+	}
+	let best = candidates
+		.into_iter()
+		.map(|(_, tag_name, distance)| (distance, tag_name))
+		.reduce(|best, candidate| {
+			if candidate.0 < best.0 {
+				candidate
+			} else {
+				best
+			}
+		});
+
+	match best {
+		Some((0, tag_name)) => writeln!(out, "{tag_name}")?,
+		Some((distance, tag_name)) => {
+			let len = min_unique_abbrev_len(repo, &id, DEFAULT_ABBREV_LEN)?;
+			writeln!(out, "{tag_name}-{distance}-g{}", &hash[..len])?;
+		}
+		None if history
+			.iter()
+			.any(|(commit, _)| lightweight_tagged.contains(commit)) =>
+		{
+			return Err(DescribeError::NoAnnotatedTags(hash))
+		}
+		None => return Err(DescribeError::NoTags(hash)),
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum ForEachRefError {
+	#[error("Unknown field name: {0}")]
+	UnknownField(String),
+
+	#[error("Malformed format string {0}")]
+	MalformedFormat(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// A `%(...)` placeholder of a `for-each-ref` format, which is also what it can sort by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RefField {
+	RefName,
+	/// Name without `refs/heads/`, `refs/tags/`, `refs/remotes/` or `refs/`.
+	RefNameShort,
+	ObjectName,
+	/// Object name abbreviated to at least [DEFAULT_ABBREV_LEN] digits.
+	ObjectNameShort,
+	ObjectType,
+}
+
+impl RefField {
+	fn parse(name: &str) -> Result<Self, ForEachRefError> {
+		match name {
+			"refname" => Ok(Self::RefName),
+			"refname:short" => Ok(Self::RefNameShort),
+			"objectname" => Ok(Self::ObjectName),
+			"objectname:short" => Ok(Self::ObjectNameShort),
+			"objecttype" => Ok(Self::ObjectType),
+			_ => Err(ForEachRefError::UnknownField(name.to_string())),
+		}
+	}
+
+	/// Value of the field for ref `name` pointing at `hash`.
+	fn value(self, repo: &Repo, name: &str, hash: &str) -> Result<String, ForEachRefError> {
+		Ok(match self {
+			Self::RefName => name.to_string(),
+			Self::RefNameShort => ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+				.iter()
+				.find_map(|prefix| name.strip_prefix(prefix))
+				.unwrap_or(name)
+				.to_string(),
+			Self::ObjectName => hash.to_string(),
+			Self::ObjectNameShort => {
+				let id = parse_object_name(repo, hash)?;
+				let len = min_unique_abbrev_len(repo, &id, DEFAULT_ABBREV_LEN)?;
+				hash[..len].to_string()
+			}
+			Self::ObjectType => read_object_raw(repo, hash.to_string())?
+				.0
+				.as_str()
+				.to_string(),
+		})
+	}
+}
+
+enum FormatPart {
+	Literal(Vec<u8>),
+	Field(RefField),
+}
+
+/// Splits a `for-each-ref` format into literal text and fields. Besides `%(field)`, `%%` stands
+/// for `%` and `%xx` for the byte with hex code `xx`, e.g. `%09` for a tab.
+fn parse_ref_format(format: &str) -> Result<Vec<FormatPart>, ForEachRefError> {
+	let malformed = || ForEachRefError::MalformedFormat(format.to_string());
+	let mut parts = Vec::new();
+	let mut literal = Vec::new();
+	let mut rest = format;
+	while let Some(idx) = rest.find('%') {
+		literal.extend_from_slice(&rest.as_bytes()[..idx]);
+		rest = &rest[idx + 1..];
+		if let Some(field) = rest.strip_prefix('(') {
+			let (name, after) = field.split_once(')').ok_or_else(malformed)?;
+			parts.push(FormatPart::Literal(std::mem::take(&mut literal)));
+			parts.push(FormatPart::Field(RefField::parse(name)?));
+			rest = after;
+		} else if let Some(after) = rest.strip_prefix('%') {
+			literal.push(b'%');
+			rest = after;
+		} else {
+			let code = rest.get(..2).ok_or_else(malformed)?;
+			literal.push(u8::from_str_radix(code, 16).map_err(|_| malformed())?);
+			rest = &rest[2..];
+		}
+	}
+	literal.extend_from_slice(rest.as_bytes());
+	parts.push(FormatPart::Literal(literal));
+	Ok(parts)
+}
+
+/// Prints every ref (or those under one of `patterns`, or matching them if they're globs),
+/// sorted by name or by the `sort` field, as `format` says. The default format is
+/// `%(objectname) %(objecttype)%09%(refname)`.
+fn for_each_ref(
+	repo: &Repo,
+	patterns: &[String],
+	format: Option<&str>,
+	sort: Option<&str>,
+	out: &mut impl Write,
+) -> Result<(), ForEachRefError> {
+	let format = parse_ref_format(format.unwrap_or("%(objectname) %(objecttype)%09%(refname)"))?;
+	let sort = match sort {
+		Some(sort) => match sort.strip_prefix('-') {
+			Some(field) => Some((RefField::parse(field)?, true)),
+			None => Some((RefField::parse(sort)?, false)),
+		},
+		None => None,
+	};
+
+	let matches = |name: &str| {
+		patterns.is_empty()
+			|| patterns.iter().any(|pattern| {
+				if pattern.contains(['*', '?', '[']) {
+					return ignore::glob_match(pattern.as_bytes(), name.as_bytes());
+				}
+				let prefix = pattern.trim_end_matches('/');
+				name == prefix
+					|| name
+						.strip_prefix(prefix)
+						.is_some_and(|rest| rest.starts_with('/'))
+			})
+	};
+	let mut refs: Vec<(String, String)> = refs::all_refs(repo)?
+		.into_iter()
+		.filter(|(name, _)| matches(name))
+		.collect();
+
+	if let Some((field, descending)) = sort {
+		let mut keyed = Vec::with_capacity(refs.len());
+		for (name, hash) in refs {
+			keyed.push((field.value(repo, &name, &hash)?, name, hash));
+		}
+		// Stable, so refs with the same key stay sorted by name.
+		keyed.sort_by(|a, b| match descending {
+			false => a.0.cmp(&b.0),
+			true => b.0.cmp(&a.0),
+		});
+		refs = keyed
+			.into_iter()
+			.map(|(_, name, hash)| (name, hash))
+			.collect();
+	}
+
+	for (name, hash) in refs {
+		let mut line = Vec::new();
+		for part in &format {
+			match part {
+				FormatPart::Literal(text) => line.extend_from_slice(text),
+				FormatPart::Field(field) => {
+					line.extend_from_slice(field.value(repo, &name, &hash)?.as_bytes())
+				}
+			}
+		}
+		line.push(b'\n');
+		out.write_all(&line)?;
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum SymbolicRefError {
+	#[error("Ref {0} is not a symbolic ref")]
+	NotSymbolic(String),
+
+	#[error("Refusing to point {0} outside of refs/")]
+	OutsideRefs(String),
+
+	#[error("Invalid ref name {0}")]
+	InvalidTarget(String),
+
+	#[error(transparent)]
+	UpdateRef(#[from] refs::UpdateRefError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Points the symbolic ref `name` at ref `target` or, without a target, prints the ref it
+/// ends at after following every indirection.
+fn symbolic_ref(
+	repo: &Repo,
+	name: &str,
+	target: Option<&str>,
+	out: &mut impl Write,
+) -> Result<(), SymbolicRefError> {
+	refs::check_ref_name(name)?;
+	match target {
+		Some(target) => {
+			if !target.starts_with("refs/") {
+				return Err(SymbolicRefError::OutsideRefs(name.to_string()));
+			}
+			if !refs::check_ref_format(target) {
+				return Err(SymbolicRefError::InvalidTarget(target.to_string()));
+			}
+			refs::write_symbolic_ref(repo, name, target)?;
+		}
+		None => {
+			if refs::read_symbolic_ref(repo, name)?.is_none() {
+				return Err(SymbolicRefError::NotSymbolic(name.to_string()));
+			}
+			writeln!(out, "{}", refs::dereference(repo, name)?)?;
+		}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum TagError {
+	#[error("'{0}' is not a valid tag name")]
+	InvalidName(String),
+
+	#[error("Tag '{0}' already exists")]
+	AlreadyExists(String),
+
+	#[error("Failed to resolve '{0}' as a valid ref")]
+	InvalidObject(String),
+
+	#[error(transparent)]
+	InvalidDate(#[from] InvalidDate),
+
+	#[error(transparent)]
+	HashObject(#[from] HashObjectError),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Lists the names of the tags.
+fn list_tags(repo: &Repo, out: &mut impl Write) -> Result<(), TagError> {
+	for (ref_name, _) in refs::all_refs(repo)? {
+		if let Some(tag) = ref_name.strip_prefix("refs/tags/") {
+			writeln!(out, "{tag}")?;
+		}
+	}
+	Ok(())
+}
+
+/// Creates tag `name` for `object` (`HEAD` by default). With a `message`, the tag points at a
+/// new tag object carrying it and the committer's identity, otherwise it points at the object
+/// directly (a lightweight tag).
+fn create_tag(
+	repo: &Repo,
+	name: &str,
+	object: Option<&str>,
+	message: Option<String>,
+) -> Result<(), TagError> {
+	let ref_name = format!("refs/tags/{name}");
+	if name.starts_with('-') || !refs::check_ref_format(&ref_name) {
+		return Err(TagError::InvalidName(name.to_string()));
+	}
+	if refs::resolve_ref(repo, &ref_name)?.is_some() {
+		return Err(TagError::AlreadyExists(name.to_string()));
+	}
+
+	let object = object.unwrap_or("HEAD");
+	let hash = match revision::resolve_revision(repo, object) {
+		Ok(hash) => hash,
+		Err(ReadObjectError::ObjectNotFound(_)) => {
+			return Err(TagError::InvalidObject(object.to_string()))
+		}
+		Err(err) => return Err(err.into()),
+	};
+
+	let hash = match message {
+		Some(message) => {
+			let (kind, _) = read_object_raw(repo, hash.clone())?;
+			let mut id = [0_u8; 20];
+			hex::decode_to_slice(&hash, &mut id)
+				.map_err(|_| TagError::InvalidObject(object.to_string()))?;
+			let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+			let tag = Tag {
+				object: id,
+				kind,
+				name: name.to_string(),
+				tagger: signature(repo, repo.committer(), "committer", now)?,
+				extra_headers: Vec::new(),
+				message: format!("{message}\n"),
+			};
+			hash_git_object(repo, GitObject::Tag(tag), true)?.hash_str
+		}
+		None => hash,
+	};
+	refs::write_ref(repo, &ref_name, &hash)?;
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum CloneError {
+	#[error("Destination path '{0}' already exists and is not an empty directory")]
+	DestinationExists(PathBuf),
+
+	#[error(transparent)]
+	Init(#[from] InitError),
+
+	#[error(transparent)]
+	Transport(#[from] transport::TransportError),
+
+	#[error(transparent)]
+	Pack(#[from] pack::PackError),
+
+	#[error(transparent)]
+	WriteObject(#[from] HashObjectError),
+
+	#[error(transparent)]
+	UpdateRef(#[from] refs::UpdateRefError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Clones the repository at `url` into `dir`, which must not exist or be empty: fetches every
+/// object reachable from the remote's branches and tags and creates the same refs. The working
+/// tree isn't checked out. With a `depth`, the history is cut that many commits deep and the
+/// commits at the cut are listed in `shallow`.
+fn clone(url: &str, dir: &Path, depth: Option<u32>) -> Result<(), CloneError> {
+	let created = match fs::read_dir(dir).map(|mut entries| entries.next().is_none()) {
+		Ok(true) => false,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+			fs::create_dir_all(dir)?;
+			true
+		}
+		_ => return Err(CloneError::DestinationExists(dir.to_owned())),
+	};
+	eprintln!("Cloning into '{}'...", dir.display());
+	let result = clone_into(url, dir, depth);
+	// Nothing is left behind by a failed clone, an empty destination is kept.
+	if result.is_err() {
+		let _ = if created {
+			fs::remove_dir_all(dir)
+		} else {
+			fs::remove_dir_all(dir.join(".git"))
+		};
+	}
+	result
+}
+
+/// Does the work of [clone] in `dir`, which is there and empty.
+fn clone_into(url: &str, dir: &Path, depth: Option<u32>) -> Result<(), CloneError> {
+	let repo = Repo::new(dir.join(".git"));
+	init(&repo)?;
+
+	let client = reqwest::blocking::Client::new();
+	let remote = transport::discover_refs(&client, url)?;
+	let mut wants: Vec<ObjectId> = Vec::new();
+	for (_, id) in remote
+		.refs
+		.iter()
+		.filter(|(name, _)| !name.ends_with("^{}"))
+	{
+		if !wants.contains(id) {
+			wants.push(*id);
+		}
+	}
+	let (objects, shallow) = if wants.is_empty() {
+		eprintln!("warning: You appear to have cloned an empty repository.");
+		(Vec::new(), Vec::new())
+	} else {
+		let fetched = transport::fetch_pack(&client, url, &wants, depth)?;
+		(pack::parse_pack(&fetched.pack)?, fetched.shallow)
+	};
+	store_clone(&repo, &remote, objects, &shallow)
+}
+
+/// Writes the fetched `objects` and the branches, tags and `HEAD` advertised by `remote`, and
+/// the `shallow` commits whose parents weren't fetched, if any. `HEAD` only points at the branch
+/// the server tells if it's a valid branch name, otherwise it's detached.
+fn store_clone(
+	repo: &Repo,
+	remote: &transport::RemoteRefs,
+	objects: Vec<(ObjectKind, Vec<u8>)>,
+	shallow: &[ObjectId],
+) -> Result<(), CloneError> {
+	for (kind, payload) in objects {
+		let mut encoded = format!("{} {}\0", kind.as_str(), payload.len()).into_bytes();
+		encoded.extend(payload);
+		write_encoded_object(repo, &encoded, true)?;
+	}
+	if !shallow.is_empty() {
+		let mut shallow: Vec<String> = shallow.iter().map(hex::encode).collect();
+		shallow.sort();
+		shallow.dedup();
+		let lines: String = shallow.iter().map(|hash| format!("{hash}\n")).collect();
+		fs::write(repo.git_dir().join("shallow"), lines)?;
+	}
+
+	let mut head = None;
+	for (name, id) in &remote.refs {
+		if name == "HEAD" {
+			head = Some(id);
+		} else if (name.starts_with("refs/heads/") || name.starts_with("refs/tags/"))
+			&& !name.ends_with("^{}")
+		{
+			refs::write_ref(repo, name, &hex::encode(id))?;
+		}
+	}
+	let target = remote
+		.head_target
+		.as_ref()
+		.filter(|target| target.starts_with("refs/heads/") && refs::check_ref_name(target).is_ok());
+	match (target, head) {
+		(Some(target), _) => refs::write_symbolic_ref(repo, "HEAD", target)?,
+		(None, Some(id)) => refs::write_ref(repo, "HEAD", &hex::encode(id))?,
+		// Empty repository, keeps the default unborn branch.
+		(None, None) => {}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum RevParseError {
+	#[error("Ambiguous argument '{0}': unknown revision or path not in the working tree")]
+	UnknownRevision(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// What `rev-parse` prints, in this order, before the revisions.
+#[derive(Debug, Clone, Copy, Default)]
+struct RevParseOptions {
+	git_dir: bool,
+	show_toplevel: bool,
+	is_inside_work_tree: bool,
+}
+
+/// Prints the location of the repository as seen from directory `cwd`, then the full object
+/// name of each of `revisions` (see [revision::resolve_revision]). Like git, the git directory
+/// is printed relative to `cwd` if it's inside of it and absolute otherwise.
+fn rev_parse(
+	repo: &Repo,
+	cwd: &Path,
+	options: RevParseOptions,
+	revisions: &[String],
+	out: &mut impl Write,
+) -> Result<(), RevParseError> {
+	let cwd = cwd.canonicalize()?;
+	let git_dir = cwd.join(repo.git_dir()).canonicalize()?;
+	let work_tree = cwd.join(repo.work_tree()).canonicalize()?;
+
+	if options.git_dir {
+		let git_dir = match git_dir.strip_prefix(&cwd) {
+			Ok(relative) if relative.as_os_str().is_empty() => Path::new("."),
+			Ok(relative) => relative,
+			Err(_) => &git_dir,
+		};
+		writeln!(out, "{}", git_dir.display())?;
+	}
+	if options.show_toplevel {
+		writeln!(out, "{}", work_tree.display())?;
+	}
+	if options.is_inside_work_tree {
+		let inside = cwd.starts_with(&work_tree) && !cwd.starts_with(&git_dir);
+		writeln!(out, "{inside}")?;
+	}
+	for name in revisions {
+		match revision::resolve_revision(repo, name) {
+			Ok(hash) => writeln!(out, "{hash}")?,
+			Err(ReadObjectError::ObjectNotFound(_)) => {
+				return Err(RevParseError::UnknownRevision(name.clone()))
+			}
+			Err(err) => return Err(err.into()),
+		}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum BundleCmdError {
+	#[error("{0} is not a ref")]
+	InvalidRef(String),
+
+	#[error("Could not open {path}: {err}")]
+	FileIo {
+		#[source]
+		err: std::io::Error,
+
+		path: PathBuf,
+	},
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	Bundle(#[from] bundle::BundleError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Writes a bundle of `revs`, each a ref given by name (e.g. `master` or `refs/tags/v1`), to
+/// `file`.
+fn bundle_create(repo: &Repo, file: &Path, revs: &[String]) -> Result<(), BundleCmdError> {
+	let mut refs = Vec::new();
+	for rev in revs {
+		let (name, hash) = revision::dwim_ref(repo, rev)?
+			.ok_or_else(|| BundleCmdError::InvalidRef(rev.clone()))?;
+		let mut id = [0_u8; 20];
+		hex::decode_to_slice(&hash, &mut id).map_err(ReadObjectError::from)?;
+		refs.push((name, id));
+	}
+
+	let file_err = |err| BundleCmdError::FileIo {
+		err,
+		path: file.to_owned(),
+	};
+	let mut out = std::io::BufWriter::new(fs::File::create(file).map_err(file_err)?);
+	bundle::write_bundle(repo, &refs, &mut out)?;
+	out.flush().map_err(file_err)?;
+	Ok(())
+}
+
+/// Stores the objects of the bundle `file` and prints its refs, one `<hash> <name>` per line.
+fn unbundle(repo: &Repo, file: &Path, out: &mut impl Write) -> Result<(), BundleCmdError> {
+	let data = fs::read(file).map_err(|err| BundleCmdError::FileIo {
+		err,
+		path: file.to_owned(),
+	})?;
+	for (name, id) in bundle::unbundle(repo, &data)? {
+		writeln!(out, "{} {name}", hex::encode(id))?;
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+enum FastExportError {
+	#[error("{0} is not a ref")]
+	InvalidRef(String),
+
+	#[error("Not a valid commit name {0}")]
+	InvalidRevision(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	FastImport(#[from] fast_import::FastImportError),
+}
+
+/// Writes the history of `revs` as a fast-import stream. Each is a ref given by name, possibly
+/// as `<rev>..<ref>` to leave out what `<rev>` reaches (an empty side meaning `HEAD`).
+fn fast_export(repo: &Repo, revs: &[String], out: &mut impl Write) -> Result<(), FastExportError> {
+	let or_head = |name: &str| if name.is_empty() { "HEAD" } else { name }.to_string();
+	let mut refs = Vec::new();
+	let mut excluded = Vec::new();
+	for rev in revs {
+		let rev = match rev.split_once("..") {
+			Some((from, to)) => {
+				let from = or_head(from);
+				let commit = revision::resolve_revision(repo, &from)
+					.and_then(|hash| revision::peel(repo, hash, "commit"));
+				let commit = match commit {
+					Ok(Some(commit)) => commit,
+					Ok(None) | Err(ReadObjectError::ObjectNotFound(_)) => {
+						return Err(FastExportError::InvalidRevision(from))
+					}
+					Err(err) => return Err(err.into()),
+				};
+				excluded.push(parse_object_name(repo, &commit)?);
+				or_head(to)
+			}
+			None => rev.clone(),
+		};
+		let (name, hash) =
+			revision::dwim_ref(repo, &rev)?.ok_or(FastExportError::InvalidRef(rev))?;
+		refs.push((name, parse_object_name(repo, &hash)?));
+	}
+	fast_import::export(repo, &refs, &excluded, out)?;
+	Ok(())
+}
+
+/// Imports the fast-import stream `input`.
+fn fast_import(repo: &Repo, mut input: impl Read) -> Result<(), fast_import::FastImportError> {
+	let mut stream = Vec::new();
+	input.read_to_end(&mut stream)?;
+	fast_import::import(repo, &stream)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{init_repo, write_raw_object, TempDir};
+
+	#[test]
+	fn read_object_raw_returns_unparsed_payloads() {
+		let (_dir, repo) = init_repo();
+		// Not a valid tree, but it isn't parsed.
+		let hash = write_raw_object(&repo, ObjectKind::Tree, b"garbage");
+		assert_eq!(
+			read_object_raw(&repo, hash[..7].to_string()).unwrap(),
+			(ObjectKind::Tree, b"garbage".to_vec())
+		);
+		assert!(decode_object(&repo, hash).is_err());
+
+		let write_loose = |hash: &str, content: &[u8]| {
+			let path = repo.object_path(hash);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+			encoder.write_all(content).unwrap();
+			fs::write(path, encoder.finish().unwrap()).unwrap();
+		};
+		let repo = Repo::new(repo.git_dir()).with_verify_objects(false);
+		let hash = "1".repeat(40);
+		write_loose(&hash, b"blob 3\0abcdef");
+		assert_eq!(
+			read_object_raw(&repo, hash.clone()).unwrap(),
+			(ObjectKind::Blob, b"abc".to_vec())
+		);
+		write_loose(&hash, b"blob 9\0abc");
+		assert!(matches!(
+			read_object_raw(&repo, hash.clone()),
+			Err(ReadObjectError::CorruptedObject {
+				context: "truncated payload",
+				..
+			})
+		));
+		write_loose(&hash, b"what 3\0abc");
+		assert!(matches!(
+			read_object_raw(&repo, hash),
+			Err(ReadObjectError::UnknownObjectKind { .. })
+		));
+	}
+
+	#[test]
+	fn decode_object_with_empty_size() {
+		let result = decode_object_bytes("", b"tree \0");
+		assert!(matches!(
+			result,
+			Err(ReadObjectError::CorruptedObject {
+				context: "missing object size",
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn decode_object_with_missing_size() {
+		let result = decode_object_bytes("", b"blob abc");
+		assert!(matches!(
+			result,
+			Err(ReadObjectError::CorruptedObject { .. })
+		));
+	}
+
+	#[test]
+	fn decode_object_with_overflowing_size() {
+		let result = decode_object_bytes("", b"blob 99999999999999999999999\0");
+		assert!(matches!(
+			result,
+			Err(ReadObjectError::CorruptedObject {
+				context: "object size out of range",
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn hash_object_writes_to_printed_path() {
+		let (dir, repo) = init_repo();
+		let file = dir.0.join("hello.txt");
+		fs::write(&file, "hello world\n").unwrap();
+
+		let hash = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad";
+		let object_path = repo.object_path(hash);
+		assert!(object_path.ends_with("objects/3b/18e512dba79e4c8300dd08aeb37f8e728b8dad"));
+		let expected = format!("{hash}\n{}\n", object_path.display());
+		let run = |write| {
+			let mut out = Vec::new();
+			hash_object_cmd(&repo, Some(file.clone()), write, true, false, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+
+		// --dry-run
+		assert_eq!(run(false), expected);
+		assert!(!object_path.exists());
+
+		assert_eq!(run(true), expected);
+		assert!(object_path.exists());
+
+		let parse = |args: &[&str]| Args::try_parse_from(["git", "hash-object"].iter().chain(args));
+		assert!(parse(&["--dry-run", "--print-path", "f"]).is_ok());
+		assert!(parse(&["-w", "--print-path", "f"]).is_ok());
+		assert!(parse(&["--print-path", "f"]).is_err());
+		assert!(parse(&["-w", "--dry-run", "f"]).is_err());
+	}
+
+	#[test]
+	fn hash_object_streams_large_files() {
+		let (dir, repo) = init_repo();
+		let file = dir.0.join("large.bin");
+		// Not a multiple of the chunk size
+		let content: Vec<u8> = (0..3 * HASH_CHUNK_SIZE + 123)
+			.map(|n| (n % 251) as u8)
+			.collect();
+		fs::write(&file, &content).unwrap();
+		let repo = with_config(&repo, "core.bigFileThreshold", "1k");
+
+		let mut progress = Vec::new();
+		let mut reader = RecordingReader {
+			inner: fs::File::open(&file).unwrap(),
+			reads: Vec::new(),
+		};
+		let len = content.len() as u64;
+		let hashed = hash_blob(&repo, &file, &mut reader, len, true, Some(&mut progress)).unwrap();
+		assert_eq!(hashed.hash, compute_id(ObjectKind::Blob, &content));
+		// The file is never held whole: no more than a chunk of it is read at once, and the
+		// compressed object goes straight to a file.
+		assert!(reader.reads.iter().all(|&len| len <= HASH_CHUNK_SIZE));
+		let object = repo.object_path(&hashed.hash_str);
+		let compressed = test_utils::COMPRESSED_OBJECTS
+			.lock()
+			.unwrap()
+			.contains(&object);
+		assert!(!compressed);
+		let progress = String::from_utf8(progress).unwrap();
+		assert!(progress.ends_with(&format!("100% ({0}/{0} bytes), done.\n", content.len())));
+
+		let GitObject::Blob(read_back) = decode_object(&repo, hashed.hash_str).unwrap() else {
+			panic!("not a blob");
+		};
+		assert!(read_back == content);
+		// Only the object itself is left in `objects/`.
+		let leftovers: Vec<_> = fs::read_dir(repo.objects_dir())
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.filter(|name| name.to_string_lossy().starts_with("tmp_obj_"))
+			.collect();
+		assert!(leftovers.is_empty());
+
+		let hashed_again = hash_object(&repo, &file, false, None).unwrap();
+		assert_eq!(hashed_again.hash, hashed.hash);
+	}
+
+	/// Reader recording the size of the buffer each read is made into.
+	struct RecordingReader<R> {
+		inner: R,
+		reads: Vec<usize>,
+	}
+
+	impl<R: Read> Read for RecordingReader<R> {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			self.reads.push(buf.len());
+			self.inner.read(buf)
+		}
+	}
+
+	/// `repo` with config `key` set to `value`, as through `GIT_CONFIG_*`.
+	fn with_config(repo: &Repo, key: &str, value: &str) -> Repo {
+		let (key, value) = (key.to_string(), value.to_string());
+		Repo::new(repo.git_dir()).with_env(move |name| match name {
+			"GIT_CONFIG_COUNT" => Some("1".into()),
+			"GIT_CONFIG_KEY_0" => Some(key.clone().into()),
+			"GIT_CONFIG_VALUE_0" => Some(value.clone().into()),
+			_ => None,
+		})
+	}
+
+	#[test]
+	fn big_file_threshold_picks_streaming() {
+		let (dir, repo) = init_repo();
+		let content: Vec<u8> = (0..2 * HASH_CHUNK_SIZE + 5).map(|n| n as u8).collect();
+		let hash = |repo: &Repo| {
+			let mut reader = RecordingReader {
+				inner: &content[..],
+				reads: Vec::new(),
+			};
+			let len = content.len() as u64;
+			let hashed = hash_blob(repo, Path::new("big"), &mut reader, len, false, None).unwrap();
+			assert_eq!(hashed.hash, compute_id(ObjectKind::Blob, &content));
+			reader.reads
+		};
+
+		// Under the threshold (512 MiB by default) the blob is read whole.
+		assert_eq!(hash(&repo)[0], content.len());
+		// Over it, it's streamed in chunks.
+		let big = with_config(&repo, "core.bigFileThreshold", "64k");
+		let reads = hash(&big);
+		assert!(reads.len() > 2);
+		assert!(reads.iter().all(|&len| len <= HASH_CHUNK_SIZE));
+
+		// Building trees streams them too, instead of compressing them from memory.
+		fs::write(dir.0.join("big.bin"), &content).unwrap();
+		fs::write(dir.0.join("small.txt"), "small\n").unwrap();
+		write_tree_at_dir(&big, &dir.0).unwrap();
+		for (content, buffered) in [(&content[..], false), (b"small\n", true)] {
+			let object = repo.object_path(&hex::encode(compute_id(ObjectKind::Blob, content)));
+			assert!(object.exists());
+			let compressed = test_utils::COMPRESSED_OBJECTS
+				.lock()
+				.unwrap()
+				.contains(&object);
+			assert_eq!(compressed, buffered);
+		}
+	}
+
+	#[test]
+	fn objects_are_moved_into_place_once_written() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"hello\n"));
+		let hashed = hash_git_object(&repo, blob, true).unwrap();
+		let path = repo.object_path(&hashed.hash_str);
+		let written = fs::read(&path).unwrap();
+		let names: Vec<_> = fs::read_dir(repo.objects_dir())
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+			.collect();
+		assert!(names.iter().all(|name| !name.starts_with("tmp_obj_")));
+
+		// An existing object is left alone.
+		fs::write(&path, b"placeholder").unwrap();
+		hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"hello\n")), true).unwrap();
+		assert_eq!(fs::read(&path).unwrap(), b"placeholder");
+		fs::write(&path, written).unwrap();
+		assert!(decode_object(&repo, hashed.hash_str).is_ok());
+	}
+
+	#[test]
+	fn hash_object_from_stdin() {
+		let (_dir, repo) = init_repo();
+		let hashed = hash_reader(&repo, &b"hello world\n"[..], false).unwrap();
+		assert_eq!(hashed.hash_str, "3b18e512dba79e4c8300dd08aeb37f8e728b8dad");
+		assert!(!repo.object_path(&hashed.hash_str).exists());
+
+		let hashed = hash_reader(&repo, &b"hello world\n"[..], true).unwrap();
+		assert!(repo.object_path(&hashed.hash_str).exists());
+
+		assert!(Args::try_parse_from(["git", "hash-object", "-w", "--stdin"]).is_ok());
+		assert!(Args::try_parse_from(["git", "hash-object", "--stdin", "file"]).is_err());
+		assert!(Args::try_parse_from(["git", "hash-object"]).is_err());
+	}
+
+	#[test]
+	fn commit_on_detached_head_rewrites_head() {
+		let (_dir, repo) = init_repo();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+
+		let commit = |message: &str| {
+			let mut out = Vec::new();
+			commit(&repo, message.to_string(), true, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+		let head = || fs::read_to_string(repo.git_dir().join("HEAD")).unwrap();
+		let master = || fs::read_to_string(repo.git_dir().join("refs/heads/master")).unwrap();
+
+		let first = commit("first");
+		assert_eq!(master(), first);
+
+		fs::write(repo.git_dir().join("HEAD"), &first).unwrap();
+		let second = commit("second");
+		assert_eq!(head(), second);
+		assert_eq!(master(), first);
+
+		// Like commit-tree, writing a commit object alone moves no ref.
+		create_commit(&repo, tree.hash_str, vec![], "dangling".into()).unwrap();
+		assert_eq!(head(), second);
+		assert_eq!(master(), first);
+	}
+
+	#[test]
+	fn commit_moves_the_branch_under_its_lock() {
+		let (_dir, repo) = init_repo();
+		let master = repo.git_dir().join("refs/heads/master");
+		commit(&repo, "first".into(), true, &mut Vec::new()).unwrap();
+		let first = fs::read_to_string(&master).unwrap();
+
+		fs::write(repo.git_dir().join("refs/heads/master.lock"), "").unwrap();
+		let err = commit(&repo, "second".into(), true, &mut Vec::new()).unwrap_err();
+		assert!(matches!(
+			err,
+			CommitError::UpdateHead(refs::UpdateRefError::Locked(_))
+		));
+		assert_eq!(fs::read_to_string(&master).unwrap(), first);
+	}
+
+	#[test]
+	fn commit_dates_from_env() {
+		let (_dir, repo) = init_repo();
+		let repo = Repo::new(repo.git_dir()).with_env(|name| match name {
+			"GIT_AUTHOR_DATE" => Some("@1700000000 +0000".into()),
+			"GIT_COMMITTER_DATE" => Some("Thu, 07 Apr 2005 22:13:13 +0200".into()),
+			_ => None,
+		});
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+
+		let commit = create_commit(&repo, tree.hash_str, vec![], "dated".into()).unwrap();
+		let (_, bytes) = inflate_object(&repo, commit.hash_str).unwrap();
+		let text = String::from_utf8(bytes).unwrap();
+		assert!(text.contains("\nauthor Foo Bar <foo@bar.com> 1700000000 +0000\n"));
+		assert!(text.contains("\ncommitter Foo Bar <foo@bar.com> 1112904793 +0200\n"));
+	}
+
+	#[test]
+	fn commit_identity_from_config_env() {
+		let (_dir, repo) = init_repo();
+		let repo = Repo::new(repo.git_dir()).with_env(|name| match name {
+			"GIT_CONFIG_COUNT" => Some("3".into()),
+			"GIT_CONFIG_KEY_0" => Some("user.name".into()),
+			"GIT_CONFIG_VALUE_0" => Some("Bot".into()),
+			"GIT_CONFIG_KEY_1" => Some("User.Email".into()),
+			"GIT_CONFIG_VALUE_1" => Some("bot@example.com".into()),
+			"GIT_CONFIG_KEY_2" => Some("committer.name".into()),
+			"GIT_CONFIG_VALUE_2" => Some("Committer Bot".into()),
+			"GIT_AUTHOR_DATE" => Some("@1700000000 +0000".into()),
+			"GIT_COMMITTER_DATE" => Some("@1700000000 +0000".into()),
+			_ => None,
+		});
+		assert_eq!(repo.config("USER.name"), Some("Bot"));
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+
+		let commit = create_commit(&repo, tree.hash_str, vec![], "configured".into()).unwrap();
+		let (_, bytes) = inflate_object(&repo, commit.hash_str).unwrap();
+		let text = String::from_utf8(bytes).unwrap();
+		assert!(text.contains("\nauthor Bot <bot@example.com> 1700000000 +0000\n"));
+		assert!(text.contains("\ncommitter Committer Bot <bot@example.com> 1700000000 +0000\n"));
+	}
+
+	#[test]
+	fn merge_commit_with_identity_from_env() {
+		let (_dir, repo) = init_repo();
+		let repo = Repo::new(repo.git_dir()).with_env(|name| match name {
+			"GIT_AUTHOR_NAME" => Some("Ada Lovelace".into()),
+			"GIT_AUTHOR_EMAIL" => Some("ada@example.com".into()),
+			"GIT_COMMITTER_NAME" => Some("Committer".into()),
+			_ => None,
+		});
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let (first, second) = ("1".repeat(40), "2".repeat(40));
+
+		let commit = create_commit(
+			&repo,
+			tree.hash_str.clone(),
+			vec![first.clone(), second.clone()],
+			"merge".into(),
+		)
+		.unwrap();
+		let (_, bytes) = inflate_object(&repo, commit.hash_str).unwrap();
+		let text = String::from_utf8(bytes).unwrap();
+		let headers = text.split_once('\0').unwrap().1;
+		let lines: Vec<_> = headers.lines().take(5).collect();
+		assert_eq!(lines[0], format!("tree {}", tree.hash_str));
+		assert_eq!(lines[1], format!("parent {first}"));
+		assert_eq!(lines[2], format!("parent {second}"));
+		assert!(lines[3].starts_with("author Ada Lovelace <ada@example.com> "));
+		assert!(lines[4].starts_with("committer Committer <foo@bar.com> "));
+
+		let err = create_commit(&repo, tree.hash_str, vec!["nope".into()], "x".into());
+		assert!(matches!(err, Err(CommitTreeError::InvalidParentSha1(_))));
+	}
+
+	#[test]
+	fn diff_no_index_of_files() {
+		let dir = TempDir::new();
+		let (old, new) = (dir.0.join("old.txt"), dir.0.join("new.txt"));
+		fs::write(&old, "one\ntwo\nthree\n").unwrap();
+		fs::write(&new, "one\n2\nthree\n").unwrap();
+
+		let mut out = Vec::new();
+		assert!(diff_no_index(&old, &new, DiffOptions::default(), &mut out).unwrap());
+		let (old, new) = (old.display(), new.display());
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!(
+				"diff --git a/{old} b/{new}\n\
+				 index 4cb29ea..f04eb26 100644\n\
+				 --- a/{old}\n\
+				 +++ b/{new}\n\
+				 @@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n"
+			)
+		);
+
+		let mut out = Vec::new();
+		let same = dir.0.join("old.txt");
+		assert!(!diff_no_index(&same, &same, DiffOptions::default(), &mut out).unwrap());
+		assert!(out.is_empty());
+		assert!(matches!(
+			diff_no_index(
+				&dir.0.join("missing"),
+				&same,
+				DiffOptions::default(),
+				&mut out
+			),
+			Err(DiffError::ReadFile { .. })
+		));
+
+		// No repository is needed.
+		let args = Args::try_parse_from(["git", "diff", "--no-index", "old.txt", "new.txt"]);
+		assert!(find_command_git_dir(&args.unwrap().command, &dir.0, None).is_ok());
+		let args = Args::try_parse_from(["git", "diff"]).unwrap();
+		assert!(find_command_git_dir(&args.command, &dir.0, None).is_err());
+	}
+
+	#[test]
+	fn diff_word_diff_marks_changed_words() {
+		let dir = TempDir::new();
+		let (old, new) = (dir.0.join("old.txt"), dir.0.join("new.txt"));
+		fs::write(&old, "one\ntwo words here\nthree\nten\n").unwrap();
+		fs::write(&new, "one\ntwo wards here  x\nthree\nTEN\nnew line\n").unwrap();
+
+		let args = Args::try_parse_from(["git", "diff", "--no-index", "--word-diff", "a", "b"]);
+		let Command::Diff { word_diff, .. } = args.unwrap().command else {
+			panic!("not a diff");
+		};
+		let options = DiffOptions {
+			word_diff,
+			..DiffOptions::default()
+		};
+		let mut out = Vec::new();
+		assert!(diff_no_index(&old, &new, options, &mut out).unwrap());
+		let (old, new) = (old.display(), new.display());
+		// Same as `git diff --word-diff`
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!(
+				"diff --git a/{old} b/{new}\n\
+				 index 2922738..2215ee3 100644\n\
+				 --- a/{old}\n\
+				 +++ b/{new}\n\
+				 @@ -1,4 +1,5 @@\n\
+				 one\n\
+				 two [-words-]{{+wards+}} here  {{+x+}}\n\
+				 three\n\
+				 [-ten-]{{+TEN+}}\n\
+				 {{+new line+}}\n"
+			)
+		);
+	}
+
+	#[test]
+	fn diff_binary_writes_binary_patches() {
+		let dir = TempDir::new();
+		let (old, new) = (dir.0.join("old.bin"), dir.0.join("new.bin"));
+		fs::write(&old, "a\0b\n").unwrap();
+		fs::write(&new, "a\0c\nmore\n").unwrap();
+
+		let args = Args::try_parse_from(["git", "diff", "--no-index", "--binary", "a", "b"]);
+		let Command::Diff { binary, .. } = args.unwrap().command else {
+			panic!("not a diff");
+		};
+		let options = DiffOptions {
+			binary,
+			..DiffOptions::default()
+		};
+		let mut out = Vec::new();
+		assert!(diff_no_index(&old, &new, options, &mut out).unwrap());
+		let patch = String::from_utf8(out).unwrap();
+		let (old, new) = (old.display(), new.display());
+		// Same as `git diff --binary`
+		assert_eq!(
+			patch,
+			format!(
+				"diff --git a/{old} b/{new}\n\
+				 index 1a23e4be731d2f539deeea324686d000ccdfbfcd..\
+				 f08f88b4ce7be307a5074c8c05e385c3419dac18 100644\n\
+				 GIT binary patch\n\
+				 literal 9\n\
+				 QcmYdfNao7TFG}}SC01RpZi~s-t\n\
+				 \n\
+				 literal 4\n\
+				 LcmYdfNa6wj0#*Rd\n\
+				 \n"
+			)
+		);
+		assert_eq!(
+			binary_patch::apply_binary_patch(&patch).unwrap(),
+			b"a\0c\nmore\n"
+		);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn diff_compares_symlinks_by_target() {
+		let (dir, repo) = init_repo();
+		fs::write(dir.0.join("a.txt"), "a\n").unwrap();
+		std::os::unix::fs::symlink("a.txt", dir.0.join("link")).unwrap();
+		std::os::unix::fs::symlink("missing", dir.0.join("dangling")).unwrap();
+		let paths = ["a.txt", "link", "dangling"].map(PathBuf::from);
+		update_index(&repo, &paths, true).unwrap();
+
+		let mut out = Vec::new();
+		assert!(!diff_worktree(&repo, DiffOptions::default(), &mut out).unwrap());
+		assert!(out.is_empty());
+
+		fs::remove_file(dir.0.join("link")).unwrap();
+		std::os::unix::fs::symlink("missing", dir.0.join("link")).unwrap();
+		assert!(diff_worktree(&repo, DiffOptions::default(), &mut out).unwrap());
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"diff --git a/link b/link\n\
+			 index 8d14cbf..6eab79a 120000\n\
+			 --- a/link\n\
+			 +++ b/link\n\
+			 @@ -1 +1 @@\n\
+			 -a.txt\n\
+			 \\ No newline at end of file\n\
+			 +missing\n\
+			 \\ No newline at end of file\n"
+		);
+	}
+
+	#[test]
+	fn diff_exit_code_reports_modified_files() {
+		let (dir, repo) = init_repo();
+		let entries = ["a.txt", "b.txt"].map(|path| {
+			fs::write(dir.0.join(path), "one\ntwo\n").unwrap();
+			let blob = GitObject::Blob(Cow::Borrowed(b"one\ntwo\n"));
+			(path, hash_git_object(&repo, blob, true).unwrap().hash)
+		});
+		fs::write(repo.index_path(), index_bytes(&entries, &[])).unwrap();
+
+		let mut out = Vec::new();
+		assert!(
+			check_differences(diff_worktree(&repo, DiffOptions::default(), &mut out), true).is_ok()
+		);
+		assert!(out.is_empty());
+
+		fs::write(dir.0.join("b.txt"), "one\n2\n").unwrap();
+		let err = check_differences(diff_worktree(&repo, DiffOptions::default(), &mut out), true)
+			.unwrap_err();
+		assert!(err.is::<DifferencesFound>());
+		assert!(check_differences(
+			diff_worktree(&repo, DiffOptions::default(), &mut Vec::new()),
+			false
+		)
+		.is_ok());
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"diff --git a/b.txt b/b.txt\n\
+			 index 814f4a4..99b356d 100644\n\
+			 --- a/b.txt\n\
+			 +++ b/b.txt\n\
+			 @@ -1,2 +1,2 @@\n one\n-two\n+2\n"
+		);
+
+		fs::remove_file(dir.0.join("a.txt")).unwrap();
+		let mut out = Vec::new();
+		assert!(diff_worktree(&repo, DiffOptions::default(), &mut out).unwrap());
+		assert!(String::from_utf8(out).unwrap().starts_with(
+			"diff --git a/a.txt b/a.txt\n\
+			 deleted file mode 100644\n\
+			 index 814f4a4..0000000\n\
+			 --- a/a.txt\n\
+			 +++ /dev/null\n\
+			 @@ -1,2 +0,0 @@\n"
+		));
+	}
+
+	#[test]
+	fn clean_removes_untracked_files() {
+		let (dir, repo) = init_repo();
+		let tracked = [".gitignore", "a.txt", "t/tracked.txt"].map(|path| (path, [1; 20]));
+		fs::write(repo.index_path(), index_bytes(&tracked, &[])).unwrap();
+		let files = [
+			(".gitignore", "*.log\nbuild/\n"),
+			("a.txt", ""),
+			("b.txt", ""),
+			("c.log", ""),
+			("build/out", ""),
+			("t/tracked.txt", ""),
+			("t/new.txt", ""),
+			("u/x.txt", ""),
+			("u/y.log", ""),
+			("v/z.txt", ""),
+		];
+		for (path, content) in files {
+			let path = dir.0.join(path);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, content).unwrap();
+		}
+		let run = |options| {
+			let mut out = Vec::new();
+			clean(&repo, options, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+
+		assert!(matches!(
+			clean(&repo, CleanOptions::default(), &mut Vec::new()),
+			Err(CleanError::NotForced)
+		));
+		let dry_run = CleanOptions {
+			dry_run: true,
+			directories: true,
+			..Default::default()
+		};
+		assert_eq!(
+			run(dry_run),
+			"Would remove b.txt\nWould remove t/new.txt\nWould remove u/x.txt\nWould remove v/\n"
+		);
+		assert!(dir.0.join("b.txt").exists());
+
+		let force = CleanOptions {
+			force: true,
+			..Default::default()
+		};
+		assert_eq!(run(force), "Removing b.txt\nRemoving t/new.txt\n");
+		assert!(!dir.0.join("b.txt").exists());
+		assert!(dir.0.join("u/x.txt").exists());
+
+		let everything = CleanOptions {
+			force: true,
+			directories: true,
+			ignored: true,
+			..Default::default()
+		};
+		assert_eq!(
+			run(everything),
+			"Removing build/\nRemoving c.log\nRemoving u/\nRemoving v/\n"
+		);
+		let mut left = Vec::new();
+		for entry in fs::read_dir(&dir.0).unwrap() {
+			left.push(entry.unwrap().file_name().into_string().unwrap());
+		}
+		left.sort();
+		assert_eq!(left, [".git", ".gitignore", "a.txt", "t"]);
+	}
+
+	#[test]
+	fn commit_records_the_index_on_the_current_branch() {
+		let (dir, repo) = init_repo();
+		let commit_output = |message: &str| {
+			let mut out = Vec::new();
+			commit(&repo, message.to_string(), false, &mut out).unwrap();
+			String::from_utf8(out).unwrap().trim_end().to_string()
+		};
+
+		fs::write(dir.0.join("a.txt"), "a\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], true).unwrap();
+		let first = commit_output("first");
+		assert_eq!(
+			refs::resolve_ref(&repo, "refs/heads/master").unwrap(),
+			Some(first.clone())
+		);
+		let GitObject::Commit(first_commit) = decode_object(&repo, first.clone()).unwrap() else {
+			panic!("not a commit");
+		};
+		assert!(first_commit.parents.is_empty());
+		assert_eq!(first_commit.message, "first\n");
+		let index = read_index(&repo).unwrap();
+		assert_eq!(
+			first_commit.tree,
+			write_tree_from_index(&repo, &index.entries).unwrap()
+		);
+
+		fs::write(dir.0.join("a.txt"), "changed\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], false).unwrap();
+		let second = commit_output("second");
+		let GitObject::Commit(second_commit) = decode_object(&repo, second.clone()).unwrap() else {
+			panic!("not a commit");
+		};
+		assert_eq!(hex::encode(second_commit.parents[0]), first);
+		assert_ne!(second_commit.tree, first_commit.tree);
+		assert_eq!(
+			refs::resolve_ref(&repo, "HEAD").unwrap(),
+			Some(second.clone())
+		);
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+
+			let hook = repo.git_dir().join("hooks/pre-commit");
+			fs::create_dir_all(hook.parent().unwrap()).unwrap();
+			fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+			fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+			assert!(matches!(
+				commit(&repo, "rejected".to_string(), false, &mut Vec::new()),
+				Err(CommitError::Hook(hooks::HookError::Failed { .. }))
+			));
+			assert_eq!(refs::resolve_ref(&repo, "HEAD").unwrap(), Some(second));
+			commit(&repo, "unverified".to_string(), true, &mut Vec::new()).unwrap();
+		}
+	}
+
+	#[test]
+	fn branches_are_listed_created_and_deleted() {
+		let (dir, repo) = init_repo();
+		let list = || {
+			let mut out = Vec::new();
+			list_branches(&repo, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+		assert!(matches!(
+			create_branch(&repo, "topic", None),
+			Err(BranchError::InvalidStartPoint(_))
+		));
+
+		fs::write(dir.0.join("a.txt"), "a\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], true).unwrap();
+		commit(&repo, "first".to_string(), true, &mut Vec::new()).unwrap();
+		let first = refs::resolve_ref(&repo, "HEAD").unwrap().unwrap();
+		create_branch(&repo, "merged", None).unwrap();
+		fs::write(dir.0.join("a.txt"), "b\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], false).unwrap();
+		commit(&repo, "second".to_string(), true, &mut Vec::new()).unwrap();
+		let second = refs::resolve_ref(&repo, "HEAD").unwrap().unwrap();
+		create_branch(&repo, "feature/x", Some(&second)).unwrap();
+
+		assert_eq!(list(), "  feature/x\n* master\n  merged\n");
+		for name in ["a..b", "HEAD", "-x", "x.lock"] {
+			assert!(matches!(
+				create_branch(&repo, name, None),
+				Err(BranchError::InvalidName(_))
+			));
+		}
+		assert!(matches!(
+			create_branch(&repo, "merged", None),
+			Err(BranchError::AlreadyExists(_))
+		));
+		assert!(matches!(
+			delete_branch(&repo, "master", false, &mut Vec::new()),
+			Err(BranchError::CheckedOut(_))
+		));
+		assert!(matches!(
+			delete_branch(&repo, "missing", false, &mut Vec::new()),
+			Err(BranchError::NotFound(_))
+		));
+
+		let mut out = Vec::new();
+		delete_branch(&repo, "merged", false, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!("Deleted branch merged (was {}).\n", &first[..7])
+		);
+
+		// Detached at the first commit, the second one isn't merged.
+		fs::write(repo.git_dir().join("HEAD"), format!("{first}\n")).unwrap();
+		assert_eq!(
+			list(),
+			format!(
+				"* (HEAD detached at {})\n  feature/x\n  master\n",
+				&first[..7]
+			)
+		);
+		assert!(matches!(
+			delete_branch(&repo, "feature/x", false, &mut Vec::new()),
+			Err(BranchError::NotMerged(_))
+		));
+		delete_branch(&repo, "feature/x", true, &mut Vec::new()).unwrap();
+		assert_eq!(
+			refs::resolve_ref(&repo, "refs/heads/feature/x").unwrap(),
+			None
+		);
+	}
+
+	#[test]
+	fn rev_list_lists_commits_then_objects() {
+		let (dir, repo) = init_repo();
+		fs::create_dir(dir.0.join("d")).unwrap();
+		fs::write(dir.0.join("d/a.txt"), "a\n").unwrap();
+		fs::write(dir.0.join("b.txt"), "a\n").unwrap();
+		update_index(
+			&repo,
+			&[PathBuf::from("d/a.txt"), PathBuf::from("b.txt")],
+			true,
+		)
+		.unwrap();
+		commit(&repo, "first".to_string(), true, &mut Vec::new()).unwrap();
+		let first = refs::resolve_ref(&repo, "HEAD").unwrap().unwrap();
+		create_tag(&repo, "v1", None, Some("one".to_string())).unwrap();
+		let tag = refs::resolve_ref(&repo, "refs/tags/v1").unwrap().unwrap();
+		fs::write(dir.0.join("b.txt"), "b\n").unwrap();
+		update_index(&repo, &[PathBuf::from("b.txt")], false).unwrap();
+		commit(&repo, "second".to_string(), true, &mut Vec::new()).unwrap();
+		let second = refs::resolve_ref(&repo, "HEAD").unwrap().unwrap();
+
+		let run = |revisions: &[&str], objects| {
+			let revisions: Vec<String> = revisions.iter().map(|r| r.to_string()).collect();
+			let mut out = Vec::new();
+			rev_list(&repo, &revisions, objects, &mut out).map(|()| String::from_utf8(out).unwrap())
+		};
+		assert_eq!(
+			run(&["HEAD"], false).unwrap(),
+			format!("{second}\n{first}\n")
+		);
+		assert_eq!(run(&["v1"], false).unwrap(), format!("{first}\n"));
+
+		let hash = |name: &str| revision::resolve_revision(&repo, name).unwrap();
+		// The first b.txt is the same blob as d/a.txt, and d didn't change: they aren't listed
+		// again for the first commit.
+		assert_eq!(
+			run(&["master", "v1"], true).unwrap(),
+			format!(
+				"{second}\n{first}\n{tag} v1\n{} \n{} b.txt\n{} d\n{} d/a.txt\n{} \n",
+				hash("HEAD:"),
+				hash("HEAD:b.txt"),
+				hash("HEAD:d"),
+				hash("HEAD:d/a.txt"),
+				hash("HEAD~:"),
+			)
+		);
+		assert!(matches!(
+			run(&["HEAD:b.txt"], false),
+			Err(RevListError::InvalidRevision(_))
+		));
+	}
+
+	#[test]
+	fn describe_names_commits_after_the_nearest_tag() {
+		let (dir, repo) = init_repo();
+		let commit_file = |content: &str| {
+			fs::write(dir.0.join("a.txt"), content).unwrap();
+			update_index(&repo, &[PathBuf::from("a.txt")], true).unwrap();
+			commit(&repo, content.to_string(), true, &mut Vec::new()).unwrap();
+			refs::resolve_ref(&repo, "HEAD").unwrap().unwrap()
+		};
+		let run = |name: &str, lightweight| {
+			let mut out = Vec::new();
+			describe(&repo, name, lightweight, &mut out).map(|()| String::from_utf8(out).unwrap())
+		};
+
+		commit_file("1");
+		assert!(matches!(run("HEAD", true), Err(DescribeError::NoTags(_))));
+		create_tag(&repo, "light", None, None).unwrap();
+		assert!(matches!(
+			run("HEAD", false),
+			Err(DescribeError::NoAnnotatedTags(_))
+		));
+		assert_eq!(run("HEAD", true).unwrap(), "light\n");
+
+		// Annotated tags win over lightweight ones of the same commit.
+		create_tag(&repo, "v1", None, Some("one".to_string())).unwrap();
+		assert_eq!(run("HEAD", true).unwrap(), "v1\n");
+		commit_file("2");
+		create_tag(&repo, "light2", None, None).unwrap();
+		let third = commit_file("3");
+
+		assert_eq!(
+			run("HEAD", false).unwrap(),
+			format!("v1-2-g{}\n", &third[..7])
+		);
+		assert_eq!(
+			run("HEAD", true).unwrap(),
+			format!("light2-1-g{}\n", &third[..7])
+		);
+		assert_eq!(run("v1", false).unwrap(), "v1\n");
+		assert!(matches!(
+			run("nope", false),
+			Err(DescribeError::InvalidRevision(_))
+		));
+	}
+
+	#[test]
+	fn for_each_ref_formats_and_sorts_refs() {
+		let (dir, repo) = init_repo();
+		fs::write(dir.0.join("a.txt"), "a\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], true).unwrap();
+		commit(&repo, "first".to_string(), true, &mut Vec::new()).unwrap();
+		let head = refs::resolve_ref(&repo, "HEAD").unwrap().unwrap();
+		create_tag(&repo, "v1", None, Some("one".to_string())).unwrap();
+		let tag = refs::resolve_ref(&repo, "refs/tags/v1").unwrap().unwrap();
+		create_branch(&repo, "topic", None).unwrap();
+
+		let run = |patterns: &[&str], format: Option<&str>, sort: Option<&str>| {
+			let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+			let mut out = Vec::new();
+			for_each_ref(&repo, &patterns, format, sort, &mut out)
+				.map(|()| String::from_utf8(out).unwrap())
+		};
+		assert_eq!(
+			run(&[], None, None).unwrap(),
+			format!(
+				"{head} commit\trefs/heads/master\n{head} commit\trefs/heads/topic\n\
+				 {tag} tag\trefs/tags/v1\n"
+			)
+		);
+		assert_eq!(
+			run(
+				&["refs/heads"],
+				Some("%(refname:short)%%%(objectname:short)"),
+				None
+			)
+			.unwrap(),
+			format!("master%{0}\ntopic%{0}\n", &head[..7])
+		);
+		assert_eq!(
+			run(&["refs/*/t*"], Some("%(refname)"), Some("-refname")).unwrap(),
+			"refs/heads/topic\n"
+		);
+		assert_eq!(
+			run(&["refs/hea"], None, None).unwrap(),
+			"",
+			"prefixes end at a slash"
+		);
+		assert_eq!(
+			run(&[], Some("%(objecttype)"), Some("-objecttype")).unwrap(),
+			"tag\ncommit\ncommit\n"
+		);
+		assert!(matches!(
+			run(&[], Some("%(nope)"), None),
+			Err(ForEachRefError::UnknownField(_))
+		));
+		assert!(matches!(
+			run(&[], Some("%(refname"), None),
+			Err(ForEachRefError::MalformedFormat(_))
+		));
+	}
+
+	#[test]
+	fn symbolic_refs_are_read_and_written() {
+		let (_dir, repo) = init_repo();
+		let read = |name: &str| {
+			let mut out = Vec::new();
+			symbolic_ref(&repo, name, None, &mut out).map(|()| String::from_utf8(out).unwrap())
+		};
+		assert_eq!(read("HEAD").unwrap(), "refs/heads/master\n");
+
+		symbolic_ref(&repo, "HEAD", Some("refs/heads/topic"), &mut Vec::new()).unwrap();
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			"ref: refs/heads/topic\n"
+		);
+		// Followed all the way.
+		symbolic_ref(
+			&repo,
+			"refs/heads/topic",
+			Some("refs/heads/other"),
+			&mut Vec::new(),
+		)
+		.unwrap();
+		assert_eq!(read("HEAD").unwrap(), "refs/heads/other\n");
+
+		assert!(matches!(
+			symbolic_ref(&repo, "HEAD", Some("master"), &mut Vec::new()),
+			Err(SymbolicRefError::OutsideRefs(_))
+		));
+		assert!(matches!(
+			symbolic_ref(&repo, "HEAD", Some("refs/heads/a..b"), &mut Vec::new()),
+			Err(SymbolicRefError::InvalidTarget(_))
+		));
+		for name in ["../../escaped", "/tmp/abs", "topic"] {
+			assert!(matches!(
+				symbolic_ref(&repo, name, Some("refs/heads/x"), &mut Vec::new()),
+				Err(SymbolicRefError::UpdateRef(refs::UpdateRefError::BadName(
+					_
+				)))
+			));
+			assert!(matches!(
+				read(name),
+				Err(SymbolicRefError::UpdateRef(refs::UpdateRefError::BadName(
+					_
+				)))
+			));
+		}
+		assert!(!repo.git_dir().join("../../escaped").exists());
+		refs::write_ref(&repo, "HEAD", &"1".repeat(40)).unwrap();
+		assert!(matches!(
+			read("HEAD"),
+			Err(SymbolicRefError::NotSymbolic(_))
+		));
+	}
+
+	#[test]
+	fn fast_export_takes_ranges_and_tags() {
+		let (dir, repo) = init_repo();
+		let repo = repo.with_env(|name| match name {
+			"GIT_AUTHOR_NAME" | "GIT_COMMITTER_NAME" => Some("A".into()),
+			"GIT_AUTHOR_EMAIL" | "GIT_COMMITTER_EMAIL" => Some("a@x".into()),
+			"GIT_AUTHOR_DATE" | "GIT_COMMITTER_DATE" => Some("1700000000 +0000".into()),
+			_ => None,
+		});
+		for n in 1..=3 {
+			for path in [format!("f{n}"), "common".to_string()] {
+				fs::write(dir.0.join(&path), format!("{n}\n")).unwrap();
+				update_index(&repo, &[PathBuf::from(path)], true).unwrap();
+			}
+			commit(&repo, format!("c{n}"), true, &mut Vec::new()).unwrap();
+		}
+		create_tag(&repo, "v1", Some("HEAD~1"), Some("tag msg".to_string())).unwrap();
+		let export = |revs: &[&str]| {
+			let revs: Vec<_> = revs.iter().map(|rev| rev.to_string()).collect();
+			let mut out = Vec::new();
+			fast_export(&repo, &revs, &mut out).map(|()| String::from_utf8(out).unwrap())
+		};
+
+		// Same as `git fast-export HEAD~2..master`
+		let commit = |message, from: &str, changes| {
+			format!(
+				"commit refs/heads/master\n\
+				 mark :{}\n\
+				 author A <a@x> 1700000000 +0000\n\
+				 committer A <a@x> 1700000000 +0000\n\
+				 data 3\n{message}\n{from}{changes}\n",
+				if message == "c2" { 3 } else { 5 }
+			)
+		};
+		assert_eq!(
+			export(&["HEAD~2..master"]).unwrap(),
+			"blob\nmark :1\ndata 2\n2\n\nblob\nmark :2\ndata 2\n1\n\n".to_string()
+				+ &commit(
+					"c2",
+					"",
+					"M 100644 :1 common\nM 100644 :2 f1\nM 100644 :1 f2\n"
+				) + "blob\nmark :4\ndata 2\n3\n\n"
+				+ &commit("c3", "from :3\n", "M 100644 :4 common\nM 100644 :4 f3\n")
+		);
+		assert_eq!(export(&["master..master"]).unwrap(), "");
+		assert!(matches!(
+			export(&["nope..master"]),
+			Err(FastExportError::InvalidRevision(rev)) if rev == "nope"
+		));
+
+		// Tags are peeled to their commit and written after it.
+		let stream = export(&["master", "v1"]).unwrap();
+		assert!(stream.ends_with(
+			"M 100644 :5 f3\n\n\
+			 tag v1\nfrom :4\ntagger A <a@x> 1700000000 +0000\ndata 8\ntag msg\n\n"
+		));
+		let (_other_dir, other) = init_repo();
+		let imported = fast_import::import(&other, stream.as_bytes()).unwrap();
+		let names = ["refs/heads/master", "refs/tags/v1"];
+		for (name, (imported_name, id)) in names.iter().zip(imported) {
+			assert_eq!(imported_name, *name);
+			assert_eq!(
+				Some(hex::encode(id)),
+				refs::resolve_ref(&repo, name).unwrap()
+			);
+		}
+	}
+
+	#[test]
+	fn tags_are_lightweight_or_annotated() {
+		let (dir, repo) = init_repo();
+		fs::write(dir.0.join("a.txt"), "a\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], true).unwrap();
+		commit(&repo, "first".to_string(), true, &mut Vec::new()).unwrap();
+		let head = refs::resolve_ref(&repo, "HEAD").unwrap().unwrap();
+
+		create_tag(&repo, "light", None, None).unwrap();
+		assert_eq!(
+			refs::resolve_ref(&repo, "refs/tags/light").unwrap(),
+			Some(head.clone())
+		);
+
+		let repo = repo.with_env(|name| match name {
+			"GIT_COMMITTER_NAME" => Some("T Agger".into()),
+			"GIT_COMMITTER_EMAIL" => Some("t@example.com".into()),
+			"GIT_COMMITTER_DATE" => Some("1700000000 +0100".into()),
+			_ => None,
+		});
+		create_tag(
+			&repo,
+			"v1.0",
+			Some("light"),
+			Some("Release 1.0".to_string()),
+		)
+		.unwrap();
+		let tag = refs::resolve_ref(&repo, "refs/tags/v1.0").unwrap().unwrap();
+		let (kind, payload) = read_object_raw(&repo, tag.clone()).unwrap();
+		assert_eq!(kind, ObjectKind::Tag);
+		assert_eq!(
+			String::from_utf8(payload).unwrap(),
+			format!(
+				"object {head}\ntype commit\ntag v1.0\ntagger T Agger <t@example.com> 1700000000 +0100\n\nRelease 1.0\n"
+			)
+		);
+		assert_eq!(
+			revision::resolve_revision(&repo, "v1.0^{commit}").unwrap(),
+			head
+		);
+
+		let mut out = Vec::new();
+		list_tags(&repo, &mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), "light\nv1.0\n");
+		assert!(matches!(
+			create_tag(&repo, "v1.0", None, None),
+			Err(TagError::AlreadyExists(_))
+		));
+		assert!(matches!(
+			create_tag(&repo, "bad^name", None, None),
+			Err(TagError::InvalidName(_))
+		));
+		assert!(matches!(
+			create_tag(&repo, "other", Some("missing"), None),
+			Err(TagError::InvalidObject(_))
+		));
+	}
+
+	#[test]
+	fn log_walks_history_from_head() {
+		let (_dir, repo) = init_repo();
+		let repo = Repo::new(repo.git_dir()).with_env(|name| match name {
+			"GIT_AUTHOR_DATE" => Some("@1700000000 +0100".into()),
+			_ => None,
+		});
+		let options = |graph, oneline, max_count| LogOptions {
+			graph,
+			oneline,
+			max_count,
+			revision: None,
+		};
+		let log_output = |options| {
+			let mut out = Vec::new();
+			log(&repo, options, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+		assert!(matches!(
+			log(&repo, options(false, false, None), &mut Vec::new()),
+			Err(LogError::UnbornBranch(branch)) if branch == "master"
+		));
+
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let first = create_commit(&repo, tree.hash_str.clone(), vec![], "first".into()).unwrap();
+		let second = create_commit(
+			&repo,
+			tree.hash_str.clone(),
+			vec![first.hash_str.clone()],
+			"second\n\nbody".into(),
+		)
+		.unwrap();
+		refs::write_ref(&repo, "refs/heads/master", &second.hash_str).unwrap();
+
+		assert_eq!(
+			log_output(options(true, false, None)),
+			format!("* {} second\n* {} first\n", second.hash_str, first.hash_str)
+		);
+		assert_eq!(
+			log_output(options(false, false, None)),
+			format!(
+				"commit {}\n\
+				 Author: Foo Bar <foo@bar.com>\n\
+				 Date:   Tue Nov 14 23:13:20 2023 +0100\n\
+				 \n    second\n    \n    body\n\
+				 \n\
+				 commit {}\n\
+				 Author: Foo Bar <foo@bar.com>\n\
+				 Date:   Tue Nov 14 23:13:20 2023 +0100\n\
+				 \n    first\n",
+				second.hash_str, first.hash_str
+			)
+		);
+
+		// Merged branches are only shown in the graph.
+		let side = create_commit(&repo, tree.hash_str.clone(), vec![], "side".into()).unwrap();
+		let merge = create_commit(
+			&repo,
+			tree.hash_str,
+			vec![second.hash_str.clone(), side.hash_str.clone()],
+			"merge".into(),
+		)
+		.unwrap();
+		refs::write_ref(&repo, "refs/heads/master", &merge.hash_str).unwrap();
+		assert_eq!(
+			log_output(options(false, true, None)),
+			format!(
+				"{} merge\n{} second\n{} first\n",
+				merge.hash_str, second.hash_str, first.hash_str
+			)
+		);
+		assert!(log_output(options(true, false, None)).contains(" side\n"));
+		assert_eq!(
+			log_output(options(false, false, Some(1))),
+			format!(
+				"commit {}\n\
+				 Merge: {} {}\n\
+				 Author: Foo Bar <foo@bar.com>\n\
+				 Date:   Tue Nov 14 23:13:20 2023 +0100\n\
+				 \n    merge\n",
+				merge.hash_str,
+				&second.hash_str[..7],
+				&side.hash_str[..7]
+			)
+		);
+		assert_eq!(log_output(options(true, false, Some(0))), "");
+
+		let from = |revision: &str| LogOptions {
+			revision: Some(revision.to_string()),
+			..options(false, true, None)
+		};
+		assert_eq!(
+			log_output(from(&second.hash_str[..7])),
+			format!("{} second\n{} first\n", second.hash_str, first.hash_str)
+		);
+		assert_eq!(
+			log_output(from("master")),
+			log_output(options(false, true, None))
+		);
+		assert!(matches!(
+			log(&repo, from("nope"), &mut Vec::new()),
+			Err(LogError::InvalidRevision(revision)) if revision == "nope"
+		));
+		assert!(matches!(
+			log(
+				&repo,
+				from(&format!("{}^{{tree}}", merge.hash_str)),
+				&mut Vec::new()
+			),
+			Err(LogError::InvalidRevision(_))
+		));
+	}
+
+	#[test]
+	fn staging_honors_git_index_file() {
+		let (dir, repo) = init_repo();
+		let index_file = dir.0.join("alt-index");
+		let repo = Repo::new(repo.git_dir()).with_env(|name| {
+			(name == "GIT_INDEX_FILE").then(|| index_file.clone().into_os_string())
+		});
+		assert_eq!(repo.index_path(), index_file);
+
+		fs::write(dir.0.join("a.txt"), "a\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a.txt")], true).unwrap();
+		assert!(index_file.exists());
+		assert!(!repo.git_dir().join("index").exists());
+
+		let index = parse_index(&fs::read(&index_file).unwrap()).unwrap();
+		let staged: Vec<_> = index.entries.iter().map(|e| e.path.as_str()).collect();
+		assert_eq!(staged, ["a.txt"]);
+		assert_eq!(read_index(&repo).unwrap().entries.len(), 1);
+	}
+
+	#[test]
+	fn merge_tree_with_clean_and_conflicting_files() {
+		let (_dir, repo) = init_repo();
+		let tree = |files: &[(&str, &str)]| {
+			let entries = files
+				.iter()
+				.map(|(name, content)| {
+					let blob = GitObject::Blob(Cow::Borrowed(content.as_bytes()));
+					TreeEntry {
+						mode: 0o100644,
+						name: Cow::Owned(name.to_string()),
+						object_hash: Cow::Owned(hash_git_object(&repo, blob, true).unwrap().hash),
+					}
+				})
+				.collect::<Vec<_>>();
+			hash_git_object(&repo, GitObject::Tree(Cow::Owned(entries)), true)
+				.unwrap()
+				.hash
+		};
+
+		let base = tree(&[("clean.txt", "1\n2\n3\n"), ("conflict.txt", "base\n")]);
+		let ours = tree(&[("clean.txt", "one\n2\n3\n"), ("conflict.txt", "ours\n")]);
+		let theirs = tree(&[
+			("clean.txt", "1\n2\nthree\n"),
+			("conflict.txt", "theirs\n"),
+			("new.txt", "new\n"),
+		]);
+
+		let entries = merge::merge_trees(&repo, Some(&base), &ours, &theirs).unwrap();
+		let paths: Vec<_> = entries.iter().map(|e| e.path()).collect();
+		assert_eq!(paths, ["clean.txt", "conflict.txt", "new.txt"]);
+
+		let merge::MergeEntry::Clean { stage, .. } = &entries[0] else {
+			panic!("clean.txt should merge cleanly");
+		};
+		let expected = GitObject::Blob(Cow::Borrowed(b"one\n2\nthree\n"));
+		assert_eq!(
+			stage.hash,
+			hash_git_object(&repo, expected, false).unwrap().hash
+		);
+
+		let merge::MergeEntry::Conflict { kind, content, .. } = &entries[1] else {
+			panic!("conflict.txt should conflict");
+		};
+		assert_eq!(*kind, merge::ConflictKind::Content);
+		assert_eq!(
+			content.as_deref(),
+			Some(&b"<<<<<<< .our\nours\n=======\ntheirs\n>>>>>>> .their\n"[..])
+		);
+		assert!(matches!(entries[2], merge::MergeEntry::Clean { .. }));
+	}
+
+	#[test]
+	fn object_names_are_trimmed_and_lowercased() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"what is up, doc?"));
+		let hashed = hash_git_object(&repo, blob, true).unwrap();
+
+		let padded = format!("  {}\n", hashed.hash_str.to_ascii_uppercase());
+		let object = normalize_object_name(&padded).unwrap();
+		assert_eq!(object, hashed.hash_str);
+		assert!(matches!(
+			decode_object(&repo, object),
+			Ok(GitObject::Blob(content)) if *content == *b"what is up, doc?"
+		));
+
+		assert_eq!(normalize_object_name(&hashed.hash_str[..3]), None);
+		assert_eq!(
+			normalize_object_name(&format!("{}0", hashed.hash_str)),
+			None
+		);
+		assert_eq!(
+			normalize_object_name(&format!("{}g", &hashed.hash_str[..39])),
+			None
+		);
+	}
+
+	#[test]
+	fn abbreviations_grow_until_unique() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"abbreviate me"));
+		let hashed = hash_git_object(&repo, blob, true).unwrap();
+		assert_eq!(min_unique_abbrev_len(&repo, &hashed.hash, 7).unwrap(), 7);
+		assert_eq!(min_unique_abbrev_len(&repo, &hashed.hash, 1).unwrap(), 4);
+
+		// Another object sharing the first 4 digits only
+		let fifth = if &hashed.hash_str[4..5] == "0" {
+			"1"
+		} else {
+			"0"
+		};
+		let twin = format!("{}{fifth}{}", &hashed.hash_str[..4], "0".repeat(35));
+		fs::write(repo.object_path(&twin), "").unwrap();
+		assert_eq!(min_unique_abbrev_len(&repo, &hashed.hash, 4).unwrap(), 5);
+		assert_eq!(min_unique_abbrev_len(&repo, &hashed.hash, 7).unwrap(), 7);
+	}
+
+	#[test]
+	fn abbreviated_object_names() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"abbreviate me"));
+		let hashed = hash_git_object(&repo, blob, true).unwrap();
+
+		for len in [4, 7, 39, 40] {
+			assert!(matches!(
+				decode_object(&repo, hashed.hash_str[..len].to_string()),
+				Ok(GitObject::Blob(content)) if *content == *b"abbreviate me"
+			));
+		}
+		let other = if hashed.hash_str.starts_with("ffff") {
+			"0000"
+		} else {
+			"ffff"
+		};
+		assert!(matches!(
+			decode_object(&repo, other.to_string()),
+			Err(ReadObjectError::ObjectNotFound(_))
+		));
+
+		// A second object with the same 7 character prefix
+		let twin = format!("{}{}", &hashed.hash_str[..7], "0".repeat(33));
+		let twin = if twin == hashed.hash_str {
+			format!("{}{}", &hashed.hash_str[..7], "1".repeat(33))
+		} else {
+			twin
+		};
+		fs::write(repo.object_path(&twin), "").unwrap();
+		let err = decode_object(&repo, hashed.hash_str[..7].to_string())
+			.err()
+			.unwrap();
+		let ReadObjectError::AmbiguousObjectName { candidates, .. } = err else {
+			panic!("expected an ambiguous name, got {err}");
+		};
+		let mut expected = vec![hashed.hash_str.clone(), twin];
+		expected.sort();
+		assert_eq!(candidates, expected);
+		assert!(decode_object(&repo, hashed.hash_str[..8].to_string()).is_ok());
+	}
+
+	#[test]
+	fn corrupted_object_error_names_the_object() {
+		let (_dir, repo) = init_repo();
+		let hash = "0123456789abcdef0123456789abcdef01234567";
+		let path = repo.object_path(hash);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+		encoder.write_all(b"tree \0").unwrap();
+		fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+		// Verification would catch it first.
+		let repo = repo.with_verify_objects(false);
+		let err = decode_object(&repo, hash.to_string()).err().unwrap();
+		assert_eq!(
+			err.to_string(),
+			format!("Corrupted object {hash}: missing object size")
+		);
+	}
+
+	#[test]
+	fn objects_not_hashing_to_their_name_are_rejected() {
+		let (_dir, repo) = init_repo();
+		let hashed =
+			hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"original\n")), true).unwrap();
+		let other =
+			hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"tampered\n")), true).unwrap();
+		let path = repo.object_path(&hashed.hash_str);
+		fs::copy(repo.object_path(&other.hash_str), &path).unwrap();
+
+		let err = decode_object(&repo, hashed.hash_str.clone()).err().unwrap();
+		let ReadObjectError::HashMismatch { expected, actual } = err else {
+			panic!("expected a hash mismatch, got {err}");
+		};
+		assert_eq!(
+			(expected, actual),
+			(hashed.hash_str.clone(), other.hash_str)
+		);
+
+		let repo = repo.with_verify_objects(false);
+		let GitObject::Blob(content) = decode_object(&repo, hashed.hash_str).unwrap() else {
+			panic!("not a blob");
+		};
+		assert_eq!(&content[..], b"tampered\n");
+	}
+
+	#[test]
+	fn ls_tree_reads_object_names_from_stdin() {
+		let (_dir, repo) = init_repo();
+		let tree = |names: &[&str]| {
+			let blob = GitObject::Blob(Cow::Borrowed(b""));
+			let blob = hash_git_object(&repo, blob, true).unwrap().hash;
+			let entries = names
+				.iter()
+				.map(|name| TreeEntry {
+					mode: 0o100644,
+					name: Cow::Owned(name.to_string()),
+					object_hash: Cow::Owned(blob),
+				})
+				.collect::<Vec<_>>();
+			hash_git_object(&repo, GitObject::Tree(Cow::Owned(entries)), true)
+				.unwrap()
+				.hash_str
+		};
+		let first = tree(&["a.txt", "b.txt"]);
+		let second = tree(&["c.txt"]);
+
+		let input = format!("{first}\n\n{second}\n");
+		let mut out = Vec::new();
+		for_each_object_name(input.as_bytes(), &mut out, |object, out| {
+			ls_tree(&repo, object, true, false, out)
+		})
+		.unwrap();
+
+		assert_eq!(String::from_utf8(out).unwrap(), "a.txt\nb.txt\n\nc.txt\n");
+	}
+
+	#[test]
+	fn compute_id_matches_written_objects() {
+		let (_dir, repo) = init_repo();
+		let blob =
+			hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"hello\n")), true).unwrap();
+		assert_eq!(compute_id(ObjectKind::Blob, b"hello\n"), blob.hash);
+		assert_eq!(
+			hex::encode(compute_id(ObjectKind::Blob, b"")),
+			"e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+		);
+
+		let entries = [TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed("hello.txt"),
+			object_hash: Cow::Owned(blob.hash),
+		}];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&entries)), true).unwrap();
+		let (_, bytes) = inflate_object(&repo, tree.hash_str).unwrap();
+		let payload = &bytes[bytes.iter().position(|b| *b == 0).unwrap() + 1..];
+		assert_eq!(compute_id(ObjectKind::Tree, payload), tree.hash);
+	}
+
+	#[test]
+	fn ls_tree_defaults_to_full_entries() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"a\n"));
+		let blob = hash_git_object(&repo, blob, true).unwrap();
+		let subtree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let entry = |mode, name, hash| TreeEntry {
+			mode,
+			name: Cow::Borrowed(name),
+			object_hash: Cow::Owned(hash),
+		};
+		let entries = [
+			entry(0o100644, "a.txt", blob.hash),
+			entry(0o40000, "dir", subtree.hash),
+			entry(0o100755, "run.sh", blob.hash),
+		];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&entries)), true).unwrap();
+
+		let args = Args::try_parse_from(["git", "ls-tree", &tree.hash_str]).unwrap();
+		let Command::LsTree {
+			name_only,
+			recursive,
+			object: Some(object),
+			..
+		} = args.command
+		else {
+			panic!("not ls-tree");
+		};
+		let mut out = Vec::new();
+		ls_tree(&repo, object, name_only, recursive, &mut out).unwrap();
+		let (blob, subtree) = (blob.hash_str, subtree.hash_str);
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!(
+				"100644 blob {blob}\ta.txt\n040000 tree {subtree}\tdir\n100755 blob {blob}\trun.sh\n"
+			)
+		);
+	}
+
+	#[test]
+	fn ls_tree_recurses_into_subtrees() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"content"));
+		let blob = hash_git_object(&repo, blob, true).unwrap().hash;
+		let tree = |entries: &[(u32, &str, [u8; 20])]| {
+			let entries = entries
+				.iter()
+				.map(|(mode, name, hash)| TreeEntry {
+					mode: *mode,
+					name: Cow::Owned(name.to_string()),
+					object_hash: Cow::Owned(*hash),
+				})
+				.collect::<Vec<_>>();
+			hash_git_object(&repo, GitObject::Tree(Cow::Owned(entries)), true)
+				.unwrap()
+				.hash
+		};
+		let inner = tree(&[(0o100644, "main.rs", blob)]);
+		let src = tree(&[(0o40000, "bin", inner), (0o100644, "lib.rs", blob)]);
+		let root = hex::encode(tree(&[(0o100644, "README", blob), (0o40000, "src", src)]));
+
+		let mut out = Vec::new();
+		ls_tree(&repo, root.clone(), true, true, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"README\nsrc/bin/main.rs\nsrc/lib.rs\n"
+		);
+
+		let mut out = Vec::new();
+		ls_tree(&repo, root, false, true, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap().lines().nth(1).unwrap(),
+			format!("100644 blob {}\tsrc/bin/main.rs", hex::encode(blob))
+		);
+	}
+
+	#[test]
+	fn cat_file_prints_trees_like_ls_tree() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"content"));
+		let blob = hash_git_object(&repo, blob, true).unwrap().hash;
+		let subtree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true)
+			.unwrap()
+			.hash;
+		let entries = [
+			(0o100644, "a.txt", blob),
+			(0o100755, "run.sh", blob),
+			(0o40000, "sub", subtree),
+		]
+		.map(|(mode, name, hash)| TreeEntry {
+			mode,
+			name: Cow::Borrowed(name),
+			object_hash: Cow::Owned(hash),
+		});
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&entries)), true)
+			.unwrap()
+			.hash_str;
+
+		let mut cat_file_out = Vec::new();
+		cat_file(
+			&repo,
+			tree.clone(),
+			CatFileMode::PrettyPrint,
+			false,
+			&mut cat_file_out,
+		)
+		.unwrap();
+		let mut ls_tree_out = Vec::new();
+		ls_tree(&repo, tree, false, false, &mut ls_tree_out).unwrap();
+
+		assert_eq!(cat_file_out, ls_tree_out);
+		assert_eq!(
+			String::from_utf8(ls_tree_out).unwrap(),
+			format!(
+				"100644 blob {blob}\ta.txt\n100755 blob {blob}\trun.sh\n040000 tree {subtree}\tsub\n",
+				blob = hex::encode(blob),
+				subtree = hex::encode(subtree),
+			)
+		);
+	}
+
+	#[test]
+	fn replace_refs_substitute_objects() {
+		let (_dir, repo) = init_repo();
+		let original = GitObject::Blob(Cow::Borrowed(b"original"));
+		let original = hash_git_object(&repo, original, true).unwrap().hash_str;
+		let replacement = GitObject::Blob(Cow::Borrowed(b"replacement"));
+		let replacement = hash_git_object(&repo, replacement, true).unwrap().hash_str;
+
+		let replace_dir = repo.git_dir().join("refs/replace");
+		fs::create_dir_all(&replace_dir).unwrap();
+		fs::write(replace_dir.join(&original), format!("{replacement}\n")).unwrap();
+
+		let read_blob = |repo: &Repo| match decode_object(repo, original.clone()).unwrap() {
+			GitObject::Blob(content) => content.into_owned(),
+			_ => panic!("expected a blob"),
+		};
+		assert_eq!(read_blob(&repo), b"replacement");
+
+		let repo = repo.with_replace_objects(false);
+		assert_eq!(read_blob(&repo), b"original");
+	}
+
+	#[test]
+	fn truncated_object_is_corrupted() {
+		let (_dir, repo) = init_repo();
+		let hash = "0123456789abcdef0123456789abcdef01234567";
+		let path = repo.object_path(hash);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+		encoder.write_all(b"blob 20\0helloworldhelloworld").unwrap();
+		let compressed = encoder.finish().unwrap();
+		fs::write(&path, &compressed[..compressed.len() - 8]).unwrap();
+
+		let result = decode_object(&repo, hash.to_string());
+		assert!(matches!(
+			result,
+			Err(ReadObjectError::CorruptedObject {
+				context: "truncated payload",
+				..
+			})
+		));
+
+		// Only the checksum is missing
+		fs::write(&path, &compressed[..compressed.len() - 1]).unwrap();
+		let result = decode_object(&repo, hash.to_string());
+		assert!(matches!(
+			result,
+			Err(ReadObjectError::CorruptedObject {
+				context: "corrupt zlib stream",
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn verify_reports_corrupted_objects() {
+		let (_dir, repo) = init_repo();
+		let good = GitObject::Blob(Cow::Borrowed(b"good"));
+		let good = hash_git_object(&repo, good, true).unwrap().hash_str;
+		let bad = GitObject::Blob(Cow::Borrowed(b"bad"));
+		let bad = hash_git_object(&repo, bad, true).unwrap().hash_str;
+
+		// Same header and size, different content
+		let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+		encoder.write_all(b"blob 3\0bed").unwrap();
+		fs::write(repo.object_path(&bad), encoder.finish().unwrap()).unwrap();
+
+		let input = format!("{good}\n{bad}\n");
+		let mut out = Vec::new();
+		let result = verify(&repo, input.as_bytes(), &mut out);
+		assert!(matches!(result, Err(VerifyError::Failed(1))));
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!("{good} ok\n{bad} fail: Corrupted object {bad}: hash mismatch\n")
+		);
+	}
+
+	#[test]
+	fn prune_keeps_objects_reachable_from_any_ref() {
+		let (_dir, repo) = init_repo();
+		let blob = |content: &'static [u8]| {
+			hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(content)), true).unwrap()
+		};
+		let stashed = blob(b"stashed");
+		let tree = [TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed("a.txt"),
+			object_hash: Cow::Owned(stashed.hash),
+		}];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&tree)), true).unwrap();
+		let stash = create_commit(&repo, tree.hash_str.clone(), Vec::new(), "WIP".into()).unwrap();
+		// HEAD's branch is unborn, only refs/stash reaches the commit.
+		refs::write_ref(&repo, "refs/stash", &stash.hash_str).unwrap();
+		let staged = blob(b"staged");
+		fs::write(
+			repo.index_path(),
+			index_bytes(&[("b.txt", staged.hash)], &[]),
+		)
+		.unwrap();
+		let garbage = blob(b"garbage").hash_str;
+
+		let mut out = Vec::new();
+		prune(&repo, true, false, &mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), format!("{garbage} blob\n"));
+		assert!(repo.object_path(&garbage).exists());
+
+		let mut out = Vec::new();
+		prune(&repo, false, false, &mut out).unwrap();
+		assert!(out.is_empty());
+		assert!(!repo.object_path(&garbage).exists());
+		for hash in [
+			stash.hash_str,
+			tree.hash_str,
+			stashed.hash_str,
+			staged.hash_str,
+		] {
+			assert!(repo.object_path(&hash).exists(), "{hash} was pruned");
+		}
+	}
+
+	#[test]
+	fn decode_commits() {
+		let tree = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+		let parent = "1".repeat(40);
+		let other_parent = "2".repeat(40);
+		let object = |payload: &str| format!("commit {}\0{payload}", payload.len());
+
+		let root = format!(
+			"tree {tree}\nauthor A U Thor <a@example.com> 1700000000 +0100\ncommitter C O Mitter <c@example.com> 1700000001 -0230\n\ninitial\n"
+		);
+		let Ok(GitObject::Commit(commit)) = decode_object_bytes("", object(&root).as_bytes())
+		else {
+			panic!("expected a commit");
+		};
+		assert_eq!(hex::encode(commit.tree), tree);
+		assert!(commit.parents.is_empty());
+		assert_eq!(
+			commit.author,
+			Signature {
+				name: "A U Thor".to_string(),
+				email: "a@example.com".to_string(),
+				timestamp: 1700000000,
+				tz_offset: 60,
+			}
+		);
+		assert_eq!(commit.committer.tz_offset, -150);
+		assert_eq!(commit.message, "initial\n");
+
+		let merge = format!(
+			"tree {tree}\nparent {parent}\nparent {other_parent}\nauthor A <a@example.com> 1 +0000\ncommitter C <c@example.com> 2 +0000\ngpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n\nmerge\n"
+		);
+		let Ok(GitObject::Commit(commit)) = decode_object_bytes("", object(&merge).as_bytes())
+		else {
+			panic!("expected a commit");
+		};
+		assert_eq!(
+			commit.parents,
+			[[0x11; 20], [0x22; 20]],
+			"both parents in order"
+		);
+		assert_eq!(commit.extra_headers[0].0, "gpgsig");
+		let mut encoded = Vec::new();
+		encode_commit(commit, &mut encoded).unwrap();
+		assert_eq!(String::from_utf8(encoded).unwrap(), object(&merge));
+
+		let malformed = format!("tree {tree}\nauthor A <a@example.com> nope +0000\n");
+		assert!(matches!(
+			decode_object_bytes("", object(&malformed).as_bytes()),
+			Err(ReadObjectError::CorruptedObject {
+				context: "malformed author line",
+				..
+			})
+		));
+		assert!(matches!(
+			decode_object_bytes("", object("parent x\n").as_bytes()),
+			Err(ReadObjectError::CorruptedObject {
+				context: "missing tree",
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn decode_and_print_tags() {
+		let (_dir, repo) = init_repo();
+		let target = "1".repeat(40);
+		let payload = format!(
+			"object {target}\ntype commit\ntag v1.0\ntagger A U Thor <a@example.com> 1700000000 +0100\n\nRelease 1.0\n"
+		);
+		let tag = write_raw_object(&repo, ObjectKind::Tag, payload.as_bytes());
+
+		let GitObject::Tag(decoded) = decode_object(&repo, tag.clone()).unwrap() else {
+			panic!("expected a tag");
+		};
+		assert_eq!(decoded.object, [0x11; 20]);
+		assert_eq!(decoded.kind, ObjectKind::Commit);
+		assert_eq!(decoded.name, "v1.0");
+		assert_eq!(decoded.tagger.email, "a@example.com");
+		assert_eq!(decoded.message, "Release 1.0\n");
+		let mut encoded = Vec::new();
+		encode_tag(decoded, &mut encoded).unwrap();
+		assert_eq!(
+			encoded,
+			[
+				format!("tag {}\0", payload.len()).as_bytes(),
+				payload.as_bytes()
+			]
+			.concat()
+		);
+
+		let mut out = Vec::new();
+		cat_file(
+			&repo,
+			tag.clone(),
+			CatFileMode::PrettyPrint,
+			false,
+			&mut out,
+		)
+		.unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), payload);
+		let mut out = Vec::new();
+		cat_file(&repo, tag, CatFileMode::Type, false, &mut out).unwrap();
+		assert_eq!(out, b"tag\n");
+
+		let object = |payload: &str| format!("tag {}\0{payload}", payload.len());
+		let no_tagger = format!("object {target}\ntype commit\ntag v1.0\n\nmessage\n");
+		assert!(matches!(
+			decode_object_bytes("", object(&no_tagger).as_bytes()),
+			Err(ReadObjectError::CorruptedObject {
+				context: "missing tagger line",
+				..
+			})
+		));
+		let out_of_order = format!("type commit\nobject {target}\ntag v1.0\n");
+		assert!(matches!(
+			decode_object_bytes("", object(&out_of_order).as_bytes()),
+			Err(ReadObjectError::CorruptedObject {
+				context: "missing object line",
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn cat_file_prints_raw_commits() {
+		let (_dir, repo) = init_repo();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let commit = create_commit(&repo, tree.hash_str, vec![], "message".into()).unwrap();
+
+		let mut out = Vec::new();
+		cat_file(
+			&repo,
+			commit.hash_str.clone(),
+			CatFileMode::PrettyPrint,
+			false,
+			&mut out,
+		)
+		.unwrap();
+		let (_, bytes) = inflate_object(&repo, commit.hash_str).unwrap();
+		assert_eq!(bytes.split(|b| *b == 0).nth(1).unwrap(), out);
+		assert!(out.ends_with(b"\n\nmessage\n"));
+	}
+
+	#[test]
+	fn decode_empty_objects() {
+		assert!(matches!(
+			decode_object_bytes("", b"blob 0\0"),
+			Ok(GitObject::Blob(content)) if content.is_empty()
+		));
+		assert!(matches!(
+			decode_object_bytes("", b"tree 0\0"),
+			Ok(GitObject::Tree(entries)) if entries.is_empty()
+		));
+	}
+
+	#[test]
+	fn cat_file_allows_unknown_type() {
+		let (_dir, repo) = init_repo();
+		let hash = "0123456789abcdef0123456789abcdef01234567";
+		let path = repo.object_path(hash);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+		encoder.write_all(b"whatever 3\0abc").unwrap();
+		fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+		let cat = |mode, allow_unknown_type| {
+			let mut out = Vec::new();
+			cat_file(&repo, hash.into(), mode, allow_unknown_type, &mut out)
+				.map(|_| String::from_utf8(out).unwrap())
+		};
+		assert_eq!(cat(CatFileMode::Type, true).unwrap(), "whatever\n");
+		assert_eq!(cat(CatFileMode::Size, true).unwrap(), "3\n");
+		assert!(matches!(
+			cat(CatFileMode::Type, false),
+			Err(CatFileError::ReadObject(
+				ReadObjectError::UnknownObjectKind { .. }
+			))
+		));
+	}
+
+	#[test]
+	fn cat_file_reports_missing_objects() {
+		let (_dir, repo) = init_repo();
+		let hash = "0123456789abcdef0123456789abcdef01234567";
+		for mode in [CatFileMode::PrettyPrint, CatFileMode::Type] {
+			let err = cat_file(&repo, hash.into(), mode, false, &mut Vec::new()).unwrap_err();
+			assert!(matches!(
+				&err,
+				CatFileError::ReadObject(ReadObjectError::ObjectNotFound(name)) if name == hash
+			));
+			assert_eq!(err.to_string(), format!("Not a valid object name {hash}"));
+		}
+
+		// Other errors are still reported as such.
+		let path = repo.object_path(hash);
+		fs::create_dir_all(&path).unwrap();
+		assert!(matches!(
+			cat_file(
+				&repo,
+				hash.into(),
+				CatFileMode::Type,
+				false,
+				&mut Vec::new()
+			),
+			Err(CatFileError::ReadObject(ReadObjectError::Io(_)))
+		));
+	}
+
+	#[test]
+	fn cat_file_type_and_size_of_every_kind() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"12345"));
+		let blob = hash_git_object(&repo, blob, true).unwrap();
+		let entries = [TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed("file"),
+			object_hash: Cow::Owned(blob.hash),
+		}];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&entries)), true).unwrap();
+		let commit = create_commit(&repo, tree.hash_str.clone(), vec![], "m".into()).unwrap();
+
+		let cat = |object: &str, mode| {
+			let mut out = Vec::new();
+			cat_file(&repo, object.to_string(), mode, false, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+		assert_eq!(cat(&blob.hash_str, CatFileMode::Type), "blob\n");
+		assert_eq!(cat(&blob.hash_str, CatFileMode::Size), "5\n");
+		assert_eq!(cat(&tree.hash_str, CatFileMode::Type), "tree\n");
+		// "100644 file\0" and the hash
+		assert_eq!(cat(&tree.hash_str, CatFileMode::Size), "32\n");
+		assert_eq!(cat(&commit.hash_str, CatFileMode::Type), "commit\n");
+
+		for flags in [["-t", "-s"], ["-p", "-t"], ["-p", "-s"]] {
+			let args = ["git", "cat-file", flags[0], flags[1], &blob.hash_str];
+			assert!(Args::try_parse_from(args).is_err(), "{flags:?} accepted");
+		}
+	}
+
+	#[test]
+	fn cat_file_batch_splits_input_on_nul() {
+		let (_dir, repo) = init_repo();
+		let blob = GitObject::Blob(Cow::Borrowed(b"line one\nline two\n"));
+		let blob = hash_git_object(&repo, blob, true).unwrap().hash_str;
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true)
+			.unwrap()
+			.hash_str;
+		let missing = "0".repeat(40);
+
+		let input = format!("{blob}\0{tree}\0{missing}\0");
+		let mut out = Vec::new();
+		cat_file_batch(&repo, input.as_bytes(), &mut out, None, false, true).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!("{blob} blob 18\0{tree} tree 0\0{missing} missing\0")
+		);
+
+		let mut out = Vec::new();
+		let input = format!("{blob}\n");
+		cat_file_batch(&repo, input.as_bytes(), &mut out, None, true, false).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!("{blob} blob 18\nline one\nline two\n\n")
+		);
+	}
+
+	#[test]
+	fn cat_file_batch_peels_tags() {
+		let (_dir, repo) = init_repo();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let commit = create_commit(&repo, tree.hash_str.clone(), vec![], "tagged".into())
+			.unwrap()
+			.hash_str;
+		let tag = |target: &str, kind: &str, name: &str| {
+			let payload = format!(
+				"object {target}\ntype {kind}\ntag {name}\ntagger A U Thor <a@example.com> 1700000000 +0000\n\n{name}\n"
+			);
+			write_raw_object(&repo, ObjectKind::Tag, payload.as_bytes())
+		};
+		let tag = tag(&commit, "commit", "v1");
+		// A tag of a tag
+		let outer = write_raw_object(
+			&repo,
+			ObjectKind::Tag,
+			format!("object {tag}\ntype tag\ntag outer\n\nouter\n").as_bytes(),
+		);
+
+		let input = format!("{tag}\n{}\n{}\n", &outer[..8], tree.hash_str);
+		let mut out = Vec::new();
+		let format = "%(objectname) %(objecttype) %(*objecttype) %(*objectname)";
+		cat_file_batch(
+			&repo,
+			input.as_bytes(),
+			&mut out,
+			Some(format),
+			false,
+			false,
+		)
+		.unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!(
+				"{tag} tag commit {commit}\n{outer} tag commit {commit}\n{tree} tree  \n",
+				tree = tree.hash_str
+			)
+		);
+
+		let err = cat_file_batch(
+			&repo,
+			input.as_bytes(),
+			&mut Vec::new(),
+			Some("%(nope)"),
+			false,
+			false,
+		);
+		assert!(matches!(err, Err(CatFileError::UnknownFormatAtom(atom)) if atom == "nope"));
+		assert!(Args::try_parse_from(["git", "cat-file", "--batch-check=%(objectname)"]).is_ok());
+	}
+
+	#[test]
+	fn cat_file_batch_resolves_peel_syntax() {
+		let (_dir, repo) = init_repo();
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"a\n")), true).unwrap();
+		let entries = vec![TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed("a"),
+			object_hash: Cow::Owned(blob.hash),
+		}];
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Owned(entries)), true).unwrap();
+		let commit = create_commit(&repo, tree.hash_str.clone(), vec![], "first".into()).unwrap();
+		refs::update_ref(&repo, "HEAD", &commit.hash_str, None).unwrap();
+
+		let mut out = Vec::new();
+		let input = format!("HEAD^{{tree}}\n{}^{{}}\nHEAD^{{blob}}\n", commit.hash_str);
+		cat_file_batch(&repo, input.as_bytes(), &mut out, None, false, false).unwrap();
+		let (_, commit_bytes) = inflate_object(&repo, commit.hash_str.clone()).unwrap();
+		let commit_size =
+			commit_bytes.len() - commit_bytes.iter().position(|b| *b == 0).unwrap() - 1;
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!(
+				"{} tree 29\n{} commit {commit_size}\nHEAD^{{blob}} missing\n",
+				tree.hash_str, commit.hash_str
+			)
+		);
+	}
+
+	/// Builds an index file with given entries, each a path and a blob hash.
+	fn index_bytes(entries: &[(&str, [u8; 20])], extension: &[u8]) -> Vec<u8> {
+		let mut index = b"DIRC".to_vec();
+		index.extend(2_u32.to_be_bytes());
+		index.extend((entries.len() as u32).to_be_bytes());
+		for (path, sha1) in entries {
+			let start = index.len();
+			for field in [1, 2, 3, 4, 5, 6, 0o100644, 1000, 1000, 42_u32] {
+				index.extend(field.to_be_bytes());
+			}
+			index.extend(sha1);
+			index.extend((path.len() as u16).to_be_bytes());
+			index.extend(path.as_bytes());
+			let len = index.len() - start;
+			index.resize(start + (len + 8) / 8 * 8, 0);
+		}
+		index.extend(extension);
+		index.extend(sha1::sha1(&index));
+		index
+	}
+
+	#[test]
+	fn read_tree_replaces_the_index() {
+		let (dir, repo) = init_repo();
+		fs::create_dir(dir.0.join("d")).unwrap();
+		for path in ["a.txt", "d/b.txt"] {
+			fs::write(dir.0.join(path), path).unwrap();
+		}
+		update_index(
+			&repo,
+			&[PathBuf::from("a.txt"), PathBuf::from("d/b.txt")],
+			true,
+		)
+		.unwrap();
+		commit(&repo, "first".to_string(), true, &mut Vec::new()).unwrap();
+		let tree = revision::resolve_revision(&repo, "HEAD^{tree}").unwrap();
+
+		fs::write(dir.0.join("c.txt"), "c").unwrap();
+		update_index(&repo, &[PathBuf::from("c.txt")], true).unwrap();
+		read_tree(&repo, "HEAD").unwrap();
+
+		let index = read_index(&repo).unwrap();
+		let paths: Vec<_> = index.entries.iter().map(|e| e.path.as_str()).collect();
+		assert_eq!(paths, ["a.txt", "d/b.txt"]);
+		assert_eq!(
+			hex::encode(write_tree_from_index(&repo, &index.entries).unwrap()),
+			tree
+		);
+		// The working tree is left alone.
+		assert!(dir.0.join("c.txt").exists());
+
+		// `<rev>:<path>` naming a tree works, one naming a blob can't be peeled to a tree.
+		read_tree(&repo, "HEAD:d").unwrap();
+		let index = read_index(&repo).unwrap();
+		assert_eq!(index.entries.len(), 1);
+		assert_eq!(index.entries[0].path, "b.txt");
+		revision::resolve_revision(&repo, "HEAD:a.txt").unwrap();
+		assert!(matches!(
+			read_tree(&repo, "HEAD:a.txt"),
+			Err(ReadTreeError::InvalidTree(_))
+		));
+
+		// Paths that would be written out of the working tree aren't imported.
+		let blob = compute_id(ObjectKind::Blob, b"a.txt");
+		let mut hooks = b"100755 pre-commit\0".to_vec();
+		hooks.extend(blob);
+		let hooks = test_utils::write_raw_object(&repo, ObjectKind::Tree, &hooks);
+		let mut tree = b"40000 .git\0".to_vec();
+		tree.extend(hex::decode(hooks).unwrap());
+		let tree = test_utils::write_raw_object(&repo, ObjectKind::Tree, &tree);
+		assert!(matches!(
+			read_tree(&repo, &tree),
+			Err(ReadTreeError::Checkout(checkout::CheckoutError::InvalidPath(path)))
+				if path == ".git"
+		));
+		assert_eq!(read_index(&repo).unwrap().entries[0].path, "b.txt");
+	}
+
+	#[test]
+	fn ls_files_lists_index_entries() {
+		let (_dir, repo) = init_repo();
+		let (a, b) = ([0xaa; 20], [0xbb; 20]);
+		let entries = [("a.txt", a), ("dir/b.txt", b), ("dir/b.txt", a)];
+		fs::write(repo.index_path(), index_bytes(&entries, &[])).unwrap();
+		let mut index = read_index(&repo).unwrap();
+		index.entries[1].flags |= 2 << 12;
+		index.entries[2].flags |= 3 << 12;
+		write_index(&repo, &index).unwrap();
+
+		let mut out = Vec::new();
+		ls_files(&repo, false, false, &mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), "a.txt\ndir/b.txt\n");
+		let mut out = Vec::new();
+		ls_files(&repo, false, true, &mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), "a.txt\0dir/b.txt\0");
+		let mut out = Vec::new();
+		ls_files(&repo, true, false, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!(
+				"100644 {a} 0\ta.txt\n100644 {b} 2\tdir/b.txt\n100644 {a} 3\tdir/b.txt\n",
+				a = hex::encode(a),
+				b = hex::encode(b)
+			)
+		);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn normalized_modes_match_git() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let (dir, repo) = init_repo();
+		let work_tree = dir.0.join("work");
+		fs::create_dir_all(work_tree.join("sub")).unwrap();
+		for (path, content, mode) in [
+			("b.txt", "b\n", 0o664),
+			("run.sh", "#!/bin/sh\n", 0o775),
+			("sub/x.txt", "x\n", 0o600),
+		] {
+			let path = work_tree.join(path);
+			fs::write(&path, content).unwrap();
+			fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+		}
+
+		let tree = write_tree_at_dir(&repo, &work_tree).unwrap();
+		// Same as `git write-tree` of these files
+		assert_eq!(
+			hex::encode(*tree.hash),
+			"3a9d183c1816f70801f7f3fc4f1e4d990c0747d2"
+		);
+
+		assert_eq!(normalize_mode(0o100664), 0o100644);
+		assert_eq!(normalize_mode(0o100744), 0o100755);
+		assert_eq!(normalize_mode(0o120777), 0o120000);
+		assert_eq!(normalize_mode(0o40775), 0o40000);
+
+		let mode_of = |path: &str| {
+			let path = work_tree.join(path);
+			file_mode(&fs::symlink_metadata(&path).unwrap(), &path)
+		};
+		assert_eq!(mode_of("run.sh"), 0o100755);
+		assert_eq!(mode_of("b.txt"), 0o100644);
+		assert_eq!(mode_of("sub"), 0o40000);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn worktree_symlinks_are_stored_as_links() {
+		use std::os::unix::fs::symlink;
+
+		let (dir, repo) = init_repo();
+		let work_tree = dir.0.join("work");
+		fs::create_dir_all(work_tree.join("d")).unwrap();
+		fs::write(work_tree.join("d/f.txt"), "hi\n").unwrap();
+		symlink("d", work_tree.join("dirlink")).unwrap();
+		symlink("d/f.txt", work_tree.join("filelink")).unwrap();
+		symlink("missing", work_tree.join("dangling")).unwrap();
+		// Would recurse forever if followed.
+		symlink("..", work_tree.join("d/up")).unwrap();
+
+		let tree = write_tree_at_dir(&repo, &work_tree).unwrap();
+		// Same as `git write-tree` of these files
+		assert_eq!(
+			hex::encode(*tree.hash),
+			"998977c57357651fe8169db4d8a0354cd2c66396"
+		);
+		let mut out = Vec::new();
+		ls_tree(&repo, hex::encode(*tree.hash), false, false, &mut out).unwrap();
+		assert!(String::from_utf8(out)
+			.unwrap()
+			.contains("120000 blob c59d9b6344f1af00e504ba698129f07a34bbed8d\tdirlink\n"));
+	}
+
+	#[test]
+	fn identical_files_are_compressed_once() {
+		let (dir, repo) = init_repo();
+		let files: Vec<_> = (0..64)
+			.map(|i| {
+				let name = format!("file{i}.txt");
+				let path = dir.0.join(&name);
+				fs::write(&path, "same\n").unwrap();
+				WorktreeFile {
+					metadata: fs::symlink_metadata(&path).unwrap(),
+					path,
+					rel_path: name.clone(),
+					name,
+				}
+			})
+			.collect();
+		let entries = hash_worktree_files(&repo, &files, &Attributes::default(), 8).unwrap();
+		assert_eq!(entries.len(), 64);
+
+		let object = repo.object_path(&hex::encode(compute_id(ObjectKind::Blob, b"same\n")));
+		assert!(object.exists());
+		let compressed = test_utils::COMPRESSED_OBJECTS.lock().unwrap();
+		assert_eq!(compressed.iter().filter(|path| **path == object).count(), 1);
+	}
+
+	#[test]
+	fn worktree_files_are_hashed_in_parallel() {
+		let (dir, repo) = init_repo();
+		let mut paths = Vec::new();
+		for i in 0..300 {
+			let path = if i % 3 == 0 {
+				format!("sub/file{i}.txt")
+			} else {
+				format!("file{i}.txt")
+			};
+			fs::create_dir_all(dir.0.join(&path).parent().unwrap()).unwrap();
+			// Some files have the same content, so the same object is written concurrently.
+			fs::write(dir.0.join(&path), format!("content {}\n", i % 50)).unwrap();
+			paths.push(PathBuf::from(path));
+		}
+
+		let files: Vec<_> = paths
+			.iter()
+			.map(|path| WorktreeFile {
+				path: dir.0.join(path),
+				name: path.file_name().unwrap().to_str().unwrap().to_string(),
+				rel_path: path.to_str().unwrap().to_string(),
+				metadata: fs::symlink_metadata(dir.0.join(path)).unwrap(),
+			})
+			.collect();
+		let attributes = Attributes::default();
+		let hash = |threads| {
+			let entries = hash_worktree_files(&repo, &files, &attributes, threads).unwrap();
+			let mut entries: Vec<_> = entries.iter().map(format_tree_entry).collect();
+			entries.sort();
+			entries
+		};
+		let serial = hash(1);
+		assert_eq!(serial.len(), 300);
+		assert_eq!(hash(8), serial);
+		for i in 0..50 {
+			let blob = compute_id(ObjectKind::Blob, format!("content {i}\n").as_bytes());
+			assert!(repo.object_path(&hex::encode(blob)).exists());
+		}
+		let leftovers = fs::read_dir(repo.objects_dir())
+			.unwrap()
+			.filter(|entry| {
+				let name = entry.as_ref().unwrap().file_name();
+				name.to_string_lossy().starts_with("tmp_obj_")
+			})
+			.count();
+		assert_eq!(leftovers, 0);
+
+		// Same tree as the one of the index, whose files were hashed one by one.
+		let tree = write_tree_at_dir(&repo, &dir.0).unwrap();
+		update_index(&repo, &paths, true).unwrap();
+		let index = read_index(&repo).unwrap();
+		assert_eq!(
+			*tree.hash,
+			write_tree_from_index(&repo, &index.entries).unwrap()
+		);
+	}
+
+	#[test]
+	fn diff_tree_reports_changed_paths() {
+		let (dir, repo) = init_repo();
+		let write = |files: &[(&str, &str)]| {
+			let work_tree = dir.0.join("work");
+			let _ = fs::remove_dir_all(&work_tree);
+			for (path, content) in files {
+				let path = work_tree.join(path);
+				fs::create_dir_all(path.parent().unwrap()).unwrap();
+				fs::write(path, content).unwrap();
+			}
+			hex::encode(*write_tree_at_dir(&repo, &work_tree).unwrap().hash)
+		};
+		let old = write(&[
+			("a.txt", "1"),
+			("d/e/f", "1"),
+			("d/same", "1"),
+			("gone", "1"),
+			("t", "1"),
+			("x/y", "1"),
+		]);
+		let new = write(&[
+			("a", "1"),
+			("a.txt", "2"),
+			("d/e/f", "2"),
+			("d/same", "1"),
+			("new/n", "1"),
+			("t/z", "1"),
+			("x", "1"),
+		]);
+
+		let diff = |recursive| {
+			let mut out = Vec::new();
+			diff_tree(&repo, &old, &new, recursive, &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+		// Same as `git diff-tree --name-status`
+		assert_eq!(
+			diff(false),
+			"A\ta\nM\ta.txt\nM\td\nD\tgone\nA\tnew\nD\tt\nA\tt\nA\tx\nD\tx\n"
+		);
+		assert_eq!(
+			diff(true),
+			"A\ta\nM\ta.txt\nM\td/e/f\nD\tgone\nA\tnew/n\nD\tt\nA\tt/z\nA\tx\nD\tx/y\n"
+		);
+
+		let mut out = Vec::new();
+		diff_tree(&repo, &old, &old, true, &mut out).unwrap();
+		assert!(out.is_empty());
+		assert!(matches!(
+			diff_tree(&repo, &old, "nope", false, &mut out),
+			Err(DiffTreeError::InvalidTreeName(name)) if name == "nope"
+		));
+	}
+
+	#[test]
+	fn worktree_trees_honor_gitignore() {
+		let (dir, repo) = init_repo();
+		let files = [
+			(".gitignore", "target/\n*.log\n!keep.log\n"),
+			(".hidden", ""),
+			("debug.log", ""),
+			("keep.log", ""),
+			("src/.gitignore", "/generated.rs\n"),
+			("src/generated.rs", ""),
+			("src/main.rs", ""),
+			("src/nested/generated.rs", ""),
+			("src/target/out", ""),
+			("target/out", ""),
+		];
+		for (path, content) in files {
+			let path = dir.0.join(path);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, content).unwrap();
+		}
+		fs::create_dir_all(dir.0.join("empty")).unwrap();
+
+		let tree = write_tree_at_dir(&repo, &dir.0).unwrap();
+		let mut out = Vec::new();
+		ls_tree(&repo, hex::encode(*tree.hash), true, true, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			".gitignore\n.hidden\nkeep.log\nsrc/.gitignore\nsrc/main.rs\nsrc/nested/generated.rs\n"
+		);
+	}
+
+	#[test]
+	fn gitattributes_normalize_line_endings_of_matching_files() {
+		let (dir, repo) = init_repo();
+		for (path, content) in [
+			(".gitattributes", "*.txt text eol=lf\n"),
+			("a.txt", "one\r\ntwo\r\n"),
+			("sub/b.txt", "three\r\n"),
+			("c.bat", "four\r\n"),
+		] {
+			let path = dir.0.join(path);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, content).unwrap();
+		}
+
+		let tree = write_tree_at_dir(&repo, &dir.0).unwrap();
+		let mut out = Vec::new();
+		ls_tree(&repo, hex::encode(*tree.hash), false, true, &mut out).unwrap();
+		let mut expected = String::new();
+		for (path, content) in [
+			(".gitattributes", "*.txt text eol=lf\n"),
+			("a.txt", "one\ntwo\n"),
+			("c.bat", "four\r\n"),
+			("sub/b.txt", "three\n"),
+		] {
+			let id = hex::encode(compute_id(ObjectKind::Blob, content.as_bytes()));
+			expected += &format!("100644 blob {id}\t{path}\n");
+		}
+		assert_eq!(String::from_utf8(out).unwrap(), expected);
+	}
+
+	#[test]
+	fn write_tree_from_index_builds_nested_trees() {
+		let (_dir, repo) = init_repo();
+		let paths = ["a.txt", "a/b/c.txt", "a/d.txt", "e.txt"];
+		let entries = paths.map(|path| {
+			let blob = GitObject::Blob(Cow::Borrowed(path.as_bytes()));
+			(path, hash_git_object(&repo, blob, true).unwrap().hash)
+		});
+		fs::write(repo.index_path(), index_bytes(&entries, &[])).unwrap();
+
+		let index = read_index(&repo).unwrap();
+		let tree = write_tree_from_index(&repo, &index.entries).unwrap();
+		// Same as `git write-tree` of these files
+		assert_eq!(
+			hex::encode(tree),
+			"1a626609a8b2a31489a40dbd44b7ac59a9095f6a"
+		);
+		let mut out = Vec::new();
+		ls_tree(&repo, hex::encode(tree), true, true, &mut out).unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), paths.join("\n") + "\n");
+
+		// Group-writable modes, as some indexes record, are stored normalized.
+		let mut entries = index.entries.clone();
+		entries[0].mode = 0o100664;
+		assert_eq!(write_tree_from_index(&repo, &entries).unwrap(), tree);
+
+		assert_eq!(
+			hex::encode(write_tree_from_index(&repo, &[]).unwrap()),
+			"4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+		);
+	}
+
+	#[test]
+	fn parse_index_at_any_alignment() {
+		let index = index_bytes(&[("a.txt", [0xab; 20]), ("dir/b", [0xcd; 20])], &[]);
+		// Misaligns the index by 0 to 3 bytes, which must not matter to how fields are read.
+		for offset in 0..4 {
+			let mut buffer = vec![0; offset];
+			buffer.extend_from_slice(&index);
+			let parsed = parse_index(&buffer[offset..]).unwrap();
+
+			assert_eq!(parsed.version, 2);
+			assert_eq!(parsed.sha1, index[index.len() - 20..]);
+			let entry = &parsed.entries[1];
+			assert_eq!(
+				(entry.ctime_s, entry.ino, entry.mode, entry.uid, entry.size),
+				(1, 6, 0o100644, 1000, 42)
+			);
+			assert_eq!(entry.sha1, [0xcd; 20]);
+			assert_eq!(entry.flags, 5);
+			assert_eq!(entry.path, "dir/b");
+		}
+	}
+
+	#[test]
+	fn mv_renames_index_entries() {
+		let (dir, repo) = init_repo();
+		let entries = [
+			("a.txt", [1; 20]),
+			("dir/b.txt", [2; 20]),
+			("z.txt", [3; 20]),
+		];
+		fs::write(repo.index_path(), index_bytes(&entries, &[])).unwrap();
+		fs::create_dir_all(dir.0.join("dir")).unwrap();
+		fs::create_dir_all(dir.0.join("sub")).unwrap();
+		for (path, _) in entries {
+			fs::write(dir.0.join(path), path).unwrap();
+		}
+		let paths = || {
+			let index = read_index(&repo).unwrap();
+			index
+				.entries
+				.iter()
+				.map(|e| (e.path.clone(), e.sha1))
+				.collect::<Vec<_>>()
+		};
+
+		mv(&repo, "z.txt".as_ref(), "b.txt".as_ref()).unwrap();
+		assert_eq!(
+			paths(),
+			[
+				("a.txt".to_string(), [1; 20]),
+				("b.txt".to_string(), [3; 20]),
+				("dir/b.txt".to_string(), [2; 20]),
+			]
+		);
+		assert_eq!(fs::read_to_string(dir.0.join("b.txt")).unwrap(), "z.txt");
+		assert!(!dir.0.join("z.txt").exists());
+
+		// Into an existing directory
+		mv(&repo, "dir".as_ref(), "sub".as_ref()).unwrap();
+		assert_eq!(
+			paths(),
+			[
+				("a.txt".to_string(), [1; 20]),
+				("b.txt".to_string(), [3; 20]),
+				("sub/dir/b.txt".to_string(), [2; 20]),
+			]
+		);
+		assert!(dir.0.join("sub/dir/b.txt").exists());
+
+		assert!(matches!(
+			mv(&repo, "a.txt".as_ref(), "b.txt".as_ref()),
+			Err(MvError::DestinationExists(_))
+		));
+		fs::write(dir.0.join("untracked"), "").unwrap();
+		assert!(matches!(
+			mv(&repo, "untracked".as_ref(), "c.txt".as_ref()),
+			Err(MvError::NotTracked(_))
+		));
+	}
+
+	#[test]
+	fn read_index_without_entries() {
+		let (_dir, repo) = init_repo();
+		fs::write(repo.index_path(), index_bytes(&[], &[])).unwrap();
+
+		let index = read_index(&repo).unwrap();
+		assert!(index.entries.is_empty());
+
+		fs::write(repo.index_path(), b"DIRC").unwrap();
+		assert!(matches!(
+			read_index(&repo),
+			Err(ReadIndexError::NoIndexHash)
+		));
+	}
+
+	#[test]
+	fn read_index_stops_before_extensions() {
+		let (_dir, repo) = init_repo();
+		let extension = b"TREE\0\0\0\x19\0-1 0\n01234567890123456789";
+		let entries = [("a.txt", [1; 20]), ("dir/b.txt", [2; 20])];
+		fs::write(repo.index_path(), index_bytes(&entries, extension)).unwrap();
+
+		let index = read_index(&repo).unwrap();
+		let paths: Vec<_> = index.entries.iter().map(|e| e.path.as_str()).collect();
+		assert_eq!(paths, ["a.txt", "dir/b.txt"]);
+		assert_eq!(index.entries[1].sha1, [2; 20]);
+		assert_eq!(index.entries[1].mode, 0o100644);
+		assert_eq!(index.entries[1].size, 42);
+	}
+
+	#[test]
+	fn rev_parse_from_subdirectory() {
+		let (dir, repo) = init_repo();
+		let top = dir.0.canonicalize().unwrap();
+		let sub = top.join("a/b");
+		fs::create_dir_all(&sub).unwrap();
+		let options = RevParseOptions {
+			git_dir: true,
+			show_toplevel: true,
+			is_inside_work_tree: true,
+		};
+		let run = |cwd: &Path, options| {
+			let mut out = Vec::new();
+			rev_parse(&repo, cwd, options, &[], &mut out).unwrap();
+			String::from_utf8(out).unwrap()
+		};
+
+		let top_str = top.display();
+		assert_eq!(
+			run(&sub, options),
+			format!("{top_str}/.git\n{top_str}\ntrue\n")
+		);
+		assert_eq!(run(&top, options), format!(".git\n{top_str}\ntrue\n"));
+		let only_inside = RevParseOptions {
+			is_inside_work_tree: true,
+			..Default::default()
+		};
+		assert_eq!(run(&top.join(".git"), only_inside), "false\n");
+		let only_git_dir = RevParseOptions {
+			git_dir: true,
+			..Default::default()
+		};
+		assert_eq!(run(&top.join(".git"), only_git_dir), ".\n");
+
+		// The branch is unborn.
+		assert!(matches!(
+			rev_parse(&repo, &top, only_git_dir, &["HEAD".to_string()], &mut Vec::new()),
+			Err(RevParseError::UnknownRevision(name)) if name == "HEAD"
+		));
+	}
+
+	#[test]
+	fn clone_stores_fetched_objects_and_refs() {
+		let (_dir, repo) = init_repo();
+		let blob = b"hello\n";
+		let blob_id = compute_id(ObjectKind::Blob, blob);
+		let mut tree = b"100644 hello.txt\0".to_vec();
+		tree.extend(blob_id);
+		let tree_id = compute_id(ObjectKind::Tree, &tree);
+		let commit = format!(
+			"tree {}\nauthor A <a@example.com> 1700000000 +0000\n\
+			committer A <a@example.com> 1700000000 +0000\n\ninitial\n",
+			hex::encode(tree_id)
+		);
+		let commit_id = compute_id(ObjectKind::Commit, commit.as_bytes());
+		let pack = test_utils::pack_of(&[(1, commit.as_bytes()), (2, &tree), (3, blob)]);
+
+		let remote = transport::RemoteRefs {
+			head_target: Some("refs/heads/main".to_string()),
+			refs: vec![
+				("HEAD".to_string(), commit_id),
+				("refs/heads/main".to_string(), commit_id),
+				("refs/tags/v1".to_string(), commit_id),
+			],
+		};
+		store_clone(&repo, &remote, pack::parse_pack(&pack).unwrap(), &[]).unwrap();
+
+		assert_eq!(
+			repo.head().unwrap(),
+			refs::Head::Symbolic {
+				ref_name: "refs/heads/main".to_string(),
+				target: Some(commit_id),
+			}
+		);
+		assert_eq!(
+			refs::resolve_ref(&repo, "refs/tags/v1").unwrap(),
+			Some(hex::encode(commit_id))
+		);
+		let mut out = Vec::new();
+		ls_tree(&repo, hex::encode(tree_id), false, true, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			format!("100644 blob {}\thello.txt\n", hex::encode(blob_id))
+		);
+		let GitObject::Commit(commit) = decode_object(&repo, hex::encode(commit_id)).unwrap()
+		else {
+			panic!("not a commit");
+		};
+		assert_eq!(commit.message, "initial\n");
+
+		// A HEAD pointing anywhere but at a branch is detached instead.
+		for target in ["../../escaped", "refs/tags/v1", "HEAD"] {
+			let (_dir, repo) = init_repo();
+			let remote = transport::RemoteRefs {
+				head_target: Some(target.to_string()),
+				..remote.clone()
+			};
+			store_clone(&repo, &remote, Vec::new(), &[]).unwrap();
+			assert_eq!(repo.head().unwrap(), refs::Head::Detached(commit_id));
+		}
+	}
+
+	#[test]
+	fn failed_clone_leaves_nothing_behind() {
+		let hash = "1".repeat(40);
+		let mut advertisement = b"001e# service=git-upload-pack\n0000".to_vec();
+		let line = format!("{hash} refs/heads/main\0\n");
+		advertisement.extend(format!("{:04x}{line}", line.len() + 4).into_bytes());
+		advertisement.extend(b"0000");
+		let responses = vec![advertisement.clone(), b"000aERR x\n".to_vec()];
+		let (url, server) = test_utils::serve_http(responses.clone());
+
+		let dir = test_utils::TempDir::new();
+		let created = dir.0.join("clone");
+		assert!(matches!(
+			clone(&url, &created, None),
+			Err(CloneError::Transport(_))
+		));
+		assert!(!created.exists());
+		server.join().unwrap();
+
+		// An empty destination that was there is kept.
+		let (url, server) = test_utils::serve_http(responses);
+		assert!(clone(&url, &dir.0, None).is_err());
+		assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn shallow_clone_deepens_and_records_the_cut() {
+		let blob = b"hello\n";
+		let mut tree = b"100644 hello.txt\0".to_vec();
+		tree.extend(compute_id(ObjectKind::Blob, blob));
+		let tree_id = compute_id(ObjectKind::Tree, &tree);
+		// The parent is never sent.
+		let commit = format!(
+			"tree {}\nparent {}\nauthor A <a@example.com> 1700000000 +0000\n\
+			committer A <a@example.com> 1700000000 +0000\n\nsecond\n",
+			hex::encode(tree_id),
+			"1".repeat(40)
+		);
+		let commit_id = compute_id(ObjectKind::Commit, commit.as_bytes());
+		let hash = hex::encode(commit_id);
+
+		let mut advertisement = b"001e# service=git-upload-pack\n0000".to_vec();
+		for line in [
+			format!("{hash} HEAD\0shallow symref=HEAD:refs/heads/main\n"),
+			format!("{hash} refs/heads/main\n"),
+		] {
+			advertisement.extend(format!("{:04x}{line}", line.len() + 4).into_bytes());
+		}
+		advertisement.extend(b"0000");
+		let mut upload_pack = format!("0035shallow {hash}\n00000008NAK\n").into_bytes();
+		upload_pack.extend(test_utils::pack_of(&[
+			(1, commit.as_bytes()),
+			(2, &tree),
+			(3, blob),
+		]));
+		let (url, server) = test_utils::serve_http(vec![advertisement, upload_pack]);
+
+		let dir = test_utils::TempDir::new();
+		clone(&format!("{url}/repo.git"), &dir.0, Some(1)).unwrap();
+		let requests = server.join().unwrap();
+		assert_eq!(requests[1].0, "POST /repo.git/git-upload-pack HTTP/1.1");
+		assert_eq!(
+			String::from_utf8(requests[1].1.clone()).unwrap(),
+			format!("003awant {hash} shallow\n000ddeepen 1\n00000009done\n")
+		);
+
+		let repo = Repo::new(dir.0.join(".git"));
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("shallow")).unwrap(),
+			format!("{hash}\n")
+		);
+		// The history ends at the cut instead of failing on the missing parent.
+		let history = graph::topo_order(&repo, &[commit_id]).unwrap();
+		assert_eq!(history.len(), 1);
+		assert!(history[0].1.parents.is_empty());
+	}
+
+	#[test]
+	fn packed_objects_are_read_when_not_loose() {
+		let (_dir, repo) = init_repo();
+		let ids = test_utils::write_pack(&repo, &[(3, b"packed\n")]);
+		let hash = hex::encode(ids[0]);
+
+		let mut out = Vec::new();
+		cat_file(
+			&repo,
+			hash[..7].to_string(),
+			CatFileMode::PrettyPrint,
+			false,
+			&mut out,
+		)
+		.unwrap();
+		assert_eq!(out, b"packed\n");
+
+		// The loose copy of an object is read first.
+		hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"packed\n")), true).unwrap();
+		assert_eq!(expand_object_name(&repo, &hash[..7]).unwrap(), hash);
+		assert!(matches!(
+			decode_object(&repo, "0".repeat(40)),
+			Err(ReadObjectError::ObjectNotFound(hash)) if hash == "0".repeat(40)
+		));
+	}
+
+	#[test]
+	fn update_index_stages_files() {
+		let (dir, repo) = init_repo();
+		fs::create_dir_all(dir.0.join("sub")).unwrap();
+		fs::write(dir.0.join("b.txt"), "b\n").unwrap();
+		fs::write(dir.0.join("sub/a.txt"), "a\n").unwrap();
+
+		let paths = [PathBuf::from("sub/a.txt"), PathBuf::from("b.txt")];
+		assert!(matches!(
+			update_index(&repo, &paths, false),
+			Err(UpdateIndexError::NotInIndex(path)) if path == "sub/a.txt"
+		));
+		update_index(&repo, &paths, true).unwrap();
+		let index = read_index(&repo).unwrap();
+		let staged: Vec<_> = index.entries.iter().map(|e| e.path.as_str()).collect();
+		assert_eq!(staged, ["b.txt", "sub/a.txt"]);
+		assert_eq!(index.entries[0].sha1, compute_id(ObjectKind::Blob, b"b\n"));
+		assert_eq!(index.entries[0].mode, 0o100644);
+		let metadata = fs::metadata(dir.0.join("b.txt")).unwrap();
+		assert!(index.entries[0].stat_matches(&metadata));
+		// Same as `git write-tree` after `git add` of these files
+		assert_eq!(
+			hex::encode(write_tree_from_index(&repo, &index.entries).unwrap()),
+			"c7b8c970cbb07b0de19a3b5a5537f5438e17b79d"
+		);
+
+		// Updating a staged file doesn't need `--add`.
+		fs::write(dir.0.join("b.txt"), "changed\n").unwrap();
+		update_index(&repo, &paths[1..], false).unwrap();
+		let updated = read_index(&repo).unwrap();
+		assert_eq!(updated.entries.len(), 2);
+		assert_eq!(
+			updated.entries[0].sha1,
+			compute_id(ObjectKind::Blob, b"changed\n")
+		);
+
+		// What's written reads back the same.
+		write_index(&repo, &updated).unwrap();
+		assert_eq!(read_index(&repo).unwrap().entries, updated.entries);
+	}
+
+	#[test]
+	fn write_tree_rewrites_only_invalidated_subtrees() {
+		let (dir, repo) = init_repo();
+		let paths = ["a/x.txt", "b/y.txt"].map(PathBuf::from);
+		for path in &paths {
+			fs::create_dir_all(dir.0.join(path.parent().unwrap())).unwrap();
+			fs::write(dir.0.join(path), "x\n").unwrap();
+		}
+		update_index(&repo, &paths, true).unwrap();
+		write_tree(&repo, false).unwrap();
+		let index = read_index(&repo).unwrap();
+		let cache = index.cache_tree.unwrap();
+		let root = write_tree_from_index(&repo, &index.entries).unwrap();
+		assert_eq!(cache.tree, Some((2, root)));
+		let names: Vec<_> = cache
+			.subtrees
+			.iter()
+			.map(|(name, _)| name.as_str())
+			.collect();
+		assert_eq!(names, ["a", "b"]);
+
+		// Staging a file invalidates the trees it's in, and only those.
+		fs::write(dir.0.join("a/x.txt"), "changed\n").unwrap();
+		update_index(&repo, &paths[..1], false).unwrap();
+		let mut index = read_index(&repo).unwrap();
+		let cache = index.cache_tree.as_mut().unwrap();
+		assert_eq!(cache.tree, None);
+		assert_eq!(cache.subtree("a").unwrap().tree, None);
+		assert!(cache.subtree("b").unwrap().tree.is_some());
+
+		// A tree that isn't b's, to tell whether it's reused or written again.
+		let empty = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		cache.subtrees[1].1.tree = Some((1, empty.hash));
+		write_index(&repo, &index).unwrap();
+		write_tree(&repo, false).unwrap();
+
+		let (_, root) = read_index(&repo).unwrap().cache_tree.unwrap().tree.unwrap();
+		let GitObject::Tree(entries) = decode_object(&repo, hex::encode(root)).unwrap() else {
+			panic!("not a tree");
+		};
+		let a_tree = [TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed("x.txt"),
+			object_hash: Cow::Owned(compute_id(ObjectKind::Blob, b"changed\n")),
+		}];
+		let a = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&a_tree)), false).unwrap();
+		let ids: Vec<_> = entries.iter().map(|e| (&*e.name, *e.object_hash)).collect();
+		assert_eq!(ids, [("a", a.hash), ("b", empty.hash)]);
+	}
+
+	#[test]
+	fn paths_are_relative_to_the_current_directory() {
+		let (dir, repo) = init_repo();
+		fs::create_dir_all(dir.0.join("sub/deeper")).unwrap();
+		for path in ["top.txt", "sub/x.txt"] {
+			fs::write(dir.0.join(path), path).unwrap();
+		}
+		assert_eq!(worktree_prefix(&repo, &dir.0), Path::new(""));
+		assert_eq!(
+			worktree_prefix(&repo, &dir.0.join("sub/deeper")),
+			Path::new("sub/deeper")
+		);
+		assert_eq!(worktree_prefix(&repo, Path::new("/")), Path::new(""));
+
+		let prefix = worktree_prefix(&repo, &dir.0.join("sub"));
+		let paths = ["x.txt", "../top.txt", "./deeper/../x.txt"]
+			.map(|path| from_prefix(&prefix, Path::new(path)));
+		assert_eq!(
+			paths,
+			["sub/x.txt", "top.txt", "sub/x.txt"].map(PathBuf::from)
+		);
+		update_index(&repo, &paths[..2], true).unwrap();
+		let staged: Vec<_> = read_index(&repo)
+			.unwrap()
+			.entries
+			.into_iter()
+			.map(|e| e.path)
+			.collect();
+		assert_eq!(staged, ["sub/x.txt", "top.txt"]);
+
+		let outside = from_prefix(&prefix, Path::new("../../out.txt"));
+		assert_eq!(outside, Path::new("../out.txt"));
+		assert!(matches!(
+			update_index(&repo, &[outside], true),
+			Err(UpdateIndexError::InvalidPath(_))
+		));
+	}
+
+	#[test]
+	fn update_index_normalizes_text_files() {
+		let (dir, repo) = init_repo();
+		fs::write(dir.0.join(".gitattributes"), "*.txt text\n").unwrap();
+		fs::write(dir.0.join("crlf.txt"), "one\r\ntwo\r\n").unwrap();
+
+		update_index(&repo, &[PathBuf::from("crlf.txt")], true).unwrap();
+		let index = read_index(&repo).unwrap();
+		assert_eq!(
+			index.entries[0].sha1,
+			compute_id(ObjectKind::Blob, b"one\ntwo\n")
+		);
+		assert!(!diff_worktree(&repo, DiffOptions::default(), &mut Vec::new()).unwrap());
+	}
+
+	#[test]
+	fn update_index_refuses_file_directory_conflicts() {
+		let (dir, repo) = init_repo();
+		fs::write(dir.0.join("a"), "a\n").unwrap();
+		update_index(&repo, &[PathBuf::from("a")], true).unwrap();
+
+		fs::remove_file(dir.0.join("a")).unwrap();
+		fs::create_dir(dir.0.join("a")).unwrap();
+		fs::write(dir.0.join("a/b"), "b\n").unwrap();
+		assert!(matches!(
+			update_index(&repo, &[PathBuf::from("a/b")], true),
+			Err(UpdateIndexError::FileDirectoryConflict(path)) if path == "a/b"
+		));
+
+		// The other way around, with a file staged below the path.
+		fs::write(dir.0.join("ab"), "ab\n").unwrap();
+		fs::remove_file(repo.index_path()).unwrap();
+		update_index(&repo, &[PathBuf::from("a/b"), PathBuf::from("ab")], true).unwrap();
+		fs::remove_dir_all(dir.0.join("a")).unwrap();
+		fs::write(dir.0.join("a"), "a\n").unwrap();
+		assert!(matches!(
+			update_index(&repo, &[PathBuf::from("a")], true),
+			Err(UpdateIndexError::FileDirectoryConflict(path)) if path == "a"
+		));
+		let staged: Vec<_> = read_index(&repo)
+			.unwrap()
+			.entries
+			.into_iter()
+			.map(|e| e.path)
+			.collect();
+		assert_eq!(staged, ["a/b", "ab"]);
+	}
+
+	#[test]
+	fn skip_worktree_entries_survive_restaging() {
+		let (dir, repo) = init_repo();
+		fs::create_dir_all(dir.0.join("sub")).unwrap();
+		fs::write(dir.0.join("b.txt"), "b\n").unwrap();
+		fs::write(dir.0.join("sub/a.txt"), "a\n").unwrap();
+		let paths = [PathBuf::from("sub/a.txt"), PathBuf::from("b.txt")];
+		update_index(&repo, &paths, true).unwrap();
+
+		mark_skip_worktree(&repo, &paths[..1], true).unwrap();
+		assert!(matches!(
+			mark_skip_worktree(&repo, &[PathBuf::from("nope")], true),
+			Err(UpdateIndexError::CannotMark(path)) if path == "nope"
+		));
+		// Left out of the sparse checkout.
+		fs::remove_dir_all(dir.0.join("sub")).unwrap();
+		fs::write(dir.0.join("b.txt"), "changed\n").unwrap();
+		update_index(&repo, &paths, false).unwrap();
+
+		let index = read_index(&repo).unwrap();
+		assert_eq!(index.version, 3);
+		assert!(index.entries[1].skip_worktree());
+		assert_eq!(index.entries[1].sha1, compute_id(ObjectKind::Blob, b"a\n"));
+		assert_eq!(
+			index.entries[0].sha1,
+			compute_id(ObjectKind::Blob, b"changed\n")
+		);
+		// Still in the tree.
+		let mut tree = Vec::new();
+		let restaged = write_tree_from_index(&repo, &index.entries).unwrap();
+		ls_tree(&repo, hex::encode(restaged), true, true, &mut tree).unwrap();
+		assert_eq!(String::from_utf8(tree).unwrap(), "b.txt\nsub/a.txt\n");
+		// The missing file isn't a change either.
+		assert!(!refresh_index(&repo, &mut Vec::new()).unwrap());
+		assert!(!diff_worktree(&repo, DiffOptions::default(), &mut Vec::new()).unwrap());
+
+		mark_skip_worktree(&repo, &paths[..1], false).unwrap();
+		let index = read_index(&repo).unwrap();
+		assert_eq!(index.version, 2);
+		assert!(!index.entries[1].skip_worktree());
+	}
+
+	#[test]
+	fn refresh_index_updates_stat_of_unchanged_files() {
+		let (dir, repo) = init_repo();
+		let same = compute_id(ObjectKind::Blob, b"same\n");
+		let entries = [("a.txt", same), ("b.txt", same), ("c.txt", same)];
+		fs::write(repo.index_path(), index_bytes(&entries, &[])).unwrap();
+		fs::write(dir.0.join("a.txt"), "same\n").unwrap();
+		fs::write(dir.0.join("b.txt"), "changed\n").unwrap();
+
+		let mut out = Vec::new();
+		assert!(refresh_index(&repo, &mut out).unwrap());
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			"b.txt: needs update\nc.txt: needs update\n"
+		);
+		let index = read_index(&repo).unwrap();
+		let metadata = fs::metadata(dir.0.join("a.txt")).unwrap();
+		assert!(index.entries[0].stat_matches(&metadata));
+		assert_eq!(index.entries[0].sha1, same);
+		// Left alone
+		assert_eq!((index.entries[1].mtime_s, index.entries[1].size), (3, 42));
+
+		// Touching the file changes its mtime, not its content.
+		let file = fs::File::options()
+			.write(true)
+			.open(dir.0.join("a.txt"))
+			.unwrap();
+		let mtime = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+		file.set_modified(mtime).unwrap();
+		let metadata = file.metadata().unwrap();
+		assert!(!read_index(&repo).unwrap().entries[0].stat_matches(&metadata));
+		fs::remove_file(dir.0.join("b.txt")).unwrap();
+		fs::write(dir.0.join("b.txt"), "same\n").unwrap();
+		fs::write(dir.0.join("c.txt"), "same\n").unwrap();
+
+		let mut out = Vec::new();
+		assert!(!refresh_index(&repo, &mut out).unwrap());
+		assert!(out.is_empty());
+		let index = read_index(&repo).unwrap();
+		assert!(index.entries[0].stat_matches(&metadata));
+		assert!(index.entries.iter().all(|entry| entry.sha1 == same));
+	}
+}
@@ -0,0 +1,399 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::diff::{self, Hunk};
+use crate::repo::Repo;
+use crate::{decode_object, hash_git_object, GitObject, HashObjectError, ReadObjectError};
+
+const TREE_MODE: u32 = 0o40000;
+const GITLINK_MODE: u32 = 0o160000;
+
+#[derive(Debug, Error)]
+pub enum MergeError {
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	HashObject(#[from] HashObjectError),
+
+	#[error("{0} is not a tree")]
+	NotATree(String),
+
+	#[error("{0} is not a blob")]
+	NotABlob(String),
+}
+
+/// Version of a path on one side of a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage {
+	pub mode: u32,
+	pub hash: [u8; 20],
+}
+
+impl Stage {
+	pub fn is_tree(&self) -> bool {
+		self.mode == TREE_MODE
+	}
+
+	fn is_blob(&self) -> bool {
+		!self.is_tree() && self.mode != GITLINK_MODE
+	}
+}
+
+#[derive(Debug)]
+pub enum MergeEntry {
+	/// Path merged without conflicts.
+	Clean { path: String, stage: Stage },
+
+	/// Path that needs to be resolved manually.
+	Conflict {
+		path: String,
+		kind: ConflictKind,
+		base: Option<Stage>,
+		ours: Option<Stage>,
+		theirs: Option<Stage>,
+		/// Merged content with conflict markers, for conflicting blobs.
+		content: Option<Vec<u8>>,
+	},
+}
+
+impl MergeEntry {
+	pub fn path(&self) -> &str {
+		match self {
+			MergeEntry::Clean { path, .. } | MergeEntry::Conflict { path, .. } => path,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+	/// Both sides changed the same lines.
+	Content,
+	/// Both sides added the path with different contents.
+	AddAdd,
+	/// One side modified the path, the other deleted it.
+	ModifyDelete,
+	/// The path is a tree on one side and something else on the other.
+	FileDirectory,
+}
+
+impl fmt::Display for ConflictKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ConflictKind::Content => "content",
+			ConflictKind::AddAdd => "add/add",
+			ConflictKind::ModifyDelete => "modify/delete",
+			ConflictKind::FileDirectory => "file/directory",
+		})
+	}
+}
+
+/// Merges the trees `ours` and `theirs` using `base` as their common ancestor.
+///
+/// Nothing is written to the object store, entries that merged cleanly carry the hash their
+/// merged content would have. Returned entries are leaves (blobs, symlinks and submodules),
+/// sorted by path.
+pub fn merge_trees(
+	repo: &Repo,
+	base: Option<&[u8; 20]>,
+	ours: &[u8; 20],
+	theirs: &[u8; 20],
+) -> Result<Vec<MergeEntry>, MergeError> {
+	let mut out = Vec::new();
+	merge_dir(repo, "", [base, Some(ours), Some(theirs)], &mut out)?;
+	Ok(out)
+}
+
+fn merge_dir(
+	repo: &Repo,
+	prefix: &str,
+	trees: [Option<&[u8; 20]>; 3],
+	out: &mut Vec<MergeEntry>,
+) -> Result<(), MergeError> {
+	let mut paths: BTreeMap<String, [Option<Stage>; 3]> = BTreeMap::new();
+	for (side, tree) in trees.into_iter().enumerate() {
+		let Some(tree) = tree else {
+			continue;
+		};
+		for (name, stage) in read_tree(repo, tree)? {
+			paths.entry(name).or_default()[side] = Some(stage);
+		}
+	}
+
+	for (name, stages) in paths {
+		let path = join_path(prefix, &name);
+		merge_path(repo, path, stages, out)?;
+	}
+
+	Ok(())
+}
+
+fn merge_path(
+	repo: &Repo,
+	path: String,
+	[base, ours, theirs]: [Option<Stage>; 3],
+	out: &mut Vec<MergeEntry>,
+) -> Result<(), MergeError> {
+	if ours == theirs || base == theirs {
+		return match ours {
+			Some(stage) => take_side(repo, path, stage, out),
+			None => Ok(()),
+		};
+	}
+	if base == ours {
+		return match theirs {
+			Some(stage) => take_side(repo, path, stage, out),
+			None => Ok(()),
+		};
+	}
+
+	// Both sides changed the path, and differently.
+	let conflict = |kind, content| MergeEntry::Conflict {
+		path: path.clone(),
+		kind,
+		base,
+		ours,
+		theirs,
+		content,
+	};
+	match (ours, theirs) {
+		(Some(o), Some(t)) if o.is_tree() && t.is_tree() => {
+			let base_tree = base.filter(Stage::is_tree).map(|b| b.hash);
+			merge_dir(
+				repo,
+				&path,
+				[base_tree.as_ref(), Some(&o.hash), Some(&t.hash)],
+				out,
+			)?;
+		}
+		(Some(o), Some(t)) if o.is_blob() && t.is_blob() => {
+			let base_blob = base.filter(Stage::is_blob);
+			let base_content = match base_blob {
+				Some(b) => read_blob(repo, &b.hash)?,
+				None => Vec::new(),
+			};
+			let merged = merge_content(
+				&base_content,
+				&read_blob(repo, &o.hash)?,
+				&read_blob(repo, &t.hash)?,
+			);
+			let mode = match base {
+				Some(b) if b.mode == o.mode => t.mode,
+				_ => o.mode,
+			};
+
+			if merged.conflicts == 0 {
+				let hashed =
+					hash_git_object(repo, GitObject::Blob(Cow::Owned(merged.content)), false)?;
+				out.push(MergeEntry::Clean {
+					path,
+					stage: Stage {
+						mode,
+						hash: hashed.hash,
+					},
+				});
+			} else {
+				let kind = match base_blob {
+					Some(_) => ConflictKind::Content,
+					None => ConflictKind::AddAdd,
+				};
+				out.push(conflict(kind, Some(merged.content)));
+			}
+		}
+		(Some(_), None) | (None, Some(_)) => out.push(conflict(ConflictKind::ModifyDelete, None)),
+		_ => out.push(conflict(ConflictKind::FileDirectory, None)),
+	}
+
+	Ok(())
+}
+
+/// Takes one side's version of `path` as is, listing all leaves when it's a tree.
+fn take_side(
+	repo: &Repo,
+	path: String,
+	stage: Stage,
+	out: &mut Vec<MergeEntry>,
+) -> Result<(), MergeError> {
+	if !stage.is_tree() {
+		out.push(MergeEntry::Clean { path, stage });
+		return Ok(());
+	}
+
+	for (name, stage) in read_tree(repo, &stage.hash)? {
+		take_side(repo, join_path(&path, &name), stage, out)?;
+	}
+	Ok(())
+}
+
+fn read_tree(repo: &Repo, hash: &[u8; 20]) -> Result<Vec<(String, Stage)>, MergeError> {
+	let hash_str = hex::encode(hash);
+	let GitObject::Tree(entries) = decode_object(repo, hash_str.clone())? else {
+		return Err(MergeError::NotATree(hash_str));
+	};
+	Ok(entries
+		.iter()
+		.map(|entry| {
+			(
+				entry.name.to_string(),
+				Stage {
+					mode: entry.mode,
+					hash: *entry.object_hash,
+				},
+			)
+		})
+		.collect())
+}
+
+fn read_blob(repo: &Repo, hash: &[u8; 20]) -> Result<Vec<u8>, MergeError> {
+	let hash_str = hex::encode(hash);
+	match decode_object(repo, hash_str.clone())? {
+		GitObject::Blob(content) => Ok(content.into_owned()),
+		_ => Err(MergeError::NotABlob(hash_str)),
+	}
+}
+
+fn join_path(prefix: &str, name: &str) -> String {
+	if prefix.is_empty() {
+		name.to_string()
+	} else {
+		format!("{prefix}/{name}")
+	}
+}
+
+/// Result of a three-way merge of a file's content.
+#[derive(Debug)]
+pub struct MergedContent {
+	pub content: Vec<u8>,
+	/// Number of conflict hunks (marked with `<<<<<<<`/`=======`/`>>>>>>>`) in `content`.
+	pub conflicts: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+	Ours,
+	Theirs,
+}
+
+/// Merges the lines of `ours` and `theirs` using `base` as their common ancestor.
+///
+/// Changes touching overlapping or adjacent lines of `base` conflict, unless both sides made the
+/// exact same change.
+pub fn merge_content(base: &[u8], ours: &[u8], theirs: &[u8]) -> MergedContent {
+	let base_lines = diff::lines(base);
+	let ours_lines = diff::lines(ours);
+	let theirs_lines = diff::lines(theirs);
+
+	let ours_hunks = diff::hunks(&diff::diff(&base_lines, &ours_lines));
+	let theirs_hunks = diff::hunks(&diff::diff(&base_lines, &theirs_lines));
+
+	let mut changes: Vec<(&Hunk, Side)> = ours_hunks
+		.iter()
+		.map(|h| (h, Side::Ours))
+		.chain(theirs_hunks.iter().map(|h| (h, Side::Theirs)))
+		.collect();
+	changes.sort_by_key(|(h, _)| (h.a.start, h.a.end));
+
+	let mut out = Vec::new();
+	let mut conflicts = 0;
+	let mut base_pos = 0;
+
+	let mut i = 0;
+	while i < changes.len() {
+		// Group changes touching the same region of base.
+		let lo = changes[i].0.a.start;
+		let mut hi = changes[i].0.a.end;
+		let mut j = i + 1;
+		while j < changes.len() && changes[j].0.a.start <= hi {
+			hi = hi.max(changes[j].0.a.end);
+			j += 1;
+		}
+		let cluster = &changes[i..j];
+
+		out.extend(base_lines[base_pos..lo].concat());
+
+		let ours_part = side_lines(cluster, Side::Ours, lo..hi, &ours_lines);
+		let theirs_part = side_lines(cluster, Side::Theirs, lo..hi, &theirs_lines);
+		match (ours_part, theirs_part) {
+			(Some(part), None) | (None, Some(part)) => out.extend(part.concat()),
+			(Some(o), Some(t)) if o == t => out.extend(o.concat()),
+			(Some(o), Some(t)) => {
+				conflicts += 1;
+				out.extend_from_slice(b"<<<<<<< .our\n");
+				push_lines(&mut out, o);
+				out.extend_from_slice(b"=======\n");
+				push_lines(&mut out, t);
+				out.extend_from_slice(b">>>>>>> .their\n");
+			}
+			(None, None) => unreachable!("cluster without changes"),
+		}
+
+		base_pos = hi;
+		i = j;
+	}
+	out.extend(base_lines[base_pos..].concat());
+
+	MergedContent {
+		content: out,
+		conflicts,
+	}
+}
+
+/// Lines of `side` replacing `base_range`, or `None` if that side didn't change it.
+fn side_lines<'a>(
+	cluster: &[(&Hunk, Side)],
+	side: Side,
+	base_range: std::ops::Range<usize>,
+	lines: &'a [&'a [u8]],
+) -> Option<&'a [&'a [u8]]> {
+	let mut hunks = cluster.iter().filter(|(_, s)| *s == side).map(|(h, _)| h);
+	let first = hunks.next()?;
+	let last = hunks.next_back().unwrap_or(first);
+
+	let start = first.b.start - (first.a.start - base_range.start);
+	let end = last.b.end + (base_range.end - last.a.end);
+	Some(&lines[start..end])
+}
+
+/// Appends `lines`, making sure the result ends with a newline so a marker can follow.
+fn push_lines(out: &mut Vec<u8>, lines: &[&[u8]]) {
+	out.extend(lines.concat());
+	if out.last().is_some_and(|b| *b != b'\n') {
+		out.push(b'\n');
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn merge_content_non_overlapping() {
+		let merged = merge_content(
+			b"one\ntwo\nthree\nfour\nfive\n",
+			b"ONE\ntwo\nthree\nfour\nfive\n",
+			b"one\ntwo\nthree\nfour\nFIVE\nsix\n",
+		);
+		assert_eq!(merged.conflicts, 0);
+		assert_eq!(merged.content, b"ONE\ntwo\nthree\nfour\nFIVE\nsix\n");
+	}
+
+	#[test]
+	fn merge_content_conflict() {
+		let merged = merge_content(b"a\nb\nc\n", b"a\nours\nc\n", b"a\ntheirs\nc\n");
+		assert_eq!(merged.conflicts, 1);
+		assert_eq!(
+			String::from_utf8(merged.content).unwrap(),
+			"a\n<<<<<<< .our\nours\n=======\ntheirs\n>>>>>>> .their\nc\n"
+		);
+	}
+
+	#[test]
+	fn merge_content_same_change() {
+		let merged = merge_content(b"a\nb\n", b"a\nx\n", b"a\nx\n");
+		assert_eq!(merged.conflicts, 0);
+		assert_eq!(merged.content, b"a\nx\n");
+	}
+}
@@ -0,0 +1,253 @@
+//! Notes: text attached to objects without changing them, like `git notes`. Each note is a blob
+//! in the tree of the commit `refs/notes/commits` points at, named after the object it's
+//! attached to. Trees with many notes fan them out into subtrees named after the first byte of
+//! the object's name (`ab/cdef...`), possibly several levels deep.
+
+use std::borrow::Cow;
+use std::io::Write;
+
+use thiserror::Error;
+
+use crate::repo::Repo;
+use crate::{
+	create_commit, decode_object, hash_git_object, refs, revision, sort_tree_entries,
+	CommitTreeError, GitObject, HashObjectError, ObjectId, ReadObjectError, TreeEntry,
+};
+
+/// The ref whose commits hold the notes.
+pub const NOTES_REF: &str = "refs/notes/commits";
+
+const TREE_MODE: u32 = 0o40000;
+
+#[derive(Debug, Error)]
+pub enum NotesError {
+	#[error("Failed to resolve '{0}' as a valid ref.")]
+	InvalidObject(String),
+
+	#[error(
+		"Cannot add notes. Found existing notes for object {0}. Use '-f' to overwrite existing \
+		 notes"
+	)]
+	AlreadyExists(String),
+
+	#[error("no note found for object {0}.")]
+	NotFound(String),
+
+	#[error("{0} is not a tree")]
+	NotATree(String),
+
+	#[error("{0} is not a blob")]
+	NotABlob(String),
+
+	#[error(transparent)]
+	ReadObject(#[from] ReadObjectError),
+
+	#[error(transparent)]
+	HashObject(#[from] HashObjectError),
+
+	#[error(transparent)]
+	CommitTree(#[from] CommitTreeError),
+
+	#[error(transparent)]
+	UpdateRef(#[from] refs::UpdateRefError),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Attaches a note with `message` to `object`, replacing the one it has if `force` is set: the
+/// note is added to the notes tree and committed on top of [NOTES_REF].
+pub fn add_note(repo: &Repo, object: &str, message: &str, force: bool) -> Result<(), NotesError> {
+	let hash = resolve_object(repo, object)?;
+	let parent = refs::resolve_ref(repo, NOTES_REF)?;
+	let entries = match &parent {
+		Some(commit) => notes_tree(repo, commit)?,
+		None => Vec::new(),
+	};
+	if !force && find_note(repo, &entries, &hash)?.is_some() {
+		return Err(NotesError::AlreadyExists(hash));
+	}
+
+	// Like the messages of commits, with trailing whitespace stripped and a final newline.
+	let note = format!("{}\n", message.trim_end());
+	let blob = hash_git_object(repo, GitObject::Blob(Cow::Owned(note.into_bytes())), true)?;
+	let tree = insert_note(repo, entries, &hash, blob.hash)?;
+	let commit = create_commit(
+		repo,
+		hex::encode(tree),
+		parent.iter().cloned().collect(),
+		"Notes added by 'git notes add'".to_string(),
+	)?;
+	let old = parent.unwrap_or_else(|| "0".repeat(40));
+	refs::update_ref(repo, NOTES_REF, &commit.hash_str, Some(&old))?;
+	Ok(())
+}
+
+/// Writes the note attached to `object`.
+pub fn show_note(repo: &Repo, object: &str, out: &mut impl Write) -> Result<(), NotesError> {
+	let hash = resolve_object(repo, object)?;
+	let entries = match refs::resolve_ref(repo, NOTES_REF)? {
+		Some(commit) => notes_tree(repo, &commit)?,
+		None => Vec::new(),
+	};
+	let blob = find_note(repo, &entries, &hash)?.ok_or(NotesError::NotFound(hash))?;
+	let blob = hex::encode(blob);
+	let GitObject::Blob(note) = decode_object(repo, blob.clone())? else {
+		return Err(NotesError::NotABlob(blob));
+	};
+	out.write_all(&note)?;
+	Ok(())
+}
+
+fn resolve_object(repo: &Repo, object: &str) -> Result<String, NotesError> {
+	match revision::resolve_revision(repo, object) {
+		Ok(hash) => Ok(hash),
+		Err(ReadObjectError::ObjectNotFound(_)) => Err(NotesError::InvalidObject(object.into())),
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// Entries of the tree of notes commit `commit`.
+fn notes_tree(repo: &Repo, commit: &str) -> Result<Vec<TreeEntry<'static>>, NotesError> {
+	let tree = revision::peel(repo, commit.to_string(), "tree")?
+		.ok_or_else(|| NotesError::NotATree(commit.to_string()))?;
+	read_tree(repo, tree)
+}
+
+fn read_tree(repo: &Repo, tree: String) -> Result<Vec<TreeEntry<'static>>, NotesError> {
+	match decode_object(repo, tree.clone())? {
+		GitObject::Tree(entries) => Ok(entries.into_owned()),
+		_ => Err(NotesError::NotATree(tree)),
+	}
+}
+
+/// The note of the object whose name ends with `rest` in the notes tree (or fanout subtree)
+/// `entries`.
+fn find_note(
+	repo: &Repo,
+	entries: &[TreeEntry],
+	rest: &str,
+) -> Result<Option<ObjectId>, NotesError> {
+	for entry in entries {
+		if entry.mode != TREE_MODE && entry.name == rest {
+			return Ok(Some(*entry.object_hash));
+		}
+		if entry.mode == TREE_MODE && entry.name.len() == 2 && rest.starts_with(&*entry.name) {
+			let subtree = read_tree(repo, hex::encode(*entry.object_hash))?;
+			if let Some(note) = find_note(repo, &subtree, &rest[2..])? {
+				return Ok(Some(note));
+			}
+		}
+	}
+	Ok(None)
+}
+
+/// Writes the notes tree (or fanout subtree) `entries` with `blob` as the note of the object
+/// whose name ends with `rest`, returning its id. The note goes into the fanout subtree of its
+/// prefix if there's one, so the layout of the tree is kept.
+fn insert_note(
+	repo: &Repo,
+	mut entries: Vec<TreeEntry<'static>>,
+	rest: &str,
+	blob: ObjectId,
+) -> Result<ObjectId, NotesError> {
+	let fanout = entries
+		.iter_mut()
+		.find(|e| e.mode == TREE_MODE && e.name.len() == 2 && rest.starts_with(&*e.name));
+	match fanout {
+		Some(entry) => {
+			let subtree = read_tree(repo, hex::encode(*entry.object_hash))?;
+			let id = insert_note(repo, subtree, &rest[2..], blob)?;
+			entry.object_hash = Cow::Owned(id);
+		}
+		None => {
+			entries.retain(|e| e.name != rest);
+			entries.push(TreeEntry {
+				mode: 0o100644,
+				name: Cow::Owned(rest.to_string()),
+				object_hash: Cow::Owned(blob),
+			});
+		}
+	}
+	sort_tree_entries(&mut entries);
+	Ok(hash_git_object(repo, GitObject::Tree(Cow::Owned(entries)), true)?.hash)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::init_repo;
+
+	fn show(repo: &Repo, object: &str) -> Result<String, NotesError> {
+		let mut out = Vec::new();
+		show_note(repo, object, &mut out)?;
+		Ok(String::from_utf8(out).unwrap())
+	}
+
+	#[test]
+	fn notes_are_added_and_shown() {
+		let (_dir, repo) = init_repo();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let commit = create_commit(&repo, tree.hash_str, Vec::new(), "first".into()).unwrap();
+		let hash = commit.hash_str;
+		assert!(matches!(show(&repo, &hash), Err(NotesError::NotFound(h)) if h == hash));
+
+		add_note(&repo, &hash, "looks good", false).unwrap();
+		assert_eq!(show(&repo, &hash).unwrap(), "looks good\n");
+		let first = refs::resolve_ref(&repo, NOTES_REF).unwrap().unwrap();
+		let entries = notes_tree(&repo, &first).unwrap();
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].name, hash);
+
+		assert!(matches!(
+			add_note(&repo, &hash, "again", false),
+			Err(NotesError::AlreadyExists(h)) if h == hash
+		));
+		add_note(&repo, &hash, "again", true).unwrap();
+		assert_eq!(show(&repo, &hash).unwrap(), "again\n");
+		let second = refs::resolve_ref(&repo, NOTES_REF).unwrap().unwrap();
+		let GitObject::Commit(notes) = decode_object(&repo, second).unwrap() else {
+			panic!("not a commit");
+		};
+		assert_eq!(
+			notes.parents,
+			[<[u8; 20]>::try_from(hex::decode(first).unwrap()).unwrap()]
+		);
+	}
+
+	#[test]
+	fn notes_are_found_and_added_in_fanout_subtrees() {
+		let (_dir, repo) = init_repo();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let commit = create_commit(&repo, tree.hash_str, Vec::new(), "first".into()).unwrap();
+		let hash = commit.hash_str;
+
+		// As git lays out notes trees with many notes: `ab/cdef...`.
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"old\n")), true).unwrap();
+		let note = [TreeEntry {
+			mode: 0o100644,
+			name: Cow::Borrowed(&hash[2..]),
+			object_hash: Cow::Owned(blob.hash),
+		}];
+		let fanout = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&note)), true).unwrap();
+		let root = [TreeEntry {
+			mode: TREE_MODE,
+			name: Cow::Borrowed(&hash[..2]),
+			object_hash: Cow::Owned(fanout.hash),
+		}];
+		let root = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&root)), true).unwrap();
+		let notes = create_commit(&repo, root.hash_str, Vec::new(), "notes".into()).unwrap();
+		refs::write_ref(&repo, NOTES_REF, &notes.hash_str).unwrap();
+		assert_eq!(show(&repo, &hash).unwrap(), "old\n");
+
+		add_note(&repo, &hash, "new", true).unwrap();
+		assert_eq!(show(&repo, &hash).unwrap(), "new\n");
+		let notes = refs::resolve_ref(&repo, NOTES_REF).unwrap().unwrap();
+		let entries = notes_tree(&repo, &notes).unwrap();
+		assert_eq!(entries.len(), 1);
+		assert_eq!(
+			(&*entries[0].name, entries[0].mode),
+			(&hash[..2], TREE_MODE)
+		);
+	}
+}
@@ -0,0 +1,706 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use thiserror::Error;
+
+use crate::repo::Repo;
+use crate::{sha1, ObjectId, ObjectKind};
+
+#[derive(Debug, Error)]
+pub enum PackError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+
+	#[error("Corrupt pack index {path}: {context}")]
+	CorruptIndex {
+		path: PathBuf,
+		context: &'static str,
+	},
+
+	#[error("Corrupt multi-pack-index {path}: {context}")]
+	CorruptMultiPackIndex {
+		path: PathBuf,
+		context: &'static str,
+	},
+
+	#[error("Not a packfile")]
+	MissingSignature,
+
+	#[error("Unsupported packfile version {0}")]
+	UnsupportedVersion(u32),
+
+	#[error("Packfile is truncated")]
+	Truncated,
+
+	#[error("Packfile checksum mismatch")]
+	ChecksumMismatch,
+
+	#[error("Invalid object type {kind} at offset {offset}")]
+	InvalidObjectType { kind: u8, offset: usize },
+
+	#[error("Deltified objects are not supported yet (object at offset {offset})")]
+	UnsupportedDelta { offset: usize },
+
+	#[error("Object at offset {offset} inflated to {got} bytes, expected {expected}")]
+	SizeMismatch {
+		offset: usize,
+		expected: usize,
+		got: usize,
+	},
+
+	#[error("Failed to inflate object at offset {offset}: {err}")]
+	Inflate {
+		offset: usize,
+		#[source]
+		err: std::io::Error,
+	},
+}
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// Parses a packfile (version 2 or 3) as sent by `git-upload-pack`, returning the kind and
+/// payload of each object in the order they are stored. The trailing checksum is verified.
+///
+/// Only base objects are supported, a deltified object fails with
+/// [PackError::UnsupportedDelta].
+pub fn parse_pack(data: &[u8]) -> Result<Vec<(ObjectKind, Vec<u8>)>, PackError> {
+	let (content, checksum) = data
+		.len()
+		.checked_sub(20)
+		.map(|len| data.split_at(len))
+		.ok_or(PackError::Truncated)?;
+	let header = content.get(..12).ok_or(PackError::Truncated)?;
+	if &header[..4] != b"PACK" {
+		return Err(PackError::MissingSignature);
+	}
+	let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+	if version != 2 && version != 3 {
+		return Err(PackError::UnsupportedVersion(version));
+	}
+	if sha1::sha1(content) != checksum {
+		return Err(PackError::ChecksumMismatch);
+	}
+	let count = u32::from_be_bytes(header[8..12].try_into().unwrap());
+
+	let mut objects = Vec::with_capacity(count as usize);
+	let mut offset = 12;
+	for _ in 0..count {
+		let entry = content.get(offset..).ok_or(PackError::Truncated)?;
+		let (kind, payload, len) = read_entry(entry, offset)?;
+		objects.push((kind, payload));
+		offset += len;
+	}
+	Ok(objects)
+}
+
+/// Reads the base object stored at `offset` of a pack from `entry`, positioned there. Returns
+/// its kind, its payload and the length of the entry.
+fn read_entry(
+	mut entry: impl BufRead,
+	offset: usize,
+) -> Result<(ObjectKind, Vec<u8>, usize), PackError> {
+	let mut header = Vec::new();
+	loop {
+		let mut byte = [0];
+		entry
+			.read_exact(&mut byte)
+			.map_err(|err| match err.kind() {
+				std::io::ErrorKind::UnexpectedEof => PackError::Truncated,
+				_ => err.into(),
+			})?;
+		header.push(byte[0]);
+		if byte[0] & 0x80 == 0 {
+			break;
+		}
+	}
+	let (kind, size, header_len) = parse_entry_header(&header)?;
+	let kind = match kind {
+		OBJ_COMMIT => ObjectKind::Commit,
+		OBJ_TREE => ObjectKind::Tree,
+		OBJ_BLOB => ObjectKind::Blob,
+		OBJ_TAG => ObjectKind::Tag,
+		OBJ_OFS_DELTA | OBJ_REF_DELTA => return Err(PackError::UnsupportedDelta { offset }),
+		kind => return Err(PackError::InvalidObjectType { kind, offset }),
+	};
+
+	let mut decoder = ZlibDecoder::new(entry);
+	let mut payload = Vec::with_capacity(size);
+	decoder
+		.read_to_end(&mut payload)
+		.map_err(|err| PackError::Inflate { offset, err })?;
+	if payload.len() != size {
+		return Err(PackError::SizeMismatch {
+			offset,
+			expected: size,
+			got: payload.len(),
+		});
+	}
+	Ok((kind, payload, header_len + decoder.total_in() as usize))
+}
+
+/// Parses the type and inflated size of a pack entry: 3 bits of type and 4 bits of size in the
+/// first byte, then 7 more bits of size per byte while the high bit is set. Returns them along
+/// with the length of the header.
+fn parse_entry_header(data: &[u8]) -> Result<(u8, usize, usize), PackError> {
+	let first = *data.first().ok_or(PackError::Truncated)?;
+	let kind = (first >> 4) & 0b111;
+	let mut size = (first & 0b1111) as usize;
+	let mut shift = 4;
+	let mut len = 1;
+	let mut byte = first;
+	while byte & 0x80 != 0 {
+		byte = *data.get(len).ok_or(PackError::Truncated)?;
+		size |= ((byte & 0x7f) as usize)
+			.checked_shl(shift)
+			.ok_or(PackError::Truncated)?;
+		shift += 7;
+		len += 1;
+	}
+	Ok((kind, size, len))
+}
+
+/// Encodes `objects` as a version 2 packfile, each stored whole (no deltas), followed by the
+/// checksum of the pack.
+pub fn encode_pack(objects: &[(ObjectKind, Vec<u8>)]) -> Result<Vec<u8>, PackError> {
+	let mut pack = b"PACK".to_vec();
+	pack.extend(2_u32.to_be_bytes());
+	pack.extend((objects.len() as u32).to_be_bytes());
+	for (kind, payload) in objects {
+		let kind = match kind {
+			ObjectKind::Commit => OBJ_COMMIT,
+			ObjectKind::Tree => OBJ_TREE,
+			ObjectKind::Blob => OBJ_BLOB,
+			ObjectKind::Tag => OBJ_TAG,
+		};
+		pack.extend(encode_entry_header(kind, payload.len()));
+		let mut encoder = ZlibEncoder::new(pack, flate2::Compression::default());
+		encoder.write_all(payload)?;
+		pack = encoder.finish()?;
+	}
+	let checksum = sha1::sha1(&pack);
+	pack.extend(checksum);
+	Ok(pack)
+}
+
+/// Encodes the header of a pack entry, see [parse_entry_header].
+fn encode_entry_header(kind: u8, mut size: usize) -> Vec<u8> {
+	let mut header = Vec::new();
+	let mut byte = (kind << 4) | (size & 0b1111) as u8;
+	size >>= 4;
+	while size > 0 {
+		header.push(byte | 0x80);
+		byte = (size & 0x7f) as u8;
+		size >>= 7;
+	}
+	header.push(byte);
+	header
+}
+
+/// Version 2 `.idx` file of a pack: a fanout table counting the objects whose ids start with
+/// each byte value, the sorted ids, their CRC32s, their offsets in the pack (with a table of
+/// 64-bit offsets for those past 2 GiB) and the checksums of the pack and of the index.
+pub struct PackIndex {
+	path: PathBuf,
+	data: Vec<u8>,
+	count: usize,
+}
+
+const IDX_SIGNATURE: &[u8] = b"\xfftOc";
+
+impl PackIndex {
+	pub fn parse(data: Vec<u8>, path: PathBuf) -> Result<Self, PackError> {
+		let corrupt = |context| PackError::CorruptIndex {
+			path: path.clone(),
+			context,
+		};
+		if data.get(..4) != Some(IDX_SIGNATURE) || data.get(4..8) != Some(&2_u32.to_be_bytes()) {
+			return Err(corrupt("not a version 2 pack index"));
+		}
+		let fanout_end = 8 + 256 * 4;
+		let count = data
+			.get(fanout_end - 4..fanout_end)
+			.map(|count| u32::from_be_bytes(count.try_into().unwrap()) as usize)
+			.ok_or_else(|| corrupt("truncated fanout table"))?;
+		// Ids, CRCs and offsets, followed by the two checksums
+		if data.len() < fanout_end + count * 28 + 40 {
+			return Err(corrupt("truncated"));
+		}
+		Ok(PackIndex { path, data, count })
+	}
+
+	fn fanout(&self, byte: usize) -> usize {
+		let start = 8 + byte * 4;
+		u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap()) as usize
+	}
+
+	fn id(&self, idx: usize) -> &[u8] {
+		let start = 8 + 256 * 4 + idx * 20;
+		&self.data[start..start + 20]
+	}
+
+	/// Ids of the objects in the pack, sorted.
+	pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+		(0..self.count).map(|idx| self.id(idx).try_into().unwrap())
+	}
+
+	/// Offset of object `id` in the pack, if it's there.
+	pub fn find(&self, id: &ObjectId) -> Option<usize> {
+		let first = id[0] as usize;
+		let start = if first == 0 {
+			0
+		} else {
+			self.fanout(first - 1)
+		};
+		let (mut low, mut high) = (start, self.fanout(first).min(self.count));
+		let idx = loop {
+			if low >= high {
+				return None;
+			}
+			let mid = (low + high) / 2;
+			match self.id(mid).cmp(&id[..]) {
+				std::cmp::Ordering::Less => low = mid + 1,
+				std::cmp::Ordering::Greater => high = mid,
+				std::cmp::Ordering::Equal => break mid,
+			}
+		};
+
+		let offsets = 8 + 256 * 4 + self.count * 24;
+		let offset = u32::from_be_bytes(self.data[offsets + idx * 4..][..4].try_into().unwrap());
+		if offset & 0x8000_0000 == 0 {
+			return Some(offset as usize);
+		}
+		let large = offsets + self.count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+		let offset = u64::from_be_bytes(self.data.get(large..large + 8)?.try_into().unwrap());
+		usize::try_from(offset).ok()
+	}
+}
+
+/// `objects/pack/multi-pack-index`, indexing the objects of several packs at once: a header
+/// (signature, version, hash version, number of chunks and of packs), a table of contents of the
+/// chunks and the chunks, of which these are read:
+/// - `PNAM`: the names of the `.idx` files of the packs, NUL-terminated and sorted,
+/// - `OIDF`, `OIDL`: a fanout table and the sorted ids, like in a pack index,
+/// - `OOFF`: the pack (by position in `PNAM`) and offset of each object, offsets with the high
+///   bit set pointing into `LOFF`, a table of 64-bit offsets.
+pub struct MultiPackIndex {
+	data: Vec<u8>,
+	packs: Vec<String>,
+	fanout: usize,
+	ids: usize,
+	offsets: usize,
+	large_offsets: Option<usize>,
+	count: usize,
+}
+
+const MIDX_SIGNATURE: &[u8] = b"MIDX";
+
+impl MultiPackIndex {
+	pub fn parse(data: Vec<u8>, path: &Path) -> Result<Self, PackError> {
+		let corrupt = |context| PackError::CorruptMultiPackIndex {
+			path: path.to_owned(),
+			context,
+		};
+		if data.get(..4) != Some(MIDX_SIGNATURE) || data.len() < 12 {
+			return Err(corrupt("bad signature"));
+		}
+		if data[4] != 1 || data[5] != 1 {
+			return Err(corrupt("unsupported version"));
+		}
+		let chunk_count = data[6] as usize;
+		let pack_count = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+
+		// Table of contents: id and offset of each chunk, then a terminating entry whose offset
+		// ends the last chunk.
+		let toc = data
+			.get(12..12 + (chunk_count + 1) * 12)
+			.ok_or_else(|| corrupt("truncated chunk table"))?;
+		let mut chunks = HashMap::new();
+		for (i, entry) in toc.chunks(12).take(chunk_count).enumerate() {
+			let start = u64::from_be_bytes(entry[4..12].try_into().unwrap());
+			let end = u64::from_be_bytes(toc[(i + 1) * 12 + 4..][..8].try_into().unwrap());
+			let (start, end) = match (usize::try_from(start), usize::try_from(end)) {
+				(Ok(start), Ok(end)) if start <= end && end <= data.len() => (start, end),
+				_ => return Err(corrupt("chunk out of bounds")),
+			};
+			chunks.insert(<[u8; 4]>::try_from(&entry[..4]).unwrap(), start..end);
+		}
+		let chunk = |id: &[u8; 4]| {
+			chunks
+				.get(id)
+				.cloned()
+				.ok_or_else(|| corrupt("missing chunk"))
+		};
+
+		let names = chunk(b"PNAM")?;
+		let packs: Vec<String> = data[names]
+			.split(|b| *b == 0)
+			.filter(|name| !name.is_empty())
+			.map(|name| String::from_utf8_lossy(name).into_owned())
+			.collect();
+		if packs.len() != pack_count {
+			return Err(corrupt("wrong number of pack names"));
+		}
+		let fanout = chunk(b"OIDF")?;
+		if fanout.len() != 256 * 4 {
+			return Err(corrupt("bad fanout table"));
+		}
+		let count = u32::from_be_bytes(data[fanout.end - 4..fanout.end].try_into().unwrap());
+		let count = count as usize;
+		let ids = chunk(b"OIDL")?;
+		let offsets = chunk(b"OOFF")?;
+		if ids.len() != count * 20 || offsets.len() != count * 8 {
+			return Err(corrupt("truncated object table"));
+		}
+		Ok(MultiPackIndex {
+			packs,
+			fanout: fanout.start,
+			ids: ids.start,
+			offsets: offsets.start,
+			large_offsets: chunks.get(b"LOFF").map(|range| range.start),
+			count,
+			data,
+		})
+	}
+
+	fn fanout(&self, byte: usize) -> usize {
+		let start = self.fanout + byte * 4;
+		u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap()) as usize
+	}
+
+	fn id(&self, idx: usize) -> &[u8] {
+		let start = self.ids + idx * 20;
+		&self.data[start..start + 20]
+	}
+
+	/// Ids of the objects of every pack, sorted.
+	pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+		(0..self.count).map(|idx| self.id(idx).try_into().unwrap())
+	}
+
+	/// Whether the pack with index `idx_path` is one of the indexed packs.
+	pub fn covers(&self, idx_path: &Path) -> bool {
+		idx_path
+			.file_name()
+			.is_some_and(|name| self.packs.iter().any(|pack| name == pack.as_str()))
+	}
+
+	/// Name of the `.idx` file of the pack holding object `id`, and its offset in the pack, if
+	/// it's there.
+	pub fn find(&self, id: &ObjectId) -> Option<(&str, usize)> {
+		let first = id[0] as usize;
+		let start = if first == 0 {
+			0
+		} else {
+			self.fanout(first - 1)
+		};
+		let (mut low, mut high) = (start, self.fanout(first).min(self.count));
+		let idx = loop {
+			if low >= high {
+				return None;
+			}
+			let mid = (low + high) / 2;
+			match self.id(mid).cmp(&id[..]) {
+				std::cmp::Ordering::Less => low = mid + 1,
+				std::cmp::Ordering::Greater => high = mid,
+				std::cmp::Ordering::Equal => break mid,
+			}
+		};
+
+		let entry = &self.data[self.offsets + idx * 8..][..8];
+		let pack = self
+			.packs
+			.get(u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize)?;
+		let offset = u32::from_be_bytes(entry[4..].try_into().unwrap());
+		if offset & 0x8000_0000 == 0 {
+			return Some((pack, offset as usize));
+		}
+		let large = self.large_offsets? + (offset & 0x7fff_ffff) as usize * 8;
+		let offset = u64::from_be_bytes(self.data.get(large..large + 8)?.try_into().unwrap());
+		Some((pack, usize::try_from(offset).ok()?))
+	}
+}
+
+/// Paths of the `.idx` files in `objects/pack/`, sorted.
+fn pack_indexes(repo: &Repo) -> std::io::Result<Vec<PathBuf>> {
+	let read_dir = match fs::read_dir(repo.objects_dir().join("pack")) {
+		Ok(v) => v,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(err),
+	};
+	let mut indexes = Vec::new();
+	for entry in read_dir {
+		let path = entry?.path();
+		if path.extension().is_some_and(|ext| ext == "idx") {
+			indexes.push(path);
+		}
+	}
+	indexes.sort();
+	Ok(indexes)
+}
+
+/// Parsed pack indexes of a repository by path, see [crate::repo::Repo::pack_indexes]. Packs
+/// are named after their checksum, so an index at a given path never changes. The
+/// multi-pack-index is read once too.
+#[derive(Default)]
+pub struct PackIndexes {
+	indexes: Mutex<HashMap<PathBuf, Arc<PackIndex>>>,
+	multi_pack_index: OnceLock<Option<MultiPackIndex>>,
+}
+
+/// The multi-pack-index of the repository, if it has one. Like git, packs are looked up through
+/// their own indexes instead if it can't be read.
+fn multi_pack_index(repo: &Repo) -> Option<&MultiPackIndex> {
+	repo.pack_indexes()
+		.multi_pack_index
+		.get_or_init(|| {
+			let path = repo.objects_dir().join("pack/multi-pack-index");
+			let data = fs::read(&path).ok()?;
+			MultiPackIndex::parse(data, &path).ok()
+		})
+		.as_ref()
+}
+
+/// Indexes of the packs of the repository that `midx` doesn't cover, in the order of
+/// [pack_indexes].
+fn indexes(repo: &Repo, midx: Option<&MultiPackIndex>) -> Result<Vec<Arc<PackIndex>>, PackError> {
+	let mut cache = repo.pack_indexes().indexes.lock().unwrap();
+	let mut indexes = Vec::new();
+	for path in pack_indexes(repo)? {
+		if midx.is_some_and(|midx| midx.covers(&path)) {
+			continue;
+		}
+		let index = match cache.get(&path) {
+			Some(index) => index.clone(),
+			None => {
+				let index = Arc::new(PackIndex::parse(fs::read(&path)?, path.clone())?);
+				cache.insert(path, index.clone());
+				index
+			}
+		};
+		indexes.push(index);
+	}
+	Ok(indexes)
+}
+
+/// Ids of every object in the packs of the repository.
+pub fn packed_ids(repo: &Repo) -> Result<Vec<ObjectId>, PackError> {
+	let midx = multi_pack_index(repo);
+	let mut ids: Vec<ObjectId> = midx.iter().flat_map(|midx| midx.ids()).collect();
+	for index in indexes(repo, midx)? {
+		ids.extend(index.ids());
+	}
+	Ok(ids)
+}
+
+/// Looks object `id` up in the packs of the repository, returning its kind and payload. The
+/// multi-pack-index is consulted first, then the indexes of the packs it doesn't cover. Only
+/// the entry of the object is read from the pack.
+pub fn read_packed_object(
+	repo: &Repo,
+	id: &ObjectId,
+) -> Result<Option<(ObjectKind, Vec<u8>)>, PackError> {
+	let midx = multi_pack_index(repo);
+	let found = match midx.and_then(|midx| midx.find(id)) {
+		Some((idx, offset)) => Some((repo.objects_dir().join("pack").join(idx), offset)),
+		None => indexes(repo, midx)?
+			.into_iter()
+			.find_map(|index| Some((index.path.clone(), index.find(id)?))),
+	};
+	let Some((idx_path, offset)) = found else {
+		return Ok(None);
+	};
+	let mut pack = fs::File::open(idx_path.with_extension("pack"))?;
+	pack.seek(SeekFrom::Start(offset as u64))?;
+	let (kind, payload, _) = read_entry(BufReader::new(pack), offset)?;
+	Ok(Some((kind, payload)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{init_repo, pack_of, write_pack};
+
+	/// Writes a multi-pack-index covering the packs of the repository as they are.
+	fn write_multi_pack_index(repo: &Repo) {
+		let paths = pack_indexes(repo).unwrap();
+		let mut objects = Vec::new();
+		for (pack, path) in paths.iter().enumerate() {
+			let index = PackIndex::parse(fs::read(path).unwrap(), path.clone()).unwrap();
+			for id in index.ids() {
+				objects.push((id, pack as u32, index.find(&id).unwrap() as u32));
+			}
+		}
+		objects.sort();
+		objects.dedup_by_key(|(id, _, _)| *id);
+
+		let mut names = Vec::new();
+		for path in &paths {
+			names.extend(path.file_name().unwrap().as_encoded_bytes());
+			names.push(0);
+		}
+		names.resize(names.len().next_multiple_of(4), 0);
+		let mut fanout = Vec::new();
+		for byte in 0..=255 {
+			let count = objects.iter().filter(|(id, _, _)| id[0] <= byte).count();
+			fanout.extend((count as u32).to_be_bytes());
+		}
+		let ids: Vec<u8> = objects.iter().flat_map(|(id, _, _)| *id).collect();
+		let offsets: Vec<u8> = objects
+			.iter()
+			.flat_map(|(_, pack, offset)| [pack.to_be_bytes(), offset.to_be_bytes()].concat())
+			.collect();
+
+		let chunks = [
+			(b"PNAM", names),
+			(b"OIDF", fanout),
+			(b"OIDL", ids),
+			(b"OOFF", offsets),
+		];
+		let mut midx = MIDX_SIGNATURE.to_vec();
+		midx.extend([1, 1, chunks.len() as u8, 0]);
+		midx.extend((paths.len() as u32).to_be_bytes());
+		let mut offset = midx.len() + (chunks.len() + 1) * 12;
+		for (id, chunk) in &chunks {
+			midx.extend(*id);
+			midx.extend((offset as u64).to_be_bytes());
+			offset += chunk.len();
+		}
+		midx.extend([0; 4]);
+		midx.extend((offset as u64).to_be_bytes());
+		for (_, chunk) in chunks {
+			midx.extend(chunk);
+		}
+		midx.extend(sha1::sha1(&midx));
+		fs::write(repo.objects_dir().join("pack/multi-pack-index"), midx).unwrap();
+	}
+
+	#[test]
+	fn parses_base_objects() {
+		let large = vec![b'x'; 1000];
+		let pack = pack_of(&[(OBJ_BLOB, b"hello\n"), (OBJ_TREE, b""), (OBJ_BLOB, &large)]);
+
+		let objects = parse_pack(&pack).unwrap();
+		assert_eq!(
+			objects,
+			[
+				(ObjectKind::Blob, b"hello\n".to_vec()),
+				(ObjectKind::Tree, Vec::new()),
+				(ObjectKind::Blob, large),
+			]
+		);
+	}
+
+	#[test]
+	fn encoded_packs_parse_back() {
+		let objects = vec![
+			(ObjectKind::Commit, b"tree 0\n".to_vec()),
+			(ObjectKind::Blob, vec![b'x'; 1000]),
+			(ObjectKind::Tag, Vec::new()),
+		];
+		let pack = encode_pack(&objects).unwrap();
+		assert_eq!(parse_pack(&pack).unwrap(), objects);
+		assert_eq!(encode_entry_header(OBJ_BLOB, 1000), [0xb8, 0x3e]);
+	}
+
+	#[test]
+	fn rejects_corrupt_packs() {
+		let mut pack = pack_of(&[(OBJ_BLOB, b"hello\n")]);
+		let last = pack.len() - 1;
+		pack[last] ^= 1;
+		assert!(matches!(
+			parse_pack(&pack),
+			Err(PackError::ChecksumMismatch)
+		));
+
+		let pack = pack_of(&[(OBJ_REF_DELTA, b"delta")]);
+		assert!(matches!(
+			parse_pack(&pack),
+			Err(PackError::UnsupportedDelta { offset: 12 })
+		));
+		assert!(matches!(parse_pack(b"PACK"), Err(PackError::Truncated)));
+	}
+
+	#[test]
+	fn objects_are_found_through_the_index() {
+		let (_dir, repo) = init_repo();
+		let objects: Vec<(u8, Vec<u8>)> = (0..50)
+			.map(|n| (OBJ_BLOB, format!("blob {n}\n").into_bytes()))
+			.collect();
+		let objects: Vec<(u8, &[u8])> = objects.iter().map(|(k, p)| (*k, &p[..])).collect();
+		let ids = write_pack(&repo, &objects);
+
+		for (id, (_, payload)) in ids.iter().zip(&objects) {
+			assert_eq!(
+				read_packed_object(&repo, id).unwrap(),
+				Some((ObjectKind::Blob, payload.to_vec()))
+			);
+		}
+		assert_eq!(read_packed_object(&repo, &[0; 20]).unwrap(), None);
+		let mut packed = packed_ids(&repo).unwrap();
+		let mut expected = ids.clone();
+		packed.sort();
+		expected.sort();
+		assert_eq!(packed, expected);
+
+		// Indexes are parsed once per repository.
+		let idx = pack_indexes(&repo).unwrap().remove(0);
+		fs::write(&idx, b"garbage").unwrap();
+		assert!(read_packed_object(&repo, &ids[0]).unwrap().is_some());
+		assert!(matches!(
+			read_packed_object(&Repo::new(repo.git_dir()), &ids[0]),
+			Err(PackError::CorruptIndex { .. })
+		));
+	}
+
+	#[test]
+	fn multi_pack_index_finds_objects_of_several_packs() {
+		let (_dir, repo) = init_repo();
+		let first = write_pack(&repo, &[(OBJ_BLOB, b"one\n"), (OBJ_BLOB, b"shared\n")]);
+		let second = write_pack(&repo, &[(OBJ_BLOB, b"shared\n"), (OBJ_BLOB, b"two\n")]);
+		write_multi_pack_index(&repo);
+
+		let paths = pack_indexes(&repo).unwrap();
+		let path = repo.objects_dir().join("pack/multi-pack-index");
+		let midx = MultiPackIndex::parse(fs::read(&path).unwrap(), &path).unwrap();
+		for id in first.iter().chain(&second) {
+			let (pack, offset) = midx.find(id).unwrap();
+			let idx_path = paths.iter().find(|path| path.ends_with(pack)).unwrap();
+			let index = PackIndex::parse(fs::read(idx_path).unwrap(), idx_path.clone()).unwrap();
+			assert_eq!(index.find(id), Some(offset));
+		}
+		assert_eq!(midx.find(&[0; 20]), None);
+		assert!(paths.iter().all(|path| midx.covers(path)));
+
+		// Covered packs are looked up through the multi-pack-index alone.
+		for path in &paths {
+			fs::write(path, b"garbage").unwrap();
+		}
+		let repo = Repo::new(repo.git_dir());
+		assert_eq!(
+			read_packed_object(&repo, &second[1]).unwrap(),
+			Some((ObjectKind::Blob, b"two\n".to_vec()))
+		);
+		assert_eq!(
+			read_packed_object(&repo, &first[0]).unwrap(),
+			Some((ObjectKind::Blob, b"one\n".to_vec()))
+		);
+		// A pack added since has its own index read.
+		let third = write_pack(&repo, &[(OBJ_BLOB, b"three\n")]);
+		assert_eq!(
+			read_packed_object(&repo, &third[0]).unwrap(),
+			Some((ObjectKind::Blob, b"three\n".to_vec()))
+		);
+		// The object in both packs is listed once.
+		assert_eq!(packed_ids(&repo).unwrap().len(), 4);
+	}
+}
@@ -0,0 +1,545 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::repo::Repo;
+use crate::ObjectId;
+
+/// How many `ref: ...` indirections are followed before a ref is considered broken.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// State of `HEAD`, see [Repo::head].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Head {
+	/// `HEAD` points at a branch, e.g. `refs/heads/master`. `target` is `None` while the branch
+	/// is unborn (it has no commits yet).
+	Symbolic {
+		ref_name: String,
+		target: Option<ObjectId>,
+	},
+	/// `HEAD` points directly at a commit.
+	Detached(ObjectId),
+}
+
+/// Reads `HEAD`, resolving the branch it points at.
+pub fn read_head(repo: &Repo) -> std::io::Result<Head> {
+	let head = fs::read_to_string(repo.git_dir().join("HEAD"))?;
+	let head = head.trim_end();
+
+	match head.strip_prefix("ref: ") {
+		Some(ref_name) => {
+			let target = resolve_ref(repo, ref_name)?
+				.map(|hash| parse_object_id(&hash, ref_name))
+				.transpose()?;
+			Ok(Head::Symbolic {
+				ref_name: ref_name.to_string(),
+				target,
+			})
+		}
+		None => Ok(Head::Detached(parse_object_id(head, "HEAD")?)),
+	}
+}
+
+fn parse_object_id(hash: &str, ref_name: &str) -> std::io::Result<ObjectId> {
+	let mut id = [0_u8; 20];
+	hex::decode_to_slice(hash, &mut id).map_err(|err| {
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("{ref_name} doesn't point at an object: {err}"),
+		)
+	})?;
+	Ok(id)
+}
+
+/// Points ref `name` (e.g. `refs/heads/master`) at `hash_str`, creating it if needed.
+pub fn write_ref(repo: &Repo, name: &str, hash_str: &str) -> std::io::Result<()> {
+	write_ref_locked(repo, name, &format!("{hash_str}\n"), None).map_err(|err| match err {
+		UpdateRefError::Io(err) => err,
+		err => std::io::Error::other(err),
+	})
+}
+
+#[derive(Debug, Error)]
+pub enum UpdateRefError {
+	#[error("Unable to create '{0}': File exists. Another git process seems to be running")]
+	Locked(PathBuf),
+
+	#[error("Cannot lock ref '{name}': is at {actual} but expected {expected}")]
+	Mismatch {
+		name: String,
+		actual: String,
+		expected: String,
+	},
+
+	#[error("Cannot lock ref '{0}': reference already exists")]
+	AlreadyExists(String),
+
+	#[error("Cannot lock ref '{0}': unable to resolve reference")]
+	Missing(String),
+
+	#[error("refusing to update ref with bad name '{0}'")]
+	BadName(String),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// Points ref `name` at `hash_str` like [write_ref], but following symbolic refs (so `HEAD`
+/// moves the branch it points at) and, if `old` is given, only if the ref points at it
+/// (or doesn't exist, for the null hash). The check can't race with other writers: the new
+/// value is written to `<ref>.lock`, created exclusively, and renamed over the ref.
+pub fn update_ref(
+	repo: &Repo,
+	name: &str,
+	hash_str: &str,
+	old: Option<&str>,
+) -> Result<(), UpdateRefError> {
+	check_ref_name(name)?;
+	let name = dereference(repo, name)?;
+	write_ref_locked(repo, &name, &format!("{hash_str}\n"), old)
+}
+
+/// Reads the symbolic ref `name` (like `HEAD`), returning the name of the ref it points at, or
+/// `None` if it points directly at an object or doesn't exist.
+pub fn read_symbolic_ref(repo: &Repo, name: &str) -> std::io::Result<Option<String>> {
+	match fs::read_to_string(repo.git_dir().join(name)) {
+		Ok(content) => Ok(content.trim_end().strip_prefix("ref: ").map(str::to_string)),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(err),
+	}
+}
+
+/// Makes `name` a symbolic ref pointing at ref `target`, through a lock file like
+/// [update_ref].
+pub fn write_symbolic_ref(repo: &Repo, name: &str, target: &str) -> Result<(), UpdateRefError> {
+	write_ref_locked(repo, name, &format!("ref: {target}\n"), None)
+}
+
+/// Writes `content` to ref `name` through `<ref>.lock`, see [update_ref].
+fn write_ref_locked(
+	repo: &Repo,
+	name: &str,
+	content: &str,
+	old: Option<&str>,
+) -> Result<(), UpdateRefError> {
+	check_ref_name(name)?;
+	let path = repo.git_dir().join(name);
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	let mut lock_path = path.clone().into_os_string();
+	lock_path.push(".lock");
+	let lock_path = PathBuf::from(lock_path);
+	let mut lock = match fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(&lock_path)
+	{
+		Ok(v) => v,
+		Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+			return Err(UpdateRefError::Locked(lock_path))
+		}
+		Err(err) => return Err(err.into()),
+	};
+
+	let result = check_ref(repo, name, old).and_then(|()| {
+		lock.write_all(content.as_bytes())?;
+		drop(lock);
+		Ok(fs::rename(&lock_path, &path)?)
+	});
+	if result.is_err() {
+		let _ = fs::remove_file(&lock_path);
+	}
+	result
+}
+
+/// Checks that `name` can be written as a ref: `HEAD` or a well-formed name under `refs/`, so
+/// it can't point outside the git directory.
+pub fn check_ref_name(name: &str) -> Result<(), UpdateRefError> {
+	if name == "HEAD" || (name.starts_with("refs/") && check_ref_format(name)) {
+		Ok(())
+	} else {
+		Err(UpdateRefError::BadName(name.to_string()))
+	}
+}
+
+/// Checks that ref `name` points at `old`, if given, or doesn't exist if it's the null hash.
+fn check_ref(repo: &Repo, name: &str, old: Option<&str>) -> Result<(), UpdateRefError> {
+	let Some(expected) = old else {
+		return Ok(());
+	};
+	let must_be_missing = expected.bytes().all(|b| b == b'0');
+	match (resolve_ref(repo, name)?, must_be_missing) {
+		(Some(_), true) => Err(UpdateRefError::AlreadyExists(name.to_string())),
+		(None, false) => Err(UpdateRefError::Missing(name.to_string())),
+		(Some(actual), false) if actual != expected => Err(UpdateRefError::Mismatch {
+			name: name.to_string(),
+			actual,
+			expected: expected.to_string(),
+		}),
+		_ => Ok(()),
+	}
+}
+
+/// Follows the `ref: ...` indirections of `name`, returning the name of the ref it ends at,
+/// which may not exist yet (like the branch of an unborn `HEAD`).
+pub fn dereference(repo: &Repo, name: &str) -> std::io::Result<String> {
+	let mut name = name.to_string();
+	for _ in 0..MAX_SYMREF_DEPTH {
+		match read_symbolic_ref(repo, &name)? {
+			Some(target) => name = target,
+			None => return Ok(name),
+		}
+	}
+	Err(std::io::Error::new(
+		std::io::ErrorKind::InvalidData,
+		format!("{name}: too many levels of symbolic refs"),
+	))
+}
+
+/// Deletes ref `name`, loose or packed. Returns whether it existed.
+pub fn delete_ref(repo: &Repo, name: &str) -> std::io::Result<bool> {
+	let path = repo.git_dir().join(name);
+	let mut existed = match fs::remove_file(&path) {
+		Ok(()) => true,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+		Err(err) => return Err(err),
+	};
+	// Leave no empty directories below `refs/heads` and the like behind, they would get in the
+	// way of a ref with their name.
+	let refs_dir = repo.git_dir().join("refs");
+	for dir in path.ancestors().skip(1) {
+		let is_namespace = dir.parent().is_none_or(|parent| parent == refs_dir);
+		if is_namespace || !dir.starts_with(&refs_dir) || fs::remove_dir(dir).is_err() {
+			break;
+		}
+	}
+
+	let packed_path = repo.git_dir().join("packed-refs");
+	let content = match fs::read_to_string(&packed_path) {
+		Ok(v) => v,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(existed),
+		Err(err) => return Err(err),
+	};
+	let mut kept = String::new();
+	let mut removed = false;
+	for line in content.lines() {
+		// A peeled line goes with the ref on the line before it.
+		if !line.starts_with('^') {
+			removed = line
+				.split_once(' ')
+				.is_some_and(|(_, ref_name)| ref_name == name);
+		}
+		if removed {
+			existed = true;
+		} else {
+			kept.push_str(line);
+			kept.push('\n');
+		}
+	}
+	if kept.len() != content.len() {
+		fs::write(packed_path, kept)?;
+	}
+	Ok(existed)
+}
+
+/// Whether `name` is a valid ref name, following the rules of `git check-ref-format`: no
+/// component starts with `.` or ends with `.lock`, no `..`, `@{`, `//`, ASCII control
+/// characters, spaces or any of `~^:?*[\`, and it doesn't end with `/` or `.` or equal `@`.
+pub fn check_ref_format(name: &str) -> bool {
+	!name.is_empty()
+		&& name != "@"
+		&& !name.contains("..")
+		&& !name.contains("@{")
+		&& !name.ends_with('.')
+		&& !name
+			.bytes()
+			.any(|b| b.is_ascii_control() || b" ~^:?*[\\".contains(&b))
+		&& name.split('/').all(|component| {
+			!component.is_empty() && !component.starts_with('.') && !component.ends_with(".lock")
+		})
+}
+
+/// How many `refs/replace/` indirections are followed before giving up, like git.
+const MAX_REPLACE_DEPTH: usize = 5;
+
+/// Resolves `hash_str` through `refs/replace/<hash>`, returning the object that should be read
+/// in its place. Returns `hash_str` itself if it isn't replaced or replacement is disabled.
+pub fn replacement(repo: &Repo, hash_str: &str) -> std::io::Result<String> {
+	let mut hash_str = hash_str.to_string();
+	if !repo.replace_objects() {
+		return Ok(hash_str);
+	}
+
+	for _ in 0..MAX_REPLACE_DEPTH {
+		let path = repo.git_dir().join("refs/replace").join(&hash_str);
+		let replacement = match fs::read_to_string(path) {
+			Ok(v) => v.trim().to_ascii_lowercase(),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => break,
+			Err(err) => return Err(err),
+		};
+		if replacement.len() != 40 || !replacement.bytes().all(|b| b.is_ascii_hexdigit()) {
+			break;
+		}
+		hash_str = replacement;
+	}
+
+	Ok(hash_str)
+}
+
+/// Enumerates every ref under `refs/` (heads, tags, remotes, notes, stash, replace, ...), loose
+/// and packed, with the object each one points at. Sorted by ref name.
+///
+/// Symbolic refs are resolved; ones that don't resolve to an object are skipped.
+pub fn all_refs(repo: &Repo) -> std::io::Result<Vec<(String, String)>> {
+	let mut refs = read_packed_refs(repo)?;
+
+	let mut loose = Vec::new();
+	collect_loose_refs(&repo.git_dir().join("refs"), "refs", &mut loose)?;
+	for name in loose {
+		match resolve_ref(repo, &name)? {
+			Some(hash) => {
+				refs.insert(name, hash);
+			}
+			None => {
+				refs.remove(&name);
+			}
+		}
+	}
+
+	Ok(refs.into_iter().collect())
+}
+
+/// Resolves ref `name` (e.g. `HEAD` or `refs/heads/master`) to the object it points at,
+/// following symbolic refs. Returns `None` for refs that don't exist or are dangling symbolic
+/// refs.
+pub fn resolve_ref(repo: &Repo, name: &str) -> std::io::Result<Option<String>> {
+	let mut name = name.to_string();
+	for _ in 0..MAX_SYMREF_DEPTH {
+		let content = match fs::read_to_string(repo.git_dir().join(&name)) {
+			Ok(v) => v,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+				return Ok(read_packed_refs(repo)?.remove(&name));
+			}
+			Err(err) => return Err(err),
+		};
+		match content.trim_end().strip_prefix("ref: ") {
+			Some(target) => name = target.to_string(),
+			None => return Ok(Some(content.trim().to_string())),
+		}
+	}
+	Ok(None)
+}
+
+/// Reads `packed-refs`, skipping comments and peeled (`^<hash>`) lines.
+fn read_packed_refs(repo: &Repo) -> std::io::Result<BTreeMap<String, String>> {
+	let content = match fs::read_to_string(repo.git_dir().join("packed-refs")) {
+		Ok(v) => v,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+		Err(err) => return Err(err),
+	};
+
+	Ok(content
+		.lines()
+		.filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+		.filter_map(|line| line.split_once(' '))
+		.map(|(hash, name)| (name.to_string(), hash.to_string()))
+		.collect())
+}
+
+fn collect_loose_refs(dir: &Path, prefix: &str, out: &mut Vec<String>) -> std::io::Result<()> {
+	let read_dir = match fs::read_dir(dir) {
+		Ok(v) => v,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err),
+	};
+
+	for entry in read_dir {
+		let entry = entry?;
+		let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+			continue;
+		};
+		// Lock files of in-progress ref updates
+		if name.ends_with(".lock") {
+			continue;
+		}
+		let ref_name = format!("{prefix}/{name}");
+		if entry.file_type()?.is_dir() {
+			collect_loose_refs(&entry.path(), &ref_name, out)?;
+		} else {
+			out.push(ref_name);
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::init_repo;
+
+	#[test]
+	fn refs_are_deleted_loose_and_packed() {
+		let (_dir, repo) = init_repo();
+		let hash = |n: u8| format!("{n:040x}");
+		write_ref(&repo, "refs/heads/loose", &hash(1)).unwrap();
+		let packed = format!(
+			"# pack-refs with: peeled\n{} refs/heads/loose\n{} refs/tags/v1\n^{}\n{} refs/tags/v2\n",
+			hash(2),
+			hash(3),
+			hash(4),
+			hash(5)
+		);
+		fs::write(repo.git_dir().join("packed-refs"), packed).unwrap();
+
+		assert!(delete_ref(&repo, "refs/heads/loose").unwrap());
+		assert_eq!(resolve_ref(&repo, "refs/heads/loose").unwrap(), None);
+		write_ref(&repo, "refs/heads/nested/x", &hash(1)).unwrap();
+		assert!(delete_ref(&repo, "refs/heads/nested/x").unwrap());
+		assert!(!repo.git_dir().join("refs/heads/nested").exists());
+		assert!(repo.git_dir().join("refs/heads").exists());
+		assert!(delete_ref(&repo, "refs/tags/v1").unwrap());
+		assert!(!delete_ref(&repo, "refs/tags/v1").unwrap());
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("packed-refs")).unwrap(),
+			format!("# pack-refs with: peeled\n{} refs/tags/v2\n", hash(5))
+		);
+	}
+
+	#[test]
+	fn ref_updates_are_checked_and_locked() {
+		let (_dir, repo) = init_repo();
+		let hash = |n: u8| format!("{n:040x}");
+		let null = "0".repeat(40);
+		let branch = || resolve_ref(&repo, "refs/heads/master").unwrap();
+
+		// Through the unborn HEAD.
+		update_ref(&repo, "HEAD", &hash(1), Some(&null)).unwrap();
+		assert_eq!(branch(), Some(hash(1)));
+		assert!(matches!(
+			update_ref(&repo, "HEAD", &hash(2), Some(&null)),
+			Err(UpdateRefError::AlreadyExists(name)) if name == "refs/heads/master"
+		));
+		assert!(matches!(
+			update_ref(&repo, "refs/heads/master", &hash(2), Some(&hash(3))),
+			Err(UpdateRefError::Mismatch { actual, .. }) if actual == hash(1)
+		));
+		assert!(matches!(
+			update_ref(&repo, "refs/heads/other", &hash(2), Some(&hash(1))),
+			Err(UpdateRefError::Missing(_))
+		));
+		assert_eq!(branch(), Some(hash(1)));
+		update_ref(&repo, "refs/heads/master", &hash(2), Some(&hash(1))).unwrap();
+		assert_eq!(branch(), Some(hash(2)));
+		assert_eq!(
+			fs::read_to_string(repo.git_dir().join("HEAD")).unwrap(),
+			"ref: refs/heads/master\n"
+		);
+
+		let lock = repo.git_dir().join("refs/heads/master.lock");
+		fs::write(&lock, "").unwrap();
+		assert!(matches!(
+			update_ref(&repo, "refs/heads/master", &hash(3), None),
+			Err(UpdateRefError::Locked(path)) if path == lock
+		));
+		fs::remove_file(&lock).unwrap();
+
+		for name in [
+			"../../escaped",
+			"/tmp/abs",
+			"refs/heads/../../x",
+			"master",
+			"refs/",
+		] {
+			assert!(
+				matches!(
+					update_ref(&repo, name, &hash(3), None),
+					Err(UpdateRefError::BadName(bad)) if bad == name
+				),
+				"{name}"
+			);
+		}
+		assert!(!repo.git_dir().join("../../escaped").exists());
+		update_ref(&repo, "refs/heads/master", &hash(3), None).unwrap();
+		assert_eq!(branch(), Some(hash(3)));
+		assert!(!lock.exists());
+	}
+
+	#[test]
+	fn ref_names_are_checked() {
+		for name in [
+			"refs/heads/master",
+			"refs/heads/feature/x-1",
+			"refs/tags/v1.0",
+		] {
+			assert!(check_ref_format(name), "{name}");
+		}
+		for name in [
+			"",
+			"@",
+			"refs/heads/a..b",
+			"refs/heads/.hidden",
+			"refs/heads/x.lock",
+			"refs/heads/x/",
+			"refs/heads//x",
+			"refs/heads/x.",
+			"refs/heads/a b",
+			"refs/heads/a~1",
+			"refs/heads/a^",
+			"refs/heads/a:b",
+			"refs/heads/a@{1}",
+			"refs/heads/a\\b",
+		] {
+			assert!(!check_ref_format(name), "{name}");
+		}
+	}
+
+	#[test]
+	fn all_refs_covers_every_namespace() {
+		let (_dir, repo) = init_repo();
+		let hash = |n: u8| format!("{n:040x}");
+		let write_ref = |name: &str, content: &str| {
+			let path = repo.git_dir().join(name);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, content).unwrap();
+		};
+
+		write_ref("refs/heads/master", &format!("{}\n", hash(1)));
+		write_ref("refs/stash", &format!("{}\n", hash(2)));
+		write_ref("refs/notes/commits", &format!("{}\n", hash(3)));
+		write_ref(
+			"refs/remotes/origin/HEAD",
+			"ref: refs/remotes/origin/main\n",
+		);
+		write_ref(
+			"refs/remotes/origin/dangling",
+			"ref: refs/remotes/origin/nope\n",
+		);
+		write_ref(
+			"packed-refs",
+			&format!(
+				"# pack-refs with: peeled\n{} refs/remotes/origin/main\n{} refs/tags/v1\n^{}\n",
+				hash(4),
+				hash(5),
+				hash(6)
+			),
+		);
+
+		let refs = all_refs(&repo).unwrap();
+		assert_eq!(
+			refs,
+			vec![
+				("refs/heads/master".to_string(), hash(1)),
+				("refs/notes/commits".to_string(), hash(3)),
+				("refs/remotes/origin/HEAD".to_string(), hash(4)),
+				("refs/remotes/origin/main".to_string(), hash(4)),
+				("refs/stash".to_string(), hash(2)),
+				("refs/tags/v1".to_string(), hash(5)),
+			]
+		);
+	}
+}
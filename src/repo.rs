@@ -0,0 +1,585 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, ReadDir};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::pack::PackIndexes;
+use crate::refs::{self, Head};
+use crate::{read_object_header, ObjectId, ObjectKind, ReadObjectError};
+
+/// A git repository on disk, identified by its git directory (usually `.git`).
+pub struct Repo {
+	git_dir: PathBuf,
+	index_file: PathBuf,
+	replace_objects: bool,
+	verify_objects: bool,
+	author: IdentityOverrides,
+	committer: IdentityOverrides,
+	/// Config set through `GIT_CONFIG_COUNT`, `GIT_CONFIG_KEY_<n>` and `GIT_CONFIG_VALUE_<n>`,
+	/// keys normalized with [normalize_config_key].
+	config: Vec<(String, String)>,
+	/// Config of the `config` file in the git directory, read the first time it's needed.
+	file_config: OnceLock<Vec<(String, String)>>,
+	/// Pack indexes read so far.
+	pack_indexes: PackIndexes,
+	/// Commits listed in `shallow`, read the first time they're needed.
+	shallow: OnceLock<HashSet<ObjectId>>,
+}
+
+/// Author or committer identity given through the environment (`GIT_AUTHOR_NAME`,
+/// `GIT_AUTHOR_EMAIL`, `GIT_AUTHOR_DATE` and their `GIT_COMMITTER_` counterparts).
+#[derive(Debug, Clone, Default)]
+pub struct IdentityOverrides {
+	pub name: Option<String>,
+	pub email: Option<String>,
+	/// Date instead of now, unparsed (see [crate::date::parse_date]).
+	pub date: Option<String>,
+}
+
+impl Repo {
+	pub fn new(git_dir: impl Into<PathBuf>) -> Self {
+		let git_dir = git_dir.into();
+		Repo {
+			index_file: git_dir.join("index"),
+			git_dir,
+			replace_objects: true,
+			verify_objects: true,
+			author: IdentityOverrides::default(),
+			committer: IdentityOverrides::default(),
+			config: Vec::new(),
+			file_config: OnceLock::new(),
+			pack_indexes: PackIndexes::default(),
+			shallow: OnceLock::new(),
+		}
+	}
+
+	/// Applies overrides from git's environment variables, looked up with `var`
+	/// (usually [std::env::var_os]).
+	///
+	/// Supported: `GIT_INDEX_FILE`, `GIT_NO_REPLACE_OBJECTS`, `GIT_NO_VERIFY_OBJECTS`,
+	/// `GIT_AUTHOR_{NAME,EMAIL,DATE}`, `GIT_COMMITTER_{NAME,EMAIL,DATE}`, `GIT_CONFIG_COUNT` with
+	/// `GIT_CONFIG_{KEY,VALUE}_<n>`.
+	pub fn with_env(mut self, var: impl Fn(&str) -> Option<OsString>) -> Self {
+		if let Some(index_file) = var("GIT_INDEX_FILE").filter(|v| !v.is_empty()) {
+			self.index_file = index_file.into();
+		}
+		if var("GIT_NO_REPLACE_OBJECTS").is_some() {
+			self.replace_objects = false;
+		}
+		if var("GIT_NO_VERIFY_OBJECTS").is_some() {
+			self.verify_objects = false;
+		}
+		let string_var = |name: &str| var(name).map(|v| v.to_string_lossy().into_owned());
+		let identity = |role| IdentityOverrides {
+			name: string_var(&format!("GIT_{role}_NAME")),
+			email: string_var(&format!("GIT_{role}_EMAIL")),
+			date: string_var(&format!("GIT_{role}_DATE")),
+		};
+		self.author = identity("AUTHOR");
+		self.committer = identity("COMMITTER");
+
+		let count = string_var("GIT_CONFIG_COUNT").and_then(|count| count.parse::<usize>().ok());
+		for n in 0..count.unwrap_or(0) {
+			let Some(key) = string_var(&format!("GIT_CONFIG_KEY_{n}")) else {
+				continue;
+			};
+			let value = string_var(&format!("GIT_CONFIG_VALUE_{n}")).unwrap_or_default();
+			self.config.push((normalize_config_key(&key), value));
+		}
+		self
+	}
+
+	/// Value of config `key`, e.g. `user.name`: set through the environment or else in the
+	/// `config` file of the git directory. The last value set wins.
+	pub fn config(&self, key: &str) -> Option<&str> {
+		let key = normalize_config_key(key);
+		let file_config = self.file_config.get_or_init(|| {
+			let content = fs::read_to_string(self.git_dir.join("config")).unwrap_or_default();
+			parse_config(&content)
+		});
+		[&self.config, file_config].into_iter().find_map(|config| {
+			config
+				.iter()
+				.rev()
+				.find(|(k, _)| *k == key)
+				.map(|(_, value)| value.as_str())
+		})
+	}
+
+	/// Size above which blobs are streamed instead of being read whole, `core.bigFileThreshold`
+	/// (512 MiB by default). Like git, the value may end with `k`, `m` or `g`.
+	pub fn big_file_threshold(&self) -> u64 {
+		self.config("core.bigFileThreshold")
+			.and_then(parse_config_size)
+			.unwrap_or(512 << 20)
+	}
+
+	/// Whether objects should be substituted according to `refs/replace/`.
+	pub fn with_replace_objects(mut self, replace_objects: bool) -> Self {
+		self.replace_objects = replace_objects;
+		self
+	}
+
+	pub fn replace_objects(&self) -> bool {
+		self.replace_objects
+	}
+
+	/// Whether objects are checked to hash to their id when they are read.
+	pub fn with_verify_objects(mut self, verify_objects: bool) -> Self {
+		self.verify_objects = verify_objects;
+		self
+	}
+
+	pub fn verify_objects(&self) -> bool {
+		self.verify_objects
+	}
+
+	/// Identity new commits are authored with.
+	pub fn author(&self) -> &IdentityOverrides {
+		&self.author
+	}
+
+	/// Identity new commits are committed with.
+	pub fn committer(&self) -> &IdentityOverrides {
+		&self.committer
+	}
+
+	pub fn git_dir(&self) -> &Path {
+		&self.git_dir
+	}
+
+	/// Directory with the checked out files, the parent of the git directory.
+	pub fn work_tree(&self) -> &Path {
+		match self.git_dir.parent() {
+			Some(parent) if !parent.as_os_str().is_empty() => parent,
+			_ => Path::new("."),
+		}
+	}
+
+	pub fn index_path(&self) -> &Path {
+		&self.index_file
+	}
+
+	pub fn objects_dir(&self) -> PathBuf {
+		self.git_dir.join("objects")
+	}
+
+	/// Indexes of the packs in `objects/pack/`, each parsed the first time it's needed.
+	pub fn pack_indexes(&self) -> &PackIndexes {
+		&self.pack_indexes
+	}
+
+	/// Whether commit `id` is listed in `shallow`, the commits whose parents a shallow clone
+	/// (`clone --depth`) didn't fetch.
+	pub fn is_shallow(&self, id: &ObjectId) -> bool {
+		self.shallow
+			.get_or_init(|| {
+				let shallow = fs::read_to_string(self.git_dir.join("shallow")).unwrap_or_default();
+				shallow
+					.lines()
+					.filter_map(|line| {
+						let mut id = [0_u8; 20];
+						hex::decode_to_slice(line, &mut id).ok().map(|()| id)
+					})
+					.collect()
+			})
+			.contains(id)
+	}
+
+	/// Path of the loose object with given hex encoded hash (`objects/xx/yyy...`).
+	pub fn object_path(&self, hash_str: &str) -> PathBuf {
+		self.objects_dir()
+			.join(&hash_str[0..2])
+			.join(&hash_str[2..])
+	}
+}
+
+impl Repo {
+	/// Reads `HEAD`, telling apart a checked out branch, possibly unborn, and a detached `HEAD`.
+	pub fn head(&self) -> std::io::Result<Head> {
+		refs::read_head(self)
+	}
+
+	/// Iterates over every object in the repository along with its kind. Only the object headers
+	/// are inflated.
+	pub(crate) fn objects(&self) -> std::io::Result<Objects> {
+		let fanout = match fs::read_dir(self.objects_dir()) {
+			Ok(v) => Some(v),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+			Err(err) => return Err(err),
+		};
+		Ok(Objects {
+			fanout,
+			current: None,
+		})
+	}
+}
+
+/// Iterator over loose objects, see [Repo::objects].
+pub(crate) struct Objects {
+	/// Entries of `objects/`.
+	fanout: Option<ReadDir>,
+	/// Fanout directory being listed and its name, the first two hex digits of its objects.
+	current: Option<(String, ReadDir)>,
+}
+
+impl Iterator for Objects {
+	type Item = Result<(ObjectId, ObjectKind), ReadObjectError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some((prefix, dir)) = &mut self.current {
+				let entry = match dir.next() {
+					Some(Ok(v)) => v,
+					Some(Err(err)) => return Some(Err(err.into())),
+					None => {
+						self.current = None;
+						continue;
+					}
+				};
+				// Skips temporary files of objects being written.
+				let Some(name) = entry
+					.file_name()
+					.to_str()
+					.filter(|n| is_hex(n, 38))
+					.map(str::to_owned)
+				else {
+					continue;
+				};
+
+				let hash_str = format!("{prefix}{name}");
+				let mut id = [0_u8; 20];
+				hex::decode_to_slice(&hash_str, &mut id).expect("name was checked to be hex");
+				let header = read_object_header(&entry.path(), &hash_str);
+				return Some(header.map(|(kind, _)| (id, kind)));
+			}
+
+			let entry = match self.fanout.as_mut()?.next()? {
+				Ok(v) => v,
+				Err(err) => return Some(Err(err.into())),
+			};
+			// Skips `info/`, `pack/` and anything else that isn't a fanout directory.
+			let Some(prefix) = entry
+				.file_name()
+				.to_str()
+				.filter(|n| is_hex(n, 2))
+				.map(str::to_owned)
+			else {
+				continue;
+			};
+			match fs::read_dir(entry.path()) {
+				Ok(dir) => self.current = Some((prefix, dir)),
+				Err(err) if err.kind() == std::io::ErrorKind::NotADirectory => continue,
+				Err(err) => return Some(Err(err.into())),
+			}
+		}
+	}
+}
+
+/// Lowercases the section and name of a config key, leaving the subsection (which is case
+/// sensitive) as is: `Remote.Origin.URL` becomes `remote.Origin.url`.
+fn normalize_config_key(key: &str) -> String {
+	match (key.split_once('.'), key.rsplit_once('.')) {
+		(Some((section, _)), Some((middle, name))) if middle.len() > section.len() => format!(
+			"{}{}.{}",
+			section.to_ascii_lowercase(),
+			&middle[section.len()..],
+			name.to_ascii_lowercase()
+		),
+		_ => key.to_ascii_lowercase(),
+	}
+}
+
+/// Parses a config file (see git-config(1)) into its variables, in order, with keys normalized
+/// like by [normalize_config_key]. A variable without a value (`name` alone) is `true`. Lines
+/// that can't be parsed are skipped.
+fn parse_config(content: &str) -> Vec<(String, String)> {
+	let mut variables = Vec::new();
+	let mut section = None;
+	let mut lines = content.lines();
+	while let Some(line) = lines.next() {
+		let mut line = line.trim_start();
+		if let Some(header) = line.strip_prefix('[') {
+			let Some((header, rest)) = header.split_once(']') else {
+				section = None;
+				continue;
+			};
+			section = Some(match header.split_once(char::is_whitespace) {
+				// `[section "subsection"]`, the subsection is case sensitive.
+				Some((name, subsection)) => {
+					let subsection = subsection.trim();
+					let subsection = subsection
+						.strip_prefix('"')
+						.and_then(|s| s.strip_suffix('"'))
+						.unwrap_or(subsection)
+						.replace("\\\"", "\"")
+						.replace("\\\\", "\\");
+					format!("{}.{subsection}", name.to_ascii_lowercase())
+				}
+				// Also the deprecated `[section.subsection]`.
+				None => header.to_ascii_lowercase(),
+			});
+			// A variable may follow on the same line.
+			line = rest.trim_start();
+		}
+		if line.is_empty() || line.starts_with(['#', ';']) {
+			continue;
+		}
+		let Some(section) = &section else {
+			continue;
+		};
+
+		let (name, value) = match line.split_once('=') {
+			Some((name, value)) => (name.trim(), Some(value)),
+			None => (line.trim(), None),
+		};
+		if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+			continue;
+		}
+		let value = match value {
+			Some(value) => parse_config_value(value, &mut lines),
+			None => "true".to_string(),
+		};
+		variables.push((format!("{section}.{}", name.to_ascii_lowercase()), value));
+	}
+	variables
+}
+
+/// Value of a config variable, whose first line is `raw`: leading and trailing whitespace is
+/// removed and other whitespace outside quotes becomes spaces. `"` quotes parts of it, `\`
+/// escapes the next character (`\"`, `\\`, `\n`, `\t` or `\b`) or, at the end of the line,
+/// continues the value on the next one. `#` or `;` outside quotes starts a comment.
+fn parse_config_value<'a>(raw: &'a str, lines: &mut impl Iterator<Item = &'a str>) -> String {
+	let mut value = String::new();
+	// Whitespace outside quotes, kept only if something follows it.
+	let mut space = String::new();
+	let mut quoted = false;
+	let mut chars = raw.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => match chars.next() {
+				Some(escaped) => {
+					value += &space;
+					space.clear();
+					value.push(match escaped {
+						'n' => '\n',
+						't' => '\t',
+						'b' => '\x08',
+						other => other,
+					});
+				}
+				None => match lines.next() {
+					Some(next) => chars = next.chars(),
+					None => break,
+				},
+			},
+			'"' => {
+				value += &space;
+				space.clear();
+				quoted = !quoted;
+			}
+			'#' | ';' if !quoted => break,
+			c if c.is_whitespace() && !quoted => {
+				if !value.is_empty() {
+					space.push(' ');
+				}
+			}
+			c => {
+				value += &space;
+				space.clear();
+				value.push(c);
+			}
+		}
+	}
+	value
+}
+
+/// Size in a config value: a number optionally followed by `k`, `m` or `g` (case-insensitive)
+/// for KiB, MiB or GiB.
+fn parse_config_size(value: &str) -> Option<u64> {
+	let value = value.trim();
+	let (number, shift) = match value.chars().last()?.to_ascii_lowercase() {
+		'k' => (&value[..value.len() - 1], 10),
+		'm' => (&value[..value.len() - 1], 20),
+		'g' => (&value[..value.len() - 1], 30),
+		_ => (value, 0),
+	};
+	number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Finds the git directory of the repository `dir` is in: `git_dir_env` (the value of `GIT_DIR`,
+/// relative to `dir`) if set, otherwise the first `.git` directory found in `dir` or one of its
+/// ancestors.
+pub fn find_git_dir(dir: &Path, git_dir_env: Option<OsString>) -> std::io::Result<PathBuf> {
+	if let Some(git_dir) = git_dir_env.filter(|v| !v.is_empty()) {
+		return Ok(dir.join(git_dir));
+	}
+	dir.ancestors()
+		.map(|dir| dir.join(".git"))
+		.find(|git_dir| git_dir.is_dir())
+		.ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				"not a git repository (or any of the parent directories): .git",
+			)
+		})
+}
+
+fn is_hex(name: &str, len: usize) -> bool {
+	name.len() == len && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+
+	use super::*;
+	use crate::refs::Head;
+	use crate::test_utils::init_repo;
+	use crate::{hash_git_object, GitObject, ObjectKind};
+
+	#[test]
+	fn head_states() {
+		let (_dir, repo) = init_repo();
+		let head_path = repo.git_dir().join("HEAD");
+		let hash = [0xab; 20];
+
+		assert_eq!(
+			repo.head().unwrap(),
+			Head::Symbolic {
+				ref_name: "refs/heads/master".to_string(),
+				target: None,
+			}
+		);
+
+		std::fs::write(
+			repo.git_dir().join("refs/heads/master"),
+			format!("{}\n", hex::encode(hash)),
+		)
+		.unwrap();
+		assert_eq!(
+			repo.head().unwrap(),
+			Head::Symbolic {
+				ref_name: "refs/heads/master".to_string(),
+				target: Some(hash),
+			}
+		);
+
+		std::fs::write(&head_path, format!("{}\n", hex::encode(hash))).unwrap();
+		assert_eq!(repo.head().unwrap(), Head::Detached(hash));
+
+		std::fs::write(&head_path, "garbage\n").unwrap();
+		assert!(repo.head().is_err());
+	}
+
+	#[test]
+	fn objects_lists_every_written_object() {
+		let (_dir, repo) = init_repo();
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"a")), true).unwrap();
+		let other_blob =
+			hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"b")), true).unwrap();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+
+		// Not objects
+		std::fs::create_dir_all(repo.objects_dir().join("info")).unwrap();
+		std::fs::write(
+			repo.object_path(&blob.hash_str).with_file_name("tmp_obj_1"),
+			"",
+		)
+		.unwrap();
+
+		let mut objects = repo
+			.objects()
+			.unwrap()
+			.collect::<Result<Vec<_>, _>>()
+			.unwrap();
+		objects.sort();
+
+		let mut expected = vec![
+			(blob.hash, ObjectKind::Blob),
+			(other_blob.hash, ObjectKind::Blob),
+			(tree.hash, ObjectKind::Tree),
+		];
+		expected.sort();
+		assert_eq!(objects, expected);
+	}
+
+	#[test]
+	fn git_dir_is_found_in_ancestors() {
+		let (dir, repo) = init_repo();
+		let nested = dir.0.join("a/b");
+		fs::create_dir_all(&nested).unwrap();
+
+		assert_eq!(find_git_dir(&nested, None).unwrap(), repo.git_dir());
+		assert_eq!(find_git_dir(&dir.0, None).unwrap(), repo.git_dir());
+		assert_eq!(
+			find_git_dir(&nested, Some("../../other.git".into())).unwrap(),
+			nested.join("../../other.git")
+		);
+		assert_eq!(Repo::new(".git").work_tree(), Path::new("."));
+		assert_eq!(repo.work_tree(), dir.0);
+	}
+
+	#[test]
+	fn config_is_read_from_the_git_dir_with_env_overrides() {
+		let (_dir, repo) = init_repo();
+		fs::write(
+			repo.git_dir().join("config"),
+			"[core]\n\
+			 \thooksPath = \"my hooks\"  # comment\n\
+			 \tbare\n\
+			 [User] name = Foo \\\n\
+			 \tBar ; comment\n\
+			 [remote \"Origin\"]\n\
+			 \turl = https://example.com/repo.git\n\
+			 [core]\n\
+			 \tbigFileThreshold = 1k\n",
+		)
+		.unwrap();
+		let repo = Repo::new(repo.git_dir()).with_env(|name| match name {
+			"GIT_CONFIG_COUNT" => Some("1".into()),
+			"GIT_CONFIG_KEY_0" => Some("user.name".into()),
+			"GIT_CONFIG_VALUE_0" => Some("Env".into()),
+			_ => None,
+		});
+
+		assert_eq!(repo.config("core.hooksPath"), Some("my hooks"));
+		assert_eq!(repo.config("core.bare"), Some("true"));
+		assert_eq!(repo.config("core.bigfilethreshold"), Some("1k"));
+		assert_eq!(
+			repo.config("remote.Origin.url"),
+			Some("https://example.com/repo.git")
+		);
+		assert_eq!(repo.config("remote.origin.url"), None);
+		assert_eq!(repo.config("user.name"), Some("Env"));
+		assert_eq!(
+			Repo::new(repo.git_dir()).config("user.name"),
+			Some("Foo  Bar")
+		);
+		assert_eq!(
+			crate::hooks::hooks_dir(&repo),
+			repo.work_tree().join("my hooks")
+		);
+	}
+
+	#[test]
+	fn config_sizes_take_unit_suffixes() {
+		assert_eq!(parse_config_size("100"), Some(100));
+		assert_eq!(parse_config_size("2k"), Some(2048));
+		assert_eq!(parse_config_size("1M"), Some(1 << 20));
+		assert_eq!(parse_config_size("3g"), Some(3 << 30));
+		assert_eq!(parse_config_size("lots"), None);
+		assert_eq!(parse_config_size(""), None);
+	}
+
+	#[test]
+	fn config_keys_ignore_case_outside_subsections() {
+		assert_eq!(normalize_config_key("User.Name"), "user.name");
+		assert_eq!(
+			normalize_config_key("Remote.Origin.URL"),
+			"remote.Origin.url"
+		);
+		assert_eq!(normalize_config_key("core"), "core");
+	}
+}
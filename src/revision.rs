@@ -0,0 +1,285 @@
+use crate::repo::Repo;
+use crate::{
+	decode_object, expand_object_name, inflate_object, normalize_object_name, parse_object_header,
+	peel_tag, refs, GitObject, ReadObjectError,
+};
+
+/// Where a ref name given by the user is looked for, in order (`%s` being the name), like
+/// git's `ref_rev_parse_rules`.
+const REF_RULES: [&str; 6] = [
+	"%s",
+	"refs/%s",
+	"refs/tags/%s",
+	"refs/heads/%s",
+	"refs/remotes/%s",
+	"refs/remotes/%s/HEAD",
+];
+
+/// Resolves revision `name` to the full name of the object it means. Supported syntax:
+/// - a full or abbreviated object name
+/// - a ref, e.g. `HEAD`, `master`, `v1.0` or `refs/heads/master`, see [REF_RULES], or `@` for
+///   `HEAD`
+/// - either followed by any number of suffixes:
+///   - a peel suffix: `^{}` follows tags to the first object that isn't a tag, `^{<type>}`
+///     follows tags and commits (to their tree) until an object of that type and `^{object}`
+///     only checks that the object exists
+///   - `^<n>` for the n-th parent of the commit (`^` being `^1` and `^0` the commit itself)
+///   - `~<n>` for its n-th generation ancestor following first parents (`~` being `~1`)
+/// - `<rev>:<path>` for the object at `path` in the tree of revision `rev`
+pub fn resolve_revision(repo: &Repo, name: &str) -> Result<String, ReadObjectError> {
+	let not_found = || ReadObjectError::ObjectNotFound(name.to_string());
+	let or_not_found = |err| match err {
+		ReadObjectError::ObjectNotFound(_) => not_found(),
+		err => err,
+	};
+
+	if let Some((rev, path)) = name.split_once(':') {
+		let object = resolve_revision(repo, rev).map_err(or_not_found)?;
+		let tree = peel(repo, object, "tree")?.ok_or_else(not_found)?;
+		return tree_entry(repo, tree, path)?.ok_or_else(not_found);
+	}
+
+	// Ref names can't contain `^` or `~`, so the suffixes start at the first one.
+	let (base, mut suffixes) = name.split_at(name.find(['^', '~']).unwrap_or(name.len()));
+	let base = if base == "@" { "HEAD" } else { base };
+	let mut object = match dwim_ref(repo, base)? {
+		Some((_, object)) => object,
+		None => {
+			let object = normalize_object_name(base).ok_or_else(not_found)?;
+			expand_object_name(repo, &object)?
+		}
+	};
+
+	while !suffixes.is_empty() {
+		if let Some(rest) = suffixes.strip_prefix("^{") {
+			let (kind, rest) = rest.split_once('}').ok_or_else(not_found)?;
+			object = peel(repo, object, kind)?.ok_or_else(not_found)?;
+			suffixes = rest;
+			continue;
+		}
+
+		let (op, rest) = suffixes.split_at(1);
+		let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+		let (count, rest) = rest.split_at(digits);
+		let count: usize = match count {
+			"" => 1,
+			count => count.parse().map_err(|_| not_found())?,
+		};
+		suffixes = rest;
+
+		object = peel(repo, object, "commit")?.ok_or_else(not_found)?;
+		match op {
+			"^" if count > 0 => {
+				object = parent(repo, object, count - 1)?.ok_or_else(not_found)?;
+			}
+			"^" => {}
+			_ => {
+				for _ in 0..count {
+					object = parent(repo, object, 0)?.ok_or_else(not_found)?;
+				}
+			}
+		}
+	}
+	Ok(object)
+}
+
+/// Follows `object` to an object of kind `kind` like the `^{<kind>}` suffix of
+/// [resolve_revision] (an empty `kind` being `^{}`). Returns `None` if it doesn't lead to one.
+/// To peel a revision, resolve it first: a `<rev>:<path>` revision takes everything after the
+/// colon as the path, so a suffix can't be appended to it.
+pub fn peel(repo: &Repo, object: String, kind: &str) -> Result<Option<String>, ReadObjectError> {
+	let (_, bytes) = inflate_object(repo, object.clone())?;
+	let (object_kind, _, _) = parse_object_header(&object, &bytes)?;
+	match kind {
+		"object" => return Ok(Some(object)),
+		"tag" if object_kind == b"tag" => return Ok(Some(object)),
+		"tag" => return Ok(None),
+		_ => {}
+	}
+	let (peeled, peeled_kind) = peel_tag(repo, object, bytes)?;
+	match (kind, peeled_kind.as_str()) {
+		("", _) => Ok(Some(peeled)),
+		(kind, peeled_kind) if kind == peeled_kind => Ok(Some(peeled)),
+		("tree", "commit") => match decode_object(repo, peeled)? {
+			GitObject::Commit(commit) => Ok(Some(hex::encode(commit.tree))),
+			_ => Ok(None),
+		},
+		_ => Ok(None),
+	}
+}
+
+/// Parent number `idx` (from 0) of commit `commit`, if it has that many.
+fn parent(repo: &Repo, commit: String, idx: usize) -> Result<Option<String>, ReadObjectError> {
+	match decode_object(repo, commit)? {
+		GitObject::Commit(commit) => Ok(commit.parents.get(idx).map(hex::encode)),
+		_ => Ok(None),
+	}
+}
+
+/// Object at `path` (relative, `/`-separated) in `tree`, or `tree` itself for an empty path.
+fn tree_entry(repo: &Repo, tree: String, path: &str) -> Result<Option<String>, ReadObjectError> {
+	let mut object = tree;
+	for component in path.split('/').filter(|component| !component.is_empty()) {
+		let GitObject::Tree(entries) = decode_object(repo, object)? else {
+			return Ok(None);
+		};
+		match entries.iter().find(|entry| entry.name == component) {
+			Some(entry) => object = hex::encode(*entry.object_hash),
+			None => return Ok(None),
+		}
+	}
+	Ok(Some(object))
+}
+
+/// Finds the ref `name` means, following [REF_RULES] (a full object name is never a ref).
+/// Returns its full name, e.g. `refs/heads/master` for `master`, and the object it points at.
+pub fn dwim_ref(repo: &Repo, name: &str) -> Result<Option<(String, String)>, ReadObjectError> {
+	if name.is_empty() || (name.len() == 40 && name.bytes().all(|b| b.is_ascii_hexdigit())) {
+		return Ok(None);
+	}
+	for rule in REF_RULES {
+		let ref_name = rule.replace("%s", name);
+		// Only full ref names and names like `HEAD` or `ORIG_HEAD` are looked for directly in
+		// the git directory.
+		if rule == "%s"
+			&& !name.starts_with("refs/")
+			&& !name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_')
+		{
+			continue;
+		}
+		if repo.git_dir().join(&ref_name).is_dir() {
+			continue;
+		}
+		if let Some(hash) = refs::resolve_ref(repo, &ref_name)? {
+			return Ok(Some((ref_name, hash.to_ascii_lowercase())));
+		}
+	}
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::borrow::Cow;
+
+	use super::*;
+	use crate::test_utils::{init_repo, write_raw_object};
+	use crate::{create_commit, hash_git_object, ObjectKind, TreeEntry};
+
+	#[test]
+	fn revisions_resolve_and_peel() {
+		let (_dir, repo) = init_repo();
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"a\n")), true).unwrap();
+		let tree = hash_git_object(&repo, GitObject::Tree(Cow::Borrowed(&[])), true).unwrap();
+		let commit = create_commit(
+			&repo,
+			tree.hash_str.clone(),
+			Vec::new(),
+			"first".to_string(),
+		)
+		.unwrap();
+		refs::update_ref(&repo, "HEAD", &commit.hash_str, None).unwrap();
+		let tag_payload = format!(
+			"object {}\ntype commit\ntag v1\ntagger A <a@example.com> 0 +0000\n\nv1\n",
+			commit.hash_str
+		);
+		let tag = write_raw_object(&repo, ObjectKind::Tag, tag_payload.as_bytes());
+		refs::write_ref(&repo, "refs/tags/v1", &tag).unwrap();
+
+		let resolve = |name: &str| resolve_revision(&repo, name).ok();
+		assert_eq!(resolve("HEAD"), Some(commit.hash_str.clone()));
+		assert_eq!(resolve("master"), Some(commit.hash_str.clone()));
+		assert_eq!(resolve("refs/heads/master"), Some(commit.hash_str.clone()));
+		assert_eq!(
+			resolve(&commit.hash_str[..7]),
+			Some(commit.hash_str.clone())
+		);
+		assert_eq!(resolve("v1"), Some(tag.clone()));
+		assert_eq!(resolve("v1^{}"), Some(commit.hash_str.clone()));
+		assert_eq!(resolve("v1^{commit}"), Some(commit.hash_str.clone()));
+		assert_eq!(resolve("v1^{tree}"), Some(tree.hash_str.clone()));
+		assert_eq!(resolve("v1^{tag}"), Some(tag.clone()));
+		assert_eq!(resolve("HEAD^{tree}"), Some(tree.hash_str.clone()));
+		assert_eq!(resolve("HEAD^{object}"), Some(commit.hash_str.clone()));
+		assert_eq!(
+			resolve(&format!("{}^{{}}", blob.hash_str)),
+			Some(blob.hash_str.clone())
+		);
+		assert_eq!(resolve("HEAD^{blob}"), None);
+		assert_eq!(resolve("HEAD^{tag}"), None);
+		assert_eq!(resolve("nope"), None);
+		assert_eq!(resolve("objects"), None);
+
+		assert_eq!(resolve("@"), Some(commit.hash_str.clone()));
+		assert_eq!(resolve("v1^{}^{tree}"), Some(tree.hash_str.clone()));
+
+		assert_eq!(
+			dwim_ref(&repo, "master").unwrap(),
+			Some(("refs/heads/master".to_string(), commit.hash_str.clone()))
+		);
+		assert_eq!(
+			dwim_ref(&repo, "v1").unwrap(),
+			Some(("refs/tags/v1".to_string(), tag.clone()))
+		);
+	}
+
+	#[test]
+	fn ancestors_and_paths_resolve() {
+		let (_dir, repo) = init_repo();
+		let blob = hash_git_object(&repo, GitObject::Blob(Cow::Borrowed(b"a\n")), true).unwrap();
+		let entry = |name: &'static str, mode, hash: [u8; 20]| TreeEntry {
+			mode,
+			name: Cow::Borrowed(name),
+			object_hash: Cow::Owned(hash),
+		};
+		let subtree = hash_git_object(
+			&repo,
+			GitObject::Tree(Cow::Owned(vec![entry("a", 0o100644, blob.hash)])),
+			true,
+		)
+		.unwrap();
+		let tree = hash_git_object(
+			&repo,
+			GitObject::Tree(Cow::Owned(vec![entry("dir", 0o40000, subtree.hash)])),
+			true,
+		)
+		.unwrap();
+		let commit = |parents: Vec<&str>, message: &str| {
+			let parents = parents.into_iter().map(str::to_string).collect();
+			create_commit(&repo, tree.hash_str.clone(), parents, message.to_string())
+				.unwrap()
+				.hash_str
+		};
+		// first <- second <- merge, side <- merge
+		let first = commit(vec![], "first");
+		let side = commit(vec![&first], "side");
+		let second = commit(vec![&first], "second");
+		let merge = commit(vec![&second, &side], "merge");
+		refs::update_ref(&repo, "HEAD", &merge, None).unwrap();
+
+		let resolve = |name: &str| resolve_revision(&repo, name).ok();
+		assert_eq!(resolve("HEAD^"), Some(second.clone()));
+		assert_eq!(resolve("HEAD^1"), Some(second.clone()));
+		assert_eq!(resolve("HEAD^2"), Some(side.clone()));
+		assert_eq!(resolve("HEAD^0"), Some(merge.clone()));
+		assert_eq!(resolve("HEAD~"), Some(second.clone()));
+		assert_eq!(resolve("HEAD~2"), Some(first.clone()));
+		assert_eq!(resolve("HEAD^2~1"), Some(first.clone()));
+		assert_eq!(resolve("@~0"), Some(merge.clone()));
+		assert_eq!(resolve("HEAD^3"), None);
+		assert_eq!(resolve("HEAD~3"), None);
+		assert_eq!(resolve("HEAD^{tree}~1"), None);
+
+		assert_eq!(resolve("HEAD:"), Some(tree.hash_str.clone()));
+		assert_eq!(resolve("HEAD~2:dir"), Some(subtree.hash_str.clone()));
+		assert_eq!(resolve("HEAD:dir/a"), Some(blob.hash_str.clone()));
+		assert_eq!(resolve("HEAD:dir/nope"), None);
+		assert_eq!(resolve("HEAD:dir/a/b"), None);
+		assert_eq!(resolve("nope:dir"), None);
+		// Everything after the colon is the path, peeling happens on the resolved object.
+		assert_eq!(resolve("HEAD:dir^{tree}"), None);
+		let peel = |name: &str, kind| peel(&repo, resolve(name).unwrap(), kind).unwrap();
+		assert_eq!(peel("HEAD:dir", "tree"), Some(subtree.hash_str.clone()));
+		assert_eq!(peel("HEAD:dir/a", "tree"), None);
+		assert_eq!(peel("HEAD:dir/a", ""), Some(blob.hash_str.clone()));
+	}
+}
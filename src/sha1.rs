@@ -1,41 +1,92 @@
-/// Software SHA1 implementation
+/// Software SHA1 implementation, fed incrementally with [Sha1::update].
 /// Source 1: https://en.wikipedia.org/wiki/SHA-1
 /// Source 2: https://csrc.nist.gov/files/pubs/fips/180-2/upd1/final/docs/fips180-2withchangenotice.pdf
-pub fn sha1(data: &[u8]) -> [u8; 20] {
-	let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
-
-	let mut data = data.to_vec();
+#[derive(Debug, Clone)]
+pub struct Sha1 {
+	h: [u32; 5],
+	/// Start of the next block, until it is complete.
+	buffer: [u8; 64],
+	buffer_len: usize,
+	/// Bytes hashed so far.
+	len: u64,
+}
 
-	let message_len_in_bits = data.len() * 8;
-	data.push(0x80_u8);
+impl Default for Sha1 {
+	fn default() -> Self {
+		Self::new()
+	}
+}
 
-	let mut padding_needed = 448_i64 - ((message_len_in_bits + 1).rem_euclid(512) as i64);
-	if padding_needed < 0 {
-		padding_needed = (512 - ((message_len_in_bits + 1).rem_euclid(512) as i64)) + 448;
+impl Sha1 {
+	pub fn new() -> Self {
+		Sha1 {
+			h: [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0],
+			buffer: [0; 64],
+			buffer_len: 0,
+			len: 0,
+		}
 	}
 
-	padding_needed -= 7;
-	debug_assert_eq!(padding_needed % 8, 0);
+	pub fn update(&mut self, mut data: &[u8]) {
+		self.len += data.len() as u64;
 
-	let byte_padding_needed = padding_needed / 8;
-	data.extend(std::iter::repeat(0_u8).take(byte_padding_needed as usize));
-	data.extend(message_len_in_bits.to_be_bytes());
+		if self.buffer_len > 0 {
+			let take = data.len().min(64 - self.buffer_len);
+			self.buffer[self.buffer_len..self.buffer_len + take].copy_from_slice(&data[..take]);
+			self.buffer_len += take;
+			data = &data[take..];
+			if self.buffer_len < 64 {
+				return;
+			}
+			let block = self.buffer;
+			self.compress(&block);
+			self.buffer_len = 0;
+		}
 
-	let data_u32: &mut [u32] =
-		unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr().cast::<u32>(), data.len() / 4) };
+		let mut blocks = data.chunks_exact(64);
+		for block in &mut blocks {
+			self.compress(block.try_into().expect("chunks are 64 bytes"));
+		}
+		let rest = blocks.remainder();
+		self.buffer[..rest.len()].copy_from_slice(rest);
+		self.buffer_len = rest.len();
+	}
 
-	// Convert the message into 32bit big endian words.
-	data_u32.iter_mut().for_each(|n| *n = n.to_be());
+	pub fn finalize(mut self) -> [u8; 20] {
+		let message_len_in_bits = self.len.wrapping_mul(8);
 
-	for chunk in data_u32.chunks_exact_mut(16) {
+		// A one bit, zeros up to 8 bytes before the end of a block, then the length.
+		let padding_len = if self.buffer_len < 56 {
+			64 - self.buffer_len
+		} else {
+			128 - self.buffer_len
+		};
+		let mut padding = [0_u8; 128];
+		padding[0] = 0x80;
+		padding[padding_len - 8..padding_len].copy_from_slice(&message_len_in_bits.to_be_bytes());
+		self.update(&padding[..padding_len]);
+		debug_assert_eq!(self.buffer_len, 0);
+
+		let mut out = [0u8; 20];
+		for (i, word) in self.h.iter().enumerate() {
+			out[(i * 4)..((i + 1) * 4)].copy_from_slice(&word.to_be_bytes());
+		}
+		out
+	}
+
+	fn compress(&mut self, block: &[u8; 64]) {
 		let mut w = [0_u32; 80];
-		w[0..16].copy_from_slice(chunk);
+		// Convert the block into 32bit big endian words.
+		for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+			*word = u32::from_be_bytes(bytes.try_into().expect("chunks are 4 bytes"));
+		}
 
 		// Message schedule: extend the sixteen 32-bit words into eighty 32-bit words:
 		for i in 16..=79 {
 			w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
 		}
 
+		let h = &mut self.h;
 		// Initialize hash value for this chunk:
 		let mut a = h[0];
 		let mut b = h[1];
@@ -86,13 +137,24 @@ pub fn sha1(data: &[u8]) -> [u8; 20] {
 		h[3] = h[3].wrapping_add(d);
 		h[4] = h[4].wrapping_add(e);
 	}
+}
+
+impl std::io::Write for Sha1 {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.update(buf);
+		Ok(buf.len())
+	}
 
-	let mut out = [0u8; 20];
-	for i in 0..5 {
-		let slice = &mut out[(i * 4)..((i + 1) * 4)];
-		slice.copy_from_slice(h[i].to_be_bytes().as_slice());
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
 	}
-	out
+}
+
+/// SHA1 of `data` in one go.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+	let mut hasher = Sha1::new();
+	hasher.update(data);
+	hasher.finalize()
 }
 
 #[cfg(test)]
@@ -155,4 +217,41 @@ mod tests {
 
 		assert_eq!(result.as_slice(), sha1_result.as_slice());
 	}
+
+	#[test]
+	fn incremental_updates() {
+		let data = "The quick brown fox jumps over the lazy dog";
+		let mut hasher = Sha1::new();
+		for part in ["The quick", " brown fox ", "", "jumps over the lazy dog"] {
+			hasher.update(part.as_bytes());
+		}
+		assert_eq!(hasher.finalize(), sha1(data.as_bytes()));
+
+		let mut hasher = Sha1::new();
+		for byte in data.as_bytes() {
+			hasher.update(&[*byte]);
+		}
+		assert_eq!(
+			hex::encode(hasher.finalize()),
+			"2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"
+		);
+	}
+
+	#[test]
+	fn block_boundaries() {
+		use ::sha1::Digest;
+
+		// Padding fits in the last block or needs another one
+		for len in [55, 56, 63, 64, 65, 119, 120, 200] {
+			let data: Vec<u8> = (0..len).map(|n| n as u8).collect();
+			let expected = ::sha1::Sha1::digest(&data);
+			assert_eq!(sha1(&data).as_slice(), expected.as_slice(), "length {len}");
+
+			let mut hasher = Sha1::new();
+			for part in data.chunks(7) {
+				hasher.update(part);
+			}
+			assert_eq!(hasher.finalize().as_slice(), expected.as_slice());
+		}
+	}
 }
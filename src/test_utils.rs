@@ -0,0 +1,181 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use flate2::write::ZlibEncoder;
+
+use crate::repo::Repo;
+use crate::{compute_id, init, sha1, ObjectId, ObjectKind};
+
+/// Paths of the objects compressed so far, by every test, to check how often one is written.
+pub static COMPRESSED_OBJECTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Temporary directory removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+	pub fn new() -> Self {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+		let path = std::env::temp_dir().join(format!(
+			"git-starter-rust-{}-{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		fs::create_dir_all(&path).unwrap();
+		TempDir(path)
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+/// Creates a fresh repository inside a temporary directory.
+pub fn init_repo() -> (TempDir, Repo) {
+	let dir = TempDir::new();
+	let repo = Repo::new(dir.0.join(".git"));
+	init(&repo).unwrap();
+	(dir, repo)
+}
+
+/// Stores an object with given kind and payload as is, without going through the encoder.
+/// Returns its name.
+pub fn write_raw_object(repo: &Repo, kind: ObjectKind, payload: &[u8]) -> String {
+	let hash = hex::encode(compute_id(kind, payload));
+	let path = repo.object_path(&hash);
+	fs::create_dir_all(path.parent().unwrap()).unwrap();
+	let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+	write!(encoder, "{} {}\0", kind.as_str(), payload.len()).unwrap();
+	encoder.write_all(payload).unwrap();
+	fs::write(&path, encoder.finish().unwrap()).unwrap();
+	hash
+}
+
+/// Encodes `objects`, given by pack type number (1 commit, 2 tree, 3 blob, 4 tag, 6 and 7 for
+/// deltas) and payload, as a version 2 packfile.
+pub fn pack_of(objects: &[(u8, &[u8])]) -> Vec<u8> {
+	encode_pack(objects).0
+}
+
+/// Like [pack_of], also returning the offset of each object.
+fn encode_pack(objects: &[(u8, &[u8])]) -> (Vec<u8>, Vec<usize>) {
+	let mut pack = b"PACK".to_vec();
+	pack.extend(2_u32.to_be_bytes());
+	pack.extend((objects.len() as u32).to_be_bytes());
+	let mut offsets = Vec::new();
+	for (kind, payload) in objects {
+		offsets.push(pack.len());
+		let mut size = payload.len();
+		let mut byte = (kind << 4) | (size & 0b1111) as u8;
+		size >>= 4;
+		while size > 0 {
+			pack.push(byte | 0x80);
+			byte = (size & 0x7f) as u8;
+			size >>= 7;
+		}
+		pack.push(byte);
+		let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+		encoder.write_all(payload).unwrap();
+		pack.extend(encoder.finish().unwrap());
+	}
+	let checksum = sha1::sha1(&pack);
+	pack.extend(checksum);
+	(pack, offsets)
+}
+
+/// Stores `objects` (see [pack_of]) in a pack with a version 2 index in `objects/pack/`.
+/// Returns the ids of the objects.
+pub fn write_pack(repo: &Repo, objects: &[(u8, &[u8])]) -> Vec<ObjectId> {
+	let (pack, offsets) = encode_pack(objects);
+	let kinds = [
+		ObjectKind::Commit,
+		ObjectKind::Tree,
+		ObjectKind::Blob,
+		ObjectKind::Tag,
+	];
+	let ids: Vec<ObjectId> = objects
+		.iter()
+		.map(|(kind, payload)| compute_id(kinds[*kind as usize - 1], payload))
+		.collect();
+	let mut sorted: Vec<(ObjectId, usize)> = ids.iter().copied().zip(offsets).collect();
+	sorted.sort();
+
+	let mut idx = b"\xfftOc".to_vec();
+	idx.extend(2_u32.to_be_bytes());
+	for byte in 0..=255 {
+		let count = sorted.iter().filter(|(id, _)| id[0] <= byte).count();
+		idx.extend((count as u32).to_be_bytes());
+	}
+	for (id, _) in &sorted {
+		idx.extend(id);
+	}
+	// CRC32s aren't checked when reading.
+	idx.extend(vec![0; sorted.len() * 4]);
+	for (_, offset) in &sorted {
+		idx.extend((*offset as u32).to_be_bytes());
+	}
+	let pack_checksum = &pack[pack.len() - 20..];
+	idx.extend(pack_checksum);
+	idx.extend(sha1::sha1(&idx));
+
+	let dir = repo.objects_dir().join("pack");
+	fs::create_dir_all(&dir).unwrap();
+	let name = format!("pack-{}", hex::encode(pack_checksum));
+	fs::write(dir.join(format!("{name}.pack")), &pack).unwrap();
+	fs::write(dir.join(format!("{name}.idx")), idx).unwrap();
+	ids
+}
+
+/// Request line and body of a request [serve_http] answered.
+pub type HttpRequest = (String, Vec<u8>);
+
+/// Serves `responses` over HTTP on a local port, one request each and in order, like a
+/// fixture git server. Returns the base URL, and a thread yielding the requests once every
+/// response was sent.
+pub fn serve_http(responses: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<HttpRequest>>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+	let server = std::thread::spawn(move || {
+		let mut requests = Vec::new();
+		for response in responses {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(&stream);
+			let mut request_line = String::new();
+			reader.read_line(&mut request_line).unwrap();
+			let mut len = 0;
+			loop {
+				let mut header = String::new();
+				reader.read_line(&mut header).unwrap();
+				if header.trim_end().is_empty() {
+					break;
+				}
+				if let Some((name, value)) = header.split_once(':') {
+					if name.eq_ignore_ascii_case("content-length") {
+						len = value.trim().parse().unwrap();
+					}
+				}
+			}
+			let mut body = vec![0; len];
+			reader.read_exact(&mut body).unwrap();
+			requests.push((request_line.trim_end().to_string(), body));
+
+			let mut stream = &stream;
+			write!(
+				stream,
+				"HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+				response.len()
+			)
+			.unwrap();
+			stream.write_all(&response).unwrap();
+		}
+		requests
+	});
+	(url, server)
+}
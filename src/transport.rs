@@ -0,0 +1,311 @@
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use thiserror::Error;
+
+use crate::ObjectId;
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+	#[error("HTTP request to {url} failed: {err}")]
+	Http {
+		url: String,
+		#[source]
+		err: reqwest::Error,
+	},
+
+	#[error("Malformed pkt-line: {0}")]
+	MalformedPktLine(&'static str),
+
+	#[error("Unexpected response from the server: {0}")]
+	UnexpectedResponse(String),
+}
+
+/// A line of the pkt-line format: its payload, or `None` for a flush packet (`0000`).
+type PktLine<'a> = Option<&'a [u8]>;
+
+/// Encodes `payload` as a pkt-line: 4 hex digits of length (including themselves), then the
+/// payload.
+fn pkt_line(payload: &[u8]) -> Vec<u8> {
+	let mut line = format!("{:04x}", payload.len() + 4).into_bytes();
+	line.extend_from_slice(payload);
+	line
+}
+
+/// Flush packet, ending a section of pkt-lines.
+const FLUSH_PKT: &[u8] = b"0000";
+
+/// Reads the pkt-line at the start of `data`, returning it and the rest of the data.
+fn read_pkt_line(data: &[u8]) -> Result<(PktLine<'_>, &[u8]), TransportError> {
+	let len = data
+		.get(..4)
+		.and_then(|len| std::str::from_utf8(len).ok())
+		.and_then(|len| usize::from_str_radix(len, 16).ok())
+		.ok_or(TransportError::MalformedPktLine("invalid length"))?;
+	match len {
+		0 => Ok((None, &data[4..])),
+		1..=3 => Err(TransportError::MalformedPktLine("invalid length")),
+		len => {
+			let line = data
+				.get(4..len)
+				.ok_or(TransportError::MalformedPktLine("truncated line"))?;
+			Ok((Some(line), &data[len..]))
+		}
+	}
+}
+
+/// Splits `data` into pkt-lines until `flushes` flush packets have been read. Returns the lines
+/// and the rest of the data.
+fn read_pkt_lines(
+	mut data: &[u8],
+	flushes: usize,
+) -> Result<(Vec<PktLine<'_>>, &[u8]), TransportError> {
+	let mut lines = Vec::new();
+	let mut flushed = 0;
+	while flushed < flushes {
+		let (line, rest) = read_pkt_line(data)?;
+		flushed += usize::from(line.is_none());
+		lines.push(line);
+		data = rest;
+	}
+	Ok((lines, data))
+}
+
+/// References a remote advertises.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RemoteRefs {
+	/// Branch `HEAD` points at, e.g. `refs/heads/main`, if the server told.
+	pub head_target: Option<String>,
+	/// Every advertised ref, `HEAD` included, with the object it points at.
+	pub refs: Vec<(String, ObjectId)>,
+}
+
+/// Parses the response of `GET <url>/info/refs?service=git-upload-pack`: a
+/// `# service=git-upload-pack` line and a flush, then a line per ref (the first one followed by
+/// a NUL and the capabilities) and a flush.
+pub fn parse_ref_advertisement(data: &[u8]) -> Result<RemoteRefs, TransportError> {
+	let (lines, _) = read_pkt_lines(data, 2)?;
+	let mut lines = lines.into_iter();
+	match lines.next() {
+		Some(Some(b"# service=git-upload-pack\n")) => {}
+		_ => {
+			return Err(TransportError::UnexpectedResponse(
+				"missing service announcement".to_string(),
+			))
+		}
+	}
+	if lines.next() != Some(None) {
+		return Err(TransportError::MalformedPktLine("expected flush"));
+	}
+
+	let mut remote_refs = RemoteRefs::default();
+	for line in lines.flatten() {
+		let line = line.strip_suffix(b"\n").unwrap_or(line);
+		let (line, capabilities) = match line.iter().position(|b| *b == 0) {
+			Some(nul) => (&line[..nul], &line[nul + 1..]),
+			None => (line, &b""[..]),
+		};
+		let line = String::from_utf8_lossy(line);
+		let Some((hash, name)) = line.split_once(' ') else {
+			return Err(TransportError::UnexpectedResponse(line.into_owned()));
+		};
+		let mut id = [0_u8; 20];
+		if hex::decode_to_slice(hash, &mut id).is_err() {
+			return Err(TransportError::UnexpectedResponse(line.into_owned()));
+		}
+		// An empty repository advertises `capabilities^{}` with a null id.
+		if name != "capabilities^{}" {
+			remote_refs.refs.push((name.to_string(), id));
+		}
+
+		for capability in String::from_utf8_lossy(capabilities).split(' ') {
+			if let Some(target) = capability.strip_prefix("symref=HEAD:") {
+				remote_refs.head_target = Some(target.to_string());
+			}
+		}
+	}
+	Ok(remote_refs)
+}
+
+/// Discovers the refs of the repository at `url` through the smart HTTP protocol.
+pub fn discover_refs(client: &Client, url: &str) -> Result<RemoteRefs, TransportError> {
+	let url = format!(
+		"{}/info/refs?service=git-upload-pack",
+		url.trim_end_matches('/')
+	);
+	let response = client
+		.get(&url)
+		.send()
+		.and_then(|response| response.error_for_status())
+		.and_then(|response| response.bytes())
+		.map_err(|err| TransportError::Http {
+			url: url.clone(),
+			err,
+		})?;
+	parse_ref_advertisement(&response)
+}
+
+/// Body of a `git-upload-pack` request asking for `wants` and everything they reference, with
+/// no objects to negotiate since there are none locally. With a `depth`, only the commits at
+/// most that many commits away from the wants are asked for (`deepen`), which needs the
+/// `shallow` capability.
+fn upload_pack_request(wants: &[ObjectId], depth: Option<u32>) -> Vec<u8> {
+	let mut request = Vec::new();
+	for (i, want) in wants.iter().enumerate() {
+		let capabilities = if i == 0 && depth.is_some() {
+			" shallow"
+		} else {
+			""
+		};
+		request.extend(pkt_line(
+			format!("want {}{capabilities}\n", hex::encode(want)).as_bytes(),
+		));
+	}
+	if let Some(depth) = depth {
+		request.extend(pkt_line(format!("deepen {depth}\n").as_bytes()));
+	}
+	request.extend_from_slice(FLUSH_PKT);
+	request.extend(pkt_line(b"done\n"));
+	request
+}
+
+/// Packfile sent by the server, and the commits it left the parents out of when deepening.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FetchedPack {
+	pub shallow: Vec<ObjectId>,
+	pub pack: Vec<u8>,
+}
+
+/// Fetches a packfile with the objects reachable from `wants` from the repository at `url`,
+/// only `depth` commits deep if given (see [upload_pack_request]).
+pub fn fetch_pack(
+	client: &Client,
+	url: &str,
+	wants: &[ObjectId],
+	depth: Option<u32>,
+) -> Result<FetchedPack, TransportError> {
+	let url = format!("{}/git-upload-pack", url.trim_end_matches('/'));
+	let response = client
+		.post(&url)
+		.header(CONTENT_TYPE, "application/x-git-upload-pack-request")
+		.body(upload_pack_request(wants, depth))
+		.send()
+		.and_then(|response| response.error_for_status())
+		.and_then(|response| response.bytes())
+		.map_err(|err| TransportError::Http {
+			url: url.clone(),
+			err,
+		})?;
+	parse_upload_pack_response(&response, depth.is_some())
+}
+
+/// Reads an upload-pack response: when `deepened`, the shallow-update section (a
+/// `shallow <id>` line per commit whose parents are left out, then a flush), then the packfile,
+/// which follows a `NAK` line since nothing was negotiated (and no side-band was asked for).
+fn parse_upload_pack_response(
+	mut data: &[u8],
+	deepened: bool,
+) -> Result<FetchedPack, TransportError> {
+	let mut shallow = Vec::new();
+	if deepened {
+		let (lines, rest) = read_pkt_lines(data, 1)?;
+		data = rest;
+		for line in lines.into_iter().flatten() {
+			let line = String::from_utf8_lossy(line);
+			let line = line.trim_end_matches('\n');
+			let mut id = [0_u8; 20];
+			match line.strip_prefix("shallow ") {
+				Some(hash) if hex::decode_to_slice(hash, &mut id).is_ok() => shallow.push(id),
+				_ => return Err(TransportError::UnexpectedResponse(line.to_string())),
+			}
+		}
+	}
+	match read_pkt_line(data)? {
+		(Some(b"NAK\n" | b"NAK"), pack) => Ok(FetchedPack {
+			shallow,
+			pack: pack.to_vec(),
+		}),
+		(line, _) => Err(TransportError::UnexpectedResponse(
+			String::from_utf8_lossy(line.unwrap_or_default()).into_owned(),
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pkt_lines_round_trip() {
+		assert_eq!(pkt_line(b"done\n"), b"0009done\n");
+		let mut data = pkt_line(b"a\n");
+		data.extend_from_slice(FLUSH_PKT);
+		data.extend(b"rest");
+		let (lines, rest) = read_pkt_lines(&data, 1).unwrap();
+		assert_eq!(lines, [Some(&b"a\n"[..]), None]);
+		assert_eq!(rest, b"rest");
+		assert!(read_pkt_lines(b"00zz", 1).is_err());
+		assert!(read_pkt_lines(b"0010abc", 1).is_err());
+	}
+
+	#[test]
+	fn parses_ref_advertisement() {
+		let head = "1".repeat(40);
+		let branch = "2".repeat(40);
+		let mut data = pkt_line(b"# service=git-upload-pack\n");
+		data.extend_from_slice(FLUSH_PKT);
+		data.extend(pkt_line(
+			format!("{head} HEAD\0multi_ack symref=HEAD:refs/heads/main agent=git/2.40\n")
+				.as_bytes(),
+		));
+		data.extend(pkt_line(format!("{head} refs/heads/main\n").as_bytes()));
+		data.extend(pkt_line(format!("{branch} refs/heads/topic\n").as_bytes()));
+		data.extend_from_slice(FLUSH_PKT);
+
+		let refs = parse_ref_advertisement(&data).unwrap();
+		assert_eq!(refs.head_target.as_deref(), Some("refs/heads/main"));
+		assert_eq!(
+			refs.refs,
+			[
+				("HEAD".to_string(), [0x11; 20]),
+				("refs/heads/main".to_string(), [0x11; 20]),
+				("refs/heads/topic".to_string(), [0x22; 20]),
+			]
+		);
+	}
+
+	#[test]
+	fn upload_pack_exchange() {
+		assert_eq!(
+			upload_pack_request(&[[0xab; 20]], None),
+			format!("0032want {}\n00000009done\n", "ab".repeat(20)).as_bytes()
+		);
+		assert_eq!(
+			parse_upload_pack_response(b"0008NAK\nPACK...", false)
+				.unwrap()
+				.pack,
+			b"PACK..."
+		);
+		assert!(parse_upload_pack_response(b"000aERR x\n", false).is_err());
+
+		assert_eq!(
+			upload_pack_request(&[[0xab; 20], [0xcd; 20]], Some(2)),
+			format!(
+				"003awant {} shallow\n0032want {}\n000ddeepen 2\n00000009done\n",
+				"ab".repeat(20),
+				"cd".repeat(20)
+			)
+			.as_bytes()
+		);
+		let mut response = pkt_line(format!("shallow {}\n", "ef".repeat(20)).as_bytes());
+		response.extend_from_slice(FLUSH_PKT);
+		response.extend(b"0008NAK\nPACK...");
+		assert_eq!(
+			parse_upload_pack_response(&response, true).unwrap(),
+			FetchedPack {
+				shallow: vec![[0xef; 20]],
+				pack: b"PACK...".to_vec(),
+			}
+		);
+		assert!(parse_upload_pack_response(b"0008NAK\nPACK...", true).is_err());
+	}
+}